@@ -0,0 +1,350 @@
+use anyhow::Result;
+use embassy_time::Timer;
+use embedded_graphics::prelude::OriginDimensions;
+use embedded_graphics_core::{
+    pixelcolor::Rgb888,
+    prelude::{DrawTarget, Size},
+    primitives::Rectangle,
+};
+use log::info;
+use std::{borrow::Borrow, ops::RangeInclusive, sync::Mutex};
+
+use esp_idf_svc::hal::{
+    gpio::{AnyOutputPin, Output, Pin, PinDriver},
+    prelude::*,
+    spi::{config::LineWidth, *},
+    task::*,
+};
+
+use super::{pair_aligned, PanelRam, HEIGHT, WIDTH};
+use crate::anyesp;
+
+/// The panel's columns start this far into its frame memory
+const COLUMN_OFFSET: u16 = 16;
+
+/// Held while a frame streams out with the bus to itself, so the other devices on the bus can
+/// tell they would have to wait for the whole frame
+pub static STREAMING: Mutex<()> = Mutex::new(());
+
+pub struct Rm690B0<'d, T>
+where
+    T: Borrow<SpiDriver<'d>> + 'd,
+{
+    qspi: SpiDeviceDriver<'d, T>,
+    reset: PinDriver<'d, AnyOutputPin, Output>,
+}
+
+macro_rules! command_ops {
+    ($header: expr; $cmd: expr, $($itms:expr),*) => {
+        {
+        super let header: [u8; 1] = [$header];
+        super let cmd_addr: [u8; 3] = [0x00, $cmd, 0x00];
+        [
+            Operation::WriteWithWidth(&header, ::esp_idf_svc::hal::spi::config::LineWidth::Single),
+            Operation::WriteWithWidth(&cmd_addr, ::esp_idf_svc::hal::spi::config::LineWidth::Single),
+            $($itms),*
+        ]
+        }
+    };
+    ($cmd: expr$(, $itms:expr)*) => {
+        command_ops![0x02; $cmd, $($itms),*]
+    };
+    (p: $cmd: expr$(, $itms:expr)*) => {
+        command_ops![0x32; $cmd, $($itms),*]
+    };
+}
+
+macro_rules! write_buf {
+    ($buf: expr) => {{
+        super let buf = $buf;
+
+        Operation::WriteWithWidth(&buf, ::esp_idf_svc::hal::spi::config::LineWidth::Single)
+    }};
+
+    (q: $buf: expr) => {{
+        super let buf = $buf;
+
+        Operation::WriteWithWidth(&buf, ::esp_idf_svc::hal::spi::config::LineWidth::Quad)
+    }};
+}
+
+impl<'d, T> Rm690B0<'d, T>
+where
+    T: Borrow<SpiDriver<'d>> + 'd,
+{
+    pub fn new(qspi: SpiDeviceDriver<'d, T>, reset: AnyOutputPin) -> Result<Self> {
+        Ok(Self {
+            qspi,
+            reset: PinDriver::output(reset)?,
+            // buf: [0; WIDTH * HEIGHT * 3],
+        })
+    }
+
+    pub async fn init(&mut self) -> Result<()> {
+        self.reset.set_high()?;
+
+        Timer::after_millis(1).await;
+
+        self.reset.set_low()?;
+
+        Timer::after_millis(20).await;
+
+        self.reset.set_high()?;
+
+        Timer::after_millis(50).await;
+
+        info!("AMOLED RESET complete");
+
+        self.manufacturer_init().await?;
+
+        info!("AMOLED manufacturer init complete");
+
+        Ok(())
+    }
+
+    async fn manufacturer_init(&mut self) -> Result<()> {
+        // Set page
+        let mut ops = command_ops![0xFE, write_buf!([0x20])];
+        self.qspi.transaction(&mut ops)?;
+
+        // MIPI off
+        let mut ops = command_ops![0x26, write_buf!([0x0A])];
+        self.qspi.transaction(&mut ops)?;
+
+        // SPI write RAM
+        let mut ops = command_ops![0x24, write_buf!([0x80])];
+        self.qspi.transaction(&mut ops)?;
+
+        // Set page
+        let mut ops = command_ops![0xFE, write_buf!([0x00])];
+        self.qspi.transaction(&mut ops)?;
+
+        // Pixel format (8 bit color)
+        let mut ops = command_ops![0x3A, write_buf!([0x77])];
+        self.qspi.transaction(&mut ops)?;
+
+        // Display mode (internal timing)
+        let mut ops = command_ops![0xC2, write_buf!([0x00])];
+        self.qspi.transaction(&mut ops)?;
+
+        Timer::after_millis(10).await;
+
+        // Tearing Effect off
+        self.qspi.transaction(&mut command_ops![0x34])?;
+
+        // Display brightness 0
+        let mut ops = command_ops![0x51, write_buf!([0x00])];
+        self.qspi.transaction(&mut ops)?;
+
+        // Leave sleep
+        let mut ops = command_ops![0x11];
+        self.qspi.transaction(&mut ops)?;
+
+        Timer::after_millis(120).await;
+
+        // Display on
+        let mut ops = command_ops![0x29];
+        self.qspi.transaction(&mut ops)?;
+
+        Timer::after_millis(10).await;
+
+        // Brightness
+        // Max is 0xFF but only using 50% for Feather since regulator is kinda weak
+        let mut ops = command_ops![0x51, write_buf!([0x80])];
+        self.qspi.transaction(&mut ops)?;
+
+        Ok(())
+    }
+
+    pub fn all_pixels(&mut self, on: bool) -> Result<()> {
+        self.qspi
+            .transaction(&mut command_ops![if on { 0x23 } else { 0x22 }])?;
+
+        Ok(())
+    }
+
+    pub fn write_pixels(&mut self, pixels: &[u8]) -> Result<()> {
+        self.write_pixels_from_iterator(pixels.iter().cloned())
+    }
+}
+
+impl<'d, T> PanelRam for Rm690B0<'d, T>
+where
+    T: Borrow<SpiDriver<'d>> + 'd,
+{
+    type Error = anyhow::Error;
+
+    fn set_column_range(&mut self, col: RangeInclusive<u16>) -> Result<()> {
+        let col = pair_aligned(col);
+        let col = col.start() + COLUMN_OFFSET..=col.end() + COLUMN_OFFSET;
+
+        self.qspi.transaction(&mut command_ops![
+            0x2A,
+            write_buf!([
+                (col.start() >> 8) as u8,
+                (col.start() & 0xFF) as u8,
+                (col.end() >> 8) as u8,
+                (col.end() & 0xFF) as u8
+            ])
+        ])?;
+
+        Ok(())
+    }
+
+    fn set_row_range(&mut self, row: RangeInclusive<u16>) -> Result<()> {
+        let row = pair_aligned(row);
+
+        self.qspi.transaction(&mut command_ops![
+            0x2B,
+            write_buf!([
+                (row.start() >> 8) as u8,
+                (row.start() & 0xFF) as u8,
+                (row.end() >> 8) as u8,
+                (row.end() & 0xFF) as u8
+            ])
+        ])?;
+
+        Ok(())
+    }
+
+    fn write_pixels_from_iterator<I>(&mut self, pixels: I) -> Result<()>
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        self.qspi.transaction(&mut command_ops![0x2C])?;
+
+        let header = [0x32_u8, 0x00, 0x2C, 0x00];
+        let mut pixels = header.into_iter().chain(pixels.into_iter()).peekable();
+
+        {
+            let mut header_consumed = false;
+            let handle = self.qspi.device();
+            let mut buf = [0u8; 64];
+
+            use esp_idf_svc::sys::*;
+            struct BusLock(spi_device_handle_t);
+
+            impl BusLock {
+                fn new(device: spi_device_handle_t) -> Result<Self, EspError> {
+                    use esp_idf_svc::hal::delay::BLOCK;
+                    esp!(unsafe { spi_device_acquire_bus(device, BLOCK) })?;
+
+                    Ok(Self(device))
+                }
+            }
+
+            impl Drop for BusLock {
+                fn drop(&mut self) {
+                    unsafe {
+                        spi_device_release_bus(self.0);
+                    }
+                }
+            }
+
+            // Dropped after the bus lock, so nobody finds the bus free while it is still taken
+            let mut _streaming = None;
+            let mut lock = None;
+            loop {
+                let mut offset = 0usize;
+                let buf_max = if header_consumed {
+                    buf.len()
+                } else {
+                    header.len()
+                };
+                while offset < buf_max {
+                    if let Some(word) = pixels.next() {
+                        buf[offset] = word;
+                        offset += 1;
+                    } else {
+                        break;
+                    }
+                }
+                // info!("OFFSET {offset}");
+
+                if offset == 0 {
+                    break;
+                }
+
+                let mut transaction = spi_transaction_t {
+                    __bindgen_anon_1: spi_transaction_t__bindgen_ty_1 {
+                        tx_buffer: buf.as_ptr() as *const _,
+                    },
+                    __bindgen_anon_2: spi_transaction_t__bindgen_ty_2 {
+                        rx_buffer: core::ptr::null_mut() as *mut _,
+                    },
+                    flags: if header_consumed {
+                        SPI_TRANS_MODE_QIO
+                    } else {
+                        0
+                    },
+                    length: offset * 8,
+                    rxlength: 0,
+                    ..Default::default()
+                };
+
+                if pixels.peek().is_some() {
+                    if lock.is_none() {
+                        _streaming = Some(
+                            STREAMING
+                                .lock()
+                                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+                        );
+                        lock = Some(BusLock::new(handle)?);
+                    }
+
+                    transaction.flags |= SPI_TRANS_CS_KEEP_ACTIVE;
+                }
+
+                unsafe {
+                    esp!(spi_device_polling_transmit(
+                        handle,
+                        &mut transaction as *mut _
+                    ))?;
+                }
+
+                header_consumed = true;
+            }
+        }
+
+        self.qspi.transaction(&mut command_ops![p: 0x00])?;
+
+        self.qspi.transaction(&mut command_ops![0x29])?;
+
+        Ok(())
+    }
+}
+
+impl<'d, T> OriginDimensions for Rm690B0<'d, T>
+where
+    T: Borrow<SpiDriver<'d>> + 'd,
+{
+    fn size(&self) -> Size {
+        Size::new(WIDTH as u32, HEIGHT as u32)
+    }
+}
+
+impl<'d, T> DrawTarget for Rm690B0<'d, T>
+where
+    T: Borrow<SpiDriver<'d>> + 'd,
+{
+    type Color = Rgb888;
+    type Error = anyhow::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> std::result::Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = embedded_graphics_core::Pixel<Self::Color>>,
+    {
+        super::draw_iter(self, pixels)
+    }
+
+    fn fill_contiguous<I>(
+        &mut self,
+        area: &Rectangle,
+        colors: I,
+    ) -> std::result::Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        super::fill_contiguous(self, area, colors)
+    }
+}
@@ -0,0 +1,126 @@
+//! An in-memory RM690B0 for the simulator.
+//!
+//! It emulates the panel's windowed frame memory rather than drawing pixels directly, so
+//! everything goes through the same [`PanelRam`] path as on the device, pixel pairing included.
+
+use core::convert::Infallible;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+
+use embedded_graphics_core::{
+    pixelcolor::Rgb888,
+    prelude::{DrawTarget, OriginDimensions, RgbColor, Size},
+    primitives::Rectangle,
+    Pixel,
+};
+
+use super::{pair_aligned, PanelRam, HEIGHT, WIDTH};
+
+struct Memory {
+    pixels: Vec<Rgb888>,
+    columns: RangeInclusive<u16>,
+    rows: RangeInclusive<u16>,
+}
+
+/// Shared handle to the simulated panel; clones see the same memory
+#[derive(Clone)]
+pub struct SimulatedPanel {
+    memory: Arc<Mutex<Memory>>,
+}
+
+impl Default for SimulatedPanel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimulatedPanel {
+    pub fn new() -> Self {
+        Self {
+            memory: Arc::new(Mutex::new(Memory {
+                pixels: vec![Rgb888::BLACK; WIDTH * HEIGHT],
+                columns: 0..=WIDTH as u16 - 1,
+                rows: 0..=HEIGHT as u16 - 1,
+            })),
+        }
+    }
+
+    /// Copy of every pixel, row by row from the top left
+    pub fn snapshot(&self) -> Vec<Rgb888> {
+        self.memory.lock().unwrap().pixels.clone()
+    }
+}
+
+impl PanelRam for SimulatedPanel {
+    type Error = Infallible;
+
+    fn set_column_range(&mut self, col: RangeInclusive<u16>) -> Result<(), Self::Error> {
+        self.memory.lock().unwrap().columns = pair_aligned(col);
+        Ok(())
+    }
+
+    fn set_row_range(&mut self, row: RangeInclusive<u16>) -> Result<(), Self::Error> {
+        self.memory.lock().unwrap().rows = pair_aligned(row);
+        Ok(())
+    }
+
+    fn write_pixels_from_iterator<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = u8>,
+    {
+        let mut memory = self.memory.lock().unwrap();
+        let columns = memory.columns.clone();
+        let rows = memory.rows.clone();
+
+        // An inverted window takes no pixels
+        if columns.is_empty() || rows.is_empty() {
+            return Ok(());
+        }
+
+        let (mut x, mut y) = (*columns.start(), *rows.start());
+        let mut bytes = pixels.into_iter();
+
+        while let (Some(r), Some(g), Some(b)) = (bytes.next(), bytes.next(), bytes.next()) {
+            if (x as usize) < WIDTH && (y as usize) < HEIGHT {
+                memory.pixels[y as usize * WIDTH + x as usize] = Rgb888::new(r, g, b);
+            }
+
+            // Like the panel, wrap back to the start of the window once it is full
+            x += 1;
+            if x > *columns.end() {
+                x = *columns.start();
+                y += 1;
+                if y > *rows.end() {
+                    y = *rows.start();
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl OriginDimensions for SimulatedPanel {
+    fn size(&self) -> Size {
+        Size::new(WIDTH as u32, HEIGHT as u32)
+    }
+}
+
+impl DrawTarget for SimulatedPanel {
+    type Color = Rgb888;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        super::draw_iter(self, pixels)
+    }
+
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        super::fill_contiguous(self, area, colors)
+    }
+}
@@ -0,0 +1,582 @@
+//! Beacon behavior, written against the [`hal`](crate::hal) traits so it runs anywhere.
+
+use core::fmt::Debug;
+use core::future::{pending, poll_fn, Future};
+use core::pin::pin;
+
+use anyhow::anyhow;
+use embassy_time::{with_deadline, Duration, Instant, Timer};
+use embedded_graphics::pixelcolor::Rgb888;
+use embedded_graphics::prelude::*;
+use log::{info, warn};
+use tokio::sync::{mpsc, watch};
+
+use crate::hal::{Lights, NfcReader, PowerMonitor, PowerSource, SegmentDisplay, TouchPanel};
+use crate::net::{backoff, Canceller, Connectivity, NetError};
+
+pub mod dimming;
+pub mod notification;
+pub mod state;
+pub mod sync;
+
+use self::dimming::NightDimming;
+use self::notification::{Notification, Patterns};
+use self::state::{BeaconState, Event, Screen};
+
+/// How often the outputs catch up with the time without any event, such as the minutes a ping
+/// has been waiting
+const REFRESH: Duration = Duration::from_millis(750);
+/// How often the NFC reader is asked for a passport
+const PASSPORT_POLL: Duration = Duration::from_millis(500);
+/// How often the fuel gauge is asked whether the board is on battery
+const POWER_POLL: Duration = Duration::from_secs(10);
+/// Charge left on battery at which the owner is told to plug the beacon in
+const LOW_BATTERY_PERCENT: u8 = 15;
+/// Tries at a forced update before giving up until the server asks again
+const UPDATE_ATTEMPTS: u32 = 3;
+
+/// The beacon's outputs: the seven-segment display, the LEDs and the AMOLED
+pub struct Beacon<D, S> {
+    pub display: D,
+    /// Effects for the LEDs, drawn by [`effects::run`](crate::hal::effects::run)
+    pub lights: Lights,
+    pub screen: S,
+    shown: Option<Screen>,
+    dimming: Option<NightDimming>,
+    /// Last brightness sent to the display
+    brightness: Option<u8>,
+    patterns: Patterns,
+}
+
+impl<D, S> Beacon<D, S>
+where
+    D: SegmentDisplay,
+    S: DrawTarget<Color = Rgb888>,
+    S::Error: Debug,
+{
+    pub fn new(display: D, lights: Lights, screen: S) -> Self {
+        Self {
+            display,
+            lights,
+            screen,
+            shown: None,
+            dimming: None,
+            brightness: None,
+            patterns: Patterns::default(),
+        }
+    }
+
+    /// Dims the display at night as `dimming` says
+    pub fn with_dimming(mut self, dimming: NightDimming) -> Self {
+        self.dimming = Some(dimming);
+        self
+    }
+
+    /// Plays notifications with `patterns` rather than the defaults
+    pub fn with_notifications(mut self, patterns: Patterns) -> Self {
+        self.patterns = patterns;
+        self
+    }
+
+    /// Shows `state` on every output
+    pub fn show(&mut self, state: &BeaconState) -> anyhow::Result<()> {
+        if let Some(dimming) = &self.dimming {
+            let brightness = dimming.now();
+            if self.brightness != Some(brightness) {
+                self.display.set_brightness(brightness);
+                self.brightness = Some(brightness);
+            }
+        }
+
+        match state.notification() {
+            Some(notification) => {
+                let pattern = self.patterns.get(notification.kind);
+                self.display.show(pattern.display(&notification));
+                self.lights.set(pattern.effects());
+            }
+            None => {
+                self.display.show(state.display());
+                self.lights.set(state.effects());
+            }
+        }
+
+        let screen = state.screen();
+        if self.shown.as_ref() != Some(&screen) {
+            screen
+                .draw(&mut self.screen)
+                .map_err(|e| anyhow!("drawing screen: {e:?}"))?;
+            self.shown = Some(screen);
+        }
+
+        Ok(())
+    }
+
+    /// Runs the state machine from `state`, keeping the outputs in sync and publishing every
+    /// change to `states`, until every event sender is gone
+    pub async fn run(
+        &mut self,
+        mut state: BeaconState,
+        mut events: mpsc::UnboundedReceiver<Event>,
+        states: &watch::Sender<BeaconState>,
+    ) -> anyhow::Result<()> {
+        let mut next_refresh = Instant::now() + REFRESH;
+
+        loop {
+            let now = Instant::now();
+            state = state.tick(now);
+            self.show(&state)?;
+
+            states.send_if_modified(|published| {
+                let changed = *published != state;
+                if changed {
+                    published.clone_from(&state);
+                }
+                changed
+            });
+
+            match with_deadline(next_refresh, events.recv()).await {
+                Ok(Some(event)) => {
+                    info!("Beacon event {event:?}");
+                    state = state.handle(event, Instant::now());
+                }
+                Ok(None) => return Ok(()),
+                Err(_) => next_refresh += REFRESH,
+            }
+        }
+    }
+}
+
+/// Sends an event for every touch report until the panel fails or the receiver is gone
+pub async fn forward_touches<T: TouchPanel>(
+    mut touch: T,
+    events: mpsc::UnboundedSender<Event>,
+) -> anyhow::Result<()> {
+    loop {
+        if !touch.touches().await?.is_empty() && events.send(Event::Touched).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Sends an event whenever a passport comes into the reader's field
+pub async fn forward_passports<N: NfcReader>(
+    mut nfc: N,
+    events: mpsc::UnboundedSender<Event>,
+) -> anyhow::Result<()> {
+    let mut present = false;
+    loop {
+        // Reads only succeed with a tag in the field
+        let found = nfc.read_ntag(0).await.is_ok();
+        if found && !present && events.send(Event::PassportTapped).is_err() {
+            return Ok(());
+        }
+        present = found;
+
+        Timer::after(PASSPORT_POLL).await;
+    }
+}
+
+/// Publishes where the board draws power from, checking every [`POWER_POLL`], and notifies the
+/// owner once the cell runs low, until nothing is listening any more
+pub async fn watch_power<P: PowerMonitor>(
+    mut monitor: P,
+    sources: watch::Sender<PowerSource>,
+    events: mpsc::UnboundedSender<Event>,
+) {
+    let mut warned = false;
+
+    while !sources.is_closed() {
+        match monitor.power_source() {
+            Ok(source) => {
+                sources.send_if_modified(|published| {
+                    let changed = *published != source;
+                    if changed {
+                        info!("Running on {source:?} power");
+                        *published = source;
+                    }
+                    changed
+                });
+            }
+            // Keeps the last source rather than guessing
+            Err(e) => warn!("Could not tell the power source: {e}"),
+        }
+
+        if *sources.borrow() == PowerSource::Battery {
+            match monitor.charge_percent() {
+                Ok(percent) if percent <= LOW_BATTERY_PERCENT && !warned => {
+                    let notification = Event::Notified(Notification::low_battery(percent));
+                    if events.send(notification).is_err() {
+                        return;
+                    }
+                    warned = true;
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Could not read the battery charge: {e}"),
+            }
+        } else {
+            // Warns again the next time it runs down
+            warned = false;
+        }
+
+        Timer::after(POWER_POLL).await;
+    }
+}
+
+/// Sends an event for the current connectivity and every change to it
+pub async fn forward_connectivity(
+    mut connectivity: watch::Receiver<Connectivity>,
+    events: mpsc::UnboundedSender<Event>,
+) -> anyhow::Result<()> {
+    loop {
+        let state = connectivity.borrow_and_update().clone();
+        if events.send(Event::Connectivity(state)).is_err() {
+            return Ok(());
+        }
+
+        connectivity.changed().await?;
+    }
+}
+
+/// Runs `update` each time the beacon starts updating, retrying transient failures with
+/// backoff, then sends [`Event::UpdateFinished`].
+///
+/// Leaving the updating state, such as on [`Event::UpdateCancelled`], fires `canceller`, so
+/// `update` should guard what it waits on with one of its tokens.
+pub async fn run_updates<U: Future<Output = Result<(), NetError>>>(
+    mut states: watch::Receiver<BeaconState>,
+    events: mpsc::UnboundedSender<Event>,
+    canceller: Canceller,
+    mut update: impl FnMut() -> U,
+    mut random: impl FnMut() -> u32,
+) {
+    let cancel = canceller.token();
+
+    while states.wait_for(BeaconState::is_updating).await.is_ok() {
+        canceller.reset();
+
+        let mut watching = states.clone();
+        let mut watcher = pin!(async {
+            if watching
+                .wait_for(|state| !state.is_updating())
+                .await
+                .is_ok()
+            {
+                canceller.cancel();
+            }
+            pending::<()>().await
+        });
+
+        let mut attempts = pin!(async {
+            for attempt in 0..UPDATE_ATTEMPTS {
+                // Only returns if there was nothing newer or the update failed
+                match update().await {
+                    Ok(()) => break,
+                    Err(e) if e.is_transient() && attempt + 1 < UPDATE_ATTEMPTS => {
+                        warn!("Forced update failed, retrying: {e}");
+                        let retry_in = backoff(attempt, random());
+                        let waited = cancel.guard(async {
+                            Timer::after(retry_in).await;
+                            Ok::<_, NetError>(())
+                        });
+                        if waited.await.is_err() {
+                            warn!("Forced update cancelled");
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Forced update failed: {e}");
+                        break;
+                    }
+                }
+            }
+        });
+
+        // Cancelling makes the attempts wind down on their own
+        poll_fn(|cx| {
+            let _ = watcher.as_mut().poll(cx);
+            attempts.as_mut().poll(cx)
+        })
+        .await;
+
+        if events.send(Event::UpdateFinished).is_err() {
+            return;
+        }
+        if states.wait_for(|state| !state.is_updating()).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use embassy_time::with_timeout;
+    use embedded_graphics::prelude::Point;
+
+    use super::*;
+    use crate::amoled::sim::SimulatedPanel;
+    use crate::hal::fake::{FakeDisplay, FakeNfc, FakePower, FakeTouch};
+    use crate::hal::{DisplayCommand, Effects};
+    use crate::net::push::PushMessage;
+    use crate::settings::BeaconInfo;
+    use crate::testing::block_on;
+
+    fn beacon() -> (
+        Beacon<FakeDisplay, SimulatedPanel>,
+        watch::Receiver<Effects>,
+    ) {
+        let (lights, effects) = Lights::new(Effects::default());
+        (
+            Beacon::new(FakeDisplay::default(), lights, SimulatedPanel::new()),
+            effects,
+        )
+    }
+
+    fn claimed() -> Event {
+        Event::Claimed(BeaconInfo {
+            owner: "Ada".to_string(),
+            project: "Analytical engine".to_string(),
+            ..Default::default()
+        })
+    }
+
+    fn ping(by: &str) -> Event {
+        Event::Pinged {
+            id: by.to_string(),
+            by: by.to_string(),
+            message: None,
+        }
+    }
+
+    /// Runs `beacon` from unclaimed through `events`, returning the last state it published
+    fn run(beacon: &mut Beacon<FakeDisplay, SimulatedPanel>, events: Vec<Event>) -> BeaconState {
+        let (tx, rx) = mpsc::unbounded_channel();
+        for event in events {
+            tx.send(event).unwrap();
+        }
+        drop(tx);
+
+        let (states, published) = watch::channel(BeaconState::Unclaimed);
+        block_on(beacon.run(BeaconState::Unclaimed, rx, &states)).unwrap();
+
+        let state = published.borrow().clone();
+        state
+    }
+
+    #[test]
+    fn plays_pings_on_every_output() {
+        let (mut beacon, effects) = beacon();
+        let start = Instant::now();
+
+        let state = run(&mut beacon, vec![claimed(), ping("Bob"), ping("Cy")]);
+        assert_eq!(state.name(), "pinged");
+
+        let pattern = Patterns::default().ping;
+        assert_eq!(*effects.borrow(), pattern.effects());
+        // Blinking the number of pings, lit at first
+        assert_eq!(
+            Some(beacon.display.segments(start)),
+            DisplayCommand::Decimal(2).segments()
+        );
+        assert!(beacon
+            .screen
+            .snapshot()
+            .iter()
+            .any(|pixel| *pixel != Rgb888::BLACK));
+    }
+
+    #[test]
+    fn goes_back_to_the_state_once_dismissed() {
+        let (mut beacon, effects) = beacon();
+
+        let state = run(
+            &mut beacon,
+            vec![
+                claimed(),
+                Event::Notified(Notification::announcement("Demos at 3".to_string())),
+                Event::Touched,
+            ],
+        );
+        assert_eq!(state.name(), "claimed");
+        assert_eq!(*effects.borrow(), state.effects());
+    }
+
+    #[test]
+    fn dims_the_display() {
+        let (beacon, _effects) = beacon();
+        let mut beacon = beacon.with_dimming(NightDimming {
+            day: 40,
+            night: 40,
+            ..NightDimming::default()
+        });
+
+        beacon.show(&BeaconState::Unclaimed).unwrap();
+        assert_eq!(beacon.display.brightness, 40);
+    }
+
+    #[test]
+    fn forwards_touches() {
+        let (touch, reports) = FakeTouch::new();
+        let (events, mut received) = mpsc::unbounded_channel();
+
+        reports.send(vec![Point::new(10, 10)]).unwrap();
+        // Reports without any contact are lifts, not touches
+        reports.send(Vec::new()).unwrap();
+        reports
+            .send(vec![Point::new(20, 20), Point::new(30, 30)])
+            .unwrap();
+        drop(reports);
+
+        assert!(block_on(forward_touches(touch, events)).is_err());
+        assert_eq!(received.try_recv().ok(), Some(Event::Touched));
+        assert_eq!(received.try_recv().ok(), Some(Event::Touched));
+        assert!(received.try_recv().is_err());
+    }
+
+    #[test]
+    fn forwards_each_passport_once() {
+        let nfc = FakeNfc {
+            tag: Some(vec![0; 64]),
+            ..FakeNfc::default()
+        };
+        let (events, mut received) = mpsc::unbounded_channel();
+
+        block_on(async {
+            let forwarding = tokio::task::spawn(forward_passports(nfc, events));
+
+            assert_eq!(received.recv().await, Some(Event::PassportTapped));
+            // Still in the field a few polls later
+            assert!(with_timeout(PASSPORT_POLL * 3, received.recv())
+                .await
+                .is_err());
+
+            forwarding.abort();
+        });
+    }
+
+    #[test]
+    fn forwards_connectivity() {
+        let (connectivity, watched) = watch::channel(Connectivity::Offline {
+            retry_in: Duration::from_secs(0),
+        });
+        let (events, mut received) = mpsc::unbounded_channel();
+
+        block_on(async {
+            let forwarding = tokio::task::spawn(forward_connectivity(watched, events));
+
+            assert!(matches!(
+                received.recv().await,
+                Some(Event::Connectivity(Connectivity::Offline { .. }))
+            ));
+
+            let online = Connectivity::Online {
+                ssid: "lab".to_string(),
+            };
+            connectivity.send_replace(online.clone());
+            assert_eq!(received.recv().await, Some(Event::Connectivity(online)));
+
+            drop(connectivity);
+            assert!(forwarding.await.unwrap().is_err());
+        });
+    }
+
+    #[test]
+    fn warns_when_the_battery_runs_low() {
+        let power = FakePower {
+            source: PowerSource::Battery,
+            percent: LOW_BATTERY_PERCENT,
+        };
+        let (sources, source) = watch::channel(PowerSource::External);
+        let (events, mut received) = mpsc::unbounded_channel();
+
+        block_on(async {
+            let watching = tokio::task::spawn(watch_power(power, sources, events));
+
+            assert_eq!(
+                received.recv().await,
+                Some(Event::Notified(Notification::low_battery(
+                    LOW_BATTERY_PERCENT
+                )))
+            );
+            assert_eq!(*source.borrow(), PowerSource::Battery);
+
+            watching.abort();
+        });
+    }
+
+    #[test]
+    fn does_not_warn_on_external_power() {
+        let power = FakePower {
+            source: PowerSource::External,
+            percent: 5,
+        };
+        let (sources, _source) = watch::channel(PowerSource::External);
+        let (events, mut received) = mpsc::unbounded_channel();
+
+        block_on(async {
+            let watching = tokio::task::spawn(watch_power(power, sources, events));
+            assert!(with_timeout(Duration::from_millis(100), received.recv())
+                .await
+                .is_err());
+            watching.abort();
+        });
+    }
+
+    #[test]
+    fn calls_off_an_update_the_server_cancels() {
+        let (mut beacon, _effects) = beacon();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (states, _published) = watch::channel(BeaconState::Unclaimed);
+
+        let canceller = Canceller::new();
+        let cancel = canceller.token();
+        let started = Arc::new(AtomicU32::new(0));
+        let cancelled = Arc::new(AtomicU32::new(0));
+
+        let state = block_on(async {
+            tokio::task::spawn(run_updates(
+                states.subscribe(),
+                tx.clone(),
+                canceller,
+                {
+                    let (started, cancelled) = (started.clone(), cancelled.clone());
+                    move || {
+                        let (cancel, started, cancelled) =
+                            (cancel.clone(), started.clone(), cancelled.clone());
+                        async move {
+                            started.fetch_add(1, Ordering::SeqCst);
+                            cancel.cancelled().await;
+                            cancelled.fetch_add(1, Ordering::SeqCst);
+                            Err(NetError::Cancelled)
+                        }
+                    }
+                },
+                || 0,
+            ));
+
+            tokio::task::spawn(async move {
+                tx.send(claimed()).unwrap();
+                for event in PushMessage::ForceUpdate.into_events() {
+                    tx.send(event).unwrap();
+                }
+                Timer::after_millis(100).await;
+                for event in PushMessage::CancelUpdate.into_events() {
+                    tx.send(event).unwrap();
+                }
+            });
+
+            let _ = with_timeout(
+                Duration::from_millis(300),
+                beacon.run(BeaconState::Unclaimed, rx, &states),
+            )
+            .await;
+            states.borrow().clone()
+        });
+
+        assert_eq!(started.load(Ordering::SeqCst), 1);
+        assert_eq!(cancelled.load(Ordering::SeqCst), 1);
+        assert_eq!(state.name(), "claimed");
+    }
+}
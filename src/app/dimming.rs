@@ -0,0 +1,153 @@
+//! Dims the seven-segment display at night, going by the wall clock once SNTP has set it.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// Any earlier clock reading means the time has not been set since boot
+const EARLIEST_VALID_TIME: u64 = 1_704_067_200; // 2024-01-01
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+/// Display brightness by time of day
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NightDimming {
+    pub day: u8,
+    pub night: u8,
+    /// Minutes after local midnight that night starts
+    pub night_starts: u16,
+    /// Minutes after local midnight that night ends
+    pub night_ends: u16,
+    /// Local time minus UTC
+    pub utc_offset_minutes: i16,
+}
+
+impl Default for NightDimming {
+    fn default() -> Self {
+        Self {
+            day: u8::MAX,
+            night: 32,
+            night_starts: 22 * 60,
+            night_ends: 7 * 60,
+            utc_offset_minutes: 0,
+        }
+    }
+}
+
+impl NightDimming {
+    /// Brightness at `unix_time`, or at day brightness while the time is unknown
+    pub fn brightness(&self, unix_time: Option<u64>) -> u8 {
+        let Some(unix_time) = unix_time.filter(|time| *time >= EARLIEST_VALID_TIME) else {
+            return self.day;
+        };
+
+        let minute = (unix_time as i64 / 60 + self.utc_offset_minutes as i64)
+            .rem_euclid(MINUTES_PER_DAY) as u16;
+
+        let night = if self.night_starts <= self.night_ends {
+            (self.night_starts..self.night_ends).contains(&minute)
+        } else {
+            // Night wraps around midnight
+            minute >= self.night_starts || minute < self.night_ends
+        };
+
+        if night {
+            self.night
+        } else {
+            self.day
+        }
+    }
+
+    pub fn now(&self) -> u8 {
+        let unix_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|since| since.as_secs());
+
+        self.brightness(unix_time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2025-06-01 00:00 UTC
+    const MIDNIGHT: u64 = 1_748_736_000;
+
+    fn at(hours: u64, minutes: u64) -> Option<u64> {
+        Some(MIDNIGHT + hours * 3600 + minutes * 60)
+    }
+
+    #[test]
+    fn dims_through_a_night_across_midnight() {
+        let dimming = NightDimming::default();
+
+        assert_eq!(dimming.brightness(at(12, 0)), u8::MAX);
+        assert_eq!(dimming.brightness(at(21, 59)), u8::MAX);
+        assert_eq!(dimming.brightness(at(22, 0)), 32);
+        assert_eq!(dimming.brightness(at(0, 0)), 32);
+        assert_eq!(dimming.brightness(at(6, 59)), 32);
+        assert_eq!(dimming.brightness(at(7, 0)), u8::MAX);
+    }
+
+    #[test]
+    fn dims_through_a_night_within_one_day() {
+        let dimming = NightDimming {
+            night_starts: 60,
+            night_ends: 5 * 60,
+            ..Default::default()
+        };
+
+        assert_eq!(dimming.brightness(at(0, 59)), u8::MAX);
+        assert_eq!(dimming.brightness(at(1, 0)), 32);
+        assert_eq!(dimming.brightness(at(4, 59)), 32);
+        assert_eq!(dimming.brightness(at(5, 0)), u8::MAX);
+        assert_eq!(dimming.brightness(at(23, 0)), u8::MAX);
+    }
+
+    #[test]
+    fn goes_by_local_time() {
+        // 22:00 local is 03:00 UTC the next day for US Eastern in summer
+        let dimming = NightDimming {
+            utc_offset_minutes: -4 * 60,
+            ..Default::default()
+        };
+        assert_eq!(dimming.brightness(at(21, 0)), u8::MAX);
+        assert_eq!(dimming.brightness(at(2, 0)), 32);
+        assert_eq!(dimming.brightness(at(10, 59)), 32);
+        assert_eq!(dimming.brightness(at(11, 0)), u8::MAX);
+
+        let dimming = NightDimming {
+            utc_offset_minutes: 5 * 60 + 30,
+            ..Default::default()
+        };
+        assert_eq!(dimming.brightness(at(16, 29)), u8::MAX);
+        assert_eq!(dimming.brightness(at(16, 30)), 32);
+    }
+
+    #[test]
+    fn stays_bright_until_the_clock_is_set() {
+        let dimming = NightDimming::default();
+
+        assert_eq!(dimming.brightness(None), u8::MAX);
+        // Midnight on the epoch, as the clock reads before SNTP
+        assert_eq!(dimming.brightness(Some(0)), u8::MAX);
+        assert_eq!(dimming.brightness(Some(EARLIEST_VALID_TIME - 1)), u8::MAX);
+        assert_eq!(dimming.brightness(Some(EARLIEST_VALID_TIME)), 32);
+    }
+
+    #[test]
+    fn never_dims_an_empty_night() {
+        let dimming = NightDimming {
+            night_starts: 0,
+            night_ends: 0,
+            ..Default::default()
+        };
+
+        for hour in 0..24 {
+            assert_eq!(dimming.brightness(at(hour, 0)), u8::MAX);
+        }
+    }
+}
@@ -0,0 +1,307 @@
+//! Things the owner should notice from across the room, played on every output at once.
+//!
+//! Each [`NotificationKind`] has its own [`Pattern`]: the beacon LED strobes, the base runs a
+//! color wave, the digits show a count and the AMOLED says who and why. The patterns are kept in
+//! settings so a deployment can tune them.
+
+use embassy_time::Duration;
+use serde::{Deserialize, Serialize};
+use smart_leds::RGB8;
+
+use crate::hal::{DisplayCommand, Effect, Effects};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    /// Another attendee wants to talk to the owner
+    Ping,
+    /// Someone left the owner a message
+    Message,
+    /// The organizers have something to say to everyone
+    Announcement,
+    /// The cell is running out
+    LowBattery,
+}
+
+/// A notification waiting for the owner to dismiss it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub kind: NotificationKind,
+    /// Who it is from, if anyone
+    pub from: Option<String>,
+    /// What they said
+    pub text: Option<String>,
+    /// Number for the digits: how many pings are waiting, or how much battery is left
+    pub count: Option<u8>,
+}
+
+impl Notification {
+    /// A ping from `from`, with `count` of them waiting
+    pub fn ping(from: String, text: Option<String>, count: u8) -> Self {
+        Self {
+            kind: NotificationKind::Ping,
+            from: Some(from),
+            text,
+            count: Some(count),
+        }
+    }
+
+    pub fn message(from: String, text: String) -> Self {
+        Self {
+            kind: NotificationKind::Message,
+            from: Some(from),
+            text: Some(text),
+            count: None,
+        }
+    }
+
+    pub fn announcement(text: String) -> Self {
+        Self {
+            kind: NotificationKind::Announcement,
+            from: None,
+            text: Some(text),
+            count: None,
+        }
+    }
+
+    pub fn low_battery(percent: u8) -> Self {
+        Self {
+            kind: NotificationKind::LowBattery,
+            from: None,
+            text: None,
+            count: Some(percent),
+        }
+    }
+
+    /// Text for the AMOLED, one entry per paragraph
+    pub fn paragraphs(&self) -> Vec<String> {
+        let from = self.from.as_deref().unwrap_or("Someone");
+        let mut paragraphs = match self.kind {
+            NotificationKind::Ping => vec![format!("{from} wants to talk!")],
+            NotificationKind::Message => vec![format!("Message from {from}")],
+            NotificationKind::Announcement => vec!["Announcement".to_string()],
+            NotificationKind::LowBattery => vec!["Battery low".to_string()],
+        };
+
+        if let Some(text) = &self.text {
+            paragraphs.push(format!("\"{text}\""));
+        }
+
+        paragraphs.push(match (self.kind, self.count) {
+            (NotificationKind::Ping, _) => {
+                "Tap the screen to let them know you're coming".to_string()
+            }
+            (NotificationKind::LowBattery, Some(percent)) => {
+                format!("{percent}% left, plug the beacon in")
+            }
+            _ => "Tap the screen to dismiss".to_string(),
+        });
+
+        paragraphs
+    }
+}
+
+/// How one kind of notification plays on the outputs. Periods are in milliseconds, with 0
+/// keeping that output steady.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Pattern {
+    pub color: [u8; 3],
+    /// How long the beacon LED is lit for each flash
+    pub strobe_on_ms: u32,
+    /// Time from one flash of the beacon LED to the next
+    pub strobe_period_ms: u32,
+    /// Time the color wave takes to go once around the base
+    pub wave_period_ms: u32,
+    /// Whether the count on the digits blinks along with the strobe
+    pub blink_count: bool,
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Self {
+            color: [100, 0, 100],
+            strobe_on_ms: 100,
+            strobe_period_ms: 600,
+            wave_period_ms: 1200,
+            blink_count: true,
+        }
+    }
+}
+
+impl Pattern {
+    fn color(&self) -> RGB8 {
+        let [r, g, b] = self.color;
+        RGB8::new(r, g, b)
+    }
+
+    /// The beacon LED strobing over a color wave around the base
+    pub fn effects(&self) -> Effects {
+        let color = self.color();
+
+        Effects {
+            base: match self.wave_period_ms {
+                0 => Effect::Solid(color),
+                period => Effect::Wave {
+                    color,
+                    period: Duration::from_millis(period.into()),
+                },
+            },
+            beacon: match self.strobe_period_ms {
+                0 => Effect::Solid(color),
+                period => Effect::Strobe {
+                    color,
+                    on: Duration::from_millis(self.strobe_on_ms.into()),
+                    period: Duration::from_millis(period.into()),
+                },
+            },
+        }
+    }
+
+    /// The notification's count on the digits, if it has one
+    pub fn display(&self, notification: &Notification) -> DisplayCommand {
+        let Some(count) = notification.count else {
+            return DisplayCommand::Clear;
+        };
+
+        let number = DisplayCommand::Decimal(count);
+        match (self.blink_count, self.strobe_period_ms, number.segments()) {
+            (true, period @ 1.., Some(segments)) => DisplayCommand::Blink {
+                segments,
+                period: Duration::from_millis(period.into()),
+            },
+            _ => number,
+        }
+    }
+}
+
+/// The pattern for each kind of notification
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Patterns {
+    pub ping: Pattern,
+    pub message: Pattern,
+    pub announcement: Pattern,
+    pub low_battery: Pattern,
+}
+
+impl Default for Patterns {
+    fn default() -> Self {
+        Self {
+            ping: Pattern::default(),
+            message: Pattern {
+                color: [0, 80, 100],
+                strobe_period_ms: 1200,
+                ..Pattern::default()
+            },
+            announcement: Pattern {
+                color: [100, 60, 0],
+                strobe_period_ms: 0,
+                wave_period_ms: 2400,
+                ..Pattern::default()
+            },
+            low_battery: Pattern {
+                color: [100, 0, 0],
+                strobe_on_ms: 50,
+                strobe_period_ms: 3000,
+                wave_period_ms: 0,
+                blink_count: false,
+            },
+        }
+    }
+}
+
+impl Patterns {
+    pub fn get(&self, kind: NotificationKind) -> &Pattern {
+        match kind {
+            NotificationKind::Ping => &self.ping,
+            NotificationKind::Message => &self.message,
+            NotificationKind::Announcement => &self.announcement,
+            NotificationKind::LowBattery => &self.low_battery,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blinking(count: u8, period_ms: u64) -> DisplayCommand {
+        DisplayCommand::Blink {
+            segments: DisplayCommand::Decimal(count).segments().unwrap(),
+            period: Duration::from_millis(period_ms),
+        }
+    }
+
+    #[test]
+    fn blinks_the_count_along_with_the_strobe() {
+        let ping = Notification::ping("Grace".to_string(), None, 3);
+        assert_eq!(Pattern::default().display(&ping), blinking(3, 600));
+
+        let steady = Pattern {
+            blink_count: false,
+            ..Pattern::default()
+        };
+        assert_eq!(steady.display(&ping), DisplayCommand::Decimal(3));
+    }
+
+    #[test]
+    fn keeps_the_count_steady_without_a_strobe() {
+        let pattern = Pattern {
+            strobe_period_ms: 0,
+            ..Pattern::default()
+        };
+        let ping = Notification::ping("Grace".to_string(), None, 3);
+        assert_eq!(pattern.display(&ping), DisplayCommand::Decimal(3));
+    }
+
+    #[test]
+    fn clears_the_digits_without_a_count() {
+        let announcement = Notification::announcement("Demos at 3".to_string());
+        assert_eq!(
+            Pattern::default().display(&announcement),
+            DisplayCommand::Clear
+        );
+    }
+
+    #[test]
+    fn says_who_and_why() {
+        assert_eq!(
+            Notification::ping("Grace".to_string(), Some("Hi".to_string()), 1).paragraphs(),
+            [
+                "Grace wants to talk!",
+                "\"Hi\"",
+                "Tap the screen to let them know you're coming",
+            ]
+        );
+        assert_eq!(
+            Notification::message("Alan".to_string(), "See you".to_string()).paragraphs(),
+            [
+                "Message from Alan",
+                "\"See you\"",
+                "Tap the screen to dismiss"
+            ]
+        );
+        assert_eq!(
+            Notification::announcement("Demos at 3".to_string()).paragraphs(),
+            [
+                "Announcement",
+                "\"Demos at 3\"",
+                "Tap the screen to dismiss"
+            ]
+        );
+        assert_eq!(
+            Notification::low_battery(10).paragraphs(),
+            ["Battery low", "10% left, plug the beacon in"]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_someone_without_a_sender() {
+        let message = Notification {
+            from: None,
+            ..Notification::message(String::new(), "Hello".to_string())
+        };
+        assert_eq!(message.paragraphs()[0], "Message from Someone");
+    }
+}
@@ -0,0 +1,1057 @@
+//! What the beacon is doing, and what it shows while doing it.
+//!
+//! [`BeaconState`] only changes through [`BeaconState::handle`] and [`BeaconState::tick`], which
+//! take the current time as an argument, so every transition can be checked without hardware or a
+//! clock.
+
+use embassy_time::{Duration, Instant};
+use embedded_graphics::mono_font::{ascii::FONT_10X20, MonoTextStyleBuilder};
+use embedded_graphics::pixelcolor::Rgb888;
+use embedded_graphics::prelude::*;
+use embedded_graphics::text::{Alignment, Text};
+use smart_leds::RGB8;
+
+use super::notification::Notification;
+use crate::hal::{DisplayCommand, Effect, Effects};
+use crate::net::{Connectivity, Progress};
+use crate::settings::BeaconInfo;
+
+/// How long a ping waits for the owner before the beacon gives up on it
+pub const PING_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How long the acknowledgement stays up before going back to the project
+pub const ACK_DURATION: Duration = Duration::from_secs(10);
+/// How long a notification other than a ping stays up without being dismissed
+pub const NOTIFICATION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Time the display spinner takes per segment
+const SPINNER_STEP: Duration = Duration::from_millis(80);
+/// Time a blinking or breathing LED takes to go dark and back
+const BLINK_PERIOD: Duration = Duration::from_millis(1500);
+/// Time the chase takes to go once around the base
+const CHASE_PERIOD: Duration = Duration::from_millis(1000);
+
+/// Characters per line on the AMOLED with the 10x20 font, leaving a margin
+const LINE_WIDTH: usize = 40;
+const LINE_HEIGHT: i32 = 30;
+
+/// Something that can move the beacon to another state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The server assigned the beacon to an owner and their project
+    Claimed(BeaconInfo),
+    /// The owner gave the beacon back
+    Released,
+    /// Another attendee pinged the beacon from the companion site
+    Pinged {
+        id: String,
+        by: String,
+        /// Why they want to talk, if they said
+        message: Option<String>,
+    },
+    /// Something other than a ping for the owner to notice, see [`Notification`]
+    Notified(Notification),
+    /// The owner stepped away from their project
+    OwnerAway,
+    OwnerBack,
+    /// Someone touched the screen
+    Touched,
+    /// A passport was tapped on the NFC reader
+    PassportTapped,
+    Connectivity(Connectivity),
+    /// The server wants the beacon on the latest firmware now
+    UpdateRequested,
+    UpdateProgress(Progress),
+    /// The update stopped without rebooting, either failed or already up to date
+    UpdateFinished,
+    /// The server called off the running update, which is abandoned
+    UpdateCancelled,
+}
+
+/// A ping held back while the owner is away
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissedPing {
+    pub id: String,
+    pub by: String,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BeaconState {
+    /// Nobody has set the beacon up with a project yet
+    Unclaimed,
+    Claimed {
+        info: BeaconInfo,
+    },
+    /// Someone wants to talk to the owner and is waiting for an answer
+    Pinged {
+        info: BeaconInfo,
+        /// Server IDs of every ping that came in before the owner answered, oldest first, for
+        /// acknowledging them all
+        ids: Vec<String>,
+        /// Who sent the latest ping
+        by: String,
+        message: Option<String>,
+        since: Instant,
+    },
+    /// The owner saw the pings and is on their way
+    Acknowledged {
+        info: BeaconInfo,
+        ids: Vec<String>,
+        by: String,
+        since: Instant,
+    },
+    Away {
+        info: BeaconInfo,
+        /// Pings that came in meanwhile, played once the owner is back
+        missed: Vec<MissedPing>,
+    },
+    /// Cut off from the server, returning to `resume` once back online
+    Offline {
+        connectivity: Connectivity,
+        resume: Box<BeaconState>,
+    },
+    /// Installing new firmware, returning to `resume` if that does not end in a reboot
+    Updating {
+        progress: Option<Progress>,
+        resume: Box<BeaconState>,
+    },
+    /// Showing a notification over `resume` until the owner dismisses it
+    Notifying {
+        notification: Notification,
+        since: Instant,
+        resume: Box<BeaconState>,
+    },
+}
+
+impl BeaconState {
+    /// The state at boot, claimed if the beacon remembers an owner
+    pub fn new(info: Option<BeaconInfo>) -> Self {
+        match info {
+            Some(info) => Self::Claimed { info },
+            None => Self::Unclaimed,
+        }
+    }
+
+    /// Applies `event`, which happened at `now`
+    pub fn handle(self, event: Event, now: Instant) -> Self {
+        match (self, event) {
+            // The push channel and polling can both deliver the same ping, which must neither
+            // count twice nor restart the wait for the owner
+            (state, Event::Pinged { id, .. }) if state.has_ping(&id) => state,
+
+            (Self::Offline { resume, .. }, Event::Connectivity(connectivity))
+                if connectivity.is_online() =>
+            {
+                resume.tick(now)
+            }
+            (Self::Offline { resume, .. }, Event::Connectivity(connectivity)) => Self::Offline {
+                connectivity,
+                resume,
+            },
+            (state, Event::Connectivity(connectivity)) if connectivity.is_online() => state,
+            (state, Event::Connectivity(connectivity)) => Self::Offline {
+                connectivity,
+                resume: Box::new(state),
+            },
+
+            // Local input is dropped while offline, since the server would never hear about it
+            (state @ Self::Offline { .. }, Event::Touched | Event::PassportTapped) => state,
+            (
+                Self::Offline {
+                    connectivity,
+                    resume,
+                },
+                event,
+            ) => Self::Offline {
+                connectivity,
+                resume: Box::new(resume.handle(event, now)),
+            },
+
+            (Self::Updating { resume, .. }, Event::UpdateFinished | Event::UpdateCancelled) => {
+                resume.tick(now)
+            }
+            (Self::Updating { resume, .. }, Event::UpdateProgress(progress)) => Self::Updating {
+                progress: Some(progress),
+                resume,
+            },
+            // The update owns the outputs until it is done, so input is dropped here too
+            (
+                state @ Self::Updating { .. },
+                Event::Touched | Event::PassportTapped | Event::UpdateRequested,
+            ) => state,
+            (Self::Updating { progress, resume }, event) => Self::Updating {
+                progress,
+                resume: Box::new(resume.handle(event, now)),
+            },
+            (state, Event::UpdateRequested) => Self::Updating {
+                progress: None,
+                resume: Box::new(state),
+            },
+
+            // A touch dismisses the notification, while a ping takes over from it
+            (Self::Notifying { resume, .. }, Event::Touched | Event::PassportTapped) => {
+                resume.tick(now)
+            }
+            (Self::Notifying { resume, .. }, event @ Event::Pinged { .. }) => {
+                resume.handle(event, now)
+            }
+            (Self::Notifying { resume, .. }, Event::Notified(notification)) => Self::Notifying {
+                notification,
+                since: now,
+                resume,
+            },
+            (
+                Self::Notifying {
+                    notification,
+                    since,
+                    resume,
+                },
+                event,
+            ) => Self::Notifying {
+                notification,
+                since,
+                resume: Box::new(resume.handle(event, now)),
+            },
+            (state, Event::Notified(notification)) => Self::Notifying {
+                notification,
+                since: now,
+                resume: Box::new(state),
+            },
+
+            // The same owner updating their project keeps whatever the beacon was doing
+            (state, Event::Claimed(info)) if state.owner() == Some(&info.owner) => {
+                state.with_info(info)
+            }
+            (_, Event::Claimed(info)) => Self::Claimed { info },
+            (_, Event::Released) => Self::Unclaimed,
+
+            (Self::Pinged { info, mut ids, .. }, Event::Pinged { id, by, message }) => {
+                ids.push(id);
+                Self::Pinged {
+                    info,
+                    ids,
+                    by,
+                    message,
+                    since: now,
+                }
+            }
+            (
+                Self::Claimed { info } | Self::Acknowledged { info, .. },
+                Event::Pinged { id, by, message },
+            ) => Self::Pinged {
+                info,
+                ids: vec![id],
+                by,
+                message,
+                since: now,
+            },
+            (Self::Pinged { info, ids, by, .. }, Event::Touched | Event::PassportTapped) => {
+                Self::Acknowledged {
+                    info,
+                    ids,
+                    by,
+                    since: now,
+                }
+            }
+
+            (
+                Self::Claimed { info }
+                | Self::Pinged { info, .. }
+                | Self::Acknowledged { info, .. },
+                Event::OwnerAway,
+            ) => Self::Away {
+                info,
+                missed: Vec::new(),
+            },
+            // The sync only delivers a ping once, so it waits here for the owner
+            (Self::Away { info, mut missed }, Event::Pinged { id, by, message }) => {
+                missed.push(MissedPing { id, by, message });
+                Self::Away { info, missed }
+            }
+            (Self::Away { info, missed }, Event::OwnerBack) => {
+                missed
+                    .into_iter()
+                    .fold(Self::Claimed { info }, |state, ping| {
+                        state.handle(
+                            Event::Pinged {
+                                id: ping.id,
+                                by: ping.by,
+                                message: ping.message,
+                            },
+                            now,
+                        )
+                    })
+            }
+
+            (state, _) => state,
+        }
+    }
+
+    /// Expires pings and acknowledgements that have been up for long enough
+    pub fn tick(self, now: Instant) -> Self {
+        match self {
+            Self::Pinged { info, since, .. } if now >= since + PING_TIMEOUT => {
+                Self::Claimed { info }
+            }
+            Self::Acknowledged { info, since, .. } if now >= since + ACK_DURATION => {
+                Self::Claimed { info }
+            }
+            Self::Offline {
+                connectivity,
+                resume,
+            } => Self::Offline {
+                connectivity,
+                resume: Box::new(resume.tick(now)),
+            },
+            Self::Updating { progress, resume } => Self::Updating {
+                progress,
+                resume: Box::new(resume.tick(now)),
+            },
+            Self::Notifying { since, resume, .. } if now >= since + NOTIFICATION_TIMEOUT => {
+                resume.tick(now)
+            }
+            Self::Notifying {
+                notification,
+                since,
+                resume,
+            } => Self::Notifying {
+                notification,
+                since,
+                resume: Box::new(resume.tick(now)),
+            },
+            state => state,
+        }
+    }
+
+    /// Who the beacon belongs to, if anyone
+    pub fn owner(&self) -> Option<&String> {
+        match self {
+            Self::Claimed { info }
+            | Self::Pinged { info, .. }
+            | Self::Acknowledged { info, .. }
+            | Self::Away { info, .. } => Some(&info.owner),
+            Self::Unclaimed
+            | Self::Offline { .. }
+            | Self::Updating { .. }
+            | Self::Notifying { .. } => None,
+        }
+    }
+
+    fn with_info(self, info: BeaconInfo) -> Self {
+        match self {
+            Self::Claimed { .. } => Self::Claimed { info },
+            Self::Pinged {
+                ids,
+                by,
+                message,
+                since,
+                ..
+            } => Self::Pinged {
+                info,
+                ids,
+                by,
+                message,
+                since,
+            },
+            Self::Acknowledged { ids, by, since, .. } => Self::Acknowledged {
+                info,
+                ids,
+                by,
+                since,
+            },
+            Self::Away { missed, .. } => Self::Away { info, missed },
+            state => state,
+        }
+    }
+
+    /// Whether the ping with `id` is already waiting, held back or acknowledged
+    fn has_ping(&self, id: &str) -> bool {
+        match self {
+            Self::Pinged { ids, .. } | Self::Acknowledged { ids, .. } => {
+                ids.iter().any(|known| known == id)
+            }
+            Self::Away { missed, .. } => missed.iter().any(|ping| ping.id == id),
+            Self::Offline { resume, .. }
+            | Self::Updating { resume, .. }
+            | Self::Notifying { resume, .. } => resume.has_ping(id),
+            Self::Unclaimed | Self::Claimed { .. } => false,
+        }
+    }
+
+    /// Whether an update is running, even if the beacon went offline during it
+    pub fn is_updating(&self) -> bool {
+        match self {
+            Self::Updating { .. } => true,
+            Self::Offline { resume, .. } => resume.is_updating(),
+            _ => false,
+        }
+    }
+
+    /// Short name for status reports
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Unclaimed => "unclaimed",
+            Self::Claimed { .. } => "claimed",
+            Self::Pinged { .. } => "pinged",
+            Self::Acknowledged { .. } => "acknowledged",
+            Self::Away { .. } => "away",
+            Self::Offline { .. } => "offline",
+            Self::Updating { .. } => "updating",
+            Self::Notifying { .. } => "notifying",
+        }
+    }
+
+    /// Color for the LEDs
+    pub fn color(&self) -> RGB8 {
+        match self {
+            Self::Unclaimed => RGB8::new(30, 30, 30),
+            Self::Claimed { .. } => RGB8::new(0, 0, 100),
+            Self::Pinged { .. } => RGB8::new(100, 0, 100),
+            Self::Acknowledged { .. } => RGB8::new(0, 100, 0),
+            Self::Away { .. } => RGB8::new(10, 10, 10),
+            Self::Offline { connectivity, .. } => connectivity.color(),
+            Self::Updating { .. } => RGB8::new(0, 60, 100),
+            Self::Notifying { resume, .. } => resume.color(),
+        }
+    }
+
+    /// What the owner should notice, played by its [`Pattern`](super::notification::Pattern)
+    /// in place of [`effects`](Self::effects) and [`display`](Self::display)
+    pub fn notification(&self) -> Option<Notification> {
+        match self {
+            Self::Pinged {
+                ids, by, message, ..
+            } => Some(ping_notification(ids, by, message)),
+            Self::Notifying { notification, .. } => Some(notification.clone()),
+            _ => None,
+        }
+    }
+
+    /// What the LEDs show: a progress bar during an update, a chase around the base while
+    /// waiting on it, and [`color`](Self::color) otherwise
+    pub fn effects(&self) -> Effects {
+        let color = self.color();
+        let chase = Effect::Chase {
+            color,
+            period: CHASE_PERIOD,
+        };
+
+        match self {
+            Self::Offline { .. } => Effects::all(Effect::Breathe {
+                color,
+                period: BLINK_PERIOD,
+            }),
+            Self::Updating {
+                progress: Some(progress),
+                ..
+            } => Effects {
+                base: Effect::Progress {
+                    progress: *progress,
+                    color,
+                },
+                beacon: Effect::Solid(color),
+            },
+            Self::Updating { progress: None, .. } => Effects {
+                base: chase,
+                beacon: Effect::Solid(color),
+            },
+            _ => Effects::all(Effect::Solid(color)),
+        }
+    }
+
+    /// What the seven-segment display shows: how far along an update is, or a spinner while busy
+    /// without a number to show
+    pub fn display(&self) -> DisplayCommand {
+        match self {
+            Self::Updating { progress, .. } => {
+                match progress.and_then(|progress| progress.percent()) {
+                    Some(percent) => DisplayCommand::Decimal(percent),
+                    None => DisplayCommand::Spinner { step: SPINNER_STEP },
+                }
+            }
+            Self::Offline {
+                connectivity: Connectivity::Connecting { .. },
+                ..
+            } => DisplayCommand::Spinner { step: SPINNER_STEP },
+            _ => DisplayCommand::Clear,
+        }
+    }
+
+    pub fn screen(&self) -> Screen {
+        match self {
+            Self::Unclaimed => Screen::Unclaimed,
+            Self::Claimed { info } => Screen::Project(info.clone()),
+            Self::Pinged {
+                ids, by, message, ..
+            } => Screen::Notification(ping_notification(ids, by, message)),
+            Self::Notifying { notification, .. } => Screen::Notification(notification.clone()),
+            Self::Acknowledged { by, .. } => Screen::Acknowledged { by: by.clone() },
+            Self::Away { info, .. } => Screen::Away {
+                owner: info.owner.clone(),
+            },
+            Self::Offline { connectivity, .. } => Screen::Offline {
+                ssid: match connectivity {
+                    Connectivity::Connecting { ssid } => Some(ssid.clone()),
+                    _ => None,
+                },
+            },
+            Self::Updating { .. } => Screen::Updating,
+        }
+    }
+}
+
+/// The latest ping, counting every one waiting for an answer
+fn ping_notification(ids: &[String], by: &str, message: &Option<String>) -> Notification {
+    let count = u8::try_from(ids.len()).unwrap_or(u8::MAX);
+    Notification::ping(by.to_string(), message.clone(), count)
+}
+
+/// What the AMOLED shows, compared to skip redrawing an unchanged screen
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Screen {
+    Unclaimed,
+    Project(BeaconInfo),
+    Notification(Notification),
+    Acknowledged { by: String },
+    Away { owner: String },
+    Offline { ssid: Option<String> },
+    Updating,
+}
+
+impl Screen {
+    /// Text to show, one entry per paragraph
+    pub fn paragraphs(&self) -> Vec<String> {
+        match self {
+            Self::Unclaimed => vec![
+                "This beacon is free!".to_string(),
+                "Claim it on the companion site to share your project".to_string(),
+            ],
+            Self::Project(info) => [&info.owner, &info.project, &info.description]
+                .into_iter()
+                .filter(|paragraph| !paragraph.is_empty())
+                .cloned()
+                .collect(),
+            Self::Notification(notification) => notification.paragraphs(),
+            Self::Acknowledged { by } => vec![format!("On the way, {by}!")],
+            Self::Away { owner } => vec![format!("{owner} is away"), "Back soon".to_string()],
+            Self::Offline { ssid: Some(ssid) } => {
+                vec!["Offline".to_string(), format!("Connecting to {ssid}")]
+            }
+            Self::Offline { ssid: None } => vec!["Offline".to_string()],
+            Self::Updating => vec![
+                "Updating".to_string(),
+                "Keep the beacon plugged in".to_string(),
+            ],
+        }
+    }
+
+    /// Clears `display` and draws the text centered on it
+    pub fn draw<D>(&self, display: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb888>,
+    {
+        display.clear(Rgb888::BLACK)?;
+
+        let lines: Vec<Vec<String>> = self.paragraphs().iter().map(|p| wrap(p)).collect();
+        let count = lines.iter().map(Vec::len).sum::<usize>() + lines.len().saturating_sub(1);
+
+        // With a background every glyph is one window fill, which keeps the panel's pixel pairs
+        // whole instead of halving lone pixels
+        let style = MonoTextStyleBuilder::new()
+            .font(&FONT_10X20)
+            .text_color(Rgb888::WHITE)
+            .background_color(Rgb888::BLACK)
+            .build();
+        let center = display.bounding_box().center();
+        let mut y = center.y - (count as i32 - 1) * LINE_HEIGHT / 2;
+
+        for paragraph in lines {
+            for line in paragraph {
+                Text::with_alignment(&line, Point::new(center.x, y), style, Alignment::Center)
+                    .draw(display)?;
+                y += LINE_HEIGHT;
+            }
+            // A blank line between paragraphs
+            y += LINE_HEIGHT;
+        }
+
+        Ok(())
+    }
+}
+
+/// Splits `text` into lines that fit the screen, breaking between words where possible
+fn wrap(text: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+
+    for word in text.split_whitespace() {
+        let mut word = word;
+        // Words too long for a line of their own get cut
+        while word.chars().count() > LINE_WIDTH {
+            if !line.is_empty() {
+                lines.push(core::mem::take(&mut line));
+            }
+            let split = word
+                .char_indices()
+                .nth(LINE_WIDTH)
+                .map_or(word.len(), |(i, _)| i);
+            lines.push(word[..split].to_string());
+            word = &word[split..];
+        }
+
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > LINE_WIDTH {
+            lines.push(core::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(owner: &str) -> BeaconInfo {
+        BeaconInfo {
+            owner: owner.to_string(),
+            project: "Analytical engine".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn ping(id: &str, by: &str) -> Event {
+        Event::Pinged {
+            id: id.to_string(),
+            by: by.to_string(),
+            message: None,
+        }
+    }
+
+    fn offline() -> Event {
+        Event::Connectivity(Connectivity::Offline {
+            retry_in: Duration::from_secs(5),
+        })
+    }
+
+    fn online() -> Event {
+        Event::Connectivity(Connectivity::Online {
+            ssid: "lab".to_string(),
+        })
+    }
+
+    /// Applies `events` one second apart, starting at `start`
+    fn apply(state: BeaconState, start: Instant, events: Vec<Event>) -> BeaconState {
+        events
+            .into_iter()
+            .enumerate()
+            .fold(state, |state, (i, event)| {
+                state.handle(event, start + Duration::from_secs(i as u64))
+            })
+    }
+
+    fn claimed() -> BeaconState {
+        BeaconState::new(Some(info("Ada")))
+    }
+
+    fn away() -> BeaconState {
+        BeaconState::Away {
+            info: info("Ada"),
+            missed: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn starts_claimed_only_with_an_owner() {
+        assert_eq!(BeaconState::new(None), BeaconState::Unclaimed);
+        assert_eq!(claimed().name(), "claimed");
+        assert_eq!(claimed().owner(), Some(&"Ada".to_string()));
+    }
+
+    #[test]
+    fn claims_and_releases() {
+        let now = Instant::from_secs(100);
+        let state = BeaconState::Unclaimed.handle(Event::Claimed(info("Ada")), now);
+        assert_eq!(state, claimed());
+
+        let state = apply(state, now, vec![ping("1", "Bob"), Event::Released]);
+        assert_eq!(state, BeaconState::Unclaimed);
+    }
+
+    #[test]
+    fn ignores_pings_while_unclaimed() {
+        let now = Instant::from_secs(100);
+        let state = BeaconState::Unclaimed.handle(ping("1", "Bob"), now);
+        assert_eq!(state, BeaconState::Unclaimed);
+    }
+
+    #[test]
+    fn acknowledges_a_ping_with_a_touch() {
+        let now = Instant::from_secs(100);
+        let state = claimed().handle(ping("1", "Bob"), now);
+        assert_eq!(state.name(), "pinged");
+        assert_eq!(
+            state.notification(),
+            Some(Notification::ping("Bob".to_string(), None, 1))
+        );
+
+        let state = state.handle(Event::Touched, now + Duration::from_secs(3));
+        let BeaconState::Acknowledged {
+            ref ids, ref by, ..
+        } = state
+        else {
+            panic!("not acknowledged: {state:?}");
+        };
+        assert_eq!((&ids[..], by.as_str()), (&["1".to_string()][..], "Bob"));
+        assert_eq!(
+            state.screen(),
+            Screen::Acknowledged {
+                by: "Bob".to_string()
+            }
+        );
+
+        // And goes back to the project once the acknowledgement has been up for long enough
+        let later = now + Duration::from_secs(3) + ACK_DURATION;
+        assert_eq!(
+            state.clone().tick(later - Duration::from_secs(1)).name(),
+            "acknowledged"
+        );
+        assert_eq!(state.tick(later), claimed());
+    }
+
+    #[test]
+    fn acknowledges_a_ping_with_a_passport() {
+        let now = Instant::from_secs(100);
+        let state = apply(
+            claimed(),
+            now,
+            vec![ping("1", "Bob"), Event::PassportTapped],
+        );
+        assert_eq!(state.name(), "acknowledged");
+    }
+
+    #[test]
+    fn acknowledges_every_waiting_ping() {
+        let now = Instant::from_secs(100);
+        let state = apply(
+            claimed(),
+            now,
+            vec![ping("1", "Bob"), ping("2", "Grace"), Event::Touched],
+        );
+
+        let BeaconState::Acknowledged { ids, by, .. } = state else {
+            panic!("not acknowledged: {state:?}");
+        };
+        assert_eq!(ids, ["1", "2"]);
+        assert_eq!(by, "Grace");
+    }
+
+    #[test]
+    fn counts_waiting_pings() {
+        let now = Instant::from_secs(100);
+        let state = apply(claimed(), now, vec![ping("1", "Bob"), ping("2", "Grace")]);
+        assert_eq!(
+            state.notification(),
+            Some(Notification::ping("Grace".to_string(), None, 2))
+        );
+    }
+
+    #[test]
+    fn counts_each_ping_once() {
+        let now = Instant::from_secs(100);
+        let pinged = claimed().handle(ping("1", "Bob"), now);
+
+        let later = now + Duration::from_secs(30);
+        assert_eq!(pinged.clone().handle(ping("1", "Bob"), later), pinged);
+
+        let acknowledged = pinged.handle(Event::Touched, later);
+        assert_eq!(
+            acknowledged.clone().handle(ping("1", "Bob"), later),
+            acknowledged
+        );
+
+        let away = away().handle(ping("1", "Bob"), now);
+        assert_eq!(away.clone().handle(ping("1", "Bob"), later), away);
+
+        // Nor does a repeat take over from a notification
+        let notifying = apply(claimed(), now, vec![ping("1", "Bob"), announcement()]);
+        assert_eq!(notifying.clone().handle(ping("1", "Bob"), later), notifying);
+    }
+
+    #[test]
+    fn gives_up_on_unanswered_pings() {
+        let now = Instant::from_secs(100);
+        let state = claimed().handle(ping("1", "Bob"), now);
+
+        assert_eq!(
+            state
+                .clone()
+                .tick(now + PING_TIMEOUT - Duration::from_secs(1))
+                .name(),
+            "pinged"
+        );
+        assert_eq!(state.tick(now + PING_TIMEOUT), claimed());
+    }
+
+    #[test]
+    fn goes_away_and_comes_back() {
+        let now = Instant::from_secs(100);
+        let state = apply(claimed(), now, vec![ping("1", "Bob"), Event::OwnerAway]);
+        assert_eq!(state, away());
+        assert_eq!(
+            state.screen(),
+            Screen::Away {
+                owner: "Ada".to_string()
+            }
+        );
+
+        // Touches do nothing while the owner is away
+        let state = state.handle(Event::Touched, now);
+        assert_eq!(state.handle(Event::OwnerBack, now), claimed());
+    }
+
+    #[test]
+    fn holds_pings_until_the_owner_is_back() {
+        let now = Instant::from_secs(100);
+        let state = apply(away(), now, vec![ping("1", "Bob"), ping("2", "Grace")]);
+        assert_eq!(state.name(), "away");
+        assert_eq!(state.owner(), Some(&"Ada".to_string()));
+
+        let back = now + PING_TIMEOUT;
+        let state = state.handle(Event::OwnerBack, back);
+        let BeaconState::Pinged {
+            ref ids,
+            ref by,
+            since,
+            ..
+        } = state
+        else {
+            panic!("pings dropped: {state:?}");
+        };
+        assert_eq!(
+            (&ids[..], by.as_str()),
+            (&["1", "2"].map(String::from)[..], "Grace")
+        );
+        // They wait for the owner from when the owner is back
+        assert_eq!(since, back);
+
+        // Going away again without answering holds nothing back a second time
+        assert_eq!(state.handle(Event::OwnerAway, back), away());
+    }
+
+    #[test]
+    fn keeps_going_when_the_same_owner_updates_their_project() {
+        let now = Instant::from_secs(100);
+        let updated = BeaconInfo {
+            project: "Difference engine".to_string(),
+            ..info("Ada")
+        };
+
+        let state = apply(
+            claimed(),
+            now,
+            vec![ping("1", "Bob"), Event::Claimed(updated.clone())],
+        );
+        let BeaconState::Pinged { info, .. } = state else {
+            panic!("ping dropped: {state:?}");
+        };
+        assert_eq!(info, updated);
+
+        // A new owner starts over
+        let state = apply(
+            claimed(),
+            now,
+            vec![ping("1", "Bob"), Event::Claimed(self::info("Grace"))],
+        );
+        assert_eq!(
+            state,
+            BeaconState::Claimed {
+                info: self::info("Grace")
+            }
+        );
+    }
+
+    #[test]
+    fn resumes_after_going_offline() {
+        let now = Instant::from_secs(100);
+        let state = claimed().handle(offline(), now);
+        assert_eq!(state.name(), "offline");
+        assert_eq!(state.owner(), None);
+
+        // Server events still apply underneath, but local input is dropped
+        let state = apply(state, now, vec![ping("1", "Bob"), Event::Touched]);
+        assert_eq!(state.name(), "offline");
+
+        let state = state.handle(online(), now + Duration::from_secs(5));
+        assert_eq!(state.name(), "pinged");
+    }
+
+    #[test]
+    fn expires_pings_while_offline() {
+        let now = Instant::from_secs(100);
+        let state = apply(claimed(), now, vec![ping("1", "Bob"), offline()]);
+
+        let state = state.tick(now + PING_TIMEOUT);
+        assert_eq!(state.handle(online(), now + PING_TIMEOUT), claimed());
+    }
+
+    #[test]
+    fn updates_connectivity_while_offline() {
+        let now = Instant::from_secs(100);
+        let connecting = Event::Connectivity(Connectivity::Connecting {
+            ssid: "lab".to_string(),
+        });
+        let state = apply(claimed(), now, vec![offline(), connecting]);
+
+        assert_eq!(
+            state.screen(),
+            Screen::Offline {
+                ssid: Some("lab".to_string())
+            }
+        );
+        assert_eq!(
+            state.display(),
+            DisplayCommand::Spinner { step: SPINNER_STEP }
+        );
+        // Being online already is not a change
+        assert_eq!(claimed().handle(online(), now), claimed());
+    }
+
+    #[test]
+    fn resumes_after_an_update() {
+        let now = Instant::from_secs(100);
+        let progress = Progress {
+            written: 50,
+            total: Some(200),
+        };
+
+        let state = apply(
+            claimed(),
+            now,
+            vec![
+                Event::UpdateRequested,
+                Event::UpdateProgress(progress),
+                Event::Touched,
+            ],
+        );
+        assert!(state.is_updating());
+        assert_eq!(state.display(), DisplayCommand::Decimal(25));
+
+        assert_eq!(state.clone().handle(Event::UpdateFinished, now), claimed());
+        assert_eq!(state.handle(Event::UpdateCancelled, now), claimed());
+    }
+
+    #[test]
+    fn keeps_updating_while_offline() {
+        let now = Instant::from_secs(100);
+        let state = apply(claimed(), now, vec![Event::UpdateRequested, offline()]);
+        assert!(state.is_updating());
+
+        let state = apply(state, now, vec![online(), Event::UpdateFinished]);
+        assert_eq!(state, claimed());
+    }
+
+    fn announcement() -> Event {
+        Event::Notified(Notification::announcement("Demos at 3".to_string()))
+    }
+
+    #[test]
+    fn dismisses_notifications_back_to_the_state_underneath() {
+        let now = Instant::from_secs(100);
+
+        for resume in [BeaconState::Unclaimed, claimed(), away()] {
+            let state = resume.clone().handle(announcement(), now);
+            assert_eq!(state.name(), "notifying");
+            assert_eq!(state.owner(), None);
+            assert_eq!(state.handle(Event::Touched, now), resume);
+        }
+    }
+
+    #[test]
+    fn dismisses_notifications_back_to_a_waiting_ping() {
+        let now = Instant::from_secs(100);
+        let state = apply(claimed(), now, vec![ping("1", "Bob"), announcement()]);
+        assert_eq!(state.name(), "notifying");
+
+        // The first touch only dismisses the notification, the second acknowledges the ping
+        let state = state.handle(Event::Touched, now + Duration::from_secs(5));
+        assert_eq!(state.name(), "pinged");
+        let state = state.handle(Event::Touched, now + Duration::from_secs(6));
+        assert_eq!(state.name(), "acknowledged");
+    }
+
+    #[test]
+    fn lets_pings_take_over_from_notifications() {
+        let now = Instant::from_secs(100);
+        let state = apply(claimed(), now, vec![announcement(), ping("1", "Bob")]);
+        assert_eq!(state.name(), "pinged");
+    }
+
+    #[test]
+    fn replaces_notifications_with_newer_ones() {
+        let now = Instant::from_secs(100);
+        let battery = Notification::low_battery(10);
+        let state = apply(
+            claimed(),
+            now,
+            vec![announcement(), Event::Notified(battery.clone())],
+        );
+
+        assert_eq!(state.notification(), Some(battery));
+        assert_eq!(state.handle(Event::Touched, now), claimed());
+    }
+
+    #[test]
+    fn applies_server_events_under_notifications() {
+        let now = Instant::from_secs(100);
+        let state = apply(claimed(), now, vec![announcement(), Event::OwnerAway]);
+        assert_eq!(state.name(), "notifying");
+        assert_eq!(state.handle(Event::Touched, now), away());
+    }
+
+    #[test]
+    fn times_notifications_out() {
+        let now = Instant::from_secs(100);
+        let state = claimed().handle(announcement(), now);
+
+        assert_eq!(
+            state
+                .clone()
+                .tick(now + NOTIFICATION_TIMEOUT - Duration::from_secs(1))
+                .name(),
+            "notifying"
+        );
+        assert_eq!(state.tick(now + NOTIFICATION_TIMEOUT), claimed());
+    }
+
+    #[test]
+    fn resumes_notifications_after_going_offline() {
+        let now = Instant::from_secs(100);
+        let state = apply(claimed(), now, vec![offline(), announcement()]);
+        assert_eq!(state.name(), "offline");
+
+        let state = state.handle(online(), now + Duration::from_secs(5));
+        assert_eq!(state.name(), "notifying");
+        assert_eq!(state.handle(Event::Touched, now), claimed());
+    }
+
+    #[test]
+    fn wraps_text_between_words() {
+        assert_eq!(wrap(""), [""]);
+        assert_eq!(wrap("short words"), ["short words"]);
+
+        let long = "word ".repeat(20);
+        let lines = wrap(&long);
+        assert!(lines.iter().all(|line| line.len() <= LINE_WIDTH));
+        assert_eq!(lines.join(" "), long.trim());
+
+        let unbroken = "x".repeat(LINE_WIDTH + 5);
+        assert_eq!(wrap(&unbroken), ["x".repeat(LINE_WIDTH), "x".repeat(5)]);
+    }
+}
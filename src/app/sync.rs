@@ -0,0 +1,506 @@
+//! Keeps the beacon in step with the companion site.
+
+pub mod broker;
+
+use std::collections::HashSet;
+
+use embassy_time::{with_timeout, Duration, Instant};
+use log::{info, warn};
+use tokio::sync::{mpsc, watch};
+
+use super::state::{BeaconState, Event};
+use crate::hal::{KeyValueStore, NetClient};
+use crate::net::api::{ApiClient, Assignment, StatusReport};
+use crate::settings::{BeaconInfo, Settings};
+
+/// How often the server is asked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Polls between status reports
+const POLLS_PER_STATUS: u32 = 12;
+
+/// What the server last told us
+#[derive(Default)]
+struct Seen {
+    /// `None` until the first successful fetch
+    assignment: Option<Option<Assignment>>,
+    pending_pings: HashSet<String>,
+    /// Pings acknowledged to the server, so none is acknowledged twice or shown again
+    acknowledged: HashSet<String>,
+}
+
+/// Turns changes on the server into [`Event`]s, and reports acknowledged pings and the beacon's
+/// status back to it
+pub struct ServerSync<C, S> {
+    api: ApiClient<C>,
+    settings: Settings<S>,
+    rollback_reason: Option<String>,
+    /// Whether the push channel is up, which makes most polling unnecessary
+    push: Option<watch::Receiver<bool>>,
+}
+
+impl<C: NetClient, S: KeyValueStore> ServerSync<C, S> {
+    /// `rollback_reason` goes out with the first status report that gets through
+    pub fn new(api: ApiClient<C>, settings: Settings<S>, rollback_reason: Option<String>) -> Self {
+        Self {
+            api,
+            settings,
+            rollback_reason,
+            push: None,
+        }
+    }
+
+    /// Only polls for changes alongside status reports while the push channel is up
+    pub fn with_push(mut self, connected: watch::Receiver<bool>) -> Self {
+        self.push = Some(connected);
+        self
+    }
+
+    /// Polls the server until the event receiver is gone, reacting early to state changes
+    pub async fn run(
+        mut self,
+        mut states: watch::Receiver<BeaconState>,
+        events: mpsc::UnboundedSender<Event>,
+    ) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let mut seen = Seen::default();
+
+        if let Err(e) = self.api.register().await {
+            warn!("Could not register with the server: {e}");
+        }
+
+        for poll in 0u32.. {
+            let state = states.borrow_and_update().clone();
+            let status_due = poll % POLLS_PER_STATUS == 0;
+            // Changes arrive over the push channel while it is up
+            let pushed = self.push.as_ref().is_some_and(|push| *push.borrow());
+
+            if !matches!(state, BeaconState::Offline { .. }) {
+                if let BeaconState::Acknowledged { ids, .. } = &state {
+                    for id in ids {
+                        if seen.acknowledged.contains(id) {
+                            continue;
+                        }
+                        match self.api.acknowledge(id).await {
+                            Ok(()) => {
+                                seen.acknowledged.insert(id.clone());
+                            }
+                            // Such as a ping the server has already dropped
+                            Err(e) if !e.is_transient() => {
+                                warn!("Giving up on acknowledging ping {id}: {e}");
+                                seen.acknowledged.insert(id.clone());
+                            }
+                            Err(e) => warn!("Could not acknowledge ping {id}, retrying: {e}"),
+                        }
+                    }
+                }
+
+                if (status_due || !pushed) && !self.poll_changes(&mut seen, &events).await {
+                    return Ok(());
+                }
+
+                if status_due {
+                    self.report(&state, started).await;
+                }
+            }
+
+            // A new state may need acknowledging, so it cuts the wait short
+            if let Ok(Err(_)) = with_timeout(POLL_INTERVAL, states.changed()).await {
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fetches the assignment and pings, sending events for what changed. Returns whether
+    /// anyone is still listening.
+    async fn poll_changes(
+        &mut self,
+        seen: &mut Seen,
+        events: &mpsc::UnboundedSender<Event>,
+    ) -> bool {
+        match self.api.assignment().await {
+            Ok(current) => {
+                for event in assignment_events(seen.assignment.as_ref(), &current) {
+                    if let Event::Claimed(info) = &event {
+                        remember(&mut self.settings, info);
+                    }
+                    if events.send(event).is_err() {
+                        return false;
+                    }
+                }
+                seen.assignment = Some(current);
+            }
+            Err(e) => warn!("Could not fetch assignment: {e}"),
+        }
+
+        match self.api.pings().await {
+            Ok(pings) => {
+                for ping in &pings {
+                    if seen.pending_pings.contains(&ping.id) || seen.acknowledged.contains(&ping.id)
+                    {
+                        continue;
+                    }
+
+                    let event = Event::Pinged {
+                        id: ping.id.clone(),
+                        by: ping.from.clone(),
+                        message: ping.message.clone(),
+                    };
+                    if events.send(event).is_err() {
+                        return false;
+                    }
+                }
+                seen.pending_pings = pings.into_iter().map(|ping| ping.id).collect();
+                // Once the server has let go of a ping it is never listed again
+                seen.acknowledged
+                    .retain(|id| seen.pending_pings.contains(id));
+            }
+            Err(e) => warn!("Could not fetch pings: {e}"),
+        }
+
+        true
+    }
+
+    async fn report(&mut self, state: &BeaconState, started: Instant) {
+        let status = StatusReport {
+            firmware_version: env!("CARGO_PKG_VERSION"),
+            state: state.name(),
+            uptime_secs: started.elapsed().as_secs(),
+            rollback_reason: self.rollback_reason.as_deref(),
+        };
+
+        match self.api.post_status(&status).await {
+            Ok(()) => {
+                if let Some(reason) = self.rollback_reason.take() {
+                    info!("Reported rollback: {reason}");
+                }
+            }
+            Err(e) => warn!("Could not post status: {e}"),
+        }
+    }
+}
+
+/// Saves the owner so the beacon shows them straight away after a reboot
+fn remember<S: KeyValueStore>(settings: &mut Settings<S>, info: &BeaconInfo) {
+    if let Err(e) = settings.set_beacon_info(info) {
+        warn!("Could not save beacon info: {e}");
+    }
+}
+
+/// Events that take the beacon from the `previous` assignment, if one is known, to `current`
+fn assignment_events(
+    previous: Option<&Option<Assignment>>,
+    current: &Option<Assignment>,
+) -> Vec<Event> {
+    let Some(current) = current else {
+        return match previous {
+            Some(None) => Vec::new(),
+            _ => vec![Event::Released],
+        };
+    };
+
+    let previous = previous.and_then(Option::as_ref);
+    let mut events = Vec::new();
+
+    let same_claim = previous.is_some_and(|previous| {
+        (&previous.owner, &previous.title, &previous.description)
+            == (&current.owner, &current.title, &current.description)
+    });
+    if !same_claim {
+        events.push(Event::Claimed(current.clone().into()));
+    }
+
+    // A fresh claim starts out present, so only an absent owner needs announcing then
+    let was_away = same_claim && previous.is_some_and(|previous| previous.away);
+    match (was_away, current.away) {
+        (false, true) => events.push(Event::OwnerAway),
+        (true, false) => events.push(Event::OwnerBack),
+        _ => {}
+    }
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_time::Timer;
+    use http::Method;
+
+    use super::*;
+    use crate::hal::fake::{FakeNet, MemoryStore};
+    use crate::net::push::PushMessage;
+    use crate::net::Connectivity;
+    use crate::testing::block_on;
+
+    const BASE_URL: &str = "http://beacons.test/api/";
+
+    fn server() -> FakeNet {
+        let net = FakeNet::default();
+        net.respond(
+            &format!("{BASE_URL}beacons/abc"),
+            200,
+            r#"{"assignment": {"owner": "Ada", "title": "Analytical engine"}}"#,
+        );
+        net.respond(
+            &format!("{BASE_URL}beacons/abc/pings"),
+            200,
+            r#"[{"id": "1", "from": "Grace"}, {"id": "2", "from": "Alan", "message": "Hi"}]"#,
+        );
+        net
+    }
+
+    fn assignment(away: bool) -> Assignment {
+        Assignment {
+            owner: "Ada".to_string(),
+            title: "Analytical engine".to_string(),
+            description: String::new(),
+            away,
+        }
+    }
+
+    /// Runs one round of syncing from `state` and returns the events it sent
+    fn sync(net: &FakeNet, state: BeaconState, rollback_reason: Option<&str>) -> Vec<Event> {
+        let api = ApiClient::new(net, BASE_URL, "abc".to_string()).unwrap();
+        let settings = Settings::with_store(MemoryStore::default());
+        let sync = ServerSync::new(api, settings, rollback_reason.map(str::to_string));
+
+        let (_states, states_rx) = watch::channel(state);
+        let (events, mut events_rx) = mpsc::unbounded_channel();
+
+        // The first round runs straight away, then the sync waits for the next poll
+        let stopped = block_on(with_timeout(
+            Duration::from_millis(200),
+            sync.run(states_rx, events),
+        ));
+        assert!(stopped.is_err(), "sync stopped early");
+
+        let mut sent = Vec::new();
+        while let Ok(event) = events_rx.try_recv() {
+            sent.push(event);
+        }
+        sent
+    }
+
+    fn urls(net: &FakeNet) -> Vec<(Method, String)> {
+        net.requests()
+            .into_iter()
+            .map(|request| (request.method, request.url))
+            .collect()
+    }
+
+    #[test]
+    fn sends_what_changed_and_reports_status() {
+        let net = server();
+        let events = sync(&net, BeaconState::Unclaimed, Some("panicked at boot"));
+
+        assert_eq!(
+            events,
+            [
+                Event::Claimed(assignment(false).into()),
+                Event::Pinged {
+                    id: "1".to_string(),
+                    by: "Grace".to_string(),
+                    message: None,
+                },
+                Event::Pinged {
+                    id: "2".to_string(),
+                    by: "Alan".to_string(),
+                    message: Some("Hi".to_string()),
+                },
+            ]
+        );
+
+        assert_eq!(
+            urls(&net),
+            [
+                (Method::POST, format!("{BASE_URL}beacons")),
+                (Method::GET, format!("{BASE_URL}beacons/abc")),
+                (Method::GET, format!("{BASE_URL}beacons/abc/pings")),
+                (Method::POST, format!("{BASE_URL}beacons/abc/status")),
+            ]
+        );
+
+        let status = net.requests().pop().unwrap();
+        let status: serde_json::Value = serde_json::from_slice(&status.body.unwrap()).unwrap();
+        assert_eq!(status["state"], "unclaimed");
+        assert_eq!(status["rollback_reason"], "panicked at boot");
+    }
+
+    #[test]
+    fn acknowledges_and_skips_the_answered_ping() {
+        let net = server();
+        net.respond(&format!("{BASE_URL}beacons/abc/pings/1/ack"), 204, "");
+        let state = BeaconState::Acknowledged {
+            info: assignment(false).into(),
+            ids: vec!["1".to_string()],
+            by: "Grace".to_string(),
+            since: Instant::now(),
+        };
+        let events = sync(&net, state, None);
+
+        assert!(urls(&net).contains(&(Method::POST, format!("{BASE_URL}beacons/abc/pings/1/ack"))));
+        assert_eq!(
+            events
+                .iter()
+                .filter_map(|event| match event {
+                    Event::Pinged { id, .. } => Some(id.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+            ["2"]
+        );
+    }
+
+    #[test]
+    fn acknowledges_every_answered_ping() {
+        let net = server();
+        for id in ["1", "2"] {
+            net.respond(&format!("{BASE_URL}beacons/abc/pings/{id}/ack"), 204, "");
+        }
+        let state = BeaconState::Acknowledged {
+            info: assignment(false).into(),
+            ids: vec!["1".to_string(), "2".to_string()],
+            by: "Alan".to_string(),
+            since: Instant::now(),
+        };
+        let events = sync(&net, state, None);
+
+        for id in ["1", "2"] {
+            let ack = (
+                Method::POST,
+                format!("{BASE_URL}beacons/abc/pings/{id}/ack"),
+            );
+            assert!(urls(&net).contains(&ack), "ping {id} not acknowledged");
+        }
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, Event::Pinged { .. })));
+    }
+
+    #[test]
+    fn counts_a_ping_once_when_pushed_and_polled() {
+        let net = server();
+        net.respond(
+            &format!("{BASE_URL}beacons/abc/pings"),
+            200,
+            r#"[{"id": "1", "from": "Grace"}]"#,
+        );
+
+        let pushed: PushMessage =
+            serde_json::from_str(r#"{"type": "ping", "id": "1", "from": "Grace"}"#).unwrap();
+        let start = Instant::from_secs(100);
+        let state = pushed.into_events().into_iter().fold(
+            BeaconState::new(Some(assignment(false).into())),
+            |state, event| state.handle(event, start),
+        );
+
+        let polled = sync(&net, state.clone(), None);
+        assert!(polled.contains(&Event::Pinged {
+            id: "1".to_string(),
+            by: "Grace".to_string(),
+            message: None,
+        }));
+
+        let later = start + Duration::from_secs(60);
+        let state_after = polled
+            .into_iter()
+            .fold(state.clone(), |state, event| state.handle(event, later));
+        assert_eq!(state_after, state);
+    }
+
+    /// Runs the sync from `state` for a while, waking it up with a state change halfway
+    fn sync_twice(net: &FakeNet, state: BeaconState) {
+        let api = ApiClient::new(net, BASE_URL, "abc".to_string()).unwrap();
+        let sync = ServerSync::new(api, Settings::with_store(MemoryStore::default()), None);
+        let (states, states_rx) = watch::channel(state);
+        let (events, _events) = mpsc::unbounded_channel();
+
+        let stopped = block_on(async {
+            tokio::task::spawn(async move {
+                Timer::after_millis(100).await;
+                states.send_modify(|_| {});
+                // Dropping the states would stop the sync
+                Timer::after_millis(200).await;
+            });
+            with_timeout(Duration::from_millis(200), sync.run(states_rx, events)).await
+        });
+        assert!(stopped.is_err(), "sync stopped early");
+    }
+
+    fn acks(net: &FakeNet, id: &str) -> usize {
+        let ack = (
+            Method::POST,
+            format!("{BASE_URL}beacons/abc/pings/{id}/ack"),
+        );
+        urls(net).iter().filter(|url| **url == ack).count()
+    }
+
+    fn acknowledged(ids: &[&str]) -> BeaconState {
+        BeaconState::Acknowledged {
+            info: assignment(false).into(),
+            ids: ids.iter().map(|id| id.to_string()).collect(),
+            by: "Grace".to_string(),
+            since: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn retries_acknowledgements_only_while_the_server_is_unreachable() {
+        let net = server();
+        net.refuse(&format!("{BASE_URL}beacons/abc/pings/1/ack"));
+        sync_twice(&net, acknowledged(&["1", "2"]));
+
+        // The second ping is gone from the server, so its 404 is final
+        assert_eq!(acks(&net, "1"), 2);
+        assert_eq!(acks(&net, "2"), 1);
+    }
+
+    #[test]
+    fn keeps_quiet_while_offline() {
+        let net = server();
+        let state = BeaconState::Offline {
+            connectivity: Connectivity::Offline {
+                retry_in: Duration::from_secs(5),
+            },
+            resume: Box::new(BeaconState::Unclaimed),
+        };
+        let events = sync(&net, state, None);
+
+        assert_eq!(events, []);
+        assert_eq!(urls(&net), [(Method::POST, format!("{BASE_URL}beacons"))]);
+    }
+
+    #[test]
+    fn keeps_going_when_the_server_fails() {
+        let net = FakeNet::default();
+        let events = sync(&net, BeaconState::Unclaimed, None);
+
+        assert_eq!(events, []);
+        assert_eq!(net.requests().len(), 4);
+    }
+
+    #[test]
+    fn follows_the_assignment() {
+        let claimed = Some(assignment(false));
+        let away = Some(assignment(true));
+
+        assert_eq!(assignment_events(None, &None), [Event::Released]);
+        assert_eq!(assignment_events(Some(&None), &None), []);
+        assert_eq!(assignment_events(Some(&claimed), &None), [Event::Released]);
+        assert_eq!(
+            assignment_events(None, &away),
+            [Event::Claimed(assignment(true).into()), Event::OwnerAway]
+        );
+        assert_eq!(assignment_events(Some(&claimed), &claimed), []);
+        assert_eq!(assignment_events(Some(&claimed), &away), [Event::OwnerAway]);
+        assert_eq!(assignment_events(Some(&away), &claimed), [Event::OwnerBack]);
+
+        let mut other = assignment(false);
+        other.owner = "Grace".to_string();
+        assert_eq!(
+            assignment_events(Some(&away), &Some(other.clone())),
+            [Event::Claimed(other.into())]
+        );
+    }
+}
@@ -0,0 +1,357 @@
+//! Keeps the beacon in step with an MQTT broker, for deployments without the companion site.
+//!
+//! Everything lives under `beacons/<device id>/`:
+//!
+//! - `ping`: pings for the beacon, as `{"id": ..., "from": ..., "message": ...}`
+//! - `assignment`: the current claim, retained, with an empty message once it is released
+//! - `update`: `cancel` calls off a running update, and any other message forces one
+//! - `state`: the beacon's status, retained, and replaced by the will if it drops off
+//! - `ack`: acknowledged pings, as `{"id": ...}`
+
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::task::Poll;
+
+use embassy_time::{with_deadline, Duration, Instant, Timer};
+use log::{info, warn};
+use serde::Serialize;
+use tokio::sync::{mpsc, watch};
+use url::Url;
+
+use super::{assignment_events, remember, Seen};
+use crate::app::state::{BeaconState, Event};
+use crate::hal::KeyValueStore;
+use crate::net::api::{Assignment, Ping, StatusReport};
+use crate::net::mqtt::{ConnectOptions, MqttClient, MqttError, Publish, QoS, Will};
+use crate::net::{backoff, timeout, Connection, NetError, Timeouts};
+use crate::settings::Settings;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+/// Ping IDs remembered to drop redeliveries
+const REMEMBERED_PINGS: usize = 32;
+
+/// What the broker shows as the beacon's state once it has dropped off
+const WILL_PAYLOAD: &[u8] = br#"{"state":"disconnected"}"#;
+
+struct Topics {
+    ping: String,
+    assignment: String,
+    update: String,
+    state: String,
+    ack: String,
+}
+
+impl Topics {
+    fn new(device_id: &str) -> Self {
+        let topic = |name: &str| format!("beacons/{device_id}/{name}");
+
+        Self {
+            ping: topic("ping"),
+            assignment: topic("assignment"),
+            update: topic("update"),
+            state: topic("state"),
+            ack: topic("ack"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Ack<'a> {
+    id: &'a str,
+}
+
+/// What woke the session up
+enum Wake<T> {
+    Received(T),
+    /// Whether the state watch is still open
+    StateChanged(bool),
+}
+
+/// Waits for a message or a state change, whichever comes first
+async fn next_wake<T>(
+    received: impl Future<Output = T>,
+    changed: impl Future<Output = Result<(), watch::error::RecvError>>,
+) -> Wake<T> {
+    let mut received = pin!(received);
+    let mut changed = pin!(changed);
+
+    poll_fn(|cx| {
+        if let Poll::Ready(message) = received.as_mut().poll(cx) {
+            return Poll::Ready(Wake::Received(message));
+        }
+        if let Poll::Ready(result) = changed.as_mut().poll(cx) {
+            return Poll::Ready(Wake::StateChanged(result.is_ok()));
+        }
+
+        Poll::Pending
+    })
+    .await
+}
+
+/// Turns messages from the broker into [`Event`]s, and publishes acknowledged pings and the
+/// beacon's state back to it
+pub struct BrokerSync<F, S> {
+    url: Url,
+    connect: F,
+    device_id: String,
+    topics: Topics,
+    settings: Settings<S>,
+    rollback_reason: Option<String>,
+    timeouts: Timeouts,
+}
+
+impl<F, Fut, C, S> BrokerSync<F, S>
+where
+    F: FnMut(Url) -> Fut,
+    Fut: Future<Output = Result<C, NetError>>,
+    C: Connection,
+    S: KeyValueStore,
+{
+    /// `connect` opens the underlying connection to the broker URL it is given, so the broker
+    /// can be reached over TLS or plain TCP. `rollback_reason` goes out with the first state
+    /// that gets published.
+    pub fn new(
+        url: Url,
+        connect: F,
+        device_id: String,
+        settings: Settings<S>,
+        rollback_reason: Option<String>,
+    ) -> Self {
+        Self {
+            url,
+            connect,
+            topics: Topics::new(&device_id),
+            device_id,
+            settings,
+            rollback_reason,
+            timeouts: Timeouts::new(),
+        }
+    }
+
+    /// Limits for logging in and for the broker to acknowledge what we publish. Connecting is up
+    /// to `connect`.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Stays connected to the broker, reconnecting with backoff, until the event receiver or the
+    /// state watch is gone.
+    ///
+    /// `random` provides the backoff jitter.
+    pub async fn run(
+        mut self,
+        mut states: watch::Receiver<BeaconState>,
+        events: mpsc::UnboundedSender<Event>,
+        mut random: impl FnMut() -> u32,
+    ) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let mut seen = Seen::default();
+        let mut round = 0;
+
+        loop {
+            let mut connected = false;
+            match self
+                .session(&mut seen, &mut connected, &mut states, &events, started)
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(e) => warn!("Broker connection dropped: {e}"),
+            }
+
+            if connected {
+                round = 0;
+            }
+
+            let retry_in = backoff(round, random());
+            info!("Reconnecting to broker in {}s", retry_in.as_secs());
+            Timer::after(retry_in).await;
+            round = round.saturating_add(1);
+        }
+    }
+
+    /// One connection, from logging in until it fails. Returns `Ok` only once nobody is
+    /// listening anymore.
+    async fn session(
+        &mut self,
+        seen: &mut Seen,
+        connected: &mut bool,
+        states: &mut watch::Receiver<BeaconState>,
+        events: &mpsc::UnboundedSender<Event>,
+        started: Instant,
+    ) -> anyhow::Result<()> {
+        // Keeping the session lets the broker hold on to pings sent while we were away
+        let mut options = ConnectOptions::new(format!("beacon-{}", self.device_id))
+            .with_keep_alive(KEEP_ALIVE)
+            .with_clean_session(false)
+            .with_will(Will {
+                topic: self.topics.state.clone(),
+                payload: WILL_PAYLOAD.to_vec(),
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            });
+        if !self.url.username().is_empty() {
+            options = options.with_credentials(
+                self.url.username().to_string(),
+                self.url.password().map(str::to_string),
+            );
+        }
+
+        let conn = (self.connect)(self.url.clone()).await?;
+        let mut client = timeout(self.timeouts.handshake, async {
+            let mut client = MqttClient::connect(conn, &options).await?;
+            client
+                .subscribe(&[
+                    (&self.topics.ping, QoS::AtLeastOnce),
+                    (&self.topics.assignment, QoS::AtLeastOnce),
+                    (&self.topics.update, QoS::AtLeastOnce),
+                ])
+                .await?;
+            Ok::<_, MqttError>(client)
+        })
+        .await?;
+
+        info!(
+            "Connected to broker {}",
+            self.url.host_str().unwrap_or_default()
+        );
+        *connected = true;
+
+        // The will may be standing in for the state, so it always goes out again
+        let mut published = None;
+
+        loop {
+            let state = states.borrow_and_update().clone();
+
+            if let BeaconState::Acknowledged { ids, .. } = &state {
+                for id in ids {
+                    if seen.acknowledged.contains(id) {
+                        continue;
+                    }
+                    let ack = serde_json::to_vec(&Ack { id })?;
+                    timeout(
+                        self.timeouts.first_byte,
+                        client.publish(&self.topics.ack, &ack, QoS::AtLeastOnce, false),
+                    )
+                    .await?;
+
+                    if seen.acknowledged.len() >= REMEMBERED_PINGS {
+                        seen.acknowledged.clear();
+                    }
+                    seen.acknowledged.insert(id.clone());
+                }
+            }
+
+            if published != Some(state.name()) {
+                let status = StatusReport {
+                    firmware_version: env!("CARGO_PKG_VERSION"),
+                    state: state.name(),
+                    uptime_secs: started.elapsed().as_secs(),
+                    rollback_reason: self.rollback_reason.as_deref(),
+                };
+                let payload = serde_json::to_vec(&status)?;
+                timeout(
+                    self.timeouts.first_byte,
+                    client.publish(&self.topics.state, &payload, QoS::AtLeastOnce, true),
+                )
+                .await?;
+
+                published = Some(state.name());
+                if let Some(reason) = self.rollback_reason.take() {
+                    info!("Reported rollback: {reason}");
+                }
+            }
+
+            let wake = with_deadline(
+                client.next_keepalive(),
+                next_wake(client.recv(), states.changed()),
+            )
+            .await;
+
+            match wake {
+                Err(_) => client.keepalive().await?,
+                Ok(Wake::StateChanged(true)) => {}
+                Ok(Wake::StateChanged(false)) => return Ok(()),
+                Ok(Wake::Received(publish)) => {
+                    let publish = publish?;
+                    for event in self.handle(seen, &publish) {
+                        if events.send(event).is_err() {
+                            return Ok(());
+                        }
+                    }
+                    // Only once the events are queued, so nothing is lost if we go down first
+                    client.ack(&publish).await?;
+                }
+            }
+        }
+    }
+
+    /// Events for a message from the broker
+    fn handle(&mut self, seen: &mut Seen, publish: &Publish) -> Vec<Event> {
+        let topic = &publish.topic;
+
+        if *topic == self.topics.assignment {
+            let current = match publish.payload.as_slice() {
+                [] => None,
+                payload => match serde_json::from_slice::<Assignment>(payload) {
+                    Ok(assignment) => Some(assignment),
+                    Err(e) => {
+                        warn!("Ignoring malformed assignment: {e}");
+                        return Vec::new();
+                    }
+                },
+            };
+
+            let events = assignment_events(seen.assignment.as_ref(), &current);
+            for event in &events {
+                if let Event::Claimed(info) = event {
+                    remember(&mut self.settings, info);
+                }
+            }
+            seen.assignment = Some(current);
+
+            return events;
+        }
+
+        // Retained pings and updates would fire again on every reconnect
+        if publish.retain {
+            warn!("Ignoring retained message on {topic}");
+            return Vec::new();
+        }
+
+        if *topic == self.topics.ping {
+            let ping = match serde_json::from_slice::<Ping>(&publish.payload) {
+                Ok(ping) => ping,
+                Err(e) => {
+                    warn!("Ignoring malformed ping: {e}");
+                    return Vec::new();
+                }
+            };
+
+            if seen.pending_pings.contains(&ping.id) || seen.acknowledged.contains(&ping.id) {
+                return Vec::new();
+            }
+
+            if seen.pending_pings.len() >= REMEMBERED_PINGS {
+                seen.pending_pings.clear();
+            }
+            seen.pending_pings.insert(ping.id.clone());
+
+            vec![Event::Pinged {
+                id: ping.id,
+                by: ping.from,
+                message: ping.message,
+            }]
+        } else if *topic == self.topics.update {
+            if publish.payload == b"cancel" {
+                vec![Event::UpdateCancelled]
+            } else {
+                vec![Event::UpdateRequested]
+            }
+        } else {
+            warn!("Ignoring message on {topic}");
+            Vec::new()
+        }
+    }
+}
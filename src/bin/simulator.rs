@@ -0,0 +1,314 @@
+//! Runs the beacon's app logic on a desktop, against simulated peripherals.
+//!
+//! Commands are read from stdin one per line, so a session can be typed in or piped from a
+//! script:
+//!
+//! ```text
+//! touch X Y                  touch the screen
+//! tap                        tap a passport on the NFC reader
+//! wifi offline               change the Wi-Fi state the app sees
+//! wifi connecting SSID
+//! wifi online SSID
+//! claim OWNER PROJECT...     messages from the server
+//! release
+//! ping NAME [MESSAGE...]
+//! message FROM TEXT...
+//! announce TEXT...
+//! lowbattery PERCENT
+//! away
+//! back
+//! wait MS                    let the app run for a while
+//! screenshot PATH            save the AMOLED as a PNG
+//! quit
+//! ```
+//!
+//! The LEDs and the seven-segment display are drawn in the terminal whenever they change.
+
+use std::fs::File;
+use std::io::{BufRead, BufWriter};
+
+use anyhow::{anyhow, Context};
+use beacons::amoled::{self, sim::SimulatedPanel};
+use beacons::app::notification::Notification;
+use beacons::app::state::{BeaconState, Event};
+use beacons::app::{self, Beacon};
+use beacons::hal::fake::FakeTouch;
+use beacons::hal::{DisplayCommand, Effect, Effects, Lights, SegmentDisplay, Segments};
+use beacons::net::Connectivity;
+use beacons::settings::BeaconInfo;
+use embassy_time::{Duration, Timer};
+use embedded_graphics::prelude::{Point, RgbColor};
+use smart_leds::RGB8;
+use tokio::sync::{mpsc, watch};
+
+enum Command {
+    Touch(Point),
+    Wifi(Connectivity),
+    Event(Event),
+    Wait(Duration),
+    Screenshot(String),
+    Quit,
+}
+
+fn main() -> anyhow::Result<()> {
+    tokio::runtime::Builder::new_current_thread()
+        .build()?
+        .block_on(run())
+}
+
+async fn run() -> anyhow::Result<()> {
+    let panel = SimulatedPanel::new();
+    let panel_handle = panel.clone();
+    let (touch, touches) = FakeTouch::new();
+    let (connectivity_tx, connectivity) = watch::channel(Connectivity::Offline {
+        retry_in: Duration::from_secs(0),
+    });
+
+    let (events, events_rx) = mpsc::unbounded_channel();
+    let (states, _) = watch::channel(BeaconState::new(None));
+
+    tokio::task::spawn(app::forward_touches(touch, events.clone()));
+    tokio::task::spawn(app::forward_connectivity(connectivity, events.clone()));
+    let (lights, effects) = Lights::new(Effects::default());
+    tokio::task::spawn(print_effects(effects));
+    tokio::task::spawn(async move {
+        Beacon::new(TerminalDisplay::default(), lights, panel_handle)
+            .run(BeaconState::new(None), events_rx, &states)
+            .await
+            .expect("beacon");
+    });
+
+    let mut commands = read_commands();
+    while let Some(line) = commands.recv().await {
+        let command = match parse_command(&line) {
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("{e}");
+                continue;
+            }
+        };
+
+        match command {
+            Command::Touch(point) => touches.send(vec![point])?,
+            Command::Wifi(state) => {
+                connectivity_tx.send_replace(state);
+            }
+            Command::Event(event) => events.send(event)?,
+            Command::Wait(duration) => Timer::after(duration).await,
+            Command::Screenshot(path) => {
+                // Let pending touches reach the panel first
+                Timer::after_millis(10).await;
+                save_png(&panel, &path)?;
+                println!("Saved {path}");
+            }
+            Command::Quit => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Seven-segment display drawn in the terminal whenever it changes. Animations are described
+/// rather than drawn frame by frame.
+#[derive(Default)]
+struct TerminalDisplay {
+    command: Option<DisplayCommand>,
+}
+
+impl SegmentDisplay for TerminalDisplay {
+    fn show(&mut self, command: DisplayCommand) {
+        if let DisplayCommand::SetBrightness(brightness) = command {
+            println!("display brightness {brightness}");
+            return;
+        }
+        if self.command.as_ref() == Some(&command) {
+            return;
+        }
+
+        println!("display {}", describe(&command));
+        self.command = Some(command);
+    }
+}
+
+fn describe(command: &DisplayCommand) -> String {
+    match command {
+        DisplayCommand::Blink { segments, period } => {
+            format!(
+                "[{}] blinking every {}ms",
+                digits(segments),
+                period.as_millis()
+            )
+        }
+        DisplayCommand::Marquee { text, .. } => format!("scrolling [{}]", digits(text)),
+        DisplayCommand::Spinner { .. } => "spinning".to_string(),
+        DisplayCommand::Timed { command, duration } => {
+            format!("{} for {}ms", describe(command), duration.as_millis())
+        }
+        command => format!("[{}]", digits(&command.segments().unwrap_or_default())),
+    }
+}
+
+fn digits(segments: &[Segments]) -> String {
+    let mut line = String::new();
+    for digit in segments {
+        line.push(digit.to_char().unwrap_or('?'));
+        if digit.dot() {
+            line.push('.');
+        }
+    }
+    line
+}
+
+/// Prints the LED effects whenever they change. Like the display, animations are described
+/// rather than drawn frame by frame.
+async fn print_effects(mut effects: watch::Receiver<Effects>) {
+    loop {
+        let line = {
+            let effects = effects.borrow_and_update();
+            format!(
+                "LEDs base {}, beacon {}",
+                describe_effect(&effects.base),
+                describe_effect(&effects.beacon)
+            )
+        };
+        println!("{line}");
+
+        if effects.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+fn describe_effect(effect: &Effect) -> String {
+    match effect {
+        Effect::Solid(color) => swatch(*color),
+        Effect::Breathe { color, .. } => format!("{} breathing", swatch(*color)),
+        Effect::Rainbow { .. } => "rainbow".to_string(),
+        Effect::Chase { color, .. } => format!("{} chasing", swatch(*color)),
+        Effect::Wave { color, .. } => format!("{} waving", swatch(*color)),
+        Effect::Sparkle { color, .. } => format!("{} sparkling", swatch(*color)),
+        Effect::Strobe { color, .. } => format!("{} strobing", swatch(*color)),
+        Effect::Progress { progress, color } => format!(
+            "{} at {}%",
+            swatch(*color),
+            progress.percent().unwrap_or_default()
+        ),
+    }
+}
+
+fn swatch(color: RGB8) -> String {
+    format!("\x1b[48;2;{};{};{}m  \x1b[0m", color.r, color.g, color.b)
+}
+
+/// Forwards stdin lines from a blocking thread
+fn read_commands() -> mpsc::UnboundedReceiver<String> {
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    rx
+}
+
+fn parse_command(line: &str) -> anyhow::Result<Option<Command>> {
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else {
+        return Ok(None);
+    };
+
+    let mut next = |what: &str| {
+        words
+            .next()
+            .ok_or_else(|| anyhow!("{name}: missing {what}"))
+    };
+
+    let command = match name {
+        "touch" => Command::Touch(Point::new(next("x")?.parse()?, next("y")?.parse()?)),
+        "wifi" => Command::Wifi(match next("state")? {
+            "offline" => Connectivity::Offline {
+                retry_in: Duration::from_secs(0),
+            },
+            "connecting" => Connectivity::Connecting {
+                ssid: next("network")?.to_string(),
+            },
+            "online" => Connectivity::Online {
+                ssid: next("network")?.to_string(),
+            },
+            other => return Err(anyhow!("wifi: unknown state {other:?}")),
+        }),
+        "wait" => Command::Wait(Duration::from_millis(next("milliseconds")?.parse()?)),
+        "tap" => Command::Event(Event::PassportTapped),
+        "claim" => {
+            let owner = next("owner")?.to_string();
+            let project = words.collect::<Vec<_>>().join(" ");
+            Command::Event(Event::Claimed(BeaconInfo {
+                owner,
+                project,
+                ..Default::default()
+            }))
+        }
+        "release" => Command::Event(Event::Released),
+        "ping" => {
+            // Nothing acknowledges pings here, so the sender's name will do as an ID
+            let by = next("name")?.to_string();
+            let message = words.collect::<Vec<_>>().join(" ");
+            Command::Event(Event::Pinged {
+                id: by.clone(),
+                by,
+                message: (!message.is_empty()).then_some(message),
+            })
+        }
+        "message" => {
+            let from = next("sender")?.to_string();
+            let text = words.collect::<Vec<_>>().join(" ");
+            Command::Event(Event::Notified(Notification::message(from, text)))
+        }
+        "announce" => {
+            let text = words.collect::<Vec<_>>().join(" ");
+            Command::Event(Event::Notified(Notification::announcement(text)))
+        }
+        "lowbattery" => Command::Event(Event::Notified(Notification::low_battery(
+            next("percent")?.parse()?,
+        ))),
+        "away" => Command::Event(Event::OwnerAway),
+        "back" => Command::Event(Event::OwnerBack),
+        "screenshot" => Command::Screenshot(next("path")?.to_string()),
+        "quit" => Command::Quit,
+        // Comments, so scripts can explain themselves
+        name if name.starts_with('#') => return Ok(None),
+        other => return Err(anyhow!("unknown command {other:?}")),
+    };
+
+    Ok(Some(command))
+}
+
+fn save_png(panel: &SimulatedPanel, path: &str) -> anyhow::Result<()> {
+    let file = File::create(path).with_context(|| format!("creating {path}"))?;
+
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        amoled::WIDTH as u32,
+        amoled::HEIGHT as u32,
+    );
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let data: Vec<u8> = panel
+        .snapshot()
+        .into_iter()
+        .flat_map(|pixel| [pixel.r(), pixel.g(), pixel.b()])
+        .collect();
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+
+    Ok(())
+}
@@ -0,0 +1,169 @@
+//! Wi-Fi networks the beacon knows about, persisted in NVS.
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::hal::{DefaultStore, KeyValueStore};
+
+#[cfg(target_os = "espidf")]
+const NAMESPACE: &str = "wifi";
+const NETWORKS_KEY: &str = "networks";
+
+/// Most networks a beacon will remember
+pub const MAX_NETWORKS: usize = 8;
+
+/// Inner authentication for WPA2-Enterprise (EAP-TTLS)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase2Method {
+    #[default]
+    Mschapv2,
+    Mschap,
+    Pap,
+    Chap,
+    Eap,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum NetworkAuth {
+    Open,
+    Personal {
+        password: String,
+    },
+    Enterprise {
+        identity: String,
+        username: String,
+        password: String,
+        #[serde(default)]
+        phase2: Phase2Method,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkCredentials {
+    pub ssid: String,
+    pub auth: NetworkAuth,
+}
+
+pub struct CredentialStore<S = DefaultStore> {
+    store: S,
+}
+
+#[cfg(target_os = "espidf")]
+impl CredentialStore {
+    pub fn new(partition: esp_idf_svc::nvs::EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        Ok(Self::with_store(crate::hal::esp::NvsStore::new(
+            partition, NAMESPACE,
+        )?))
+    }
+}
+
+impl<S: KeyValueStore> CredentialStore<S> {
+    /// Keeps the networks in `store`, which should be dedicated to them
+    pub fn with_store(store: S) -> Self {
+        Self { store }
+    }
+
+    /// Known networks, most recently added first
+    pub fn networks(&self) -> anyhow::Result<Vec<NetworkCredentials>> {
+        match self.store.get_blob(NETWORKS_KEY)? {
+            Some(blob) => Ok(serde_json::from_slice(&blob)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Whether there is no network to connect to, so the beacon has to be provisioned
+    pub fn is_empty(&self) -> anyhow::Result<bool> {
+        Ok(self.networks()?.is_empty())
+    }
+
+    /// Stores a network, replacing any existing entry with the same SSID
+    pub fn add(&mut self, network: NetworkCredentials) -> anyhow::Result<()> {
+        let mut networks = self.networks()?;
+        networks.retain(|known| known.ssid != network.ssid);
+        networks.insert(0, network);
+        networks.truncate(MAX_NETWORKS);
+
+        self.save(&networks)
+    }
+
+    /// Forgets a network, returning whether it was known
+    pub fn remove(&mut self, ssid: &str) -> anyhow::Result<bool> {
+        let mut networks = self.networks()?;
+        let before = networks.len();
+        networks.retain(|known| known.ssid != ssid);
+
+        if networks.len() == before {
+            return Ok(false);
+        }
+
+        self.save(&networks)?;
+        Ok(true)
+    }
+
+    pub fn clear(&mut self) -> anyhow::Result<()> {
+        self.store.remove(NETWORKS_KEY)?;
+        Ok(())
+    }
+
+    fn save(&mut self, networks: &[NetworkCredentials]) -> anyhow::Result<()> {
+        if networks.iter().any(|network| network.ssid.len() > 32) {
+            return Err(anyhow!("SSIDs are limited to 32 bytes"));
+        }
+
+        self.store
+            .set_blob(NETWORKS_KEY, &serde_json::to_vec(networks)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::fake::MemoryStore;
+
+    fn network(ssid: &str) -> NetworkCredentials {
+        NetworkCredentials {
+            ssid: ssid.to_string(),
+            auth: NetworkAuth::Personal {
+                password: "hunter22".to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn keeps_the_newest_network_first() {
+        let mut credentials = CredentialStore::with_store(MemoryStore::default());
+        credentials.add(network("lab")).unwrap();
+        credentials.add(network("cafe")).unwrap();
+        credentials.add(network("lab")).unwrap();
+
+        let ssids: Vec<_> = credentials
+            .networks()
+            .unwrap()
+            .into_iter()
+            .map(|network| network.ssid)
+            .collect();
+        assert_eq!(ssids, ["lab", "cafe"]);
+    }
+
+    #[test]
+    fn is_empty_again_once_every_network_is_removed() {
+        let mut credentials = CredentialStore::with_store(MemoryStore::default());
+        assert!(credentials.is_empty().unwrap());
+
+        credentials.add(network("lab")).unwrap();
+        assert!(!credentials.is_empty().unwrap());
+
+        assert!(credentials.remove("lab").unwrap());
+        assert!(!credentials.remove("lab").unwrap());
+        assert!(credentials.is_empty().unwrap());
+    }
+
+    #[test]
+    fn rejects_overlong_ssids() {
+        let mut credentials = CredentialStore::with_store(MemoryStore::default());
+        assert!(credentials.add(network(&"x".repeat(33))).is_err());
+        assert!(credentials.is_empty().unwrap());
+    }
+}
@@ -0,0 +1,212 @@
+//! Hardware abstraction for the beacon's peripherals.
+//!
+//! App logic is written against these traits. [`esp`] implements them on top of the board's
+//! drivers, while [`fake`] keeps everything in memory so the same logic runs on a desktop.
+
+use core::future::Future;
+
+use embassy_time::Duration;
+use embedded_graphics::prelude::Point;
+use http::Method;
+use smart_leds::RGB8;
+
+use crate::net::{NetError, Progress};
+
+pub mod animation;
+pub mod effects;
+#[cfg(target_os = "espidf")]
+pub mod esp;
+pub mod fake;
+pub mod leds;
+pub mod segments;
+
+pub use self::animation::Animator;
+pub use self::effects::{Effect, Effects, Lights};
+pub use self::leds::{Pixels, PowerBudget, Zone};
+pub use self::segments::{DisplayCommand, SegmentError, Segments};
+
+/// Storage used by settings and credentials unless another one is given
+#[cfg(target_os = "espidf")]
+pub type DefaultStore = esp::NvsStore;
+#[cfg(not(target_os = "espidf"))]
+pub type DefaultStore = fake::MemoryStore;
+
+/// LEDs along the base, before the beacon LED at the end of the strip
+pub const NUM_BASE_LEDS: usize = 5;
+pub const NUM_LEDS: usize = NUM_BASE_LEDS + 1;
+
+/// The two-digit seven-segment display
+pub trait SegmentDisplay {
+    fn show(&mut self, command: DisplayCommand);
+
+    /// Shows a byte with one nibble per digit, or blanks the display
+    fn set_number(&mut self, number: Option<u8>) {
+        self.show(number.map_or(DisplayCommand::Clear, DisplayCommand::Hex));
+    }
+
+    /// Shows a number in decimal, capped at 99 to fit on two digits
+    fn set_decimal(&mut self, value: u8) {
+        self.show(DisplayCommand::Decimal(value));
+    }
+
+    /// PWM duty from off at 0 to full at 255
+    fn set_brightness(&mut self, brightness: u8) {
+        self.show(DisplayCommand::SetBrightness(brightness));
+    }
+
+    /// Shows up to two characters such as "Hi" or "E3", see [`DisplayCommand::text`]
+    fn set_text(&mut self, text: &str) -> Result<(), SegmentError> {
+        self.show(DisplayCommand::text(text)?);
+        Ok(())
+    }
+
+    /// Scrolls text that does not fit on two digits, see [`DisplayCommand::marquee`]
+    fn scroll_text(&mut self, text: &str, step: Duration) -> Result<(), SegmentError> {
+        self.show(DisplayCommand::marquee(text, step)?);
+        Ok(())
+    }
+
+    /// Shows `command` for `duration`, then goes back to what was shown before
+    fn show_for(&mut self, command: DisplayCommand, duration: Duration) {
+        self.show(command.for_duration(duration));
+    }
+
+    /// Shows a percentage, capped at 99
+    fn set_percent(&mut self, percent: u8) {
+        self.set_decimal(percent);
+    }
+}
+
+/// The LED strip, with the base LEDs first and the beacon LED last
+pub trait LedStrip {
+    /// Shows linear colors; any gamma correction is up to the strip
+    fn write(&mut self, colors: [RGB8; NUM_LEDS]) -> anyhow::Result<()>;
+
+    /// Whether the last colors written would now come out differently, so they need writing
+    /// again even though they are the same
+    fn needs_refresh(&mut self) -> bool {
+        false
+    }
+
+    /// Paints the base and the beacon LED the same color
+    fn set_all_colors(&mut self, color: RGB8) {
+        self.write([color; NUM_LEDS]).expect("valid led write");
+    }
+
+    /// Paints every LED in `zone`, turning the rest of the strip off
+    fn set_zone_color(&mut self, zone: Zone, color: RGB8) {
+        let mut pixels = Pixels::default();
+        pixels.fill(zone, color);
+        self.write(pixels.into()).expect("valid led write");
+    }
+
+    /// Fills the base LEDs as a bar proportional to `progress`, with the beacon LED lit throughout
+    fn show_progress(&mut self, progress: Progress, color: RGB8) {
+        let mut pixels = Pixels::filled(color);
+        Effect::Progress { progress, color }.render(pixels.zone_mut(Zone::Base), Duration::MIN);
+
+        self.write(pixels.into()).expect("valid led write");
+    }
+}
+
+/// Lends a strip out, such as to [`effects::run`], while keeping hold of it
+impl<L: LedStrip + ?Sized> LedStrip for &mut L {
+    fn write(&mut self, colors: [RGB8; NUM_LEDS]) -> anyhow::Result<()> {
+        (**self).write(colors)
+    }
+
+    fn needs_refresh(&mut self) -> bool {
+        (**self).needs_refresh()
+    }
+}
+
+/// Where the board is drawing power from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PowerSource {
+    /// USB, with the cell charging or full
+    #[default]
+    External,
+    /// The cell alone
+    Battery,
+}
+
+/// Tells whether the board is running on battery, and how much charge is left
+pub trait PowerMonitor {
+    fn power_source(&mut self) -> anyhow::Result<PowerSource>;
+
+    /// Charge left in the cell, from 0 to 100
+    fn charge_percent(&mut self) -> anyhow::Result<u8>;
+}
+
+/// The capacitive touch panel over the AMOLED
+pub trait TouchPanel {
+    /// Waits for the panel to report activity and returns the points in contact.
+    ///
+    /// The future is `Send` so the touch loop can run as its own task.
+    fn touches(&mut self) -> impl Future<Output = anyhow::Result<Vec<Point>>> + Send;
+}
+
+/// The NFC reader used for passports
+pub trait NfcReader {
+    /// Asks the reader for its firmware version, which proves it is up and answering
+    fn firmware_version(&mut self) -> impl Future<Output = anyhow::Result<Vec<u8>>>;
+
+    /// Reads the four pages (16 bytes) starting at `page` from an NTAG in the field
+    fn read_ntag(&mut self, page: u8) -> impl Future<Output = anyhow::Result<Vec<u8>>>;
+}
+
+/// A fully read HTTP response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl NetResponse {
+    pub fn is_success(&self) -> bool {
+        (200..=299).contains(&self.status)
+    }
+}
+
+/// Something that can make HTTP requests
+pub trait NetClient {
+    fn request(
+        &self,
+        method: Method,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Option<&[u8]>,
+    ) -> impl Future<Output = Result<NetResponse, NetError>>;
+}
+
+/// Lends a client out, such as to the API client, while keeping hold of it
+impl<C: NetClient + ?Sized> NetClient for &C {
+    fn request(
+        &self,
+        method: Method,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Option<&[u8]>,
+    ) -> impl Future<Output = Result<NetResponse, NetError>> {
+        (**self).request(method, url, headers, body)
+    }
+}
+
+/// Persistent key/value storage, one namespace per store.
+///
+/// Strings and blobs are kept apart as they are in NVS, so reading a key back needs the same
+/// type it was written with.
+pub trait KeyValueStore {
+    fn get_blob(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> anyhow::Result<()>;
+
+    fn get_str(&self, key: &str) -> anyhow::Result<Option<String>>;
+
+    fn set_str(&mut self, key: &str, value: &str) -> anyhow::Result<()>;
+
+    fn contains(&self, key: &str) -> anyhow::Result<bool>;
+
+    /// Deletes a key, returning whether it existed
+    fn remove(&mut self, key: &str) -> anyhow::Result<bool>;
+}
@@ -0,0 +1,262 @@
+//! Sequencing of animated and timed [`DisplayCommand`]s into the segments to show at each moment.
+//!
+//! Nothing here touches the display, so each frame can be worked out for any point in time.
+
+use embassy_time::Instant;
+
+use super::{DisplayCommand, Segments};
+
+/// A command along with when it started showing
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Showing {
+    command: DisplayCommand,
+    since: Instant,
+}
+
+impl Showing {
+    fn frame(&self, now: Instant) -> Option<[Segments; 2]> {
+        self.command
+            .frame(now.saturating_duration_since(self.since))
+    }
+}
+
+/// What the display shows over time: the latest content, unless a timed message is covering it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Animator {
+    content: Showing,
+    /// A [`DisplayCommand::Timed`] message and when it ends
+    message: Option<(Showing, Instant)>,
+}
+
+impl Animator {
+    pub fn new(command: DisplayCommand, now: Instant) -> Self {
+        let mut animator = Self {
+            content: Showing {
+                command: DisplayCommand::Clear,
+                since: now,
+            },
+            message: None,
+        };
+        animator.show(command, now);
+        animator
+    }
+
+    /// Starts showing `command` at `now`. Showing the content that is already up again keeps its
+    /// animation going rather than starting it over, and any timed message stays on top of it.
+    pub fn show(&mut self, command: DisplayCommand, now: Instant) {
+        self.message = self.message.take().filter(|(_, until)| now < *until);
+
+        match command {
+            // Brightness is up to the display itself
+            DisplayCommand::SetBrightness(_) => {}
+            DisplayCommand::Timed { command, duration } => {
+                self.message = Some((
+                    Showing {
+                        command: *command,
+                        since: now,
+                    },
+                    now + duration,
+                ));
+            }
+            command if command != self.content.command => {
+                self.content = Showing {
+                    command,
+                    since: now,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    /// The segments to show at `now`
+    pub fn frame(&self, now: Instant) -> [Segments; 2] {
+        self.message
+            .as_ref()
+            .filter(|(_, until)| now < *until)
+            .and_then(|(message, _)| message.frame(now))
+            .or_else(|| self.content.frame(now))
+            .unwrap_or_default()
+    }
+
+    /// Whether the frame can change without anything new being shown
+    pub fn is_animated(&self) -> bool {
+        self.message.is_some() || self.content.command.is_animated()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_time::Duration;
+
+    use super::*;
+
+    const START: Instant = Instant::from_secs(100);
+
+    fn at(millis: u64) -> Instant {
+        START + Duration::from_millis(millis)
+    }
+
+    fn text(text: &str) -> [Segments; 2] {
+        DisplayCommand::text(text).unwrap().segments().unwrap()
+    }
+
+    #[test]
+    fn blinks_on_for_the_first_half_of_each_period() {
+        let segments = text("Hi");
+        let animator = Animator::new(
+            DisplayCommand::Blink {
+                segments,
+                period: Duration::from_millis(500),
+            },
+            START,
+        );
+
+        assert!(animator.is_animated());
+        for (millis, on) in [
+            (0, true),
+            (249, true),
+            (250, false),
+            (499, false),
+            (500, true),
+        ] {
+            let expected = if on { segments } else { [Segments::BLANK; 2] };
+            assert_eq!(animator.frame(at(millis)), expected, "{millis}ms");
+        }
+
+        // Showing the same blink again keeps its phase
+        let mut animator = animator;
+        animator.show(
+            DisplayCommand::Blink {
+                segments,
+                period: Duration::from_millis(500),
+            },
+            at(300),
+        );
+        assert_eq!(animator.frame(at(300)), [Segments::BLANK; 2]);
+    }
+
+    #[test]
+    fn never_blanks_a_blink_without_a_period() {
+        let animator = Animator::new(
+            DisplayCommand::Blink {
+                segments: text("Hi"),
+                period: Duration::MIN,
+            },
+            START,
+        );
+
+        assert_eq!(animator.frame(at(0)), text("Hi"));
+        assert_eq!(animator.frame(at(1234)), text("Hi"));
+    }
+
+    #[test]
+    fn scrolls_marquees_off_and_around_again() {
+        let animator = Animator::new(
+            DisplayCommand::marquee("Hello", Duration::from_millis(300)).unwrap(),
+            START,
+        );
+
+        let frames: Vec<_> = (0..9).map(|step| animator.frame(at(step * 300))).collect();
+        assert_eq!(
+            frames,
+            [
+                text("He"),
+                text("el"),
+                text("ll"),
+                text("lo"),
+                text("o "),
+                text("  "),
+                text(" H"),
+                text("He"),
+                text("el"),
+            ]
+        );
+        assert_eq!(animator.frame(at(299)), text("He"));
+    }
+
+    #[test]
+    fn chases_a_spinner_clockwise_around_both_digits() {
+        let animator = Animator::new(
+            DisplayCommand::Spinner {
+                step: Duration::from_millis(100),
+            },
+            START,
+        );
+
+        let frames: Vec<_> = (0..9)
+            .map(|step| animator.frame(at(step * 100)).map(Segments::bits))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                [0x01, 0x00],
+                [0x00, 0x01],
+                [0x00, 0x02],
+                [0x00, 0x04],
+                [0x00, 0x08],
+                [0x08, 0x00],
+                [0x10, 0x00],
+                [0x20, 0x00],
+                [0x01, 0x00],
+            ]
+        );
+    }
+
+    #[test]
+    fn goes_back_after_a_timed_message() {
+        let mut animator = Animator::new(DisplayCommand::Decimal(42), START);
+        animator.show(
+            DisplayCommand::text("Hi")
+                .unwrap()
+                .for_duration(Duration::from_secs(2)),
+            at(1000),
+        );
+
+        assert!(animator.is_animated());
+        assert_eq!(animator.frame(at(1000)), text("Hi"));
+        assert_eq!(animator.frame(at(2999)), text("Hi"));
+        assert_eq!(animator.frame(at(3000)), text("42"));
+
+        // Content shown under the message is what comes back once it ends
+        animator.show(DisplayCommand::Decimal(7), at(2000));
+        assert_eq!(animator.frame(at(2000)), text("Hi"));
+        assert_eq!(animator.frame(at(3000)), text(" 7"));
+
+        // The expired message is dropped once anything else is shown
+        animator.show(DisplayCommand::SetBrightness(10), at(3000));
+        assert!(!animator.is_animated());
+        assert_eq!(animator.frame(at(3000)), text(" 7"));
+    }
+
+    #[test]
+    fn replaces_a_timed_message_with_a_newer_one() {
+        let mut animator = Animator::new(DisplayCommand::Clear, START);
+        animator.show(
+            DisplayCommand::text("Hi")
+                .unwrap()
+                .for_duration(Duration::from_secs(5)),
+            at(0),
+        );
+        animator.show(
+            DisplayCommand::text("Yo")
+                .unwrap()
+                .for_duration(Duration::from_secs(1)),
+            at(500),
+        );
+
+        assert_eq!(animator.frame(at(1000)), text("Yo"));
+        assert_eq!(animator.frame(at(1500)), [Segments::BLANK; 2]);
+    }
+
+    #[test]
+    fn animates_timed_messages_from_their_own_start() {
+        let mut animator = Animator::new(DisplayCommand::Decimal(1), START);
+        let spinner = DisplayCommand::Spinner {
+            step: Duration::from_millis(100),
+        };
+        animator.show(spinner.for_duration(Duration::from_secs(1)), at(5050));
+
+        assert_eq!(animator.frame(at(5050)).map(Segments::bits), [0x01, 0x00]);
+        assert_eq!(animator.frame(at(5150)).map(Segments::bits), [0x00, 0x01]);
+    }
+}
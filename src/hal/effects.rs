@@ -0,0 +1,538 @@
+//! Animated effects for the LED strip, one per [`Zone`], crossfading whenever they change.
+//!
+//! [`Engine::render`] only depends on the time it is given, so every frame can be worked out
+//! without a strip or a clock. [`run`] drives it at [`FRAME`] intervals from its own task.
+
+use core::f32::consts::TAU;
+
+use embassy_time::{Duration, Instant, Ticker};
+use smart_leds::hsv::{hsv2rgb, Hsv};
+use smart_leds::RGB8;
+use tokio::sync::watch;
+
+use super::leds::{mix, scale, Pixels, Zone};
+use super::LedStrip;
+use crate::net::Progress;
+
+/// Time between frames, fast enough that fades look smooth
+pub const FRAME: Duration = Duration::from_hz(50);
+
+/// How long one effect takes to fade into the next
+pub const CROSSFADE: Duration = Duration::from_millis(400);
+
+/// How long each sparkle stays lit
+const SPARKLE_STEP: Duration = Duration::from_millis(80);
+
+/// What one zone of the strip shows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Effect {
+    Solid(RGB8),
+    /// Fades in and out again once every `period`
+    Breathe {
+        color: RGB8,
+        period: Duration,
+    },
+    /// Goes through every hue once every `period`, spread along the zone
+    Rainbow {
+        period: Duration,
+    },
+    /// One LED going round the zone once every `period`, trailed by a dimmer one
+    Chase {
+        color: RGB8,
+        period: Duration,
+    },
+    /// Bands of light and dark going round the zone once every `period`
+    Wave {
+        color: RGB8,
+        period: Duration,
+    },
+    /// LEDs flashing at random, each one lit at any moment with a chance of `density` out of 255
+    Sparkle {
+        color: RGB8,
+        density: u8,
+    },
+    /// Lit for `on` at the start of every `period`
+    Strobe {
+        color: RGB8,
+        on: Duration,
+        period: Duration,
+    },
+    /// A bar proportional to `progress`, with the leading LED fading in
+    Progress {
+        progress: Progress,
+        color: RGB8,
+    },
+}
+
+impl Default for Effect {
+    fn default() -> Self {
+        Self::Solid(RGB8::default())
+    }
+}
+
+impl Effect {
+    /// Fills `leds` with the frame `elapsed` into the effect
+    pub fn render(&self, leds: &mut [RGB8], elapsed: Duration) {
+        // How far into the current `period`, out of 1
+        let phase = |period: Duration| match period.as_ticks() {
+            0 => 0.0,
+            period => (elapsed.as_ticks() % period) as f32 / period as f32,
+        };
+
+        match *self {
+            Self::Solid(color) => leds.fill(color),
+            Self::Breathe { color, period } => {
+                let level = (1.0 - (phase(period) * TAU).cos()) / 2.0;
+                leds.fill(scale(color, (level * 255.0) as u8));
+            }
+            Self::Rainbow { period } => {
+                let len = leds.len();
+                for (i, led) in leds.iter_mut().enumerate() {
+                    let hue = (phase(period) + i as f32 / len as f32) * 256.0;
+                    *led = hsv2rgb(Hsv {
+                        hue: hue as u32 as u8,
+                        sat: 255,
+                        val: 255,
+                    });
+                }
+            }
+            Self::Chase { color, period } => {
+                let len = leds.len();
+                let head = (phase(period) * len as f32) as usize % len.max(1);
+                let tail = (head + len - 1) % len.max(1);
+
+                leds.fill(RGB8::default());
+                if let Some(led) = leds.get_mut(tail) {
+                    *led = scale(color, 64);
+                }
+                if let Some(led) = leds.get_mut(head) {
+                    *led = color;
+                }
+            }
+            Self::Wave { color, period } => {
+                let len = leds.len();
+                for (i, led) in leds.iter_mut().enumerate() {
+                    let angle = (phase(period) - i as f32 / len as f32) * TAU;
+                    let level = (1.0 + angle.sin()) / 2.0;
+                    *led = scale(color, (level * 255.0) as u8);
+                }
+            }
+            Self::Sparkle { color, density } => {
+                let step = elapsed.as_ticks() / SPARKLE_STEP.as_ticks();
+                for (i, led) in leds.iter_mut().enumerate() {
+                    let lit = (noise(step, i) as u8) < density;
+                    *led = if lit { color } else { RGB8::default() };
+                }
+            }
+            Self::Strobe { color, on, period } => {
+                let lit = match period.as_ticks() {
+                    0 => true,
+                    period => elapsed.as_ticks() % period < on.as_ticks(),
+                };
+                leds.fill(if lit { color } else { RGB8::default() });
+            }
+            Self::Progress { progress, color } => {
+                // Scaled to 1/255ths of an LED so the leading LED fades in
+                let filled = progress
+                    .total
+                    .filter(|total| *total > 0)
+                    .map(|total| progress.written.min(total) * leds.len() as u64 * 255 / total)
+                    .unwrap_or_default();
+
+                for (i, led) in leds.iter_mut().enumerate() {
+                    let level = filled.saturating_sub(i as u64 * 255).min(255) as u8;
+                    *led = scale(color, level);
+                }
+            }
+        }
+    }
+}
+
+/// A well-mixed hash of `step` and `index`, so sparkles look random but repeat exactly
+fn noise(step: u64, index: usize) -> u64 {
+    let mut x = step
+        .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        .wrapping_add(index as u64 + 1);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// The effect for each zone
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Effects {
+    pub base: Effect,
+    pub beacon: Effect,
+}
+
+impl Effects {
+    /// The same effect on every zone
+    pub fn all(effect: Effect) -> Self {
+        Self {
+            base: effect.clone(),
+            beacon: effect,
+        }
+    }
+
+    pub fn zone(&self, zone: Zone) -> &Effect {
+        match zone {
+            Zone::Base => &self.base,
+            Zone::Beacon => &self.beacon,
+        }
+    }
+}
+
+/// An effect along with when it started
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Running {
+    effect: Effect,
+    since: Instant,
+}
+
+impl Running {
+    fn render(&self, leds: &mut [RGB8], now: Instant) {
+        self.effect
+            .render(leds, now.saturating_duration_since(self.since));
+    }
+}
+
+/// What one zone shows, along with what it is fading in from
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ZoneEffect {
+    current: Running,
+    /// What the zone showed before, still fading itself if it was changed mid-fade, so the
+    /// blend carries on from the frame that was showing
+    fading_from: Option<Box<ZoneEffect>>,
+}
+
+impl ZoneEffect {
+    fn render(&self, zone: Zone, leds: &mut [RGB8], now: Instant) {
+        self.current.render(leds, now);
+
+        let faded = now.saturating_duration_since(self.current.since);
+        let Some(from) = self.fading_from.as_ref().filter(|_| faded < CROSSFADE) else {
+            return;
+        };
+
+        let mut old = Pixels::default();
+        from.render(zone, old.zone_mut(zone), now);
+
+        let amount = (faded.as_ticks() * 255 / CROSSFADE.as_ticks()) as u8;
+        for (led, old) in leds.iter_mut().zip(old.zone(zone)) {
+            *led = mix(*old, *led, amount);
+        }
+    }
+
+    /// Forgets the fades that are over by `now`
+    fn settle(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.current.since) >= CROSSFADE {
+            self.fading_from = None;
+        } else if let Some(from) = &mut self.fading_from {
+            from.settle(now);
+        }
+    }
+}
+
+/// Works out the frame for any moment from the effects shown so far
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Engine {
+    zones: [ZoneEffect; 2],
+}
+
+impl Engine {
+    pub fn new(effects: &Effects, now: Instant) -> Self {
+        Self {
+            zones: Zone::ALL.map(|zone| ZoneEffect {
+                current: Running {
+                    effect: effects.zone(zone).clone(),
+                    since: now,
+                },
+                fading_from: None,
+            }),
+        }
+    }
+
+    /// Starts fading into `effects` at `now`. A zone whose effect stays the same keeps going
+    /// without starting over.
+    pub fn set(&mut self, effects: &Effects, now: Instant) {
+        for (zone, state) in Zone::ALL.into_iter().zip(&mut self.zones) {
+            let effect = effects.zone(zone);
+            if *effect == state.current.effect {
+                continue;
+            }
+
+            let next = ZoneEffect {
+                current: Running {
+                    effect: effect.clone(),
+                    since: now,
+                },
+                fading_from: None,
+            };
+            let mut shown = core::mem::replace(state, next);
+            shown.settle(now);
+            state.fading_from = Some(Box::new(shown));
+        }
+    }
+
+    /// The frame to show at `now`
+    pub fn render(&self, now: Instant) -> Pixels {
+        let mut pixels = Pixels::default();
+
+        for (zone, state) in Zone::ALL.into_iter().zip(&self.zones) {
+            state.render(zone, pixels.zone_mut(zone), now);
+        }
+
+        pixels
+    }
+}
+
+/// Sets what the strip shows from anywhere, while [`run`] draws it
+#[derive(Debug, Clone)]
+pub struct Lights(watch::Sender<Effects>);
+
+impl Lights {
+    /// Creates the handle along with the receiver to give to [`run`]
+    pub fn new(effects: Effects) -> (Self, watch::Receiver<Effects>) {
+        let (tx, rx) = watch::channel(effects);
+        (Self(tx), rx)
+    }
+
+    /// Fades into `effects`, unless they are already showing
+    pub fn set(&self, effects: Effects) {
+        self.0.send_if_modified(|shown| {
+            let changed = *shown != effects;
+            if changed {
+                *shown = effects;
+            }
+            changed
+        });
+    }
+}
+
+/// Draws `effects` on `strip` every [`FRAME`] until every [`Lights`] handle is gone
+pub async fn run<L: LedStrip>(
+    mut strip: L,
+    mut effects: watch::Receiver<Effects>,
+) -> anyhow::Result<()> {
+    let mut engine = Engine::new(&effects.borrow_and_update(), Instant::now());
+    let mut shown = None;
+    let mut ticker = Ticker::every(FRAME);
+
+    loop {
+        match effects.has_changed() {
+            Ok(true) => engine.set(&effects.borrow_and_update(), Instant::now()),
+            Ok(false) => {}
+            Err(_) => return Ok(()),
+        }
+
+        // Writing the strip takes a while, so only when something changed
+        let pixels = engine.render(Instant::now());
+        if shown != Some(pixels) || strip.needs_refresh() {
+            strip.write(pixels.into())?;
+            shown = Some(pixels);
+        }
+
+        ticker.next().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_time::{with_timeout, Timer};
+
+    use super::*;
+    use crate::hal::fake::FakeSmartLeds;
+    use crate::hal::leds::{Leds, PowerBudget};
+    use crate::hal::{PowerSource, NUM_BASE_LEDS, NUM_LEDS};
+    use crate::testing::block_on;
+
+    const START: Instant = Instant::from_secs(100);
+    const RED: RGB8 = RGB8::new(255, 0, 0);
+    const BLUE: RGB8 = RGB8::new(0, 0, 255);
+
+    fn render(effect: &Effect, elapsed: Duration) -> [RGB8; NUM_BASE_LEDS] {
+        let mut leds = [RGB8::default(); NUM_BASE_LEDS];
+        effect.render(&mut leds, elapsed);
+        leds
+    }
+
+    #[test]
+    fn crossfades_from_one_effect_into_the_next() {
+        let mut engine = Engine::new(&Effects::all(Effect::Solid(RED)), START);
+        let changed = START + Duration::from_secs(1);
+        engine.set(
+            &Effects {
+                base: Effect::Solid(BLUE),
+                beacon: Effect::Solid(RED),
+            },
+            changed,
+        );
+
+        let frame = engine.render(changed);
+        assert_eq!(frame, Pixels::filled(RED));
+
+        let halfway = engine.render(changed + CROSSFADE / 2).zone(Zone::Base)[0];
+        assert!((126..=129).contains(&halfway.r), "{halfway:?}");
+        assert!((126..=129).contains(&halfway.b), "{halfway:?}");
+
+        let frame = engine.render(changed + CROSSFADE);
+        assert!(frame.zone(Zone::Base).iter().all(|led| *led == BLUE));
+        // The beacon kept its effect, so it never faded
+        assert_eq!(frame.beacon(), RED);
+    }
+
+    #[test]
+    fn carries_on_from_the_frame_shown_when_changed_mid_fade() {
+        const GREEN: RGB8 = RGB8::new(0, 255, 0);
+
+        let mut engine = Engine::new(&Effects::all(Effect::Solid(RED)), START);
+        engine.set(&Effects::all(Effect::Solid(BLUE)), START);
+
+        let changed = START + CROSSFADE / 2;
+        let shown = engine.render(changed);
+        engine.set(&Effects::all(Effect::Solid(GREEN)), changed);
+        assert_eq!(engine.render(changed), shown);
+
+        // Red keeps fading out underneath rather than jumping to where blue was
+        let later = changed + CROSSFADE / 4;
+        let led = engine.render(later).zone(Zone::Base)[0];
+        assert!(led.r > 0 && led.b > 0 && led.g > 0, "{led:?}");
+        let before = engine.render(changed + CROSSFADE / 8).zone(Zone::Base)[0];
+        assert!(
+            led.r < before.r && led.g > before.g,
+            "{before:?} then {led:?}"
+        );
+
+        let frame = engine.render(changed + CROSSFADE);
+        assert_eq!(frame, Pixels::filled(GREEN));
+    }
+
+    #[test]
+    fn lets_go_of_fades_that_are_over() {
+        let mut engine = Engine::new(&Effects::all(Effect::Solid(RED)), START);
+        for (i, color) in [BLUE, RED, BLUE].into_iter().enumerate() {
+            engine.set(
+                &Effects::all(Effect::Solid(color)),
+                START + CROSSFADE * i as u32,
+            );
+        }
+
+        let [base, _] = &engine.zones;
+        let from = base.fading_from.as_ref().unwrap();
+        assert_eq!(from.current.effect, Effect::Solid(RED));
+        assert_eq!(from.fading_from, None);
+    }
+
+    #[test]
+    fn strobes_for_the_on_time_of_each_period() {
+        let strobe = Effect::Strobe {
+            color: RED,
+            on: Duration::from_millis(20),
+            period: Duration::from_millis(100),
+        };
+
+        let lit: Vec<u64> = (0..300)
+            .filter(|millis| render(&strobe, Duration::from_millis(*millis))[0] == RED)
+            .collect();
+        assert_eq!(lit.len(), 60);
+        assert!(lit.iter().all(|millis| millis % 100 < 20));
+
+        let steady = Effect::Strobe {
+            color: RED,
+            on: Duration::from_millis(20),
+            period: Duration::MIN,
+        };
+        assert_eq!(
+            render(&steady, Duration::from_millis(50)),
+            [RED; NUM_BASE_LEDS]
+        );
+    }
+
+    #[test]
+    fn moves_waves_along_the_zone() {
+        let period = Duration::from_millis(1000);
+        let wave = Effect::Wave {
+            color: BLUE,
+            period,
+        };
+        let step = period / NUM_BASE_LEDS as u32;
+
+        for frame in 0..NUM_BASE_LEDS as u32 * 2 {
+            let now = render(&wave, step * frame);
+            let next = render(&wave, step * (frame + 1));
+
+            // Each LED takes on the level the one before it had a step earlier
+            for i in 1..NUM_BASE_LEDS {
+                assert!(
+                    next[i].b.abs_diff(now[i - 1].b) <= 1,
+                    "frame {frame}, LED {i}"
+                );
+            }
+            assert!(now.iter().all(|led| led.r == 0 && led.g == 0));
+        }
+
+        // Bands of light and dark, rather than one level across the zone
+        let levels = render(&wave, Duration::from_millis(125)).map(|led| led.b);
+        assert!(levels.iter().max().unwrap() - levels.iter().min().unwrap() > 200);
+    }
+
+    #[test]
+    fn writes_the_strip_only_when_the_frame_changes() {
+        let mut leds = Leds::new(FakeSmartLeds::default());
+        let (lights, effects) = Lights::new(Effects::all(Effect::Solid(RED)));
+
+        block_on(async {
+            let setter = tokio::task::spawn(async move {
+                Timer::after_millis(200).await;
+                lights.set(Effects::all(Effect::Solid(BLUE)));
+                // Setting what is already showing changes nothing
+                Timer::after_millis(600).await;
+                lights.set(Effects::all(Effect::Solid(BLUE)));
+                Timer::after_millis(200).await;
+                lights
+            });
+
+            let stopped = with_timeout(Duration::from_millis(900), run(&mut leds, effects)).await;
+            assert!(stopped.is_err(), "run stopped early");
+            setter.abort();
+        });
+
+        let frames = &leds.leds.frames;
+        assert_eq!(frames.first().unwrap(), &vec![RED; NUM_LEDS]);
+        assert_eq!(frames.last().unwrap(), &vec![BLUE; NUM_LEDS]);
+        assert!(frames.windows(2).all(|pair| pair[0] != pair[1]));
+        // The first frame, then one per frame of the crossfade at most
+        let fade_frames = (CROSSFADE.as_ticks() / FRAME.as_ticks()) as usize;
+        assert!(frames.len() <= fade_frames + 2, "{} writes", frames.len());
+    }
+
+    #[test]
+    fn writes_the_same_frame_again_when_the_power_source_changes() {
+        let (power, power_rx) = watch::channel(PowerSource::External);
+        let budget = PowerBudget {
+            battery_current_ma: 50,
+            ..Default::default()
+        };
+        let mut leds = Leds::new(FakeSmartLeds::default()).with_budget(budget, power_rx);
+        let (lights, effects) = Lights::new(Effects::all(Effect::Solid(RED)));
+
+        block_on(async {
+            let switcher = tokio::task::spawn(async move {
+                Timer::after_millis(100).await;
+                power.send_replace(PowerSource::Battery);
+                Timer::after_millis(200).await;
+                (power, lights)
+            });
+
+            let stopped = with_timeout(Duration::from_millis(250), run(&mut leds, effects)).await;
+            assert!(stopped.is_err(), "run stopped early");
+            switcher.abort();
+        });
+
+        let [external, battery] = &leds.leds.frames[..] else {
+            panic!("expected two writes, got {}", leds.leds.frames.len());
+        };
+        assert_eq!(external, &vec![RED; NUM_LEDS]);
+        assert!(battery[0].r < RED.r);
+    }
+}
@@ -0,0 +1,620 @@
+//! The [`hal`](super) traits on top of the beacon board's drivers.
+
+use core::str::FromStr;
+use core::time::Duration;
+use std::convert::Infallible;
+use std::net::TcpStream;
+use std::os::fd::{AsRawFd, IntoRawFd};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, TryLockError};
+
+use anyhow::anyhow;
+use embassy_time::Instant;
+use embedded_graphics::prelude::Point;
+use esp_idf_svc::hal::gpio::{AnyInputPin, Input, PinDriver};
+use esp_idf_svc::hal::i2c::I2cDriver;
+use esp_idf_svc::hal::ledc::LedcDriver;
+use esp_idf_svc::hal::spi::{SpiBusDriver, SpiDeviceDriver, SpiDriver};
+use esp_idf_svc::hal::units::Hertz;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::{self, EspError};
+use esp_idf_svc::timer::{EspTaskTimerService, EspTimer};
+use esp_idf_svc::tls::{EspAsyncTls, X509};
+use ft6336::{touch::PointAction, Ft6336};
+use log::warn;
+use pn532::{i2c::I2CInterfaceWithIrq, Pn532, Request};
+use shared_bus::I2cProxy;
+use url::Url;
+use ws2812_spi::Ws2812;
+
+use super::{
+    Animator, DisplayCommand, KeyValueStore, NfcReader, PowerMonitor, PowerSource, SegmentDisplay,
+    Segments, TouchPanel,
+};
+use crate::amoled::STREAMING;
+use crate::convert_error;
+use crate::net::{
+    connect_tcp, timeout, Connection, Connector, NetError, Timeouts, TlsConfig, TlsError,
+};
+
+/// The I2C bus shared by the touch panel, NFC reader and fuel gauge
+pub type SharedI2c = I2cProxy<'static, Mutex<I2cDriver<'static>>>;
+
+/// Time each digit is lit before the other one takes over
+const DIGIT_PERIOD: Duration = Duration::from_millis(2);
+
+/// Time both digits stay dark before the segments change. LEDC only applies a new duty at the
+/// end of its PWM period, so this has to cover at least one period.
+const BLANKING: Duration = Duration::from_micros(60);
+
+/// PWM frequency on the digit enable pins, well above anything visible
+pub const DIGIT_PWM_FREQUENCY: Hertz = Hertz(20_000);
+
+/// What the timers show
+struct DisplayState {
+    animator: Mutex<Animator>,
+    brightness: AtomicU8,
+}
+
+impl DisplayState {
+    fn animator(&self) -> std::sync::MutexGuard<'_, Animator> {
+        // A frame is always whole, even if a holder panicked
+        self.animator
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// The shift register behind the segments, latched by its chip select
+pub type SegmentRegister = SpiDeviceDriver<'static, Arc<SpiDriver<'static>>>;
+
+/// The seven-segment display. Two timers multiplex the digits between them, enabling one at a
+/// time through LEDC PWM at the current brightness: one darkens both digits every
+/// [`DIGIT_PERIOD`] and arms the other, which lights the next digit once [`BLANKING`] is up.
+///
+/// Both run on the timer task, where nothing may wait for long. The register shares its bus with
+/// the AMOLED, so while a frame goes to the panel the digits stay dark rather than wait it out.
+pub struct Displays {
+    state: Arc<DisplayState>,
+    _period: EspTimer<'static>,
+    _blanking: Arc<EspTimer<'static>>,
+}
+
+impl Displays {
+    /// `low_digit` and `high_digit` are LEDC channels on the digit enable pins
+    pub fn new(
+        register: SegmentRegister,
+        low_digit: LedcDriver<'static>,
+        high_digit: LedcDriver<'static>,
+        timers: &EspTaskTimerService,
+    ) -> anyhow::Result<Self> {
+        let state = Arc::new(DisplayState {
+            animator: Mutex::new(Animator::new(DisplayCommand::Decimal(99), Instant::now())),
+            brightness: AtomicU8::new(u8::MAX),
+        });
+
+        // Only ever locked from the timer task, so never contended
+        let scanner = Arc::new(Mutex::new(Scanner {
+            state: state.clone(),
+            register,
+            digits: [high_digit, low_digit],
+            next: 0,
+            failing: false,
+        }));
+
+        let blanking = Arc::new(
+            timers
+                .timer({
+                    let scanner = scanner.clone();
+                    move || Scanner::run(&scanner, Scanner::light)
+                })
+                .map_err(convert_error)?,
+        );
+        let period = timers
+            .timer({
+                let blanking = blanking.clone();
+                move || {
+                    Scanner::run(&scanner, |scanner| {
+                        scanner.blank()?;
+                        blanking.after(BLANKING).map_err(convert_error)
+                    })
+                }
+            })
+            .map_err(convert_error)?;
+        period.every(DIGIT_PERIOD).map_err(convert_error)?;
+
+        Ok(Self {
+            state,
+            _period: period,
+            _blanking: blanking,
+        })
+    }
+}
+
+/// The register and digit enables, driven by the timers of [`Displays`]
+struct Scanner {
+    state: Arc<DisplayState>,
+    register: SegmentRegister,
+    /// The high digit first
+    digits: [LedcDriver<'static>; 2],
+    /// Index of the digit lit next
+    next: usize,
+    /// Whether the last step failed, so a failing display only warns once
+    failing: bool,
+}
+
+impl Scanner {
+    /// Runs `step`, skipping it with a warning the first time it fails
+    fn run(scanner: &Mutex<Self>, step: impl FnOnce(&mut Self) -> anyhow::Result<()>) {
+        let mut scanner = scanner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        match step(&mut scanner) {
+            Ok(()) => scanner.failing = false,
+            Err(e) if !scanner.failing => {
+                warn!("Skipping display frames: {e}");
+                scanner.failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+
+    /// Darkens both digits while the segments change, so neither shows the other's
+    fn blank(&mut self) -> anyhow::Result<()> {
+        self.digits
+            .iter_mut()
+            .try_for_each(|digit| digit.set_duty(0))
+            .map_err(convert_error)
+    }
+
+    /// Shows the next digit of the current frame
+    fn light(&mut self) -> anyhow::Result<()> {
+        let index = self.next;
+        self.next = 1 - index;
+
+        let segments = self.state.animator().frame(Instant::now())[index];
+        let brightness = self.state.brightness.load(Ordering::Relaxed) as u32;
+        let digit = &mut self.digits[index];
+        let duty = digit.get_max_duty() * brightness / u8::MAX as u32;
+
+        if segments == Segments::BLANK || duty == 0 {
+            return Ok(());
+        }
+
+        // Waiting for the panel to finish its frame would hold up every other timer
+        let _bus = match STREAMING.try_lock() {
+            Ok(bus) => bus,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return Ok(()),
+        };
+
+        // Common anode, so a segment lights up when its output is low
+        self.register
+            .write(&[!segments.bits()])
+            .map_err(convert_error)?;
+        digit.set_duty(duty).map_err(convert_error)
+    }
+}
+
+impl SegmentDisplay for Displays {
+    fn show(&mut self, command: DisplayCommand) {
+        match command {
+            DisplayCommand::SetBrightness(brightness) => {
+                self.state.brightness.store(brightness, Ordering::Relaxed)
+            }
+            command => self.state.animator().show(command, Instant::now()),
+        }
+    }
+}
+
+/// The WS2812 strip, with the base LEDs first and the beacon LED last
+pub type Leds = super::leds::Leds<Ws2812<SpiBusDriver<'static, SpiDriver<'static>>>>;
+
+/// The FT6336 touch controller and its interrupt line
+pub struct Touch {
+    panel: Ft6336<SharedI2c>,
+    irq: PinDriver<'static, AnyInputPin, Input>,
+}
+
+impl Touch {
+    pub fn new(panel: Ft6336<SharedI2c>, irq: PinDriver<'static, AnyInputPin, Input>) -> Self {
+        Self { panel, irq }
+    }
+}
+
+impl TouchPanel for Touch {
+    async fn touches(&mut self) -> anyhow::Result<Vec<Point>> {
+        self.irq
+            .wait_for_falling_edge()
+            .await
+            .map_err(convert_error)?;
+
+        Ok(self
+            .panel
+            .touch_points_iter()
+            .map_err(|e| anyhow!("reading touch points: {e:?}"))?
+            .filter(|p| matches!(p.action, PointAction::Contact))
+            .map(|p| Point::new(p.x as i32, p.y as i32))
+            .collect())
+    }
+}
+
+/// Adapts a pin whose errors are never returned in practice to the infallible pin the PN532
+/// driver wants
+pub struct InfallibleDriver<T>(pub T);
+
+impl<T> embedded_hal::digital::ErrorType for InfallibleDriver<T> {
+    type Error = Infallible;
+}
+
+impl<T: embedded_hal::digital::InputPin> embedded_hal::digital::InputPin for InfallibleDriver<T> {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.0.is_high().unwrap())
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.0.is_low().unwrap())
+    }
+}
+
+pub type Pn532Reader = Pn532<
+    I2CInterfaceWithIrq<SharedI2c, InfallibleDriver<PinDriver<'static, AnyInputPin, Input>>>,
+    (),
+    64,
+>;
+
+/// The PN532 NFC reader
+pub struct Nfc {
+    reader: Pn532Reader,
+}
+
+impl Nfc {
+    pub fn new(reader: Pn532Reader) -> Self {
+        Self { reader }
+    }
+}
+
+impl NfcReader for Nfc {
+    async fn firmware_version(&mut self) -> anyhow::Result<Vec<u8>> {
+        self.reader
+            .process_async(&Request::GET_FIRMWARE_VERSION, 4)
+            .await
+            .map(<[u8]>::to_vec)
+            .map_err(|e| anyhow!("NFC firmware version: {e:?}"))
+    }
+
+    async fn read_ntag(&mut self, page: u8) -> anyhow::Result<Vec<u8>> {
+        let data = self
+            .reader
+            .process_async(&Request::ntag_read(page), 17)
+            .await
+            .map_err(|e| anyhow!("NTAG read at page {page}: {e:?}"))?;
+
+        // The first byte is the reader's status for the exchange with the tag
+        match data.split_first() {
+            Some((0, pages)) => Ok(pages.to_vec()),
+            Some((status, _)) => Err(anyhow!("NTAG read at page {page} failed: {status:#04x}")),
+            None => Err(anyhow!("NTAG read at page {page} returned nothing")),
+        }
+    }
+}
+
+/// The MAX17048 fuel gauge on the cell
+pub struct FuelGauge {
+    i2c: SharedI2c,
+}
+
+impl FuelGauge {
+    const ADDRESS: u8 = 0x36;
+    /// State of charge in 1/256ths of a percent
+    const SOC: u8 = 0x04;
+    /// Signed charge rate in steps of 0.208 %/hr, negative while the cell discharges
+    const CRATE: u8 = 0x16;
+    /// Discharge rate that means nothing else is powering the board, about 1 %/hr. Anything
+    /// slower is noise while USB tops the cell up.
+    const DISCHARGING: i16 = -5;
+
+    pub fn new(i2c: SharedI2c) -> Self {
+        Self { i2c }
+    }
+
+    fn read(&mut self, register: u8) -> anyhow::Result<[u8; 2]> {
+        let mut value = [0; 2];
+        embedded_hal::i2c::I2c::write_read(&mut self.i2c, Self::ADDRESS, &[register], &mut value)
+            .map_err(|e| anyhow!("reading fuel gauge register {register:#04x}: {e:?}"))?;
+        Ok(value)
+    }
+}
+
+impl PowerMonitor for FuelGauge {
+    fn power_source(&mut self) -> anyhow::Result<PowerSource> {
+        Ok(
+            if i16::from_be_bytes(self.read(Self::CRATE)?) <= Self::DISCHARGING {
+                PowerSource::Battery
+            } else {
+                PowerSource::External
+            },
+        )
+    }
+
+    fn charge_percent(&mut self) -> anyhow::Result<u8> {
+        // The high byte is whole percent, and a fresh cell can read a little over 100
+        Ok(self.read(Self::SOC)?[0].min(100))
+    }
+}
+
+impl Connection for EspAsyncTls<EspTlsSocket> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, NetError> {
+        EspAsyncTls::read(self, buf)
+            .await
+            .map_err(|e| NetError::Io(convert_error(e)))
+    }
+
+    async fn write_all(&mut self, data: &[u8]) -> Result<(), NetError> {
+        EspAsyncTls::write_all(self, data)
+            .await
+            .map_err(|e| NetError::Io(convert_error(e)))
+    }
+}
+
+/// Plain TCP or TLS, for protocols that allow either
+pub enum EspStream {
+    Plain(async_io::Async<TcpStream>),
+    Tls(EspAsyncTls<EspTlsSocket>),
+}
+
+impl EspStream {
+    /// Connects to the host and port in `url`, over TLS if its scheme asks for it
+    pub async fn connect(
+        url: Url,
+        timeouts: Timeouts,
+        tls: Arc<TlsConfig>,
+    ) -> Result<Self, NetError> {
+        match url.scheme() {
+            "https" | "wss" | "mqtts" => Ok(Self::Tls(
+                generate_tls(url.as_str(), &timeouts, &tls).await?,
+            )),
+            _ => Ok(Self::Plain(connect_tcp(&url, &timeouts).await?)),
+        }
+    }
+}
+
+/// Connects over TLS where the URL asks for it, verifying servers as its [`TlsConfig`] asks
+#[derive(Debug, Clone, Default)]
+pub struct EspConnector {
+    tls: Arc<TlsConfig>,
+}
+
+impl EspConnector {
+    pub fn new(tls: Arc<TlsConfig>) -> Self {
+        Self { tls }
+    }
+}
+
+impl Connector for EspConnector {
+    type Stream = EspStream;
+
+    async fn connect(&self, url: &Url, timeouts: &Timeouts) -> Result<EspStream, NetError> {
+        EspStream::connect(url.clone(), *timeouts, self.tls.clone()).await
+    }
+}
+
+/// Opens a TLS connection to the host in `url`, on its port or the default for its scheme, and
+/// verifies the server as `config` asks
+async fn generate_tls(
+    url: &str,
+    timeouts: &Timeouts,
+    config: &TlsConfig,
+) -> Result<EspAsyncTls<EspTlsSocket>, NetError> {
+    let url = Url::from_str(url).map_err(|_| NetError::InvalidUrl(url.to_string()))?;
+
+    let socket = connect_tcp(&url, timeouts).await?;
+    let host = url.host_str().unwrap_or_default();
+
+    let mut tls = EspAsyncTls::adopt(EspTlsSocket::new(socket))
+        .map_err(|e| TlsError::Handshake(convert_error(e)))?;
+
+    let mut esp_config = esp_idf_svc::tls::Config::new();
+    match config.ca(host) {
+        Some(ca) => {
+            esp_config.ca_cert = Some(X509::pem_until_nul(ca));
+            esp_config.use_crt_bundle_attach = false;
+        }
+        None => esp_config.use_crt_bundle_attach = true,
+    }
+
+    timeout(timeouts.handshake, async {
+        let negotiated = tls.negotiate(host, &esp_config).await;
+        negotiated.map_err(|e| NetError::from(handshake_error(&tls, e)))
+    })
+    .await?;
+
+    if config.is_pinned(host) {
+        let cert = peer_certificate(&tls).ok_or(TlsError::MissingCertificate)?;
+        config.check_pins(host, &cert)?;
+    }
+
+    Ok(tls)
+}
+
+/// Turns a failed handshake into the certificate problems mbedtls found, if it got that far
+fn handshake_error(tls: &EspAsyncTls<EspTlsSocket>, e: EspError) -> TlsError {
+    let mut handle = core::ptr::null_mut();
+    let mut code = 0;
+    let mut flags = 0;
+
+    // SAFETY: the error handle belongs to the TLS context, which `tls` keeps alive
+    unsafe {
+        if sys::esp_tls_get_error_handle(tls.context_handle(), &mut handle) == sys::ESP_OK
+            && !handle.is_null()
+        {
+            sys::esp_tls_get_and_clear_last_error(handle, &mut code, &mut flags);
+        }
+    }
+
+    if flags != 0 {
+        TlsError::Untrusted(flags as u32)
+    } else {
+        TlsError::Handshake(convert_error(e))
+    }
+}
+
+/// The DER certificate the server presented during the handshake
+fn peer_certificate(tls: &EspAsyncTls<EspTlsSocket>) -> Option<Vec<u8>> {
+    // SAFETY: mbedtls keeps the peer certificate for as long as the session, which `tls` keeps
+    // alive, and it is copied out before returning
+    unsafe {
+        let ssl = sys::esp_tls_get_ssl_context(tls.context_handle());
+        if ssl.is_null() {
+            return None;
+        }
+
+        let cert = sys::mbedtls_ssl_get_peer_cert(ssl as *const sys::mbedtls_ssl_context);
+        let raw = &cert.as_ref()?.raw;
+
+        Some(core::slice::from_raw_parts(raw.p, raw.len).to_vec())
+    }
+}
+
+impl Connection for EspStream {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, NetError> {
+        match self {
+            Self::Plain(stream) => Connection::read(stream, buf).await,
+            Self::Tls(tls) => Connection::read(tls, buf).await,
+        }
+    }
+
+    async fn write_all(&mut self, data: &[u8]) -> Result<(), NetError> {
+        match self {
+            Self::Plain(stream) => Connection::write_all(stream, data).await,
+            Self::Tls(tls) => Connection::write_all(tls, data).await,
+        }
+    }
+}
+
+/// One NVS namespace
+pub struct NvsStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl NvsStore {
+    pub fn new(partition: EspDefaultNvsPartition, namespace: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            nvs: EspNvs::new(partition, namespace, true).map_err(convert_error)?,
+        })
+    }
+}
+
+impl KeyValueStore for NvsStore {
+    fn get_blob(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(len) = self.nvs.blob_len(key).map_err(convert_error)? else {
+            return Ok(None);
+        };
+
+        let mut buf = vec![0; len];
+        Ok(self
+            .nvs
+            .get_blob(key, &mut buf)
+            .map_err(convert_error)?
+            .map(<[u8]>::to_vec))
+    }
+
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+        self.nvs.set_blob(key, value).map_err(convert_error)
+    }
+
+    fn get_str(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let Some(len) = self.nvs.str_len(key).map_err(convert_error)? else {
+            return Ok(None);
+        };
+
+        // Room for the terminator
+        let mut buf = vec![0; len + 1];
+        Ok(self
+            .nvs
+            .get_str(key, &mut buf)
+            .map_err(convert_error)?
+            .map(str::to_string))
+    }
+
+    fn set_str(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        self.nvs.set_str(key, value).map_err(convert_error)
+    }
+
+    fn contains(&self, key: &str) -> anyhow::Result<bool> {
+        self.nvs.contains(key).map_err(convert_error)
+    }
+
+    fn remove(&mut self, key: &str) -> anyhow::Result<bool> {
+        self.nvs.remove(key).map_err(convert_error)
+    }
+}
+
+/// Allows for an async version of the TLS socket
+pub struct EspTlsSocket(Option<async_io::Async<TcpStream>>);
+
+impl EspTlsSocket {
+    pub const fn new(socket: async_io::Async<TcpStream>) -> Self {
+        Self(Some(socket))
+    }
+
+    pub fn handle(&self) -> i32 {
+        self.0.as_ref().unwrap().as_raw_fd()
+    }
+
+    pub fn poll_readable(
+        &self,
+        ctx: &mut core::task::Context,
+    ) -> core::task::Poll<Result<(), EspError>> {
+        self.0
+            .as_ref()
+            .unwrap()
+            .poll_readable(ctx)
+            .map_err(|_| EspError::from_infallible::<{ esp_idf_svc::sys::ESP_FAIL }>())
+    }
+
+    pub fn poll_writeable(
+        &self,
+        ctx: &mut core::task::Context,
+    ) -> core::task::Poll<Result<(), EspError>> {
+        self.0
+            .as_ref()
+            .unwrap()
+            .poll_writable(ctx)
+            .map_err(|_| EspError::from_infallible::<{ esp_idf_svc::sys::ESP_FAIL }>())
+    }
+
+    fn release(&mut self) -> Result<(), EspError> {
+        let socket = self.0.take().unwrap();
+        let _ = socket.into_inner().unwrap().into_raw_fd();
+
+        Ok(())
+    }
+}
+
+impl esp_idf_svc::tls::Socket for EspTlsSocket {
+    fn handle(&self) -> i32 {
+        EspTlsSocket::handle(self)
+    }
+
+    fn release(&mut self) -> Result<(), EspError> {
+        EspTlsSocket::release(self)
+    }
+}
+
+impl esp_idf_svc::tls::PollableSocket for EspTlsSocket {
+    fn poll_readable(
+        &self,
+        ctx: &mut core::task::Context,
+    ) -> core::task::Poll<Result<(), EspError>> {
+        EspTlsSocket::poll_readable(self, ctx)
+    }
+
+    fn poll_writable(
+        &self,
+        ctx: &mut core::task::Context,
+    ) -> core::task::Poll<Result<(), EspError>> {
+        EspTlsSocket::poll_writeable(self, ctx)
+    }
+}
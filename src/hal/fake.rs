@@ -0,0 +1,290 @@
+//! In-memory peripherals for running the app logic off the device.
+//!
+//! Each fake records what the app did to it in public fields, so tests and the simulator can
+//! look at the outputs directly.
+
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use embassy_time::Instant;
+use embedded_graphics::prelude::Point;
+use http::Method;
+use smart_leds::{SmartLedsWrite, RGB8};
+use tokio::sync::mpsc;
+
+use super::{
+    Animator, DisplayCommand, KeyValueStore, LedStrip, NetClient, NetResponse, NfcReader,
+    PowerMonitor, PowerSource, SegmentDisplay, Segments, TouchPanel, NUM_LEDS,
+};
+use crate::net::NetError;
+
+#[derive(Debug)]
+pub struct FakeDisplay {
+    /// What was shown and when, see [`FakeDisplay::segments`]
+    pub animator: Animator,
+    pub brightness: u8,
+}
+
+impl FakeDisplay {
+    /// The high and low digit as of `now`
+    pub fn segments(&self, now: Instant) -> [Segments; 2] {
+        self.animator.frame(now)
+    }
+}
+
+impl Default for FakeDisplay {
+    fn default() -> Self {
+        Self {
+            animator: Animator::new(DisplayCommand::Clear, Instant::now()),
+            brightness: u8::MAX,
+        }
+    }
+}
+
+impl SegmentDisplay for FakeDisplay {
+    fn show(&mut self, command: DisplayCommand) {
+        match command {
+            DisplayCommand::SetBrightness(brightness) => self.brightness = brightness,
+            command => self.animator.show(command, Instant::now()),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FakeLeds {
+    pub colors: [RGB8; NUM_LEDS],
+    /// Number of writes so far
+    pub writes: usize,
+}
+
+impl LedStrip for FakeLeds {
+    fn write(&mut self, colors: [RGB8; NUM_LEDS]) -> anyhow::Result<()> {
+        self.colors = colors;
+        self.writes += 1;
+        Ok(())
+    }
+}
+
+/// Power that stays on whatever source and charge it is set to
+#[derive(Debug, Default)]
+pub struct FakePower {
+    pub source: PowerSource,
+    pub percent: u8,
+}
+
+impl PowerMonitor for FakePower {
+    fn power_source(&mut self) -> anyhow::Result<PowerSource> {
+        Ok(self.source)
+    }
+
+    fn charge_percent(&mut self) -> anyhow::Result<u8> {
+        Ok(self.percent)
+    }
+}
+
+/// A smart LED driver that keeps every frame written to it, to put behind
+/// [`Leds`](super::leds::Leds)
+#[derive(Debug, Default)]
+pub struct FakeSmartLeds {
+    pub frames: Vec<Vec<RGB8>>,
+}
+
+impl SmartLedsWrite for FakeSmartLeds {
+    type Error = Infallible;
+    type Color = RGB8;
+
+    fn write<T, I>(&mut self, iterator: T) -> Result<(), Self::Error>
+    where
+        T: IntoIterator<Item = I>,
+        I: Into<Self::Color>,
+    {
+        self.frames
+            .push(iterator.into_iter().map(Into::into).collect());
+        Ok(())
+    }
+}
+
+/// Touch panel fed from a channel, one batch of contact points per report
+pub struct FakeTouch {
+    reports: mpsc::UnboundedReceiver<Vec<Point>>,
+}
+
+impl FakeTouch {
+    /// Creates the panel along with the sender that feeds it
+    pub fn new() -> (Self, mpsc::UnboundedSender<Vec<Point>>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self { reports: rx }, tx)
+    }
+}
+
+impl TouchPanel for FakeTouch {
+    async fn touches(&mut self) -> anyhow::Result<Vec<Point>> {
+        self.reports
+            .recv()
+            .await
+            .ok_or_else(|| anyhow!("touch input closed"))
+    }
+}
+
+/// NFC reader with at most one tag in its field
+#[derive(Debug, Clone)]
+pub struct FakeNfc {
+    pub version: Vec<u8>,
+    /// Contents of the tag in the field, four bytes per page
+    pub tag: Option<Vec<u8>>,
+}
+
+impl Default for FakeNfc {
+    fn default() -> Self {
+        Self {
+            // PN532, firmware 1.6, all protocols
+            version: vec![0x32, 0x01, 0x06, 0x07],
+            tag: None,
+        }
+    }
+}
+
+impl NfcReader for FakeNfc {
+    async fn firmware_version(&mut self) -> anyhow::Result<Vec<u8>> {
+        Ok(self.version.clone())
+    }
+
+    async fn read_ntag(&mut self, page: u8) -> anyhow::Result<Vec<u8>> {
+        let tag = self
+            .tag
+            .as_ref()
+            .ok_or_else(|| anyhow!("no tag in field"))?;
+
+        // Like a real NTAG, reads past the end of memory come back as zeroes
+        let start = page as usize * 4;
+        Ok((start..start + 16)
+            .map(|i| tag.get(i).copied().unwrap_or_default())
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedRequest {
+    pub method: Method,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Vec<u8>>,
+}
+
+/// Network client that answers from canned responses and remembers every request.
+///
+/// URLs without a canned response get a 404.
+#[derive(Debug, Default)]
+pub struct FakeNet {
+    responses: Mutex<HashMap<String, NetResponse>>,
+    /// URLs whose server cannot be reached
+    unreachable: Mutex<HashSet<String>>,
+    requests: Mutex<Vec<RecordedRequest>>,
+}
+
+impl FakeNet {
+    pub fn respond(&self, url: &str, status: u16, body: impl Into<Vec<u8>>) {
+        self.responses.lock().unwrap().insert(
+            url.to_string(),
+            NetResponse {
+                status,
+                body: body.into(),
+            },
+        );
+    }
+
+    /// Fails requests to `url` as if its server refused the connection
+    pub fn refuse(&self, url: &str) {
+        self.unreachable.lock().unwrap().insert(url.to_string());
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl NetClient for FakeNet {
+    async fn request(
+        &self,
+        method: Method,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Option<&[u8]>,
+    ) -> Result<NetResponse, NetError> {
+        self.requests.lock().unwrap().push(RecordedRequest {
+            method,
+            url: url.to_string(),
+            headers: headers
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            body: body.map(<[u8]>::to_vec),
+        });
+
+        if self.unreachable.lock().unwrap().contains(url) {
+            return Err(NetError::Connect(
+                std::io::ErrorKind::ConnectionRefused.into(),
+            ));
+        }
+
+        Ok(self
+            .responses
+            .lock()
+            .unwrap()
+            .get(url)
+            .cloned()
+            .unwrap_or(NetResponse {
+                status: 404,
+                body: Vec::new(),
+            }))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Str(String),
+    Blob(Vec<u8>),
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    values: HashMap<String, Value>,
+}
+
+impl KeyValueStore for MemoryStore {
+    fn get_blob(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(match self.values.get(key) {
+            Some(Value::Blob(blob)) => Some(blob.clone()),
+            _ => None,
+        })
+    }
+
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+        self.values
+            .insert(key.to_string(), Value::Blob(value.to_vec()));
+        Ok(())
+    }
+
+    fn get_str(&self, key: &str) -> anyhow::Result<Option<String>> {
+        Ok(match self.values.get(key) {
+            Some(Value::Str(value)) => Some(value.clone()),
+            _ => None,
+        })
+    }
+
+    fn set_str(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        self.values
+            .insert(key.to_string(), Value::Str(value.to_string()));
+        Ok(())
+    }
+
+    fn contains(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.values.contains_key(key))
+    }
+
+    fn remove(&mut self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.values.remove(key).is_some())
+    }
+}
@@ -0,0 +1,287 @@
+//! Per-pixel colors for the LED strip, split into the base ring and the beacon LED.
+
+use serde::{Deserialize, Serialize};
+use smart_leds::{gamma, SmartLedsWrite, RGB8};
+use tokio::sync::watch;
+
+use super::{LedStrip, PowerSource, NUM_BASE_LEDS, NUM_LEDS};
+
+/// A part of the strip that can show its own effect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// The ring of LEDs around the base
+    Base,
+    /// The single LED at the end of the strip
+    Beacon,
+}
+
+impl Zone {
+    pub const ALL: [Zone; 2] = [Zone::Base, Zone::Beacon];
+
+    /// Indices of the zone's LEDs along the strip
+    pub const fn range(self) -> core::ops::Range<usize> {
+        match self {
+            Self::Base => 0..NUM_BASE_LEDS,
+            Self::Beacon => NUM_BASE_LEDS..NUM_LEDS,
+        }
+    }
+}
+
+/// The color of every LED on the strip, in linear RGB
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Pixels(pub [RGB8; NUM_LEDS]);
+
+impl Pixels {
+    pub const fn filled(color: RGB8) -> Self {
+        Self([color; NUM_LEDS])
+    }
+
+    pub fn zone(&self, zone: Zone) -> &[RGB8] {
+        &self.0[zone.range()]
+    }
+
+    pub fn zone_mut(&mut self, zone: Zone) -> &mut [RGB8] {
+        &mut self.0[zone.range()]
+    }
+
+    pub fn fill(&mut self, zone: Zone, color: RGB8) {
+        self.zone_mut(zone).fill(color);
+    }
+
+    pub fn beacon(&self) -> RGB8 {
+        self.0[NUM_BASE_LEDS]
+    }
+}
+
+/// `from` with `to` mixed in by `amount` out of 255, where 0 keeps `from` and 255 gives `to`
+pub fn mix(from: RGB8, to: RGB8, amount: u8) -> RGB8 {
+    let mix =
+        |from: u8, to: u8| (from as i32 + (to as i32 - from as i32) * amount as i32 / 255) as u8;
+
+    RGB8::new(mix(from.r, to.r), mix(from.g, to.g), mix(from.b, to.b))
+}
+
+/// `color` at `level` out of 255
+pub fn scale(color: RGB8, level: u8) -> RGB8 {
+    mix(RGB8::default(), color, level)
+}
+
+impl From<Pixels> for [RGB8; NUM_LEDS] {
+    fn from(pixels: Pixels) -> Self {
+        pixels.0
+    }
+}
+
+/// Current a WS2812B draws per channel at full duty, in mA, as FastLED estimates it
+const RED_MA: u32 = 16;
+const GREEN_MA: u32 = 11;
+const BLUE_MA: u32 = 15;
+/// Current each LED draws even when dark
+const IDLE_MA: u32 = 1;
+
+/// Caps on what the LEDs may draw from the 5 V rail, which also feeds the AMOLED and sits behind
+/// a single-cell charger
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PowerBudget {
+    /// Applied to every frame, out of 255
+    pub brightness: u8,
+    /// Most the strip may draw on external power, in mA
+    pub max_current_ma: u32,
+    /// Most the strip may draw on battery, in mA
+    pub battery_current_ma: u32,
+}
+
+/// Leaves room on USB's 500 mA for the ESP32's Wi-Fi peaks and the AMOLED
+impl Default for PowerBudget {
+    fn default() -> Self {
+        Self {
+            brightness: u8::MAX,
+            max_current_ma: 200,
+            battery_current_ma: 100,
+        }
+    }
+}
+
+impl PowerBudget {
+    pub fn cap_ma(&self, source: PowerSource) -> u32 {
+        match source {
+            PowerSource::External => self.max_current_ma,
+            PowerSource::Battery => self.battery_current_ma.min(self.max_current_ma),
+        }
+    }
+
+    /// Scales `colors`, as written to the LEDs, by the brightness and then down to the cap for
+    /// `source` if they would draw more
+    pub fn limit(&self, colors: &mut [RGB8], source: PowerSource) {
+        for led in colors.iter_mut() {
+            *led = scale(*led, self.brightness);
+        }
+
+        let cap = self.cap_ma(source);
+        let drawn = current_ma(colors);
+        if drawn <= cap {
+            return;
+        }
+
+        // Dark LEDs still draw their idle current, which leaves nothing to light them with
+        let idle = IDLE_MA * colors.len() as u32;
+        if cap <= idle {
+            colors.fill(RGB8::default());
+            return;
+        }
+
+        // Only the lit part of the current scales with the colors
+        let allowed = cap - idle;
+        let lit = drawn - idle;
+        if lit == 0 {
+            return;
+        }
+        let scale = |channel: u8| (channel as u32 * allowed / lit) as u8;
+        for led in colors.iter_mut() {
+            *led = RGB8::new(scale(led.r), scale(led.g), scale(led.b));
+        }
+    }
+}
+
+/// Estimated current `colors` draw once written to the LEDs, in mA
+pub fn current_ma(colors: &[RGB8]) -> u32 {
+    // In mA times 255, so the rounding only happens once
+    let lit = colors
+        .iter()
+        .map(|led| led.r as u32 * RED_MA + led.g as u32 * GREEN_MA + led.b as u32 * BLUE_MA)
+        .sum::<u32>();
+
+    IDLE_MA * colors.len() as u32 + lit.div_ceil(255)
+}
+
+/// Any smart LED driver as an [`LedStrip`], gamma corrected so fades look even and kept within
+/// a [`PowerBudget`]
+pub struct Leds<W> {
+    pub leds: W,
+    budget: PowerBudget,
+    power: watch::Receiver<PowerSource>,
+}
+
+impl<W> Leds<W> {
+    /// The strip at full brightness within the default budget, assuming external power
+    pub fn new(leds: W) -> Self {
+        Self {
+            leds,
+            budget: PowerBudget::default(),
+            power: watch::channel(PowerSource::External).1,
+        }
+    }
+
+    /// Keeps the strip within `budget`, using the cap for whatever `power` says the board runs on
+    pub fn with_budget(mut self, budget: PowerBudget, power: watch::Receiver<PowerSource>) -> Self {
+        self.budget = budget;
+        self.power = power;
+        self
+    }
+}
+
+impl<W> LedStrip for Leds<W>
+where
+    W: SmartLedsWrite<Color = RGB8>,
+    W::Error: core::fmt::Debug,
+{
+    fn write(&mut self, colors: [RGB8; NUM_LEDS]) -> anyhow::Result<()> {
+        let mut corrected = [RGB8::default(); NUM_LEDS];
+        for (led, color) in corrected.iter_mut().zip(gamma(colors.into_iter())) {
+            *led = color;
+        }
+        self.budget
+            .limit(&mut corrected, *self.power.borrow_and_update());
+
+        self.leds
+            .write(corrected)
+            .map_err(|e| anyhow::anyhow!("LED write failed: {e:?}"))
+    }
+
+    /// The cap changes with the power source
+    fn needs_refresh(&mut self) -> bool {
+        self.power.has_changed().unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::fake::FakeSmartLeds;
+
+    const WHITE: RGB8 = RGB8::new(255, 255, 255);
+
+    fn budget(max_current_ma: u32, battery_current_ma: u32) -> PowerBudget {
+        PowerBudget {
+            brightness: u8::MAX,
+            max_current_ma,
+            battery_current_ma,
+        }
+    }
+
+    #[test]
+    fn keeps_frames_within_the_cap() {
+        let budget = budget(200, 100);
+
+        let mut colors = [WHITE; NUM_LEDS];
+        budget.limit(&mut colors, PowerSource::External);
+        assert!(current_ma(&colors) <= 200, "{}", current_ma(&colors));
+        assert!(current_ma(&colors) >= 190, "{}", current_ma(&colors));
+
+        let mut colors = [WHITE; NUM_LEDS];
+        budget.limit(&mut colors, PowerSource::Battery);
+        assert!(current_ma(&colors) <= 100, "{}", current_ma(&colors));
+
+        // Within the cap, nothing changes
+        let mut colors = [RGB8::new(10, 0, 0); NUM_LEDS];
+        budget.limit(&mut colors, PowerSource::Battery);
+        assert_eq!(colors, [RGB8::new(10, 0, 0); NUM_LEDS]);
+    }
+
+    #[test]
+    fn blanks_frames_when_the_cap_only_covers_idle_current() {
+        let idle = IDLE_MA * NUM_LEDS as u32;
+
+        for cap in [0, idle - 1, idle] {
+            let mut colors = [WHITE; NUM_LEDS];
+            budget(cap, cap).limit(&mut colors, PowerSource::External);
+            assert_eq!(colors, [RGB8::default(); NUM_LEDS], "cap {cap}");
+
+            let mut colors = [RGB8::default(); NUM_LEDS];
+            budget(cap, cap).limit(&mut colors, PowerSource::External);
+            assert_eq!(colors, [RGB8::default(); NUM_LEDS], "cap {cap}");
+        }
+
+        let mut colors = [WHITE; NUM_LEDS];
+        budget(idle + 1, idle + 1).limit(&mut colors, PowerSource::External);
+        assert!(current_ma(&colors) <= idle + 1);
+    }
+
+    #[test]
+    fn caps_battery_current_at_the_overall_cap() {
+        assert_eq!(budget(80, 100).cap_ma(PowerSource::Battery), 80);
+        assert_eq!(budget(200, 100).cap_ma(PowerSource::Battery), 100);
+        assert_eq!(budget(200, 100).cap_ma(PowerSource::External), 200);
+    }
+
+    #[test]
+    fn needs_a_refresh_once_the_power_source_changes() {
+        let (power, power_rx) = watch::channel(PowerSource::External);
+        let mut leds = Leds::new(FakeSmartLeds::default()).with_budget(budget(200, 50), power_rx);
+
+        leds.write([WHITE; NUM_LEDS]).unwrap();
+        assert!(!leds.needs_refresh());
+
+        power.send_replace(PowerSource::Battery);
+        assert!(leds.needs_refresh());
+        leds.write([WHITE; NUM_LEDS]).unwrap();
+        assert!(!leds.needs_refresh());
+
+        let [external, battery] = &leds.leds.frames[..] else {
+            panic!("expected two frames");
+        };
+        assert!(current_ma(external) > current_ma(battery));
+        assert!(current_ma(battery) <= 50);
+    }
+}
@@ -0,0 +1,444 @@
+//! What the two-digit seven-segment display shows, down to the individual segments.
+
+use core::fmt;
+
+use embassy_time::Duration;
+
+/// Glyphs that read unambiguously on seven segments, with segments a to g in bits 0 to 6
+const GLYPHS: &[(char, u8)] = &[
+    ('0', 0x3f),
+    ('1', 0x06),
+    ('2', 0x5b),
+    ('3', 0x4f),
+    ('4', 0x66),
+    ('5', 0x6d),
+    ('6', 0x7d),
+    ('7', 0x07),
+    ('8', 0x7f),
+    ('9', 0x6f),
+    ('A', 0x77),
+    ('b', 0x7c),
+    ('C', 0x39),
+    ('c', 0x58),
+    ('d', 0x5e),
+    ('E', 0x79),
+    ('F', 0x71),
+    ('G', 0x3d),
+    ('H', 0x76),
+    ('h', 0x74),
+    ('I', 0x06),
+    ('i', 0x04),
+    ('J', 0x1e),
+    ('L', 0x38),
+    ('n', 0x54),
+    ('O', 0x3f),
+    ('o', 0x5c),
+    ('P', 0x73),
+    ('r', 0x50),
+    ('S', 0x6d),
+    ('t', 0x78),
+    ('U', 0x3e),
+    ('u', 0x1c),
+    ('y', 0x6e),
+    ('-', 0x40),
+    ('_', 0x08),
+    (' ', 0x00),
+];
+
+const HEX_DIGITS: [u8; 16] = [
+    0x3f, 0x06, 0x5b, 0x4f, 0x66, 0x6d, 0x7d, 0x07, 0x7f, 0x6f, 0x77, 0x7c, 0x39, 0x5e, 0x79, 0x71,
+];
+
+/// The lit segments of one digit, with a to g in bits 0 to 6 and the decimal point in bit 7
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Segments(u8);
+
+impl Segments {
+    pub const BLANK: Self = Self(0);
+    const DOT: u8 = 0x80;
+
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// The hexadecimal digit for the low nibble of `value`
+    pub const fn hex_digit(value: u8) -> Self {
+        Self(HEX_DIGITS[(value & 0x0f) as usize])
+    }
+
+    /// The glyph for `c`, in the other case if only that one can be shown
+    pub fn from_char(c: char) -> Option<Self> {
+        let glyph = |c| GLYPHS.iter().find(|(glyph, _)| *glyph == c);
+
+        glyph(c)
+            .or_else(|| glyph(c.to_ascii_uppercase()))
+            .or_else(|| glyph(c.to_ascii_lowercase()))
+            .map(|(_, bits)| Self(*bits))
+    }
+
+    /// The character these segments show, ignoring the decimal point
+    pub fn to_char(self) -> Option<char> {
+        let bits = self.0 & !Self::DOT;
+
+        GLYPHS
+            .iter()
+            .find(|(_, glyph)| *glyph == bits)
+            .map(|(c, _)| *c)
+    }
+
+    pub const fn with_dot(self, lit: bool) -> Self {
+        if lit {
+            Self(self.0 | Self::DOT)
+        } else {
+            Self(self.0 & !Self::DOT)
+        }
+    }
+
+    pub const fn dot(self) -> bool {
+        self.0 & Self::DOT != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SegmentError {
+    /// The character has no glyph that reads on seven segments
+    Unsupported(char),
+    /// More characters than the two digits can show
+    TooLong(String),
+}
+
+impl fmt::Display for SegmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unsupported(c) => write!(f, "{c:?} cannot be shown on seven segments"),
+            Self::TooLong(text) => write!(f, "{text:?} does not fit on two digits"),
+        }
+    }
+}
+
+impl std::error::Error for SegmentError {}
+
+/// Segments chased by [`DisplayCommand::Spinner`], around the outside of both digits
+const SPINNER: [[u8; 2]; 8] = [
+    [0x01, 0x00],
+    [0x00, 0x01],
+    [0x00, 0x02],
+    [0x00, 0x04],
+    [0x00, 0x08],
+    [0x08, 0x00],
+    [0x10, 0x00],
+    [0x20, 0x00],
+];
+
+/// What the display should show, or how brightly
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisplayCommand {
+    Clear,
+    /// 0 to 99 without a leading zero, capped at 99
+    Decimal(u8),
+    /// One hexadecimal digit per nibble, so 0x1F shows "1F"
+    Hex(u8),
+    /// Exact segments for the high and low digit
+    Segments([Segments; 2]),
+    /// PWM duty for the lit digits, from off at 0 to full at 255. Keeps what is shown.
+    SetBrightness(u8),
+    /// The segments lit for the first half of every `period` and dark for the rest
+    Blink {
+        segments: [Segments; 2],
+        period: Duration,
+    },
+    /// Text longer than two digits, moving one digit to the left every `step` and starting over
+    /// once it has scrolled off. See [`DisplayCommand::marquee`].
+    Marquee {
+        text: Vec<Segments>,
+        step: Duration,
+    },
+    /// A single segment chasing around both digits, one segment every `step`, while busy
+    Spinner {
+        step: Duration,
+    },
+    /// Shows the command for `duration`, then goes back to what was shown before
+    Timed {
+        command: Box<DisplayCommand>,
+        duration: Duration,
+    },
+}
+
+impl DisplayCommand {
+    /// Up to two characters such as "Hi", "On" or "E3", right-aligned. A `.` lights the decimal
+    /// point of the character before it.
+    pub fn text(text: &str) -> Result<Self, SegmentError> {
+        match render(text)?[..] {
+            [] => Ok(Self::Clear),
+            [low] => Ok(Self::Segments([Segments::BLANK, low])),
+            [high, low] => Ok(Self::Segments([high, low])),
+            _ => Err(SegmentError::TooLong(text.to_string())),
+        }
+    }
+
+    /// Scrolls `text` across the display, with `.` lighting the decimal point as in
+    /// [`DisplayCommand::text`]
+    pub fn marquee(text: &str, step: Duration) -> Result<Self, SegmentError> {
+        Ok(Self::Marquee {
+            text: render(text)?,
+            step,
+        })
+    }
+
+    /// Shows `self` for `duration` before going back to what was shown before
+    pub fn for_duration(self, duration: Duration) -> Self {
+        Self::Timed {
+            command: Box::new(self),
+            duration,
+        }
+    }
+
+    /// Sets the decimal points of the high and low digit, keeping what the digits show
+    pub fn with_dots(self, high: bool, low: bool) -> Self {
+        match self.segments() {
+            Some([high_digit, low_digit]) => {
+                Self::Segments([high_digit.with_dot(high), low_digit.with_dot(low)])
+            }
+            None => self,
+        }
+    }
+
+    /// Whether what the command shows changes over time
+    pub fn is_animated(&self) -> bool {
+        match self {
+            Self::Blink { .. } | Self::Marquee { .. } | Self::Spinner { .. } => true,
+            Self::Timed { command, .. } => command.is_animated(),
+            _ => false,
+        }
+    }
+
+    /// The segments for the high and low digit `elapsed` into the command, unless it leaves them
+    /// as they are. A [`Timed`](Self::Timed) command is treated as the one it wraps.
+    pub fn frame(&self, elapsed: Duration) -> Option<[Segments; 2]> {
+        // Index of the step `elapsed` falls in, with a zero step never moving on
+        let step = |step: Duration| {
+            elapsed
+                .as_ticks()
+                .checked_div(step.as_ticks())
+                .unwrap_or_default() as usize
+        };
+
+        let segments = match self {
+            Self::Blink { segments, period } => {
+                let on = match period.as_ticks() {
+                    0 => true,
+                    period => elapsed.as_ticks() % period < period / 2,
+                };
+
+                if on {
+                    *segments
+                } else {
+                    [Segments::BLANK; 2]
+                }
+            }
+            Self::Marquee { text, step: every } => {
+                // Two blank digits after the text so it scrolls all the way off
+                let len = text.len() + 2;
+                let digit = |index: usize| text.get(index % len).copied().unwrap_or_default();
+                let index = step(*every);
+
+                [digit(index), digit(index + 1)]
+            }
+            Self::Spinner { step: every } => SPINNER[step(*every) % SPINNER.len()].map(Segments),
+            Self::Timed { command, .. } => return command.frame(elapsed),
+            command => return command.segments(),
+        };
+
+        Some(segments)
+    }
+
+    /// The segments for the high and low digit, unless the command leaves them as they are or
+    /// they change over time, see [`DisplayCommand::frame`]
+    pub fn segments(&self) -> Option<[Segments; 2]> {
+        let segments = match *self {
+            Self::Clear => [Segments::BLANK; 2],
+            Self::Decimal(value) => {
+                let value = value.min(99);
+                let tens = match value / 10 {
+                    0 => Segments::BLANK,
+                    tens => Segments::hex_digit(tens),
+                };
+
+                [tens, Segments::hex_digit(value % 10)]
+            }
+            Self::Hex(value) => [Segments::hex_digit(value >> 4), Segments::hex_digit(value)],
+            Self::Segments(segments) => segments,
+            Self::SetBrightness(_)
+            | Self::Blink { .. }
+            | Self::Marquee { .. }
+            | Self::Spinner { .. }
+            | Self::Timed { .. } => return None,
+        };
+
+        Some(segments)
+    }
+}
+
+/// The digits of `text`, with a `.` lighting the decimal point of the character before it
+fn render(text: &str) -> Result<Vec<Segments>, SegmentError> {
+    let mut digits = Vec::<Segments>::with_capacity(text.len());
+
+    for c in text.chars() {
+        match (c, digits.last_mut()) {
+            ('.', Some(last)) if !last.dot() => *last = last.with_dot(true),
+            ('.', _) => digits.push(Segments::BLANK.with_dot(true)),
+            (c, _) => digits.push(Segments::from_char(c).ok_or(SegmentError::Unsupported(c))?),
+        }
+    }
+
+    Ok(digits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What `command` shows as text, with a `.` after each lit decimal point
+    fn shown(command: &DisplayCommand) -> String {
+        let mut text = String::new();
+        for digit in command.segments().expect("fixed segments") {
+            text.push(digit.to_char().expect("a known glyph"));
+            if digit.dot() {
+                text.push('.');
+            }
+        }
+        text
+    }
+
+    fn text(text: &str) -> String {
+        shown(&DisplayCommand::text(text).unwrap())
+    }
+
+    #[test]
+    fn reads_back_every_glyph() {
+        for (i, (c, bits)) in GLYPHS.iter().enumerate() {
+            assert!(
+                GLYPHS[..i].iter().all(|(earlier, _)| earlier != c),
+                "{c:?} twice"
+            );
+            assert_eq!(Segments::from_char(*c), Some(Segments::from_bits(*bits)));
+
+            // Letters that look like digits read back as the digit
+            let read = Segments::from_bits(*bits).with_dot(true).to_char().unwrap();
+            assert_eq!(Segments::from_char(read), Some(Segments::from_bits(*bits)));
+        }
+
+        assert_eq!(Segments::from_char('O').unwrap().to_char(), Some('0'));
+        assert_eq!(Segments::from_char('S').unwrap().to_char(), Some('5'));
+    }
+
+    #[test]
+    fn falls_back_to_the_other_case() {
+        assert_eq!(Segments::from_char('a'), Segments::from_char('A'));
+        assert_eq!(Segments::from_char('B'), Segments::from_char('b'));
+        assert_eq!(Segments::from_char('Y'), Segments::from_char('y'));
+        // Both cases have a glyph of their own
+        assert_ne!(Segments::from_char('c'), Segments::from_char('C'));
+        assert_ne!(Segments::from_char('h'), Segments::from_char('H'));
+
+        for unsupported in ['m', 'W', 'x', '?', '.', 'é'] {
+            assert_eq!(Segments::from_char(unsupported), None, "{unsupported:?}");
+        }
+        assert_eq!(Segments::from_bits(0x7e).to_char(), None);
+    }
+
+    #[test]
+    fn shows_hex_digits_like_their_glyphs() {
+        for value in 0..16u8 {
+            let digit = Segments::hex_digit(value).to_char().unwrap();
+            assert!(
+                digit.eq_ignore_ascii_case(&char::from_digit(value.into(), 16).unwrap()),
+                "{value:x} shows {digit:?}"
+            );
+        }
+        assert_eq!(Segments::hex_digit(0xf3), Segments::hex_digit(3));
+    }
+
+    #[test]
+    fn shows_numbers() {
+        assert_eq!(shown(&DisplayCommand::Decimal(0)), " 0");
+        assert_eq!(shown(&DisplayCommand::Decimal(7)), " 7");
+        assert_eq!(shown(&DisplayCommand::Decimal(42)), "42");
+        assert_eq!(shown(&DisplayCommand::Decimal(99)), "99");
+        assert_eq!(shown(&DisplayCommand::Decimal(250)), "99");
+
+        assert_eq!(shown(&DisplayCommand::Hex(0x00)), "00");
+        assert_eq!(shown(&DisplayCommand::Hex(0x1f)), "1F");
+        assert_eq!(shown(&DisplayCommand::Hex(0xab)), "Ab");
+        assert_eq!(shown(&DisplayCommand::Hex(0xd0)), "d0");
+    }
+
+    #[test]
+    fn right_aligns_text() {
+        assert_eq!(text("Hi"), "Hi");
+        assert_eq!(text("7"), " 7");
+        assert_eq!(text("on"), "on");
+        assert_eq!(DisplayCommand::text("").unwrap(), DisplayCommand::Clear);
+    }
+
+    #[test]
+    fn lights_the_dot_of_the_character_before() {
+        assert_eq!(text("1.5"), "1.5");
+        assert_eq!(text("1.5."), "1.5.");
+        assert_eq!(text("E."), " E.");
+        // Without a character to attach to, a dot takes a digit of its own
+        assert_eq!(text(".5"), " .5");
+        assert_eq!(text("8.."), "8. .");
+        assert_eq!(text(".."), " . .");
+    }
+
+    #[test]
+    fn refuses_what_does_not_fit() {
+        assert_eq!(
+            DisplayCommand::text("Hey"),
+            Err(SegmentError::TooLong("Hey".to_string()))
+        );
+        assert_eq!(
+            DisplayCommand::text("1.2.3"),
+            Err(SegmentError::TooLong("1.2.3".to_string()))
+        );
+        assert_eq!(
+            DisplayCommand::text("Hm"),
+            Err(SegmentError::Unsupported('m'))
+        );
+
+        // Marquees take any length, but not any character
+        let marquee = DisplayCommand::marquee("Hello.", Duration::from_millis(300)).unwrap();
+        assert!(matches!(marquee, DisplayCommand::Marquee { text, .. } if text.len() == 5));
+        assert_eq!(
+            DisplayCommand::marquee("Hi!", Duration::from_millis(300)),
+            Err(SegmentError::Unsupported('!'))
+        );
+    }
+
+    #[test]
+    fn sets_dots_on_what_is_shown() {
+        let dotted = DisplayCommand::Decimal(42).with_dots(true, false);
+        assert_eq!(shown(&dotted), "4.2");
+        assert_eq!(shown(&dotted.with_dots(false, true)), "42.");
+        assert_eq!(
+            shown(&DisplayCommand::Hex(0x1f).with_dots(true, true)),
+            "1.F."
+        );
+        assert_eq!(shown(&DisplayCommand::Clear.with_dots(false, true)), "  .");
+
+        // Commands without fixed segments are left alone
+        let spinner = DisplayCommand::Spinner {
+            step: Duration::from_millis(100),
+        };
+        assert_eq!(spinner.clone().with_dots(true, true), spinner);
+        assert_eq!(
+            DisplayCommand::SetBrightness(10).with_dots(true, true),
+            DisplayCommand::SetBrightness(10)
+        );
+    }
+}
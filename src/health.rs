@@ -0,0 +1,123 @@
+//! First-boot health check for freshly flashed images.
+//!
+//! The bootloader starts a new OTA image as pending verification. It only becomes the permanent
+//! image once every init step in [`HealthCheck`] has succeeded before the deadline; otherwise the
+//! slot is marked invalid and the previous image boots instead.
+
+use core::future::Future;
+
+use anyhow::anyhow;
+use embassy_time::{with_timeout, Duration, Instant};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::ota::{EspOta, SlotState};
+use log::{error, info, warn};
+
+use crate::convert_error;
+
+/// Time a new image has to bring up everything before it is rolled back
+pub const HEALTH_DEADLINE: Duration = Duration::from_secs(90);
+
+const NAMESPACE: &str = "health";
+const ROLLBACK_REASON_KEY: &str = "rollback";
+
+pub struct HealthCheck {
+    ota: EspOta,
+    nvs: EspNvs<NvsDefault>,
+    pending: bool,
+    deadline: Instant,
+}
+
+impl HealthCheck {
+    pub fn new(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        let ota = EspOta::new().map_err(convert_error)?;
+        let nvs = EspNvs::new(partition, NAMESPACE, true).map_err(convert_error)?;
+
+        let slot = ota.get_running_slot().map_err(convert_error)?;
+        let pending = slot.state == SlotState::Unverified;
+
+        if pending {
+            info!(
+                "Running unverified image from {}, starting health check",
+                slot.label
+            );
+        }
+
+        Ok(Self {
+            ota,
+            nvs,
+            pending,
+            deadline: Instant::now() + HEALTH_DEADLINE,
+        })
+    }
+
+    /// Whether the running image still has to prove itself
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Runs one init step under the shared deadline.
+    ///
+    /// While the image is pending verification a failure rolls back to the previous slot and
+    /// does not return. Once the image is valid, failures are simply handed back to the caller.
+    pub async fn step<T, F>(&mut self, name: &str, step: F) -> anyhow::Result<T>
+    where
+        F: Future<Output = anyhow::Result<T>>,
+    {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+
+        let result = match with_timeout(remaining, step).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("timed out")),
+        };
+
+        match result {
+            Ok(value) => {
+                info!("Health check: {name} OK");
+                Ok(value)
+            }
+            Err(e) if self.pending => self.roll_back(&format!("{name} failed: {e}")),
+            Err(e) => Err(e.context(format!("{name} init"))),
+        }
+    }
+
+    /// Marks the running image as good so the bootloader keeps it.
+    ///
+    /// This releases the OTA handle, so it has to happen before any self-update.
+    pub fn pass(mut self) -> anyhow::Result<()> {
+        if self.pending {
+            self.ota.mark_running_slot_valid().map_err(convert_error)?;
+            info!("Health check passed, image marked valid");
+        }
+
+        Ok(())
+    }
+
+    fn roll_back(&mut self, reason: &str) -> ! {
+        let reason = format!("v{}: {reason}", env!("CARGO_PKG_VERSION"));
+        error!("Health check failed ({reason}), rolling back");
+
+        if let Err(e) = self.nvs.set_str(ROLLBACK_REASON_KEY, &reason) {
+            warn!("Could not record rollback reason: {e}");
+        }
+
+        let e = self.ota.mark_running_slot_invalid_and_reboot();
+        panic!("rollback failed: {e}");
+    }
+}
+
+/// Takes the reason for the last rollback, if any, so it is only reported once
+pub fn take_rollback_reason(partition: EspDefaultNvsPartition) -> anyhow::Result<Option<String>> {
+    let mut nvs = EspNvs::new(partition, NAMESPACE, true).map_err(convert_error)?;
+
+    let mut buf = [0; 256];
+    let reason = nvs
+        .get_str(ROLLBACK_REASON_KEY, &mut buf)
+        .map_err(convert_error)?
+        .map(str::to_string);
+
+    if reason.is_some() {
+        nvs.remove(ROLLBACK_REASON_KEY).map_err(convert_error)?;
+    }
+
+    Ok(reason)
+}
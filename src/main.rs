@@ -1,31 +1,52 @@
 use std::{
-    convert::Infallible,
     ops::{Deref, DerefMut},
     sync::{
         atomic::{AtomicU16, AtomicU32},
-        mpsc::channel,
-        Mutex,
+        Arc,
     },
     time::Duration,
 };
 
-use anyhow::anyhow;
 use beacons::{
-    amoled::{self, Rm690B0},
+    amoled::Rm690B0,
     anyesp,
-    net::{connect_to_network, self_update},
-    Displays, Leds,
+    app::{
+        self,
+        state::{BeaconState, Event},
+        sync::{broker::BrokerSync, ServerSync},
+        Beacon,
+    },
+    convert_error,
+    credentials::CredentialStore,
+    hal::{
+        effects,
+        esp::{
+            Displays, EspConnector, EspStream, FuelGauge, InfallibleDriver, Leds, Nfc, Touch,
+            DIGIT_PWM_FREQUENCY,
+        },
+        Effects, LedStrip, Lights, NfcReader, PowerSource,
+    },
+    health::{self, HealthCheck},
+    net::{
+        api::{self, ApiClient},
+        connect_to_network, mqtt,
+        push::PushChannel,
+        self_update,
+        supervisor::WifiSupervisor,
+        Canceller, HttpsClient, Timeouts,
+    },
+    provision,
+    settings::{Settings, Transport},
 };
 use build_time::build_time_utc;
 use embassy_time::Timer;
-use embedded_graphics::{pixelcolor::Rgb888, prelude::*, primitives::*};
 use embedded_hal::spi::{MODE_1, MODE_2};
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     hal::{
-        delay::Delay,
         gpio::{AnyInputPin, IOPin, Input, InputPin, OutputPin, Pin, PinDriver},
         i2c::{config::Config as I2cConfig, I2cDriver},
+        ledc::{config::TimerConfig, LedcDriver, LedcTimerDriver},
         peripheral::Peripheral,
         prelude::Peripherals,
         spi::{
@@ -41,207 +62,199 @@ use esp_idf_svc::{
     timer::EspTaskTimerService,
     wifi::{AsyncWifi, EspWifi},
 };
-use ft6336::{touch::PointAction, Ft6336};
-use log::info;
-use pn532::{
-    i2c::{I2CInterface, I2CInterfaceWithIrq},
-    Interface, Pn532, Request,
-};
-use shared_bus::I2cProxy;
-use shiftreg_spi::SipoShiftReg;
-use smart_leds::colors::RED;
+use ft6336::Ft6336;
+use log::{info, warn};
+use pn532::{i2c::I2CInterface, Interface, Pn532};
 use ws2812_spi::Ws2812;
 
-type I2c = I2cProxy<'static, Mutex<I2cDriver<'static>>>;
-
-struct InfallibleDriver<T>(T);
-
-impl<T> embedded_hal::digital::ErrorType for InfallibleDriver<T> {
-    type Error = Infallible;
-}
-
-impl<T: embedded_hal::digital::InputPin> embedded_hal::digital::InputPin for InfallibleDriver<T> {
-    fn is_high(&mut self) -> Result<bool, Self::Error> {
-        Ok(self.0.is_high().unwrap())
-    }
-
-    fn is_low(&mut self) -> Result<bool, Self::Error> {
-        Ok(self.0.is_low().unwrap())
-    }
-}
-
 async fn amain(
-    mut displays: Displays,
+    displays: Displays,
     mut leds: Leds,
     mut wifi: AsyncWifi<EspWifi<'static>>,
     mut amoled: Rm690B0<'static, std::sync::Arc<SpiDriver<'static>>>,
-    mut touch: Ft6336<I2c>,
-    mut touch_irq: PinDriver<'static, AnyInputPin, Input>,
-    mut nfc: Pn532<
-        I2CInterfaceWithIrq<I2c, InfallibleDriver<PinDriver<'static, AnyInputPin, Input>>>,
-        (),
-        64,
-    >,
+    touch: Touch,
+    mut nfc: Nfc,
+    gauge: FuelGauge,
+    nvs: EspDefaultNvsPartition,
+    sys_loop: EspSystemEventLoop,
+    provision_requested: bool,
 ) -> Result<(), anyhow::Error> {
     info!("Main async process started");
     // Red before wifi
     leds.set_all_colors(smart_leds::RGB { r: 100, g: 0, b: 0 });
 
-    // connect_to_network(&mut wifi)
-    //     .await
-    //     .expect("wifi connection");
+    let mut credentials = CredentialStore::new(nvs.clone())?;
 
-    amoled.init().await.expect("init");
-    info!("AMOLED init OK");
+    let mut settings = Settings::new(nvs.clone())?;
 
-    let mut resp = nfc.process_async(&Request::GET_FIRMWARE_VERSION, 4).await;
-    while resp.is_err() {
-        info!("Trying to init NFC...");
-        resp = nfc.process_async(&Request::GET_FIRMWARE_VERSION, 4).await;
-        Timer::after_millis(100).await;
+    if provision_requested || credentials.is_empty()? {
+        info!("Entering provisioning mode");
+        amoled.init().await?;
+        provision::run(&mut wifi, &mut credentials, &mut settings, &mut amoled).await?;
     }
-    info!("NFC firmware version {:?}", resp.unwrap());
-
-    let border_stroke = PrimitiveStyleBuilder::new()
-        .stroke_color(Rgb888::RED)
-        .stroke_width(3)
-        .stroke_alignment(StrokeAlignment::Inside)
-        .build();
-
-    // amoled
-    //     .bounding_box()
-    //     .into_styled(border_stroke)
-    //     .draw(&mut amoled)
-    //     .expect("box");
-
-    let start: u8 = 0x0;
-    for i in start..=start + 0x1F {
-        let height = 16;
-        let y = i as i32 * height + 10;
-        if i % 2 == 0 {
-            Rectangle::new(Point::new(50, y), Size::new(50, height as u32))
-                .into_styled(PrimitiveStyle::with_fill(Rgb888::WHITE))
-                .draw(&mut amoled)?;
-        }
 
-        Rectangle::new(Point::new(100, y), Size::new(300, height as u32))
-            .into_styled(PrimitiveStyle::with_fill(Rgb888::new(i, i, i)))
-            .draw(&mut amoled)?;
+    let mut health = HealthCheck::new(nvs.clone())?;
+
+    // Only a new image has to prove it can get online. A valid one keeps running offline and
+    // leaves reconnecting to the supervisor's backoff, rather than failing every boot.
+    if health.is_pending() {
+        health
+            .step("Wi-Fi", async {
+                Ok(connect_to_network(&mut wifi, &credentials).await?)
+            })
+            .await?;
+    } else if let Err(e) = connect_to_network(&mut wifi, &credentials).await {
+        warn!("Could not get online at boot, retrying in the background: {e}");
     }
 
-    // for i in 1..16 {
-    //     for j in 1..16 {
-    //         let color: u8 = ((i - 1) * 16) + (j - 1);
-
-    //         let width: i32 = (amoled::WIDTH / 16) as i32;
-    //         let height: i32 = (amoled::HEIGHT / 16) as i32;
-
-    //         Rectangle::new(
-    //             Point::new((i - 1) as i32 * width, (j - 1) as i32 * height),
-    //             Size::new(width as u32, height as u32),
-    //         )
-    //         .into_styled(PrimitiveStyle::with_fill(Rgb888::new(color, color, color)))
-    //         .draw(&mut amoled)?;
-    //     }
-    // }
-    //
-    // Rectangle::new(
-    //     Point::new(0, 0),
-    //     Size::new(amoled::WIDTH as u32, amoled::HEIGHT as u32),
-    // )
-    // .into_styled(PrimitiveStyle::with_fill(Rgb888::new(100, 255, 100)))
-    // .draw(&mut amoled)?;
-
-    info!("Draw done");
-
-    // Timer::after_secs(5).await;
+    health.step("AMOLED", amoled.init()).await?;
+    info!("AMOLED init OK");
+
+    health
+        .step("NFC", async {
+            loop {
+                match nfc.firmware_version().await {
+                    Ok(version) => {
+                        info!("NFC firmware version {version:?}");
+                        return Ok(());
+                    }
+                    Err(_) => {
+                        info!("Trying to init NFC...");
+                        Timer::after_millis(100).await;
+                    }
+                }
+            }
+        })
+        .await?;
+
+    health.pass()?;
+
+    let device_id = api::device_id(wifi.wifi().sta_netif().get_mac().map_err(convert_error)?);
+    info!("Device ID {device_id}");
+
+    let supervisor = WifiSupervisor::new(wifi, credentials, &sys_loop)?;
+    let connectivity = supervisor.subscribe();
+    tokio::task::spawn(supervisor.run());
 
     // Blue before update
     leds.set_all_colors(smart_leds::RGB { r: 0, g: 0, b: 100 });
 
-    // Do this later once I have a build system working
-    // self_update(&mut leds).await.expect("self update");
-
-    // tokio::task::spawn(async move {
-    //     let res = nfc
-    //         .process_async(&Request::ntag_read(0), 270)
-    //         .await
-    //         .expect("valid read");
-    //     println!("Got data: {res:?}");
-    // });
+    let state = BeaconState::new(settings.beacon_info()?);
+    let (events, events_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (states, states_rx) = tokio::sync::watch::channel(state.clone());
+
+    let update_channel = settings.update_channel();
+    let rollback_reason = health::take_rollback_reason(nvs)?;
+
+    // A bad address falls back to the companion site rather than stopping the beacon
+    let broker_url = match settings.transport() {
+        Transport::Https => None,
+        Transport::Mqtt => match settings.broker_url() {
+            Ok(Some(url)) => mqtt::broker_url(&url)
+                .inspect_err(|e| warn!("Unusable broker URL, using HTTPS: {e}"))
+                .ok(),
+            Ok(None) => {
+                warn!("MQTT transport selected without a broker URL, using HTTPS");
+                None
+            }
+            Err(e) => {
+                warn!("Could not read broker URL, using HTTPS: {e}");
+                None
+            }
+        },
+    };
 
-    let (touch_tx, touch_rx) = channel();
+    let timeouts = Timeouts::default();
+    let tls = Arc::new(settings.tls_config());
+    let dimming = settings.night_dimming();
+    let budget = settings.power_budget();
+    let patterns = settings.notification_patterns();
+
+    if let Some(url) = broker_url {
+        let tls = tls.clone();
+        let connect = move |url| EspStream::connect(url, timeouts, tls.clone());
+
+        tokio::task::spawn(
+            BrokerSync::new(url, connect, device_id, settings, rollback_reason)
+                .with_timeouts(timeouts)
+                .run(states_rx, events.clone(), || unsafe { sys::esp_random() }),
+        );
+    } else {
+        let client =
+            HttpsClient::with_connector(EspConnector::new(tls.clone())).with_timeouts(timeouts);
+        let api = match ApiClient::new(client.clone(), &settings.api_url(), device_id.clone()) {
+            Ok(api) => api,
+            Err(e) => {
+                warn!("Unusable API URL, using the default: {e}");
+                ApiClient::new(client, api::DEFAULT_BASE_URL, device_id)?
+            }
+        };
 
-    tokio::task::spawn(async move {
-        loop {
-            touch_irq.wait_for_falling_edge().await.unwrap();
-            for touch in touch
-                .touch_points_iter()
-                .unwrap()
-                .filter(|p| matches!(p.action, PointAction::Contact))
-            {
-                touch_tx.send(touch).unwrap();
+        let push_url = api.push_url();
+        let mut sync = ServerSync::new(api, settings, rollback_reason);
+
+        // Without the push channel, pings still arrive by polling
+        match push_url {
+            Ok(url) => {
+                let tls = tls.clone();
+                let push = PushChannel::new(url, move |url| {
+                    EspStream::connect(url, timeouts, tls.clone())
+                })
+                .with_timeouts(timeouts);
+                sync = sync.with_push(push.subscribe());
+                tokio::task::spawn(push.run(events.clone(), || unsafe { sys::esp_random() }));
             }
+            Err(e) => warn!("No push channel: {e}"),
         }
-    });
 
-    tokio::task::spawn(async move {
-        loop {
-            while let Ok(point) = touch_rx.try_recv() {
-                Rectangle::with_center(
-                    Point {
-                        x: point.x as i32,
-                        y: point.y as i32,
-                    },
-                    Size::new_equal(20),
-                )
-                .draw_styled(&PrimitiveStyle::with_fill(Rgb888::WHITE), &mut amoled)
-                .unwrap();
+        tokio::task::spawn(sync.run(states_rx, events.clone()));
+    }
+
+    // Updates run when the server forces one, reporting progress back into the state machine.
+    // Leaving the updating state calls off whatever the update is waiting on.
+    let canceller = Canceller::new();
+    let client = HttpsClient::with_connector(EspConnector::new(tls))
+        .with_timeouts(timeouts)
+        .with_cancel(canceller.token());
+    let update_events = events.clone();
+    tokio::task::spawn(app::run_updates(
+        states.subscribe(),
+        events.clone(),
+        canceller,
+        move || {
+            let client = client.clone();
+            let events = update_events.clone();
+            async move {
+                self_update(&client, update_channel, |progress| {
+                    let _ = events.send(Event::UpdateProgress(progress));
+                })
+                .await
             }
-            Timer::after_millis(10).await;
-        }
+        },
+        || unsafe { sys::esp_random() },
+    ));
+
+    tokio::task::spawn(app::forward_connectivity(connectivity, events.clone()));
+    tokio::task::spawn(app::forward_passports(nfc, events.clone()));
+
+    let (power, power_rx) = tokio::sync::watch::channel(PowerSource::default());
+    tokio::task::spawn(app::watch_power(gauge, power, events.clone()));
+
+    tokio::task::spawn(async move {
+        app::forward_touches(touch, events)
+            .await
+            .expect("touch events")
     });
 
-    let mut counter = 0_u8;
-    loop {
-        displays.set_number(Some(counter));
-        // info!("BLUE");
-        leds.set_all_colors(smart_leds::RGB { r: 0, g: 0, b: 100 });
-
-        // // amoled.all_pixels(true).expect("all pixels on");
-        // for i in 1..16 {
-        //     for j in 1..16 {
-        //         let color: u8 = ((i - 1) * 16) + (j - 1);
-
-        //         let width: i32 = (amoled::WIDTH / 16) as i32;
-        //         let height: i32 = (amoled::HEIGHT / 16) as i32;
-
-        //         Rectangle::new(
-        //             Point::new((i - 1) as i32 * width, (j - 1) as i32 * height),
-        //             Size::new(width as u32, height as u32),
-        //         )
-        //         .into_styled(PrimitiveStyle::with_fill(Rgb888::new(color, color, color)))
-        //         .draw(&mut amoled)?;
-        //     }
-        // }
-
-        Timer::after_millis(750).await;
-        counter = counter.wrapping_add(1);
-        displays.set_number(Some(counter));
-        // info!("RED");
-        leds.set_all_colors(smart_leds::RGB { r: 100, g: 0, b: 0 });
-
-        // amoled.all_pixels(false).expect("all pixels on");
-        // Rectangle::new(
-        //     Point::new(0, 0),
-        //     Size::new(amoled::WIDTH as u32, amoled::HEIGHT as u32),
-        // )
-        // .into_styled(PrimitiveStyle::with_fill(Rgb888::WHITE))
-        // .draw(&mut amoled)?;
-
-        Timer::after_millis(750).await;
-        counter = counter.wrapping_add(1);
-    }
+    let leds = leds.with_budget(budget, power_rx);
+    let (lights, effects) = Lights::new(Effects::default());
+    tokio::task::spawn(async move { effects::run(leds, effects).await.expect("LED effects") });
+
+    Beacon::new(displays, lights, amoled)
+        .with_dimming(dimming)
+        .with_notifications(patterns)
+        .run(state, events_rx, &states)
+        .await?;
 
     Ok(())
 }
@@ -264,6 +277,15 @@ fn main() {
 
     let peripherals = Peripherals::take().expect("valid peripherals");
 
+    // Holding the boot button through reset forces the setup portal
+    let provision_requested = {
+        let mut button = PinDriver::input(peripherals.pins.gpio0).expect("boot button");
+        button
+            .set_pull(esp_idf_svc::hal::gpio::Pull::Up)
+            .expect("boot button pull-up");
+        provision::long_press_at_boot(&button)
+    };
+
     let driver = SpiDriver::new_quad(
         peripherals.spi3,
         peripherals.pins.gpio18,
@@ -281,23 +303,45 @@ fn main() {
         // let data = PinDriver::output(peripherals.pins.gpio5.downgrade_output()).expect("data pin");
         // let latch = PinDriver::output(peripherals.pins.gpio6.downgrade_output()).expect("data pin");
         // let clk = PinDriver::output(peripherals.pins.gpio9.downgrade_output()).expect("data pin");
-        let low_digit = PinDriver::output(peripherals.pins.gpio10).expect("low digit");
-        let high_digit = PinDriver::output(peripherals.pins.gpio11).expect("low digit");
+        let digit_pwm = Arc::new(
+            LedcTimerDriver::new(
+                peripherals.ledc.timer0,
+                &TimerConfig::default().frequency(DIGIT_PWM_FREQUENCY),
+            )
+            .expect("digit pwm timer"),
+        );
+        let low_digit = LedcDriver::new(
+            peripherals.ledc.channel0,
+            digit_pwm.clone(),
+            peripherals.pins.gpio10,
+        )
+        .expect("low digit");
+        let high_digit = LedcDriver::new(
+            peripherals.ledc.channel1,
+            digit_pwm,
+            peripherals.pins.gpio11,
+        )
+        .expect("high digit");
 
-        let spi = SpiDeviceDriver::new(
+        let register = SpiDeviceDriver::new(
             driver.clone(),
             Some(peripherals.pins.gpio6),
             &Config::default().bit_order(esp_idf_svc::hal::spi::config::BitOrder::MsbFirst),
         )
         .expect("valid sp");
 
-        let register = SipoShiftReg::new(spi);
-        Displays::new(register, low_digit, high_digit)
+        Displays::new(
+            register,
+            low_digit,
+            high_digit,
+            &EspTaskTimerService::new().expect("timer service"),
+        )
+        .expect("displays")
     };
 
     let amoled = {
         let qspi = SpiDeviceDriver::new(
-            driver.clone(),
+            driver,
             Some(peripherals.pins.gpio13),
             &Config::default()
                 .data_mode(MODE_3)
@@ -320,7 +364,7 @@ fn main() {
 
     let bus = shared_bus::new_std!(I2cDriver = i2c).expect("i2c bus");
 
-    let (amoled_touch_irq, amoled_touch) = {
+    let touch = {
         let mut reset = PinDriver::output(peripherals.pins.gpio5).expect("reset");
         reset.set_low().expect("reset low");
         std::thread::sleep(std::time::Duration::from_millis(5));
@@ -333,19 +377,10 @@ fn main() {
         let mut touch = Ft6336::new(bus.acquire_i2c());
         touch.init().expect("touch init");
         touch.interrupt_by_state().expect("interrupt");
-        (irq, touch)
+        Touch::new(touch, irq)
     };
 
     let nfc = {
-        let spi = SpiDeviceDriver::new(
-            driver,
-            Some(peripherals.pins.gpio38),
-            &Config::default()
-                .bit_order(esp_idf_svc::hal::spi::config::BitOrder::LsbFirst)
-                .duplex(Duplex::Full),
-        )
-        .expect("valid spi");
-
         let irq = PinDriver::input(peripherals.pins.gpio12.downgrade_input()).expect("irq pin");
 
         let interface = pn532::i2c::I2CInterfaceWithIrq {
@@ -353,17 +388,17 @@ fn main() {
             irq: InfallibleDriver(irq),
         };
 
-        let mut nfc = Pn532::<_, _, 64>::new_async(interface);
-
-        nfc
+        Nfc::new(Pn532::new_async(interface))
     };
 
+    let gauge = FuelGauge::new(bus.acquire_i2c());
+
     let sys_loop = EspSystemEventLoop::take().unwrap();
     let nvs = EspDefaultNvsPartition::take().unwrap();
 
     let wifi = AsyncWifi::wrap(
-        EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs)).unwrap(),
-        sys_loop,
+        EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs.clone())).unwrap(),
+        sys_loop.clone(),
         EspTaskTimerService::new().unwrap(),
     )
     .expect("wifi");
@@ -390,8 +425,7 @@ fn main() {
                 let cfg = Config::new().baudrate(Hertz(2_000_000)).data_mode(MODE_1);
                 let bus = SpiBusDriver::new(driver, &cfg).expect("valid spi bus");
 
-                let leds = Ws2812::new(bus);
-                Leds { leds }
+                Leds::new(Ws2812::new(bus))
             };
 
             anyesp!(unsafe {
@@ -408,9 +442,12 @@ fn main() {
                     leds,
                     wifi,
                     amoled,
-                    amoled_touch,
-                    amoled_touch_irq,
+                    touch,
                     nfc,
+                    gauge,
+                    nvs,
+                    sys_loop,
+                    provision_requested,
                 ))
                 .expect("amain ok")
         })
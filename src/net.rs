@@ -1,49 +1,183 @@
-use core::str::FromStr;
-use std::net::TcpStream;
-use std::net::ToSocketAddrs;
+//! Networking: Wi-Fi, HTTPS, the companion site, MQTT brokers and over-the-air updates.
+//!
+//! The protocol code in [`api`], [`client`], [`download`], [`mqtt`], [`release`], [`response`]
+//! and [`tls`] is plain Rust. Everything that talks to the radio or the TLS stack only exists when
+//! building for the ESP.
+
+use core::future::Future;
+use std::io;
+use std::net::{SocketAddr, TcpStream};
 
 use async_io::Async;
-use dotenvy_macro::dotenv;
-use embassy_time::with_timeout;
-use esp_idf_svc::hal::reset::restart;
-use esp_idf_svc::io::{self, Write};
-use esp_idf_svc::ota::EspOta;
-use esp_idf_svc::tls::EspAsyncTls;
-use esp_idf_svc::wifi::{AsyncWifi, ClientConfiguration, Configuration, EspWifi};
-use http::Request;
-use log::info;
-use url::Url;
-
-use crate::{convert_error, EspTlsSocket, Leds};
-
-#[derive(Debug, serde::Deserialize)]
-struct GithubResponse {
-    tag_name: String,
-    assets: Vec<GithubAsset>,
+use embassy_time::Duration;
+use log::warn;
+use smart_leds::RGB8;
+use url::{Host, Url};
+
+pub mod api;
+pub mod client;
+mod dns;
+pub mod download;
+mod error;
+mod limits;
+pub mod mqtt;
+pub mod push;
+pub mod release;
+pub mod response;
+pub mod tls;
+pub mod websocket;
+
+#[cfg(target_os = "espidf")]
+pub mod supervisor;
+#[cfg(target_os = "espidf")]
+pub mod update;
+#[cfg(target_os = "espidf")]
+pub mod wifi;
+
+pub use self::error::NetError;
+pub use self::limits::{timeout, Cancel, Canceller, Timeouts};
+pub use self::tls::{Pin, TlsConfig, TlsError};
+
+pub use self::client::{HttpResponse, HttpsClient};
+#[cfg(target_os = "espidf")]
+pub use self::update::self_update;
+#[cfg(target_os = "espidf")]
+pub use self::wifi::{connect_to_network, connect_with};
+
+/// How [`HttpsClient`] connects unless it is given another connector
+#[cfg(target_os = "espidf")]
+pub type DefaultConnector = crate::hal::esp::EspConnector;
+#[cfg(not(target_os = "espidf"))]
+pub type DefaultConnector = TcpConnector;
+
+pub const USER_AGENT: &str = concat!("PHBeacon/", env!("CARGO_PKG_VERSION"));
+
+/// Delay before the second round of attempts, doubled every round after that
+const BASE_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Longest wait between rounds of attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// A byte stream the protocol clients can run over
+pub trait Connection {
+    /// Reads some bytes, returning 0 once the peer has closed the stream
+    fn read(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<usize, NetError>>;
+
+    fn write_all(&mut self, data: &[u8]) -> impl Future<Output = Result<(), NetError>>;
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct GithubAsset {
-    browser_download_url: String,
+impl Connection for Async<TcpStream> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, NetError> {
+        use std::io::Read;
+
+        self.read_with(|mut stream| stream.read(buf))
+            .await
+            .map_err(|e| NetError::Io(e.into()))
+    }
+
+    async fn write_all(&mut self, data: &[u8]) -> Result<(), NetError> {
+        use std::io::Write;
+
+        let mut data = data;
+        while !data.is_empty() {
+            let written = self
+                .write_with(|mut stream| stream.write(data))
+                .await
+                .map_err(|e| NetError::Io(e.into()))?;
+            data = &data[written..];
+        }
+
+        Ok(())
+    }
 }
 
-pub async fn generate_tls(url: &str) -> anyhow::Result<EspAsyncTls<EspTlsSocket>> {
-    let url = Url::from_str(url).unwrap();
-    let host = url.host_str().unwrap();
-    let addr = format!("{host}:443")
-        .to_socket_addrs()
-        .unwrap()
-        .collect::<Vec<_>>();
+/// Opens connections for [`HttpsClient`], deciding how servers are verified
+pub trait Connector {
+    type Stream: Connection;
 
-    let socket = Async::<TcpStream>::connect(addr[0]).await.unwrap();
+    /// Connects to the host and port in `url`, over TLS if its scheme asks for it
+    fn connect(
+        &self,
+        url: &Url,
+        timeouts: &Timeouts,
+    ) -> impl Future<Output = Result<Self::Stream, NetError>>;
+}
+
+/// Plain TCP only, for servers that do not need TLS
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpConnector;
 
-    let mut tls = esp_idf_svc::tls::EspAsyncTls::adopt(EspTlsSocket::new(socket)).unwrap();
+impl Connector for TcpConnector {
+    type Stream = Async<TcpStream>;
 
-    tls.negotiate(host, &esp_idf_svc::tls::Config::new())
-        .await
-        .unwrap();
+    async fn connect(&self, url: &Url, timeouts: &Timeouts) -> Result<Self::Stream, NetError> {
+        match url.scheme() {
+            "http" | "ws" | "mqtt" => connect_tcp(url, timeouts).await,
+            scheme => Err(NetError::UnsupportedScheme(scheme.to_string())),
+        }
+    }
+}
 
-    Ok(tls)
+/// Opens a plain TCP connection to the host in `url`, on its port or the default for its scheme.
+///
+/// Every address the host resolves to is tried in turn, each with the full connect timeout.
+/// Looking the host up gets a connect timeout of its own.
+pub async fn connect_tcp(url: &Url, timeouts: &Timeouts) -> Result<Async<TcpStream>, NetError> {
+    connect_any(url, timeouts, &dns::CACHE, Async::<TcpStream>::connect).await
+}
+
+/// How [`connect_tcp`] gets through, with names resolved by `cache` and each address tried
+/// with `connect`
+async fn connect_any<S, R: dns::Resolver, F: Future<Output = io::Result<S>>>(
+    url: &Url,
+    timeouts: &Timeouts,
+    cache: &dns::Cache<R>,
+    mut connect: impl FnMut(SocketAddr) -> F,
+) -> Result<S, NetError> {
+    let host = url
+        .host()
+        .ok_or_else(|| NetError::InvalidUrl(url.to_string()))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| NetError::InvalidUrl(url.to_string()))?;
+
+    let addrs = match host {
+        Host::Domain(name) => timeout(timeouts.connect, cache.resolve(name, port)).await?,
+        Host::Ipv4(ip) => vec![SocketAddr::from((ip, port))],
+        Host::Ipv6(ip) => vec![SocketAddr::from((ip, port))],
+    };
+
+    let mut last = None;
+    for addr in &addrs {
+        let connected = timeout(timeouts.connect, async {
+            connect(*addr).await.map_err(NetError::Connect)
+        })
+        .await;
+
+        match connected {
+            Ok(stream) => {
+                if let Host::Domain(name) = host {
+                    cache.prefer(name, port, *addr);
+                }
+                return Ok(stream);
+            }
+            Err(e) => {
+                warn!("Could not connect to {host} at {addr}: {e}");
+                last = Some(e);
+            }
+        }
+    }
+
+    // The addresses may have moved since they were cached
+    if let Host::Domain(name) = host {
+        cache.forget(name, port);
+    }
+
+    Err(NetError::Unreachable {
+        host: host.to_string(),
+        tried: addrs.len(),
+        last: Box::new(last.unwrap_or_else(|| NetError::Dns(host.to_string()))),
+    })
 }
 
 pub fn create_raw_request_no_body<T>(request: &http::Request<T>) -> String {
@@ -53,7 +187,8 @@ pub fn create_raw_request_no_body<T>(request: &http::Request<T>) -> String {
 
     let mut request_text = format!("{} {} HTTP/1.1\r\n", method, uri);
     for (key, value) in headers {
-        request_text.push_str(&format!("{}: {}\r\n", key, value.to_str().unwrap()));
+        let value = String::from_utf8_lossy(value.as_bytes());
+        request_text.push_str(&format!("{}: {}\r\n", key, value));
     }
     request_text.push_str("\r\n"); // End of headers
 
@@ -68,282 +203,153 @@ pub fn create_raw_request<T: ToString>(request: &http::Request<T>) -> String {
     text
 }
 
-pub async fn handle_redirect(url: &str) -> anyhow::Result<EspAsyncTls<EspTlsSocket>> {
-    let request = Request::builder()
-        .method("GET")
-        .header("User-Agent", "PHBeacon/1.0.0")
-        .header("Host", "github.com")
-        .uri(url)
-        .body(())
-        .unwrap();
-
-    let mut tls = generate_tls(url).await?;
-
-    let request_text = create_raw_request_no_body(&request);
-
-    tls.write_all(request_text.as_bytes())
-        .await
-        .map_err(convert_error)?;
+/// Delay after `round` failed rounds. Half of it is fixed and the other half is spread by
+/// `random`, so beacons that dropped together do not all retry in lockstep.
+pub fn backoff(round: u32, random: u32) -> Duration {
+    let full = BASE_BACKOFF
+        .as_millis()
+        .saturating_mul(1 << round.min(16))
+        .min(MAX_BACKOFF.as_millis());
+    let half = full / 2;
 
-    let mut body = [0; 8192];
-
-    let _read = io::utils::asynch::try_read_full(&mut tls, &mut body)
-        .await
-        .map_err(|(e, _)| e)
-        .unwrap();
-
-    let body = String::from_utf8(body.into()).expect("valid UTF8");
-
-    let splits = body.split("\r\n");
+    Duration::from_millis(half + random as u64 % (half + 1))
+}
 
-    for split in splits {
-        if split.to_lowercase().starts_with("location: ") {
-            let location = split.split(": ").nth(1).expect("location value");
+/// How far along a download is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub written: u64,
+    /// Full size of the download, once the server has told us
+    pub total: Option<u64>,
+}
 
-            let request = Request::builder()
-                .method("GET")
-                .header("User-Agent", "PHBeacon/1.0.0")
-                .header("Host", "githubusercontent.com")
-                .uri(location)
-                .body(())
-                .unwrap();
+impl Progress {
+    pub fn percent(&self) -> Option<u8> {
+        let total = self.total.filter(|total| *total > 0)?;
+        Some((self.written.min(total) * 100 / total) as u8)
+    }
+}
 
-            let tls = generate_tls(location).await?;
-            let request_text = create_raw_request_no_body(&request);
+/// Wi-Fi state as published by the connection supervisor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Connectivity {
+    /// Not on any network and waiting before trying again
+    Offline {
+        retry_in: Duration,
+    },
+    Connecting {
+        ssid: String,
+    },
+    Online {
+        ssid: String,
+    },
+}
 
-            tls.write_all(request_text.as_bytes())
-                .await
-                .map_err(convert_error)?;
+impl Connectivity {
+    pub fn is_online(&self) -> bool {
+        matches!(self, Self::Online { .. })
+    }
 
-            return Ok(tls);
+    /// Status color for the LEDs
+    pub fn color(&self) -> RGB8 {
+        match self {
+            Self::Offline { .. } => RGB8::new(100, 0, 0),
+            Self::Connecting { .. } => RGB8::new(100, 40, 0),
+            Self::Online { .. } => RGB8::new(0, 0, 100),
         }
     }
-
-    unreachable!("location must be in returned value!")
 }
 
-pub async fn self_update(leds: &mut Leds) -> anyhow::Result<()> {
-    info!("Checking for self-update");
-
-    let manifest: GithubResponse = {
-        let url = "https://api.github.com/repos/purduehackers/beacons/releases/latest";
-
-        let request = Request::builder()
-            .method("GET")
-            .header("User-Agent", "PHSign/1.0.0")
-            .header("Host", "api.github.com")
-            .uri(url)
-            .body(())
-            .unwrap();
-
-        let mut tls = generate_tls(url).await?;
-
-        let request_text = create_raw_request_no_body(&request);
-
-        tls.write_all(request_text.as_bytes())
-            .await
-            .map_err(convert_error)?;
-
-        let mut body = [0; 8192];
-
-        let _read = io::utils::asynch::try_read_full(&mut tls, &mut body)
-            .await
-            .map_err(|(e, _)| e)
-            .unwrap();
-
-        let body = String::from_utf8(body.into()).expect("valid UTF8");
+#[cfg(test)]
+mod tests {
+    use core::future::pending;
+    use std::cell::RefCell;
 
-        let ind = body.find("\r\n\r\n").expect("body start");
+    use super::*;
+    use crate::net::dns::FakeResolver;
+    use crate::testing::block_on;
 
-        serde_json::from_str(body[ind + 4..].trim().trim_end_matches(char::from(0)))
-            .expect("Valid parse for GitHub manifest")
+    const TIMEOUTS: Timeouts = Timeouts {
+        connect: Duration::from_millis(50),
+        ..Timeouts::new()
     };
 
-    let local = semver::Version::new(
-        env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap(),
-        env!("CARGO_PKG_VERSION_MINOR").parse().unwrap(),
-        env!("CARGO_PKG_VERSION_PATCH").parse().unwrap(),
-    );
-
-    let remote = semver::Version::from_str(&manifest.tag_name[1..]).expect("valid semver");
-
-    if remote > local {
-        info!("New release found! Downloading and updating");
-        leds.set_all_colors(smart_leds::RGB { r: 0, g: 100, b: 0 });
-        // Grab new release and update
-        let url = manifest
-            .assets
-            .first()
-            .expect("release to contain assets")
-            .browser_download_url
-            .clone();
-
-        let tls = handle_redirect(&url).await?;
-
-        // Consume until \r\n\r\n (body)
-        info!("Consuming headers...");
-        {
-            #[derive(Debug)]
-            enum ParseConsumerState {
-                None,
-                FirstCR,
-                FirstNL,
-                SecondCR,
-            }
-
-            let mut state = ParseConsumerState::None;
-
-            let mut consumption_buffer = [0; 1];
-
-            loop {
-                let read = tls
-                    .read(&mut consumption_buffer)
-                    .await
-                    .map_err(convert_error)
-                    .expect("read byte for parse consumer");
+    fn cache(addrs: &[&str]) -> dns::Cache<FakeResolver> {
+        let resolver = FakeResolver::default().with("beacons.example", addrs);
+        dns::Cache::new(resolver, Duration::from_secs(60))
+    }
 
-                if read == 0 {
-                    panic!("Invalid update parse! Reached EOF before valid body");
-                }
-                state = match state {
-                    ParseConsumerState::None => {
-                        if consumption_buffer[0] == b'\r' {
-                            ParseConsumerState::FirstCR
-                        } else {
-                            ParseConsumerState::None
-                        }
-                    }
-                    ParseConsumerState::FirstCR => {
-                        if consumption_buffer[0] == b'\n' {
-                            ParseConsumerState::FirstNL
-                        } else {
-                            ParseConsumerState::None
-                        }
-                    }
-                    ParseConsumerState::FirstNL => {
-                        if consumption_buffer[0] == b'\r' {
-                            ParseConsumerState::SecondCR
-                        } else {
-                            ParseConsumerState::None
-                        }
-                    }
-                    ParseConsumerState::SecondCR => {
-                        if consumption_buffer[0] == b'\n' {
-                            break;
-                        } else {
-                            ParseConsumerState::None
-                        }
-                    }
+    /// Connects `url` through `cache` to a network where 10.0.0.1 refuses connections, 10.0.0.2
+    /// never answers and everything else accepts them. Returns the address it got through to
+    /// and every address it tried.
+    fn connect(
+        url: &str,
+        cache: &dns::Cache<FakeResolver>,
+    ) -> (Result<SocketAddr, NetError>, Vec<SocketAddr>) {
+        let tried = RefCell::new(Vec::new());
+        let url = Url::parse(url).unwrap();
+
+        let connected = block_on(connect_any(&url, &TIMEOUTS, cache, |addr| {
+            tried.borrow_mut().push(addr);
+            async move {
+                match addr.ip().to_string().as_str() {
+                    "10.0.0.1" => Err(io::ErrorKind::ConnectionRefused.into()),
+                    "10.0.0.2" => pending().await,
+                    _ => Ok(addr),
                 }
             }
-        }
-
-        info!("Headers consumed");
+        }));
 
-        let mut body = [0; 8192];
-
-        let mut ota = EspOta::new().expect("ESP OTA success");
-
-        let mut update = ota.initiate_update().expect("update to initialize");
-
-        let mut chunk = 0_usize;
-        loop {
-            let read =
-                with_timeout(embassy_time::Duration::from_secs(10), tls.read(&mut body)).await;
-
-            match read {
-                Ok(Ok(read)) => {
-                    info!("[CHUNK {chunk:>4}] Read {read:>4}");
-
-                    update.write_all(&body[..read]).expect("write update data");
-
-                    if read == 0 {
-                        break;
-                    }
-
-                    chunk += 1;
-                }
-                Ok(Err(e)) => e.panic(),
-                Err(_) => break,
-            };
-        }
-
-        info!("Update completed! Activating...");
-
-        update
-            .finish()
-            .expect("update finalization to work")
-            .activate()
-            .expect("activation to work");
-
-        restart();
-    } else {
-        info!("Already on latest version.");
+        (connected, tried.into_inner())
     }
 
-    Ok(())
-}
-
-pub async fn connect_to_network(wifi: &mut AsyncWifi<EspWifi<'static>>) -> anyhow::Result<()> {
-    #[cfg(feature = "enterprise")]
-    {
-        use crate::anyesp;
-        let config = Configuration::Client(ClientConfiguration {
-            ssid: dotenv!("WIFI_SSID").try_into().unwrap(),
-            password: "".try_into().unwrap(),
-            auth_method: esp_idf_svc::wifi::AuthMethod::WPA2Enterprise,
-            ..Default::default()
-        });
-
-        wifi.set_configuration(&config).map_err(convert_error)?;
-
-        unsafe {
-            use esp_idf_svc::sys::*;
-            anyesp!(esp_wifi_set_mode(wifi_mode_t_WIFI_MODE_STA))?;
-            anyesp!(esp_eap_client_set_identity(
-                dotenv!("WIFI_EMAIL").as_ptr(),
-                dotenv!("WIFI_EMAIL").len() as i32
-            ))?;
-            anyesp!(esp_eap_client_set_username(
-                dotenv!("WIFI_USERNAME").as_ptr(),
-                dotenv!("WIFI_USERNAME").len() as i32
-            ))?;
-            anyesp!(esp_eap_client_set_password(
-                dotenv!("WIFI_PASSWORD").as_ptr(),
-                dotenv!("WIFI_PASSWORD").len() as i32
-            ))?;
-            anyesp!(esp_eap_client_set_ttls_phase2_method(
-                esp_eap_ttls_phase2_types_ESP_EAP_TTLS_PHASE2_MSCHAPV2
-            ))?;
-            anyesp!(esp_wifi_sta_enterprise_enable())?;
-        }
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
     }
 
-    #[cfg(feature = "personal")]
-    {
-        let config = Configuration::Client(ClientConfiguration {
-            ssid: dotenv!("WIFI_SSID").try_into().unwrap(),
-            password: dotenv!("WIFI_PASSWORD").try_into().unwrap(),
-            auth_method: esp_idf_svc::wifi::AuthMethod::WPA2Personal,
-            ..Default::default()
-        });
+    #[test]
+    fn tries_every_address_until_one_answers() {
+        let cache = cache(&["10.0.0.1:0", "10.0.0.2:0", "10.0.0.3:0"]);
 
-        wifi.set_configuration(&config).map_err(convert_error)?;
-    }
+        let (connected, tried) = connect("http://beacons.example/", &cache);
+        assert_eq!(connected.unwrap(), "10.0.0.3:80".parse().unwrap());
+        assert_eq!(tried, addrs(&["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"]));
 
-    wifi.start().await.map_err(convert_error)?;
+        // The address that worked goes first from then on
+        let (connected, tried) = connect("http://beacons.example/", &cache);
+        assert_eq!(connected.unwrap(), "10.0.0.3:80".parse().unwrap());
+        assert_eq!(tried, addrs(&["10.0.0.3:80"]));
+        assert_eq!(cache.resolver.lookups(), 1);
+    }
 
-    // Connect but with a longer timeout
-    wifi.wifi_mut().connect().map_err(convert_error)?;
-    wifi.wifi_wait(
-        |this| this.wifi().is_connected().map(|s| !s),
-        Some(std::time::Duration::from_secs(10)),
-    )
-    .await?;
+    #[test]
+    fn looks_the_host_up_again_once_every_address_failed() {
+        let cache = cache(&["10.0.0.1:0", "10.0.0.2:0"]);
+
+        let (connected, tried) = connect("http://beacons.example:8080/", &cache);
+        assert!(matches!(
+            connected,
+            Err(NetError::Unreachable { host, tried: 2, last })
+                if host == "beacons.example" && matches!(*last, NetError::Timeout(_))
+        ));
+        assert_eq!(tried, addrs(&["10.0.0.1:8080", "10.0.0.2:8080"]));
+
+        let (connected, _) = connect("http://beacons.example:8080/", &cache);
+        assert!(connected.is_err());
+        assert_eq!(cache.resolver.lookups(), 2);
+    }
 
-    wifi.wait_netif_up().await.map_err(convert_error)?;
+    #[test]
+    fn connects_to_addresses_without_a_lookup() {
+        let cache = cache(&[]);
 
-    info!("Wi-Fi connected!");
+        let (connected, tried) = connect("http://10.0.0.3:8080/", &cache);
+        assert_eq!(connected.unwrap(), "10.0.0.3:8080".parse().unwrap());
+        assert_eq!(tried, addrs(&["10.0.0.3:8080"]));
+        assert_eq!(cache.resolver.lookups(), 0);
 
-    Ok(())
+        let (connected, tried) = connect("http://nowhere.example/", &cache);
+        assert!(matches!(connected, Err(NetError::Dns(host)) if host == "nowhere.example"));
+        assert!(tried.is_empty());
+    }
 }
@@ -0,0 +1,417 @@
+//! Client for the companion site, where attendees claim beacons and ping each other.
+//!
+//! All requests go through a [`NetClient`] and every URL hangs off a configurable base, so the
+//! same client runs against the real server or a mock on a dev machine.
+
+use core::fmt;
+
+use http::Method;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use url::Url;
+
+use super::release::{BOARD_REVISION, BUILD_FLAVOR};
+use super::NetError;
+use crate::hal::NetClient;
+use crate::settings::BeaconInfo;
+
+/// Where the companion site's API lives unless the settings say otherwise
+pub const DEFAULT_BASE_URL: &str = "https://beacons.purduehackers.com/api/";
+
+#[derive(Debug)]
+pub enum ApiError {
+    InvalidUrl(url::ParseError),
+    /// The server answered with a non-success status
+    Status(u16),
+    InvalidBody(serde_json::Error),
+    /// The API URL has a scheme the beacon cannot speak
+    UnsupportedScheme(String),
+    Transport(NetError),
+}
+
+impl ApiError {
+    /// Whether trying again later might succeed, as with [`NetError::is_transient`]
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Status(status) => *status == 429 || (500..=599).contains(status),
+            Self::Transport(e) => e.is_transient(),
+            Self::InvalidUrl(_) | Self::InvalidBody(_) | Self::UnsupportedScheme(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidUrl(e) => write!(f, "invalid API URL: {e}"),
+            Self::Status(status) => write!(f, "API responded with status {status}"),
+            Self::InvalidBody(e) => write!(f, "malformed API body: {e}"),
+            Self::UnsupportedScheme(scheme) => write!(f, "unsupported API URL scheme {scheme:?}"),
+            Self::Transport(e) => write!(f, "transport error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Sent once per boot so the server knows the beacon exists and what it runs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Registration<'a> {
+    pub device_id: &'a str,
+    pub firmware_version: &'a str,
+    pub board: &'a str,
+    pub flavor: &'a str,
+}
+
+/// Who has claimed the beacon and what they are working on
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Assignment {
+    pub owner: String,
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    /// Whether the owner has stepped away from their project
+    #[serde(default)]
+    pub away: bool,
+}
+
+impl From<Assignment> for BeaconInfo {
+    fn from(assignment: Assignment) -> Self {
+        Self {
+            owner: assignment.owner,
+            project: assignment.title,
+            description: assignment.description,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+struct BeaconDetails {
+    /// Missing while the beacon is unclaimed
+    assignment: Option<Assignment>,
+}
+
+/// A request from another attendee to come talk
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Ping {
+    pub id: String,
+    /// Name of whoever sent it
+    pub from: String,
+    /// Why they want to talk, if they said
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+/// Periodic report on how the beacon is doing
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StatusReport<'a> {
+    pub firmware_version: &'a str,
+    /// Name of the beacon's current state
+    pub state: &'a str,
+    pub uptime_secs: u64,
+    /// Why the last update was rolled back, reported once after it happens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rollback_reason: Option<&'a str>,
+}
+
+/// Device ID from the station MAC address, as lowercase hex
+pub fn device_id(mac: [u8; 6]) -> String {
+    mac.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub struct ApiClient<C> {
+    client: C,
+    base_url: Url,
+    device_id: String,
+}
+
+impl<C: NetClient> ApiClient<C> {
+    /// Creates a client for the API under `base_url`, acting as `device_id`
+    pub fn new(client: C, base_url: &str, device_id: String) -> Result<Self, ApiError> {
+        let mut base_url = Url::parse(base_url).map_err(ApiError::InvalidUrl)?;
+
+        // Without a trailing slash, joining would replace the last path segment
+        if !base_url.path().ends_with('/') {
+            let path = format!("{}/", base_url.path());
+            base_url.set_path(&path);
+        }
+
+        Ok(Self {
+            client,
+            base_url,
+            device_id,
+        })
+    }
+
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    /// URL of the beacon's push channel, on the same server as the API
+    pub fn push_url(&self) -> Result<Url, ApiError> {
+        let mut url = self
+            .base_url
+            .join(&format!("beacons/{}/events", self.device_id))
+            .map_err(ApiError::InvalidUrl)?;
+
+        let scheme = match url.scheme() {
+            "http" => "ws",
+            "https" => "wss",
+            other => return Err(ApiError::UnsupportedScheme(other.to_string())),
+        };
+        url.set_scheme(scheme)
+            .map_err(|()| ApiError::UnsupportedScheme(url.scheme().to_string()))?;
+
+        Ok(url)
+    }
+
+    pub async fn register(&self) -> Result<(), ApiError> {
+        let registration = Registration {
+            device_id: &self.device_id,
+            firmware_version: env!("CARGO_PKG_VERSION"),
+            board: BOARD_REVISION,
+            flavor: BUILD_FLAVOR,
+        };
+
+        self.send(Method::POST, "beacons", Some(&registration))
+            .await
+            .map(drop)
+    }
+
+    /// Fetches the current owner and project, if the beacon has been claimed
+    pub async fn assignment(&self) -> Result<Option<Assignment>, ApiError> {
+        let path = format!("beacons/{}", self.device_id);
+        let details: BeaconDetails = self.get(&path).await?;
+
+        Ok(details.assignment)
+    }
+
+    /// Fetches the pings that have not been acknowledged yet, oldest first
+    pub async fn pings(&self) -> Result<Vec<Ping>, ApiError> {
+        self.get(&format!("beacons/{}/pings", self.device_id)).await
+    }
+
+    /// Tells the sender of a ping that the owner is on their way
+    pub async fn acknowledge(&self, ping_id: &str) -> Result<(), ApiError> {
+        let path = format!("beacons/{}/pings/{ping_id}/ack", self.device_id);
+
+        self.send::<()>(Method::POST, &path, None).await.map(drop)
+    }
+
+    pub async fn post_status(&self, status: &StatusReport<'_>) -> Result<(), ApiError> {
+        let path = format!("beacons/{}/status", self.device_id);
+
+        self.send(Method::POST, &path, Some(status)).await.map(drop)
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, ApiError> {
+        let body = self.send::<()>(Method::GET, path, None).await?;
+
+        serde_json::from_slice(&body).map_err(ApiError::InvalidBody)
+    }
+
+    /// Sends a request relative to the base URL and returns the body of a successful response
+    async fn send<T: Serialize>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&T>,
+    ) -> Result<Vec<u8>, ApiError> {
+        let url = self.base_url.join(path).map_err(ApiError::InvalidUrl)?;
+        let body = body
+            .map(serde_json::to_vec)
+            .transpose()
+            .map_err(ApiError::InvalidBody)?;
+
+        let mut headers = vec![("Accept", "application/json")];
+        if body.is_some() {
+            headers.push(("Content-Type", "application/json"));
+        }
+
+        let response = self
+            .client
+            .request(method, url.as_str(), &headers, body.as_deref())
+            .await
+            .map_err(ApiError::Transport)?;
+
+        if !response.is_success() {
+            return Err(ApiError::Status(response.status));
+        }
+
+        Ok(response.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::hal::fake::FakeNet;
+    use crate::testing::block_on;
+
+    #[test]
+    fn reads_assignments() {
+        let details: BeaconDetails = serde_json::from_value(json!({
+            "assignment": {
+                "owner": "Ada",
+                "title": "Analytical engine",
+                "description": "Bernoulli numbers",
+                "away": true,
+            },
+        }))
+        .unwrap();
+        assert_eq!(
+            details.assignment,
+            Some(Assignment {
+                owner: "Ada".to_string(),
+                title: "Analytical engine".to_string(),
+                description: "Bernoulli numbers".to_string(),
+                away: true,
+            })
+        );
+
+        let details: BeaconDetails = serde_json::from_value(json!({
+            "assignment": {"owner": "Ada", "title": "Analytical engine"},
+        }))
+        .unwrap();
+        let assignment = details.assignment.unwrap();
+        assert_eq!(assignment.description, "");
+        assert!(!assignment.away);
+
+        let info = BeaconInfo::from(assignment);
+        assert_eq!(info.owner, "Ada");
+        assert_eq!(info.project, "Analytical engine");
+
+        for unclaimed in [json!({}), json!({"assignment": null})] {
+            let details: BeaconDetails = serde_json::from_value(unclaimed).unwrap();
+            assert_eq!(details.assignment, None);
+        }
+
+        assert!(serde_json::from_value::<BeaconDetails>(json!({
+            "assignment": {"owner": "Ada"},
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn reads_pings() {
+        let pings: Vec<Ping> = serde_json::from_value(json!([
+            {"id": "1", "from": "Grace", "message": "Got a minute?"},
+            {"id": "2", "from": "Alan"},
+            {"id": "3", "from": "Edsger", "message": null},
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            pings,
+            [
+                Ping {
+                    id: "1".to_string(),
+                    from: "Grace".to_string(),
+                    message: Some("Got a minute?".to_string()),
+                },
+                Ping {
+                    id: "2".to_string(),
+                    from: "Alan".to_string(),
+                    message: None,
+                },
+                Ping {
+                    id: "3".to_string(),
+                    from: "Edsger".to_string(),
+                    message: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_status_reports() {
+        let mut status = StatusReport {
+            firmware_version: "1.2.3",
+            state: "claimed",
+            uptime_secs: 90,
+            rollback_reason: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&status).unwrap(),
+            json!({"firmware_version": "1.2.3", "state": "claimed", "uptime_secs": 90})
+        );
+
+        status.rollback_reason = Some("panicked at boot");
+        assert_eq!(
+            serde_json::to_value(&status).unwrap(),
+            json!({
+                "firmware_version": "1.2.3",
+                "state": "claimed",
+                "uptime_secs": 90,
+                "rollback_reason": "panicked at boot",
+            })
+        );
+    }
+
+    #[test]
+    fn joins_urls_onto_the_base() {
+        let api = ApiClient::new(
+            FakeNet::default(),
+            "http://localhost:8080/api",
+            "abc".to_string(),
+        )
+        .unwrap();
+        assert_eq!(api.base_url.as_str(), "http://localhost:8080/api/");
+        assert_eq!(
+            api.push_url().unwrap().as_str(),
+            "ws://localhost:8080/api/beacons/abc/events"
+        );
+
+        let api = ApiClient::new(FakeNet::default(), DEFAULT_BASE_URL, "abc".to_string()).unwrap();
+        assert_eq!(
+            api.push_url().unwrap().as_str(),
+            "wss://beacons.purduehackers.com/api/beacons/abc/events"
+        );
+    }
+
+    #[test]
+    fn refuses_push_channels_on_other_schemes() {
+        let api = ApiClient::new(
+            FakeNet::default(),
+            "ftp://example.com/api/",
+            "abc".to_string(),
+        )
+        .unwrap();
+        assert!(matches!(
+            api.push_url(),
+            Err(ApiError::UnsupportedScheme(scheme)) if scheme == "ftp"
+        ));
+
+        let api = ApiClient::new(FakeNet::default(), "beacon:api", "abc".to_string()).unwrap();
+        assert!(api.push_url().is_err());
+    }
+
+    #[test]
+    fn keeps_transport_errors_typed() {
+        let net = FakeNet::default();
+        net.refuse("http://localhost/api/beacons/abc/pings");
+        net.respond("http://localhost/api/beacons/abc", 503, "");
+        let api = ApiClient::new(&net, "http://localhost/api/", "abc".to_string()).unwrap();
+
+        let refused = block_on(api.pings()).unwrap_err();
+        assert!(matches!(refused, ApiError::Transport(NetError::Connect(_))));
+        assert!(refused.is_transient());
+
+        let unavailable = block_on(api.assignment()).unwrap_err();
+        assert!(matches!(unavailable, ApiError::Status(503)));
+        assert!(unavailable.is_transient());
+
+        let missing = block_on(api.acknowledge("1")).unwrap_err();
+        assert!(matches!(missing, ApiError::Status(404)));
+        assert!(!missing.is_transient());
+    }
+
+    #[test]
+    fn formats_device_ids() {
+        assert_eq!(
+            device_id([0x24, 0x0a, 0xc4, 0x00, 0xff, 0x1b]),
+            "240ac400ff1b"
+        );
+    }
+}
@@ -0,0 +1,477 @@
+//! An HTTP/1.1 client that follows redirects and streams response bodies.
+
+use core::str::FromStr;
+
+use embassy_time::Duration;
+use http::{Method, Request};
+use log::info;
+use url::Url;
+
+use super::response::{BodyDecoder, HeadParser, HttpError, ResponseHead};
+use super::{
+    create_raw_request_no_body, timeout, Cancel, Connection, Connector, DefaultConnector, NetError,
+    Timeouts, USER_AGENT,
+};
+use crate::hal::{NetClient, NetResponse};
+
+/// Size of the raw read buffer behind [`HttpResponse`]
+const READ_BUF_LEN: usize = 4096;
+
+pub const DEFAULT_MAX_REDIRECTS: u8 = 5;
+
+/// Headers that carry credentials, which only go to the origin they were meant for
+const CREDENTIAL_HEADERS: &[&str] = &["authorization", "cookie", "proxy-authorization"];
+
+/// HTTP client that follows redirects across hosts, over TLS for `https` URLs where the
+/// connector supports it
+#[derive(Debug, Clone)]
+pub struct HttpsClient<C = DefaultConnector> {
+    connector: C,
+    max_redirects: u8,
+    timeouts: Timeouts,
+    cancel: Cancel,
+}
+
+impl Default for HttpsClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HttpsClient {
+    pub fn new() -> Self {
+        Self::with_connector(DefaultConnector::default())
+    }
+}
+
+impl<C: Connector> HttpsClient<C> {
+    /// Opens its connections through `connector`, which decides how servers are verified
+    pub fn with_connector(connector: C) -> Self {
+        Self {
+            connector,
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            timeouts: Timeouts::new(),
+            cancel: Cancel::never(),
+        }
+    }
+
+    pub const fn with_max_redirects(mut self, max_redirects: u8) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    pub const fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Makes requests, including reads of their bodies, fail once `cancel` fires
+    pub fn with_cancel(mut self, cancel: Cancel) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    pub fn cancel(&self) -> &Cancel {
+        &self.cancel
+    }
+
+    pub async fn get(&self, url: &str) -> Result<HttpResponse<C::Stream>, NetError> {
+        self.send(Method::GET, url, &[], None).await
+    }
+
+    pub async fn post_json(
+        &self,
+        url: &str,
+        body: &[u8],
+    ) -> Result<HttpResponse<C::Stream>, NetError> {
+        self.send(
+            Method::POST,
+            url,
+            &[("Content-Type", "application/json")],
+            Some(body),
+        )
+        .await
+    }
+
+    /// Sends a request, following up to the configured number of redirects.
+    ///
+    /// The response to the last hop is returned whatever its status; use
+    /// [`HttpResponse::error_for_status`] to reject failures.
+    pub async fn send(
+        &self,
+        method: Method,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Option<&[u8]>,
+    ) -> Result<HttpResponse<C::Stream>, NetError> {
+        let mut url = Url::from_str(url).map_err(|_| NetError::InvalidUrl(url.to_string()))?;
+        let mut method = method;
+        let mut body = body;
+        let mut headers = headers.to_vec();
+
+        for _ in 0..=self.max_redirects {
+            let response = self.send_once(&method, &url, &headers, body).await?;
+
+            let status = response.status();
+            if !matches!(status, 301 | 302 | 303 | 307 | 308) {
+                return Ok(response);
+            }
+
+            let location = response
+                .head
+                .header("location")
+                .ok_or(NetError::MissingLocation(status))?;
+
+            // Relative targets are resolved against the URL that was just requested
+            let next = url
+                .join(location)
+                .map_err(|_| NetError::InvalidLocation(location.to_string()))?;
+
+            info!(
+                "{status} redirect to {}",
+                next.host_str().unwrap_or_default()
+            );
+
+            match status {
+                // 303 always switches to GET, and 301/302 do so for POST like every browser
+                303 if method != Method::HEAD => {
+                    method = Method::GET;
+                    body = None;
+                }
+                301 | 302 if method == Method::POST => {
+                    method = Method::GET;
+                    body = None;
+                }
+                _ => {}
+            }
+
+            // The body's type goes with the body, and credentials meant for one server must not
+            // follow a redirect to another. Everything else, such as `Range`, still applies.
+            let cross_origin = next.origin() != url.origin();
+            headers.retain(|(key, _)| {
+                let credential = CREDENTIAL_HEADERS
+                    .iter()
+                    .any(|credential| key.eq_ignore_ascii_case(credential));
+                (body.is_some() || !key.eq_ignore_ascii_case("content-type"))
+                    && !(cross_origin && credential)
+            });
+
+            url = next;
+        }
+
+        Err(NetError::TooManyRedirects(self.max_redirects))
+    }
+
+    async fn send_once(
+        &self,
+        method: &Method,
+        url: &Url,
+        headers: &[(&str, &str)],
+        body: Option<&[u8]>,
+    ) -> Result<HttpResponse<C::Stream>, NetError> {
+        if !matches!(url.scheme(), "https" | "http") {
+            return Err(NetError::UnsupportedScheme(url.scheme().to_string()));
+        }
+
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(NetError::InvalidUrl(url.to_string())),
+        };
+
+        let target = match url.query() {
+            Some(query) => format!("{}?{query}", url.path()),
+            None => url.path().to_string(),
+        };
+
+        let mut builder = Request::builder()
+            .method(method.clone())
+            .header("User-Agent", USER_AGENT)
+            .header("Host", host)
+            .header("Connection", "close")
+            .uri(target);
+
+        for (key, value) in headers {
+            builder = builder.header(*key, *value);
+        }
+
+        if let Some(body) = body {
+            builder = builder.header("Content-Length", body.len());
+        }
+
+        let request = builder.body(()).map_err(NetError::InvalidRequest)?;
+
+        self.cancel
+            .guard(async {
+                let mut stream = self.connector.connect(url, &self.timeouts).await?;
+
+                stream
+                    .write_all(create_raw_request_no_body(&request).as_bytes())
+                    .await?;
+
+                if let Some(body) = body {
+                    stream.write_all(body).await?;
+                }
+
+                HttpResponse::read_head(stream, method, &self.timeouts, self.cancel.clone()).await
+            })
+            .await
+    }
+}
+
+impl<C: Connector> NetClient for HttpsClient<C> {
+    async fn request(
+        &self,
+        method: Method,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Option<&[u8]>,
+    ) -> Result<NetResponse, NetError> {
+        let mut response = self.send(method, url, headers, body).await?;
+        let body = response.read_to_end().await?;
+
+        Ok(NetResponse {
+            status: response.status(),
+            body,
+        })
+    }
+}
+
+/// A response whose head has been parsed, with the body left on the connection to be streamed
+pub struct HttpResponse<S> {
+    pub head: ResponseHead,
+    stream: S,
+    body: BodyDecoder,
+    buf: Box<[u8]>,
+    start: usize,
+    end: usize,
+    /// Longest wait for the next piece of the body
+    idle: Duration,
+    cancel: Cancel,
+}
+
+impl<S: Connection> HttpResponse<S> {
+    /// Reads the status line and headers of the response to a `method` request off the
+    /// connection, which has `timeouts.first_byte` to deliver all of them. Later reads of the body
+    /// fail once `cancel` fires.
+    pub async fn read_head(
+        mut stream: S,
+        method: &Method,
+        timeouts: &Timeouts,
+        cancel: Cancel,
+    ) -> Result<Self, NetError> {
+        let mut parser = HeadParser::new();
+        let mut buf = vec![0; READ_BUF_LEN].into_boxed_slice();
+
+        let (head, start, end) = cancel
+            .guard(timeout(timeouts.first_byte, async {
+                loop {
+                    let read = stream.read(&mut buf).await?;
+                    if read == 0 {
+                        return Err(NetError::from(HttpError::UnexpectedEof));
+                    }
+
+                    if let Some((head, used)) = parser.feed(&buf[..read])? {
+                        return Ok((head, used, read));
+                    }
+                }
+            }))
+            .await?;
+
+        let body = BodyDecoder::new(head.framing(method)?);
+
+        Ok(Self {
+            head,
+            stream,
+            body,
+            buf,
+            start,
+            end,
+            idle: timeouts.idle,
+            cancel,
+        })
+    }
+
+    pub fn status(&self) -> u16 {
+        self.head.status
+    }
+
+    pub fn error_for_status(self) -> Result<Self, NetError> {
+        if (200..=299).contains(&self.status()) {
+            Ok(self)
+        } else {
+            Err(NetError::Status(self.status()))
+        }
+    }
+
+    /// Body bytes still expected, if the server declared a length
+    pub fn remaining(&self) -> Option<u64> {
+        self.body.remaining()
+    }
+
+    /// Reads the next piece of the body, returning 0 once it is complete
+    pub async fn read(&mut self, out: &mut [u8]) -> Result<usize, NetError> {
+        loop {
+            if self.body.is_done() || out.is_empty() {
+                return Ok(0);
+            }
+
+            if self.start < self.end {
+                let decoded = self.body.decode(&self.buf[self.start..self.end], out)?;
+                self.start += decoded.consumed;
+
+                if decoded.produced > 0 {
+                    return Ok(decoded.produced);
+                }
+
+                continue;
+            }
+
+            let stream = &mut self.stream;
+            let buf = &mut self.buf;
+            let read = self
+                .cancel
+                .guard(timeout(self.idle, stream.read(buf)))
+                .await?;
+            if read == 0 {
+                self.body.finish()?;
+                return Ok(0);
+            }
+
+            self.start = 0;
+            self.end = read;
+        }
+    }
+
+    pub async fn read_to_end(&mut self) -> Result<Vec<u8>, NetError> {
+        let mut body = Vec::new();
+        let mut chunk = [0; 1024];
+
+        loop {
+            let read = self.read(&mut chunk).await?;
+            if read == 0 {
+                return Ok(body);
+            }
+
+            body.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{block_on, serve};
+
+    fn redirect(status: u16, location: &str) -> String {
+        format!("HTTP/1.1 {status} Redirect\r\nLocation: {location}\r\nContent-Length: 0\r\n\r\n")
+    }
+
+    fn ok(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    /// Sends a request and reads the whole response
+    fn send(
+        method: Method,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Option<&[u8]>,
+    ) -> Result<(u16, Vec<u8>), NetError> {
+        block_on(async {
+            let mut response = HttpsClient::new().send(method, url, headers, body).await?;
+            Ok((response.status(), response.read_to_end().await?))
+        })
+    }
+
+    #[test]
+    fn keeps_everything_but_credentials_across_origins() {
+        let (asset_url, asset) = serve(vec![ok("image")]);
+        let (api_url, api) = serve(vec![redirect(302, &format!("{asset_url}/image.bin"))]);
+
+        let response = send(
+            Method::GET,
+            &format!("{api_url}/download"),
+            &[
+                ("Range", "bytes=5-"),
+                ("Accept", "application/octet-stream"),
+                ("Authorization", "Bearer secret"),
+                ("Cookie", "session=1"),
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(response, (200, b"image".to_vec()));
+
+        let api = api.join().unwrap();
+        assert!(api[0].contains("authorization: Bearer secret\r\n"));
+
+        let asset = asset.join().unwrap();
+        assert!(asset[0].starts_with("GET /image.bin HTTP/1.1\r\n"));
+        assert!(asset[0].contains("range: bytes=5-\r\n"));
+        assert!(asset[0].contains("accept: application/octet-stream\r\n"));
+        assert!(!asset[0].contains("authorization"));
+        assert!(!asset[0].contains("cookie"));
+    }
+
+    #[test]
+    fn keeps_credentials_on_the_same_origin() {
+        let (url, server) = serve(vec![redirect(307, "/moved"), ok("")]);
+
+        let response = send(
+            Method::GET,
+            &format!("{url}/start"),
+            &[("Authorization", "Bearer secret")],
+            None,
+        )
+        .unwrap();
+        assert_eq!(response.0, 200);
+
+        let requests = server.join().unwrap();
+        assert!(requests[1].starts_with("GET /moved HTTP/1.1\r\n"));
+        assert!(requests[1].contains("authorization: Bearer secret\r\n"));
+    }
+
+    #[test]
+    fn follows_posts_as_gets_without_their_body() {
+        let (url, server) = serve(vec![redirect(303, "/result"), ok("done")]);
+
+        let response = send(
+            Method::POST,
+            &format!("{url}/submit"),
+            &[("Content-Type", "application/json")],
+            Some(b"{}"),
+        )
+        .unwrap();
+        assert_eq!(response, (200, b"done".to_vec()));
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("POST /submit HTTP/1.1\r\n"));
+        assert!(requests[0].ends_with("\r\n\r\n{}"));
+        assert!(requests[1].starts_with("GET /result HTTP/1.1\r\n"));
+        assert!(!requests[1].contains("content-type"));
+        assert!(!requests[1].contains("content-length"));
+    }
+
+    #[test]
+    fn gives_up_after_too_many_redirects() {
+        let (url, _server) = serve(vec![redirect(302, "/again"); 2]);
+
+        let client = HttpsClient::new().with_max_redirects(1);
+        let sent = block_on(client.get(&format!("{url}/start")));
+        assert!(matches!(sent, Err(NetError::TooManyRedirects(1))));
+    }
+
+    #[test]
+    fn fails_redirects_without_a_location() {
+        let (url, _server) = serve(vec![
+            "HTTP/1.1 302 Found\r\nContent-Length: 0\r\n\r\n".to_string()
+        ]);
+
+        let sent = send(Method::GET, &url, &[], None);
+        assert!(matches!(sent, Err(NetError::MissingLocation(302))));
+    }
+}
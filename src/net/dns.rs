@@ -0,0 +1,277 @@
+//! Host name resolution, with a short-lived cache so reconnecting does not wait on a lookup
+//! every time.
+
+use core::future::Future;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+
+use embassy_time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+use super::NetError;
+
+/// How long a resolution is reused before the name is looked up again
+const TTL: Duration = Duration::from_secs(300);
+
+/// Hosts remembered at once. The beacon only ever talks to a handful.
+const MAX_ENTRIES: usize = 8;
+
+/// Stack for the thread a lookup blocks on, which only waits on the system resolver
+const LOOKUP_STACK: usize = 4096;
+
+/// The cache every connection shares
+pub static CACHE: Cache<SystemResolver> = Cache::new(SystemResolver, TTL);
+
+/// Turns host names into addresses
+pub trait Resolver {
+    /// Every address `host` has, or `None` if it has none
+    fn lookup(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> impl Future<Output = Result<Option<Vec<SocketAddr>>, NetError>>;
+}
+
+/// Asks the system resolver, on a thread of its own per lookup since it blocks.
+///
+/// A caller that stops waiting leaves the thread to finish the lookup and exit.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    async fn lookup(&self, host: &str, port: u16) -> Result<Option<Vec<SocketAddr>>, NetError> {
+        let (found, addrs) = oneshot::channel();
+        let name = host.to_string();
+
+        thread::Builder::new()
+            .name("dns".to_string())
+            .stack_size(LOOKUP_STACK)
+            .spawn(move || {
+                let addrs = (name.as_str(), port).to_socket_addrs();
+                let _ = found.send(addrs.map(Iterator::collect).ok());
+            })
+            .map_err(|e| NetError::Io(e.into()))?;
+
+        Ok(addrs.await.ok().flatten())
+    }
+}
+
+struct Entry {
+    host: String,
+    port: u16,
+    /// In the order to try them, the last one that worked first
+    addrs: Vec<SocketAddr>,
+    resolved: Instant,
+}
+
+impl Entry {
+    fn is_for(&self, host: &str, port: u16) -> bool {
+        self.port == port && self.host.eq_ignore_ascii_case(host)
+    }
+}
+
+/// Resolutions from `R`, reused for a while
+pub struct Cache<R> {
+    pub(super) resolver: R,
+    ttl: Duration,
+    entries: Mutex<Vec<Entry>>,
+}
+
+impl<R: Resolver> Cache<R> {
+    pub const fn new(resolver: R, ttl: Duration) -> Self {
+        Self {
+            resolver,
+            ttl,
+            entries: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn entries(&self) -> MutexGuard<'_, Vec<Entry>> {
+        // The entries stay consistent even if a holder panicked
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Every address `host` resolves to, from the cache while it is fresh
+    pub async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, NetError> {
+        if let Some(entry) = self
+            .entries()
+            .iter()
+            .find(|entry| entry.is_for(host, port))
+            .filter(|entry| entry.resolved.elapsed() < self.ttl)
+        {
+            return Ok(entry.addrs.clone());
+        }
+
+        let addrs = self
+            .resolver
+            .lookup(host, port)
+            .await?
+            .filter(|addrs| !addrs.is_empty())
+            .ok_or_else(|| NetError::Dns(host.to_string()))?;
+
+        let mut entries = self.entries();
+        entries.retain(|entry| !entry.is_for(host, port));
+        if entries.len() >= MAX_ENTRIES {
+            entries.remove(0);
+        }
+        entries.push(Entry {
+            host: host.to_string(),
+            port,
+            addrs: addrs.clone(),
+            resolved: Instant::now(),
+        });
+
+        Ok(addrs)
+    }
+
+    /// Moves `addr` to the front for `host`, since it just worked
+    pub fn prefer(&self, host: &str, port: u16, addr: SocketAddr) {
+        let mut entries = self.entries();
+        let Some(entry) = entries.iter_mut().find(|entry| entry.is_for(host, port)) else {
+            return;
+        };
+
+        if let Some(position) = entry.addrs.iter().position(|cached| *cached == addr) {
+            entry.addrs[..=position].rotate_right(1);
+        }
+    }
+
+    /// Drops the resolution of `host`, so the next attempt looks it up again
+    pub fn forget(&self, host: &str, port: u16) {
+        self.entries().retain(|entry| !entry.is_for(host, port));
+    }
+}
+
+/// Answers lookups from a fixed table, counting them
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeResolver {
+    pub hosts: std::collections::HashMap<String, Vec<SocketAddr>>,
+    pub lookups: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl FakeResolver {
+    pub fn with(mut self, host: &str, addrs: &[&str]) -> Self {
+        let addrs = addrs.iter().map(|addr| addr.parse().unwrap()).collect();
+        self.hosts.insert(host.to_string(), addrs);
+        self
+    }
+
+    pub fn lookups(&self) -> usize {
+        self.lookups.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+impl Resolver for FakeResolver {
+    async fn lookup(&self, host: &str, port: u16) -> Result<Option<Vec<SocketAddr>>, NetError> {
+        self.lookups
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+        let addrs = self.hosts.get(host).map(|addrs| {
+            addrs
+                .iter()
+                .map(|addr| SocketAddr::new(addr.ip(), port))
+                .collect()
+        });
+        Ok(addrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_time::Timer;
+
+    use super::*;
+    use crate::testing::block_on;
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    fn cache(ttl: Duration) -> Cache<FakeResolver> {
+        let resolver =
+            FakeResolver::default().with("beacons.example", &["10.0.0.1:0", "10.0.0.2:0"]);
+        Cache::new(resolver, ttl)
+    }
+
+    #[test]
+    fn reuses_resolutions_until_they_expire() {
+        let cache = cache(Duration::from_millis(100));
+
+        block_on(async {
+            let resolved = cache.resolve("beacons.example", 443).await.unwrap();
+            assert_eq!(resolved, addrs(&["10.0.0.1:443", "10.0.0.2:443"]));
+            cache.resolve("Beacons.Example", 443).await.unwrap();
+            assert_eq!(cache.resolver.lookups(), 1);
+
+            // Each port is a resolution of its own
+            let resolved = cache.resolve("beacons.example", 80).await.unwrap();
+            assert_eq!(resolved, addrs(&["10.0.0.1:80", "10.0.0.2:80"]));
+            assert_eq!(cache.resolver.lookups(), 2);
+
+            Timer::after_millis(150).await;
+            cache.resolve("beacons.example", 443).await.unwrap();
+            assert_eq!(cache.resolver.lookups(), 3);
+        });
+    }
+
+    #[test]
+    fn puts_what_worked_first_until_forgotten() {
+        let cache = cache(TTL);
+
+        block_on(async {
+            cache.resolve("beacons.example", 443).await.unwrap();
+            cache.prefer("beacons.example", 443, "10.0.0.2:443".parse().unwrap());
+            let resolved = cache.resolve("beacons.example", 443).await.unwrap();
+            assert_eq!(resolved, addrs(&["10.0.0.2:443", "10.0.0.1:443"]));
+
+            cache.forget("beacons.example", 443);
+            let resolved = cache.resolve("beacons.example", 443).await.unwrap();
+            assert_eq!(resolved, addrs(&["10.0.0.1:443", "10.0.0.2:443"]));
+            assert_eq!(cache.resolver.lookups(), 2);
+        });
+    }
+
+    #[test]
+    fn makes_room_by_dropping_the_oldest_host() {
+        let mut resolver = FakeResolver::default();
+        for host in 0..=MAX_ENTRIES {
+            resolver = resolver.with(&format!("{host}.example"), &["10.0.0.1:0"]);
+        }
+        let cache = Cache::new(resolver, TTL);
+
+        block_on(async {
+            for host in 0..=MAX_ENTRIES {
+                cache
+                    .resolve(&format!("{host}.example"), 443)
+                    .await
+                    .unwrap();
+            }
+            cache.resolve("1.example", 443).await.unwrap();
+            assert_eq!(cache.resolver.lookups(), MAX_ENTRIES + 1);
+
+            cache.resolve("0.example", 443).await.unwrap();
+            assert_eq!(cache.resolver.lookups(), MAX_ENTRIES + 2);
+        });
+    }
+
+    #[test]
+    fn fails_names_without_addresses() {
+        let cache = cache(TTL);
+
+        let result = block_on(cache.resolve("nowhere.example", 443));
+        assert!(matches!(result, Err(NetError::Dns(host)) if host == "nowhere.example"));
+    }
+
+    #[test]
+    fn looks_names_up_with_the_system() {
+        let resolved = block_on(SystemResolver.lookup("localhost", 80)).unwrap();
+        let resolved = resolved.unwrap();
+        assert!(!resolved.is_empty());
+        assert!(resolved.iter().all(|addr| addr.ip().is_loopback()));
+    }
+}
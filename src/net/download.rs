@@ -0,0 +1,266 @@
+//! Resumable downloads for large bodies such as OTA images.
+
+use embassy_time::{Duration, Timer};
+use http::Method;
+use log::{info, warn};
+
+use super::response::HttpError;
+use super::{Connector, HttpsClient, NetError, Progress};
+
+/// Consecutive attempts that make no progress before the download is abandoned
+const MAX_STALLED_ATTEMPTS: u32 = 5;
+
+enum Failure {
+    /// The connection dropped or stalled; resuming may get further
+    Dropped(NetError),
+    Fatal(NetError),
+}
+
+/// Streams `url` into `sink`, resuming with `Range` requests whenever the connection drops.
+///
+/// Returns the number of bytes written once the body is complete. `on_progress` is called after
+/// every piece handed to `sink`. The client's timeouts decide when a quiet connection counts as
+/// dropped, and its cancel token abandons the download, including the waits between attempts.
+pub async fn download<C, S, P>(
+    client: &HttpsClient<C>,
+    url: &str,
+    mut sink: S,
+    mut on_progress: P,
+) -> Result<u64, NetError>
+where
+    C: Connector,
+    S: FnMut(&[u8]) -> Result<(), NetError>,
+    P: FnMut(Progress),
+{
+    let mut written = 0;
+    let mut total = None;
+    let mut stalled = 0;
+
+    loop {
+        let before = written;
+
+        let interrupted = match attempt(
+            client,
+            url,
+            &mut written,
+            &mut total,
+            &mut sink,
+            &mut on_progress,
+        )
+        .await
+        {
+            Ok(()) => match total {
+                Some(total) if written > total => {
+                    return Err(NetError::parse(
+                        "download",
+                        format!("received {written} bytes of a {total} byte body"),
+                    ))
+                }
+                Some(total) if written < total => {
+                    warn!("Download ended early at {written} of {total} bytes");
+                    HttpError::UnexpectedEof.into()
+                }
+                _ => return Ok(written),
+            },
+            Err(Failure::Fatal(e)) => return Err(e),
+            Err(Failure::Dropped(e)) => {
+                warn!("Download interrupted at {written} bytes: {e}");
+                e
+            }
+        };
+
+        if written > before {
+            stalled = 0;
+        } else {
+            stalled += 1;
+        }
+
+        if stalled >= MAX_STALLED_ATTEMPTS {
+            warn!("Download stalled at {written} bytes");
+            return Err(interrupted);
+        }
+
+        client
+            .cancel()
+            .guard(async {
+                Timer::after(Duration::from_secs(1 << stalled)).await;
+                Ok::<_, NetError>(())
+            })
+            .await?;
+        info!("Resuming download from byte {written}");
+    }
+}
+
+async fn attempt<C, S, P>(
+    client: &HttpsClient<C>,
+    url: &str,
+    written: &mut u64,
+    total: &mut Option<u64>,
+    sink: &mut S,
+    on_progress: &mut P,
+) -> Result<(), Failure>
+where
+    C: Connector,
+    S: FnMut(&[u8]) -> Result<(), NetError>,
+    P: FnMut(Progress),
+{
+    let range = format!("bytes={written}-");
+    let range_header = [("Range", range.as_str())];
+    let headers: &[(&str, &str)] = if *written > 0 { &range_header } else { &[] };
+
+    let mut response = match client.send(Method::GET, url, headers, None).await {
+        Ok(response) => response,
+        Err(e) if e.is_transient() => return Err(Failure::Dropped(e)),
+        Err(e) => return Err(Failure::Fatal(e)),
+    };
+
+    // Bytes at the start of this body that we already have
+    let mut skip = 0;
+
+    match response.status() {
+        206 => {
+            let range = response.head.content_range().ok_or_else(|| {
+                Failure::Fatal(NetError::parse("partial response", "no Content-Range"))
+            })?;
+
+            if range.start != *written {
+                return Err(Failure::Fatal(NetError::parse(
+                    "partial response",
+                    format!("resumed at byte {} instead of {written}", range.start),
+                )));
+            }
+
+            *total = total.or(range.total);
+        }
+        200 => {
+            // The server ignored the range and is sending everything again
+            skip = *written;
+
+            let length = response
+                .head
+                .content_length()
+                .map_err(|e| Failure::Fatal(e.into()))?;
+            *total = length.or(*total);
+        }
+        status @ 500..=599 => return Err(Failure::Dropped(NetError::Status(status))),
+        status => return Err(Failure::Fatal(NetError::Status(status))),
+    }
+
+    on_progress(Progress {
+        written: *written,
+        total: *total,
+    });
+
+    let mut buf = [0; 4096];
+    loop {
+        let read = match response.read(&mut buf).await {
+            Ok(read) => read,
+            Err(NetError::Cancelled) => return Err(Failure::Fatal(NetError::Cancelled)),
+            Err(e) => return Err(Failure::Dropped(e)),
+        };
+
+        if read == 0 {
+            return Ok(());
+        }
+
+        let skipped = skip.min(read as u64) as usize;
+        skip -= skipped as u64;
+
+        let data = &buf[skipped..read];
+        if data.is_empty() {
+            continue;
+        }
+
+        sink(data).map_err(Failure::Fatal)?;
+        *written += data.len() as u64;
+
+        on_progress(Progress {
+            written: *written,
+            total: *total,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{block_on, serve};
+
+    /// Downloads `url`, returning the body and every progress report
+    fn fetch(url: &str) -> (Result<u64, NetError>, Vec<u8>, Vec<Progress>) {
+        let mut body = Vec::new();
+        let mut progress = Vec::new();
+
+        let downloaded = block_on(download(
+            &HttpsClient::new(),
+            url,
+            |data| {
+                body.extend_from_slice(data);
+                Ok(())
+            },
+            |reported| progress.push(reported),
+        ));
+
+        (downloaded, body, progress)
+    }
+
+    /// Redirects every request to `target`, as GitHub does for release assets
+    fn redirector(target: &str, count: usize) -> String {
+        let redirect =
+            format!("HTTP/1.1 302 Found\r\nLocation: {target}\r\nContent-Length: 0\r\n\r\n");
+        serve(vec![redirect; count]).0
+    }
+
+    #[test]
+    fn resumes_through_a_redirect_with_a_range() {
+        let (asset_url, asset) = serve(vec![
+            // Drops the connection halfway through the body
+            "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n01234".to_string(),
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 5-9/10\r\n\
+             Content-Length: 5\r\n\r\n56789"
+                .to_string(),
+        ]);
+        let url = redirector(&format!("{asset_url}/image.bin"), 2);
+
+        let (downloaded, body, progress) = fetch(&url);
+        assert_eq!(downloaded.unwrap(), 10);
+        assert_eq!(body, b"0123456789");
+        assert_eq!(
+            progress.last(),
+            Some(&Progress {
+                written: 10,
+                total: Some(10)
+            })
+        );
+
+        let requests = asset.join().unwrap();
+        assert!(!requests[0].contains("range"));
+        assert!(requests[1].contains("range: bytes=5-\r\n"));
+    }
+
+    #[test]
+    fn skips_what_it_has_when_the_range_is_ignored() {
+        let (url, _server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n01234".to_string(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789".to_string(),
+        ]);
+
+        let (downloaded, body, _) = fetch(&url);
+        assert_eq!(downloaded.unwrap(), 10);
+        assert_eq!(body, b"0123456789");
+    }
+
+    #[test]
+    fn fails_resumes_at_the_wrong_byte() {
+        let (url, _server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n01234".to_string(),
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-9/10\r\n\
+             Content-Length: 10\r\n\r\n0123456789"
+                .to_string(),
+        ]);
+
+        let (downloaded, body, _) = fetch(&url);
+        assert!(matches!(downloaded, Err(NetError::Parse { .. })));
+        assert_eq!(body, b"01234");
+    }
+}
@@ -0,0 +1,124 @@
+//! The error every networking operation fails with.
+
+use core::fmt;
+
+use embassy_time::Duration;
+
+use super::response::HttpError;
+use super::tls::TlsError;
+
+#[derive(Debug)]
+pub enum NetError {
+    InvalidUrl(String),
+    UnsupportedScheme(String),
+    /// The host name did not resolve to any address
+    Dns(String),
+    /// No TCP connection could be made
+    Connect(std::io::Error),
+    /// None of the addresses the host resolved to could be connected to, with the last failure
+    Unreachable {
+        host: String,
+        tried: usize,
+        last: Box<NetError>,
+    },
+    /// The TLS handshake failed or the server was not trusted
+    Tls(TlsError),
+    /// An open connection failed while reading or writing
+    Io(anyhow::Error),
+    /// The other end went quiet for longer than this
+    Timeout(Duration),
+    /// Abandoned through a [`Cancel`](super::Cancel) token
+    Cancelled,
+    /// The server responded with a non-success status
+    Status(u16),
+    /// The response did not follow HTTP
+    Http(HttpError),
+    InvalidRequest(http::Error),
+    /// A redirect status arrived without a `Location` header
+    MissingLocation(u16),
+    InvalidLocation(String),
+    TooManyRedirects(u8),
+    /// A body or value that came over the network did not parse
+    Parse {
+        what: &'static str,
+        reason: String,
+    },
+    /// A downloaded update was rejected or could not be written to flash
+    Update(anyhow::Error),
+    /// The Wi-Fi driver failed or no known network could be joined
+    Wifi(anyhow::Error),
+}
+
+impl NetError {
+    pub fn parse(what: &'static str, reason: impl fmt::Display) -> Self {
+        Self::Parse {
+            what,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Whether trying again later might succeed, as opposed to a problem with the request, the
+    /// server's data or the device itself
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Dns(_)
+            | Self::Connect(_)
+            | Self::Unreachable { .. }
+            | Self::Io(_)
+            | Self::Timeout(_)
+            | Self::Wifi(_) => true,
+            Self::Tls(e) => e.is_transient(),
+            Self::Status(status) => *status == 429 || (500..=599).contains(status),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidUrl(url) => write!(f, "invalid URL {url:?}"),
+            Self::UnsupportedScheme(scheme) => write!(f, "unsupported URL scheme {scheme:?}"),
+            Self::Dns(host) => write!(f, "could not resolve {host}"),
+            Self::Connect(e) => write!(f, "could not connect: {e}"),
+            Self::Unreachable {
+                host,
+                tried: 1,
+                last,
+            } => write!(f, "could not reach {host}: {last}"),
+            Self::Unreachable { host, tried, last } => {
+                write!(
+                    f,
+                    "could not reach {host} at any of {tried} addresses, last: {last}"
+                )
+            }
+            Self::Tls(e) => write!(f, "TLS handshake failed: {e}"),
+            Self::Io(e) => write!(f, "connection failed: {e}"),
+            Self::Timeout(after) => write!(f, "timed out after {}s", after.as_secs()),
+            Self::Cancelled => write!(f, "cancelled"),
+            Self::Status(status) => write!(f, "server responded with status {status}"),
+            Self::Http(e) => write!(f, "malformed response: {e}"),
+            Self::InvalidRequest(e) => write!(f, "invalid request: {e}"),
+            Self::MissingLocation(status) => write!(f, "{status} redirect without a Location"),
+            Self::InvalidLocation(location) => write!(f, "invalid redirect target {location:?}"),
+            Self::TooManyRedirects(max) => write!(f, "gave up after {max} redirects"),
+            Self::Parse { what, reason } => write!(f, "malformed {what}: {reason}"),
+            Self::Update(e) => write!(f, "update failed: {e}"),
+            Self::Wifi(e) => write!(f, "Wi-Fi error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for NetError {}
+
+impl From<TlsError> for NetError {
+    fn from(e: TlsError) -> Self {
+        Self::Tls(e)
+    }
+}
+
+impl From<HttpError> for NetError {
+    fn from(e: HttpError) -> Self {
+        Self::Http(e)
+    }
+}
@@ -0,0 +1,132 @@
+//! Timeouts and cancellation, so a stalled server or a change of plans never leaves a network
+//! operation hanging.
+
+use core::future::{pending, poll_fn, Future};
+use core::pin::pin;
+use core::task::Poll;
+
+use embassy_time::{with_timeout, Duration};
+use tokio::sync::watch;
+
+use super::NetError;
+
+/// How long each phase of a network operation may take before it is abandoned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Opening the TCP connection
+    pub connect: Duration,
+    /// TLS negotiation and protocol handshakes such as the WebSocket upgrade or MQTT login
+    pub handshake: Duration,
+    /// From sending a request to the start of its response
+    pub first_byte: Duration,
+    /// Between pieces of a response body
+    pub idle: Duration,
+}
+
+impl Timeouts {
+    pub const fn new() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            handshake: Duration::from_secs(15),
+            first_byte: Duration::from_secs(15),
+            idle: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fails with [`NetError::Timeout`] if `operation` takes longer than `limit`
+pub async fn timeout<T, E: From<NetError>>(
+    limit: Duration,
+    operation: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    with_timeout(limit, operation)
+        .await
+        .unwrap_or_else(|_| Err(NetError::Timeout(limit).into()))
+}
+
+/// Cancels the operations guarded by its [`Cancel`] tokens. Clones share the same state.
+#[derive(Debug, Clone)]
+pub struct Canceller(watch::Sender<bool>);
+
+impl Default for Canceller {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Canceller {
+    pub fn new() -> Self {
+        Self(watch::channel(false).0)
+    }
+
+    pub fn token(&self) -> Cancel {
+        Cancel(Some(self.0.subscribe()))
+    }
+
+    pub fn cancel(&self) {
+        self.0.send_replace(true);
+    }
+
+    /// Lets operations run again after a cancellation
+    pub fn reset(&self) {
+        self.0.send_replace(false);
+    }
+}
+
+/// Makes operations fail with [`NetError::Cancelled`] once its [`Canceller`] fires
+#[derive(Debug, Clone, Default)]
+pub struct Cancel(Option<watch::Receiver<bool>>);
+
+impl Cancel {
+    /// A token that never fires
+    pub const fn never() -> Self {
+        Self(None)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.as_ref().is_some_and(|cancelled| *cancelled.borrow())
+    }
+
+    /// Resolves once cancelled, which never happens if the canceller is gone
+    pub async fn cancelled(&self) {
+        if let Some(cancelled) = &self.0 {
+            if cancelled
+                .clone()
+                .wait_for(|cancelled| *cancelled)
+                .await
+                .is_ok()
+            {
+                return;
+            }
+        }
+
+        pending().await
+    }
+
+    /// Runs `operation` unless it is cancelled first
+    pub async fn guard<T, E: From<NetError>>(
+        &self,
+        operation: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let mut operation = pin!(operation);
+        let mut cancelled = pin!(self.cancelled());
+
+        poll_fn(|cx| {
+            if let Poll::Ready(result) = operation.as_mut().poll(cx) {
+                return Poll::Ready(result);
+            }
+            if cancelled.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(NetError::Cancelled.into()));
+            }
+
+            Poll::Pending
+        })
+        .await
+    }
+}
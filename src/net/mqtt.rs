@@ -0,0 +1,970 @@
+//! MQTT 3.1.1 client, for deployments that point beacons at a local broker instead of the
+//! companion site.
+//!
+//! Packets are built by the `encode_*` functions and split out of the incoming bytes by
+//! [`PacketDecoder`], and [`MqttClient`] drives them over any [`Connection`]: TLS or plain TCP on
+//! the device, or plain TCP against a local broker on a dev machine. QoS 2 is not supported.
+
+use core::fmt;
+use std::collections::VecDeque;
+
+use embassy_time::{Duration, Instant};
+use url::Url;
+
+use super::{Connection, NetError};
+
+pub const DEFAULT_PORT: u16 = 1883;
+pub const DEFAULT_TLS_PORT: u16 = 8883;
+
+/// Largest packet we are willing to receive
+pub const MAX_PACKET_LEN: usize = 64 * 1024;
+
+/// Longest packet body the remaining length field can describe
+const MAX_REMAINING_LEN: usize = 0x0FFF_FFFF;
+
+const READ_BUF_LEN: usize = 1024;
+
+#[derive(Debug)]
+pub enum MqttError {
+    InvalidUrl(url::ParseError),
+    UnsupportedScheme(String),
+    /// The broker turned the connection down with this CONNACK return code
+    Refused(u8),
+    /// The broker would not subscribe us to this topic
+    SubscriptionRejected(String),
+    /// The broker broke the protocol
+    Protocol(&'static str),
+    PacketTooLarge,
+    /// We were asked to send something MQTT cannot express
+    Unencodable(&'static str),
+    /// A keepalive ping went unanswered
+    KeepaliveTimeout,
+    Closed,
+    Transport(NetError),
+}
+
+impl fmt::Display for MqttError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidUrl(e) => write!(f, "invalid broker URL: {e}"),
+            Self::UnsupportedScheme(scheme) => write!(f, "unsupported broker scheme {scheme:?}"),
+            Self::Refused(code) => {
+                let reason = match code {
+                    1 => "unacceptable protocol version",
+                    2 => "client identifier rejected",
+                    3 => "server unavailable",
+                    4 => "bad user name or password",
+                    5 => "not authorized",
+                    _ => "unknown reason",
+                };
+                write!(f, "broker refused the connection: {reason} ({code})")
+            }
+            Self::SubscriptionRejected(topic) => write!(f, "subscription to {topic} rejected"),
+            Self::Protocol(reason) => write!(f, "protocol violation: {reason}"),
+            Self::PacketTooLarge => write!(f, "packet exceeds {MAX_PACKET_LEN} bytes"),
+            Self::Unencodable(reason) => write!(f, "cannot send packet: {reason}"),
+            Self::KeepaliveTimeout => write!(f, "keepalive went unanswered"),
+            Self::Closed => write!(f, "closed by broker"),
+            Self::Transport(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for MqttError {}
+
+impl From<NetError> for MqttError {
+    fn from(e: NetError) -> Self {
+        Self::Transport(e)
+    }
+}
+
+/// Parses a `mqtt://` or `mqtts://` broker URL, filling in the default port for its scheme.
+///
+/// Any user name and password in the URL are used to log in.
+pub fn broker_url(url: &str) -> Result<Url, MqttError> {
+    let mut url = Url::parse(url).map_err(MqttError::InvalidUrl)?;
+
+    let default_port = match url.scheme() {
+        "mqtt" => DEFAULT_PORT,
+        "mqtts" => DEFAULT_TLS_PORT,
+        scheme => return Err(MqttError::UnsupportedScheme(scheme.to_string())),
+    };
+
+    if url.port().is_none() {
+        url.set_port(Some(default_port))
+            .map_err(|_| MqttError::InvalidUrl(url::ParseError::EmptyHost))?;
+    }
+
+    Ok(url)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum QoS {
+    AtMostOnce,
+    AtLeastOnce,
+}
+
+impl QoS {
+    fn from_bits(bits: u8) -> Result<Self, MqttError> {
+        match bits {
+            0 => Ok(Self::AtMostOnce),
+            1 => Ok(Self::AtLeastOnce),
+            2 => Err(MqttError::Protocol("QoS 2 is not supported")),
+            _ => Err(MqttError::Protocol("invalid QoS")),
+        }
+    }
+
+    const fn bits(self) -> u8 {
+        match self {
+            Self::AtMostOnce => 0,
+            Self::AtLeastOnce => 1,
+        }
+    }
+}
+
+/// Message the broker publishes for us if we drop off without disconnecting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Will {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectOptions {
+    pub client_id: String,
+    /// Longest the broker should go without hearing from us, at one second resolution. Zero
+    /// turns keepalive pings off.
+    pub keep_alive: Duration,
+    /// Whether the broker should forget our subscriptions and queued messages
+    pub clean_session: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub will: Option<Will>,
+}
+
+impl ConnectOptions {
+    pub fn new(client_id: impl Into<String>) -> Self {
+        Self {
+            client_id: client_id.into(),
+            keep_alive: Duration::from_secs(60),
+            clean_session: true,
+            username: None,
+            password: None,
+            will: None,
+        }
+    }
+
+    pub fn with_keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn with_clean_session(mut self, clean_session: bool) -> Self {
+        self.clean_session = clean_session;
+        self
+    }
+
+    pub fn with_credentials(mut self, username: String, password: Option<String>) -> Self {
+        self.username = Some(username);
+        self.password = password;
+        self
+    }
+
+    pub fn with_will(mut self, will: Will) -> Self {
+        self.will = Some(will);
+        self
+    }
+
+    /// The keep-alive as the broker is told it, in whole seconds
+    fn keep_alive_secs(&self) -> u16 {
+        self.keep_alive.as_secs().min(u16::MAX as u64) as u16
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Publish {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    /// Set by the broker on messages it kept from before we subscribed
+    pub retain: bool,
+    /// Whether this may be a redelivery
+    pub dup: bool,
+    /// Present on QoS 1 messages
+    pub packet_id: Option<u16>,
+}
+
+/// A packet from the broker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    ConnAck {
+        session_present: bool,
+        code: u8,
+    },
+    Publish(Publish),
+    PubAck(u16),
+    SubAck {
+        packet_id: u16,
+        /// The highest QoS granted to each topic, from 0 to 2, or `None` where the subscription
+        /// failed
+        granted: Vec<Option<u8>>,
+    },
+    PingResp,
+}
+
+fn put_len(out: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+
+        if len == 0 {
+            return;
+        }
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) -> Result<(), MqttError> {
+    let len = u16::try_from(bytes.len())
+        .map_err(|_| MqttError::Unencodable("field longer than 65535 bytes"))?;
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(bytes);
+
+    Ok(())
+}
+
+/// Wraps a packet body in its fixed header
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(body.len() + 5);
+    packet.push(header);
+    put_len(&mut packet, body.len());
+    packet.extend_from_slice(body);
+
+    packet
+}
+
+pub fn encode_connect(options: &ConnectOptions) -> Result<Vec<u8>, MqttError> {
+    let mut flags = 0;
+    if options.clean_session {
+        flags |= 0x02;
+    }
+    if let Some(will) = &options.will {
+        flags |= 0x04 | (will.qos.bits() << 3);
+        if will.retain {
+            flags |= 0x20;
+        }
+    }
+    if options.username.is_some() {
+        flags |= 0x80;
+        if options.password.is_some() {
+            flags |= 0x40;
+        }
+    }
+
+    let mut body = Vec::new();
+    put_bytes(&mut body, b"MQTT")?;
+    body.push(4);
+    body.push(flags);
+    body.extend_from_slice(&options.keep_alive_secs().to_be_bytes());
+
+    put_bytes(&mut body, options.client_id.as_bytes())?;
+    if let Some(will) = &options.will {
+        put_bytes(&mut body, will.topic.as_bytes())?;
+        put_bytes(&mut body, &will.payload)?;
+    }
+    if let Some(username) = &options.username {
+        put_bytes(&mut body, username.as_bytes())?;
+        if let Some(password) = &options.password {
+            put_bytes(&mut body, password.as_bytes())?;
+        }
+    }
+
+    Ok(packet(0x10, &body))
+}
+
+/// Encodes a message we publish; `packet_id` is required for QoS 1
+pub fn encode_publish(
+    topic: &str,
+    payload: &[u8],
+    qos: QoS,
+    retain: bool,
+    packet_id: Option<u16>,
+) -> Result<Vec<u8>, MqttError> {
+    let header = 0x30 | (qos.bits() << 1) | retain as u8;
+
+    let mut body = Vec::with_capacity(topic.len() + payload.len() + 4);
+    put_bytes(&mut body, topic.as_bytes())?;
+    match (qos, packet_id) {
+        (QoS::AtMostOnce, _) => {}
+        (QoS::AtLeastOnce, Some(packet_id)) => body.extend_from_slice(&packet_id.to_be_bytes()),
+        (QoS::AtLeastOnce, None) => {
+            return Err(MqttError::Unencodable("QoS 1 message without a packet ID"))
+        }
+    }
+    body.extend_from_slice(payload);
+
+    if body.len() > MAX_REMAINING_LEN {
+        return Err(MqttError::Unencodable("message too large"));
+    }
+
+    Ok(packet(header, &body))
+}
+
+pub fn encode_puback(packet_id: u16) -> Vec<u8> {
+    packet(0x40, &packet_id.to_be_bytes())
+}
+
+pub fn encode_subscribe(packet_id: u16, topics: &[(&str, QoS)]) -> Result<Vec<u8>, MqttError> {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    for (topic, qos) in topics {
+        put_bytes(&mut body, topic.as_bytes())?;
+        body.push(qos.bits());
+    }
+
+    Ok(packet(0x82, &body))
+}
+
+pub fn encode_pingreq() -> Vec<u8> {
+    packet(0xC0, &[])
+}
+
+pub fn encode_disconnect() -> Vec<u8> {
+    packet(0xE0, &[])
+}
+
+/// Reads the fields of a packet body in order
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], MqttError> {
+        if self.0.len() < len {
+            return Err(MqttError::Protocol("packet ends early"));
+        }
+
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> Result<u16, MqttError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn str(&mut self) -> Result<&'a str, MqttError> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.take(len)?)
+            .map_err(|_| MqttError::Protocol("string is not UTF-8"))
+    }
+}
+
+fn parse_packet(header: u8, body: &[u8]) -> Result<Packet, MqttError> {
+    let flags = header & 0x0F;
+    let mut fields = Fields(body);
+
+    match header >> 4 {
+        2 => {
+            let &[ack_flags, code] = fields.take(2)? else {
+                unreachable!("took two bytes");
+            };
+            Ok(Packet::ConnAck {
+                session_present: ack_flags & 0x01 != 0,
+                code,
+            })
+        }
+        3 => {
+            let qos = QoS::from_bits((flags >> 1) & 0x03)?;
+            let topic = fields.str()?.to_string();
+            let packet_id = match qos {
+                QoS::AtMostOnce => None,
+                QoS::AtLeastOnce => Some(fields.u16()?),
+            };
+
+            Ok(Packet::Publish(Publish {
+                topic,
+                payload: fields.0.to_vec(),
+                qos,
+                retain: flags & 0x01 != 0,
+                dup: flags & 0x08 != 0,
+                packet_id,
+            }))
+        }
+        4 => Ok(Packet::PubAck(fields.u16()?)),
+        9 => {
+            let packet_id = fields.u16()?;
+            let granted = fields
+                .0
+                .iter()
+                .map(|&code| match code {
+                    0..=2 => Ok(Some(code)),
+                    0x80 => Ok(None),
+                    _ => Err(MqttError::Protocol("invalid SUBACK return code")),
+                })
+                .collect::<Result<_, _>>()?;
+
+            Ok(Packet::SubAck { packet_id, granted })
+        }
+        13 => Ok(Packet::PingResp),
+        _ => Err(MqttError::Protocol("unexpected packet type")),
+    }
+}
+
+/// Splits the bytes coming from the broker into packets
+#[derive(Debug, Default)]
+pub struct PacketDecoder {
+    buf: Vec<u8>,
+}
+
+impl PacketDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Takes the next complete packet out of what has been fed so far
+    pub fn next_packet(&mut self) -> Result<Option<Packet>, MqttError> {
+        let Some(&header) = self.buf.first() else {
+            return Ok(None);
+        };
+
+        // The remaining length takes up to four bytes, seven bits at a time
+        let mut len = 0;
+        let mut header_len = 1;
+        loop {
+            let Some(&byte) = self.buf.get(header_len) else {
+                return Ok(None);
+            };
+
+            len |= ((byte & 0x7F) as usize) << (7 * (header_len - 1));
+            header_len += 1;
+
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if header_len > 4 {
+                return Err(MqttError::Protocol("malformed remaining length"));
+            }
+        }
+
+        if len > MAX_PACKET_LEN {
+            return Err(MqttError::PacketTooLarge);
+        }
+
+        let end = header_len + len;
+        if self.buf.len() < end {
+            return Ok(None);
+        }
+
+        let packet = parse_packet(header, &self.buf[header_len..end]);
+        self.buf.drain(..end);
+
+        packet.map(Some)
+    }
+}
+
+/// Client end of an MQTT session over `C`
+pub struct MqttClient<C> {
+    conn: C,
+    decoder: PacketDecoder,
+    /// Messages that arrived while waiting for an acknowledgement
+    queued: VecDeque<Publish>,
+    next_packet_id: u16,
+    keep_alive: Duration,
+    last_sent: Instant,
+    /// When the keepalive ping still waiting for an answer went out
+    ping_sent: Option<Instant>,
+}
+
+impl<C: Connection> MqttClient<C> {
+    /// Logs in to the broker over an open connection
+    pub async fn connect(mut conn: C, options: &ConnectOptions) -> Result<Self, MqttError> {
+        conn.write_all(&encode_connect(options)?).await?;
+
+        let mut client = Self {
+            conn,
+            decoder: PacketDecoder::new(),
+            queued: VecDeque::new(),
+            next_packet_id: 1,
+            keep_alive: Duration::from_secs(options.keep_alive_secs().into()),
+            last_sent: Instant::now(),
+            ping_sent: None,
+        };
+
+        match client.read_packet().await? {
+            Packet::ConnAck { code: 0, .. } => Ok(client),
+            Packet::ConnAck { code, .. } => Err(MqttError::Refused(code)),
+            _ => Err(MqttError::Protocol("expected CONNACK")),
+        }
+    }
+
+    /// Subscribes to `topics`, failing if the broker rejects any of them
+    pub async fn subscribe(&mut self, topics: &[(&str, QoS)]) -> Result<(), MqttError> {
+        let packet_id = self.packet_id();
+        self.send(&encode_subscribe(packet_id, topics)?).await?;
+
+        let granted = loop {
+            match self.next_reply().await? {
+                Packet::SubAck {
+                    packet_id: id,
+                    granted,
+                } if id == packet_id => break granted,
+                _ => {}
+            }
+        };
+
+        if granted.len() != topics.len() {
+            return Err(MqttError::Protocol(
+                "SUBACK does not match the subscription",
+            ));
+        }
+
+        for ((topic, _), qos) in topics.iter().zip(granted) {
+            if qos.is_none() {
+                return Err(MqttError::SubscriptionRejected(topic.to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Publishes a message, waiting for the broker to take it at QoS 1
+    pub async fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+    ) -> Result<(), MqttError> {
+        let packet_id = (qos == QoS::AtLeastOnce).then(|| self.packet_id());
+        self.send(&encode_publish(topic, payload, qos, retain, packet_id)?)
+            .await?;
+
+        let Some(packet_id) = packet_id else {
+            return Ok(());
+        };
+
+        loop {
+            if let Packet::PubAck(id) = self.next_reply().await? {
+                if id == packet_id {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Waits for the next message on any of our subscriptions.
+    ///
+    /// Only reads, so it can be cancelled without losing anything. QoS 1 messages are redelivered
+    /// until they are passed to [`Self::ack`].
+    pub async fn recv(&mut self) -> Result<Publish, MqttError> {
+        if let Some(publish) = self.queued.pop_front() {
+            return Ok(publish);
+        }
+
+        loop {
+            match self.read_packet().await? {
+                Packet::Publish(publish) => return Ok(publish),
+                Packet::PingResp => self.ping_sent = None,
+                // Acknowledgements for requests we already gave up on
+                Packet::PubAck(_) | Packet::SubAck { .. } => {}
+                Packet::ConnAck { .. } => return Err(MqttError::Protocol("unexpected CONNACK")),
+            }
+        }
+    }
+
+    /// Tells the broker a received message has been handled
+    pub async fn ack(&mut self, publish: &Publish) -> Result<(), MqttError> {
+        match publish.packet_id {
+            Some(packet_id) => self.send(&encode_puback(packet_id)).await,
+            None => Ok(()),
+        }
+    }
+
+    /// When [`Self::keepalive`] next has something to do, which is never if the keep-alive is
+    /// zero as that turns it off
+    pub fn next_keepalive(&self) -> Instant {
+        if self.keep_alive == Duration::MIN {
+            return Instant::MAX;
+        }
+
+        match self.ping_sent {
+            Some(sent) => sent + self.keep_alive,
+            None => self.last_sent + self.keep_alive / 2,
+        }
+    }
+
+    /// Pings the broker if we have been quiet for a while, failing if the last ping was never
+    /// answered
+    pub async fn keepalive(&mut self) -> Result<(), MqttError> {
+        if Instant::now() < self.next_keepalive() {
+            return Ok(());
+        }
+
+        if self.ping_sent.is_some() {
+            return Err(MqttError::KeepaliveTimeout);
+        }
+
+        self.send(&encode_pingreq()).await?;
+        self.ping_sent = Some(Instant::now());
+
+        Ok(())
+    }
+
+    /// Ends the session cleanly, so the broker does not publish our will
+    pub async fn disconnect(mut self) -> Result<(), MqttError> {
+        self.send(&encode_disconnect()).await
+    }
+
+    fn packet_id(&mut self) -> u16 {
+        let packet_id = self.next_packet_id;
+        // Zero is not a valid packet ID
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+
+        packet_id
+    }
+
+    async fn send(&mut self, packet: &[u8]) -> Result<(), MqttError> {
+        self.conn.write_all(packet).await?;
+        self.last_sent = Instant::now();
+
+        Ok(())
+    }
+
+    /// Reads packets until one that is not a message, queueing messages for [`Self::recv`]
+    async fn next_reply(&mut self) -> Result<Packet, MqttError> {
+        loop {
+            match self.read_packet().await? {
+                Packet::Publish(publish) => self.queued.push_back(publish),
+                Packet::PingResp => self.ping_sent = None,
+                packet => return Ok(packet),
+            }
+        }
+    }
+
+    async fn read_packet(&mut self) -> Result<Packet, MqttError> {
+        let mut buf = [0; READ_BUF_LEN];
+
+        loop {
+            if let Some(packet) = self.decoder.next_packet()? {
+                return Ok(packet);
+            }
+
+            let read = self.conn.read(&mut buf).await?;
+            if read == 0 {
+                return Err(MqttError::Closed);
+            }
+            self.decoder.feed(&buf[..read]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread::{self, JoinHandle};
+
+    use async_io::Async;
+
+    use super::*;
+    use crate::testing::block_on;
+
+    /// Header and body of every packet a client sent
+    type Received = Vec<(u8, Vec<u8>)>;
+
+    const TOPICS: [(&str, QoS); 2] = [("a", QoS::AtLeastOnce), ("b", QoS::AtLeastOnce)];
+
+    /// Reads one packet as the broker, or `None` once the client hangs up
+    fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let mut header = [0];
+        stream.read_exact(&mut header).ok()?;
+
+        let mut len = 0;
+        for shift in (0..28).step_by(7) {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).ok()?;
+            len |= ((byte[0] & 0x7F) as usize) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).ok()?;
+        Some((header[0], body))
+    }
+
+    /// A broker on a local port that takes one client through a session.
+    ///
+    /// It answers a subscription with the `suback` return codes. While the client's first
+    /// publish waits for its acknowledgement, it sends a QoS 0 message, then it delivers one at
+    /// QoS 1. Returns everything the client sent until it hung up.
+    fn start_broker(suback: &'static [u8]) -> (SocketAddr, JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+
+            while let Some((header, body)) = read_packet(&mut stream) {
+                match header >> 4 {
+                    1 => stream.write_all(&[0x20, 2, 0, 0]).unwrap(),
+                    8 => {
+                        let mut reply = vec![0x90, 2 + suback.len() as u8, body[0], body[1]];
+                        reply.extend_from_slice(suback);
+                        stream.write_all(&reply).unwrap();
+                    }
+                    3 => {
+                        let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                        let id = &body[2 + topic_len..][..2];
+                        stream.write_all(b"\x30\x07\x00\x01anews").unwrap();
+                        stream.write_all(&[0x40, 2, id[0], id[1]]).unwrap();
+                        stream.write_all(b"\x32\x0A\x00\x01b\x00\x07hello").unwrap();
+                    }
+                    _ => {}
+                }
+                received.push((header, body));
+            }
+
+            received
+        });
+
+        (addr, broker)
+    }
+
+    fn connect(addr: SocketAddr, options: &ConnectOptions) -> MqttClient<Async<TcpStream>> {
+        block_on(async {
+            let conn = Async::<TcpStream>::connect(addr).await.unwrap();
+            MqttClient::connect(conn, options).await.unwrap()
+        })
+    }
+
+    #[test]
+    fn runs_a_session_against_a_broker() {
+        // A broker may grant more than was asked for, which is still a success
+        let (addr, broker) = start_broker(&[1, 2]);
+        let mut client = connect(addr, &ConnectOptions::new("beacon-abc"));
+
+        block_on(async {
+            client.subscribe(&TOPICS).await.unwrap();
+            client
+                .publish("state", b"up", QoS::AtLeastOnce, true)
+                .await
+                .unwrap();
+
+            let news = client.recv().await.unwrap();
+            assert_eq!((news.topic.as_str(), news.qos), ("a", QoS::AtMostOnce));
+            assert_eq!(news.payload, b"news");
+
+            let hello = client.recv().await.unwrap();
+            assert_eq!((hello.topic.as_str(), hello.packet_id), ("b", Some(7)));
+            assert_eq!(hello.payload, b"hello");
+            client.ack(&hello).await.unwrap();
+
+            client.disconnect().await.unwrap();
+        });
+
+        let received = broker.join().unwrap();
+        let headers: Vec<u8> = received.iter().map(|(header, _)| *header).collect();
+        assert_eq!(headers, [0x10, 0x82, 0x33, 0x40, 0xE0]);
+        assert_eq!(received[1].1, b"\x00\x01\x00\x01a\x01\x00\x01b\x01");
+        assert_eq!(received[2].1, b"\x00\x05state\x00\x02up");
+        assert_eq!(received[3].1, [0, 7]);
+    }
+
+    #[test]
+    fn fails_rejected_subscriptions() {
+        let (addr, broker) = start_broker(&[1, 0x80]);
+        let mut client = connect(addr, &ConnectOptions::new("beacon-abc"));
+
+        let result = block_on(client.subscribe(&TOPICS));
+        assert!(matches!(result, Err(MqttError::SubscriptionRejected(topic)) if topic == "b"));
+
+        drop(client);
+        broker.join().unwrap();
+    }
+
+    #[test]
+    fn fails_refused_logins() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_packet(&mut stream).unwrap();
+            stream.write_all(&[0x20, 2, 0, 5]).unwrap();
+        });
+
+        let result = block_on(async {
+            let conn = Async::<TcpStream>::connect(addr).await.unwrap();
+            MqttClient::connect(conn, &ConnectOptions::new("beacon-abc")).await
+        });
+        assert!(matches!(result, Err(MqttError::Refused(5))));
+        broker.join().unwrap();
+    }
+
+    #[test]
+    fn schedules_keepalive_pings() {
+        let options = ConnectOptions::new("beacon-abc").with_keep_alive(Duration::from_secs(10));
+        let (addr, broker) = start_broker(&[]);
+        let client = connect(addr, &options);
+
+        let next = client.next_keepalive();
+        assert!(next > Instant::now() + Duration::from_secs(4));
+        assert!(next <= Instant::now() + Duration::from_secs(5));
+        drop(client);
+        broker.join().unwrap();
+
+        // Less than a second goes out as zero, which turns pings off
+        for keep_alive in [Duration::MIN, Duration::from_millis(500)] {
+            let options = ConnectOptions::new("beacon-abc").with_keep_alive(keep_alive);
+            let (addr, broker) = start_broker(&[]);
+            let mut client = connect(addr, &options);
+
+            assert_eq!(client.next_keepalive(), Instant::MAX);
+            block_on(client.keepalive()).unwrap();
+            drop(client);
+
+            let received = broker.join().unwrap();
+            assert_eq!(received.len(), 1, "only the CONNECT went out");
+            assert_eq!(received[0].1[8..10], [0, 0]);
+        }
+    }
+
+    #[test]
+    fn encodes_connect() {
+        let options = ConnectOptions::new("id")
+            .with_keep_alive(Duration::from_secs(60))
+            .with_credentials("user".to_string(), Some("pw".to_string()))
+            .with_will(Will {
+                topic: "w".to_string(),
+                payload: b"gone".to_vec(),
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            });
+
+        assert_eq!(
+            encode_connect(&options).unwrap(),
+            b"\x10\x21\x00\x04MQTT\x04\xEE\x00\x3C\x00\x02id\x00\x01w\x00\x04gone\
+              \x00\x04user\x00\x02pw"
+        );
+    }
+
+    #[test]
+    fn refuses_to_encode_what_mqtt_cannot_carry() {
+        assert!(matches!(
+            encode_publish("t", b"", QoS::AtLeastOnce, false, None),
+            Err(MqttError::Unencodable(_))
+        ));
+        assert_eq!(
+            encode_publish("t", b"x", QoS::AtMostOnce, false, None).unwrap(),
+            b"\x30\x04\x00\x01tx"
+        );
+
+        let long = "t".repeat(u16::MAX as usize + 1);
+        assert!(matches!(
+            encode_publish(&long, b"", QoS::AtMostOnce, false, None),
+            Err(MqttError::Unencodable(_))
+        ));
+        assert!(encode_subscribe(1, &[(&long, QoS::AtMostOnce)]).is_err());
+        assert!(encode_connect(&ConnectOptions::new(long)).is_err());
+    }
+
+    #[test]
+    fn decodes_packets_split_anywhere() {
+        let payload = vec![0xAB; 200];
+        let mut stream = vec![0x20, 2, 1, 0];
+        // 205 bytes of remaining length take two bytes to encode
+        stream.extend([0x33, 0xCD, 0x01, 0x00, 0x01, b't', 0x00, 0x09]);
+        stream.extend(&payload);
+        stream.extend([0x90, 5, 0, 9, 0, 2, 0x80, 0xD0, 0]);
+
+        for step in 1..stream.len() {
+            let mut decoder = PacketDecoder::new();
+            let mut packets = Vec::new();
+            for chunk in stream.chunks(step) {
+                decoder.feed(chunk);
+                while let Some(packet) = decoder.next_packet().unwrap() {
+                    packets.push(packet);
+                }
+            }
+
+            assert_eq!(
+                packets,
+                [
+                    Packet::ConnAck {
+                        session_present: true,
+                        code: 0,
+                    },
+                    Packet::Publish(Publish {
+                        topic: "t".to_string(),
+                        payload: payload.clone(),
+                        qos: QoS::AtLeastOnce,
+                        retain: true,
+                        dup: false,
+                        packet_id: Some(9),
+                    }),
+                    Packet::SubAck {
+                        packet_id: 9,
+                        granted: vec![Some(0), Some(2), None],
+                    },
+                    Packet::PingResp,
+                ],
+                "step {step}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        for stream in [
+            &[0x90, 3, 0, 1, 3][..],
+            &[0x34, 4, 0, 1, b't', 0],
+            &[0x20, 1, 0],
+            &[0x50, 0],
+            &[0x30, 0xFF, 0xFF, 0xFF, 0xFF],
+        ] {
+            let mut decoder = PacketDecoder::new();
+            decoder.feed(stream);
+            assert!(
+                matches!(decoder.next_packet(), Err(MqttError::Protocol(_))),
+                "{stream:02x?}"
+            );
+        }
+
+        let mut decoder = PacketDecoder::new();
+        decoder.feed(&[0x30, 0x81, 0x80, 0x04]);
+        assert!(matches!(
+            decoder.next_packet(),
+            Err(MqttError::PacketTooLarge)
+        ));
+    }
+
+    #[test]
+    fn parses_broker_urls() {
+        assert_eq!(
+            broker_url("mqtt://localhost").unwrap().port(),
+            Some(DEFAULT_PORT)
+        );
+        assert_eq!(
+            broker_url("mqtts://broker.test").unwrap().port(),
+            Some(DEFAULT_TLS_PORT)
+        );
+        assert_eq!(
+            broker_url("mqtt://localhost:1884").unwrap().port(),
+            Some(1884)
+        );
+        assert!(matches!(
+            broker_url("http://localhost"),
+            Err(MqttError::UnsupportedScheme(_))
+        ));
+    }
+}
@@ -0,0 +1,333 @@
+//! The companion site's push channel: a WebSocket the server sends events down as they happen,
+//! so pings show up without waiting for the next poll.
+
+use core::future::Future;
+
+use embassy_time::{Duration, Timer};
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::sync::{mpsc, watch};
+use url::Url;
+
+use super::api::Assignment;
+use super::websocket::{Message, WebSocket, WsError};
+use super::{backoff, timeout, Cancel, Connection, NetError, Timeouts};
+use crate::app::notification::Notification;
+use crate::app::state::Event;
+
+/// Quiet time after which the server is pinged to check the connection is still there
+const KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// A message from the server, tagged by its `type` field
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PushMessage {
+    Ping {
+        id: String,
+        from: String,
+        #[serde(default)]
+        message: Option<String>,
+    },
+    /// A message left for the owner
+    Message {
+        from: String,
+        text: String,
+    },
+    /// Something the organizers want every beacon to show
+    Announcement {
+        text: String,
+    },
+    ProjectUpdated {
+        assignment: Assignment,
+    },
+    Unclaimed,
+    ForceUpdate,
+    /// Calls off a forced update that has not finished
+    CancelUpdate,
+}
+
+impl PushMessage {
+    pub fn into_events(self) -> Vec<Event> {
+        match self {
+            Self::Ping { id, from, message } => vec![Event::Pinged {
+                id,
+                by: from,
+                message,
+            }],
+            Self::Message { from, text } => {
+                vec![Event::Notified(Notification::message(from, text))]
+            }
+            Self::Announcement { text } => {
+                vec![Event::Notified(Notification::announcement(text))]
+            }
+            Self::ProjectUpdated { assignment } => {
+                let presence = if assignment.away {
+                    Event::OwnerAway
+                } else {
+                    Event::OwnerBack
+                };
+                vec![Event::Claimed(assignment.into()), presence]
+            }
+            Self::Unclaimed => vec![Event::Released],
+            Self::ForceUpdate => vec![Event::UpdateRequested],
+            Self::CancelUpdate => vec![Event::UpdateCancelled],
+        }
+    }
+}
+
+/// Keeps a WebSocket to `url` open, reconnecting with backoff whenever it drops.
+///
+/// `connect` opens the underlying connection to the URL it is given, so the channel runs over
+/// TLS on the device and over plain TCP against a local server.
+pub struct PushChannel<F> {
+    url: Url,
+    connect: F,
+    connected: watch::Sender<bool>,
+    timeouts: Timeouts,
+    cancel: Cancel,
+}
+
+impl<F, Fut, C> PushChannel<F>
+where
+    F: FnMut(Url) -> Fut,
+    Fut: Future<Output = Result<C, NetError>>,
+    C: Connection,
+{
+    pub fn new(url: Url, connect: F) -> Self {
+        Self {
+            url,
+            connect,
+            connected: watch::channel(false).0,
+            timeouts: Timeouts::new(),
+            cancel: Cancel::never(),
+        }
+    }
+
+    /// Limits for the WebSocket upgrade. Connecting is up to `connect`.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Stops the channel, connected or waiting to reconnect, once `cancel` fires
+    pub fn with_cancel(mut self, cancel: Cancel) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Whether the channel is currently up
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.connected.subscribe()
+    }
+
+    /// Forwards server events to `events` until its receiver is gone, or fails with
+    /// [`NetError::Cancelled`] once cancelled.
+    ///
+    /// `random` provides the handshake nonces, the frame masks and the backoff jitter, so it has
+    /// to be a real random source such as the hardware RNG.
+    pub async fn run(
+        mut self,
+        events: mpsc::UnboundedSender<Event>,
+        mut random: impl FnMut() -> u32,
+    ) -> Result<(), NetError> {
+        let cancel = self.cancel.clone();
+        let mut round = 0;
+
+        loop {
+            let result = cancel.guard(self.session(&events, &mut random)).await;
+            let was_connected = self.connected.send_replace(false);
+
+            match result {
+                Ok(()) => return Ok(()),
+                Err(WsError::Transport(NetError::Cancelled)) => return Err(NetError::Cancelled),
+                Err(e) => warn!("Push channel dropped: {e}"),
+            }
+
+            if was_connected {
+                round = 0;
+            }
+
+            let retry_in = backoff(round, random());
+            info!("Reconnecting push channel in {}s", retry_in.as_secs());
+            cancel
+                .guard(async {
+                    Timer::after(retry_in).await;
+                    Ok::<_, NetError>(())
+                })
+                .await?;
+            round = round.saturating_add(1);
+        }
+    }
+
+    /// One connection, from the handshake until it fails. Returns `Ok` only once nobody is
+    /// listening for events anymore.
+    async fn session(
+        &mut self,
+        events: &mpsc::UnboundedSender<Event>,
+        random: &mut impl FnMut() -> u32,
+    ) -> Result<(), WsError> {
+        let host = match (self.url.host_str(), self.url.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(NetError::InvalidUrl(self.url.to_string()).into()),
+        };
+        let path = match self.url.query() {
+            Some(query) => format!("{}?{query}", self.url.path()),
+            None => self.url.path().to_string(),
+        };
+
+        let conn = (self.connect)(self.url.clone()).await?;
+        let mut ws = timeout(
+            self.timeouts.handshake,
+            WebSocket::connect(conn, &host, &path, random),
+        )
+        .await?;
+
+        info!("Push channel connected");
+        self.connected.send_replace(true);
+
+        let mut awaiting_pong = false;
+        loop {
+            let message = match ws.recv_within(KEEPALIVE).await? {
+                Some(message) => message,
+                None if awaiting_pong => return Err(NetError::Timeout(KEEPALIVE).into()),
+                None => {
+                    ws.ping(&[]).await?;
+                    awaiting_pong = true;
+                    continue;
+                }
+            };
+
+            // Anything from the server shows the connection is alive
+            awaiting_pong = false;
+
+            let Message::Text(text) = message else {
+                continue;
+            };
+
+            match serde_json::from_str::<PushMessage>(&text) {
+                Ok(message) => {
+                    for event in message.into_events() {
+                        if events.send(event).is_err() {
+                            return Ok(());
+                        }
+                    }
+                }
+                Err(e) => warn!("Ignoring push message: {e}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::future::pending;
+    use std::net::TcpStream;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use async_io::Async;
+
+    use super::*;
+    use crate::net::Canceller;
+    use crate::testing::block_on;
+
+    fn url() -> Url {
+        Url::parse("ws://127.0.0.1:9/api/beacons/abc/events").unwrap()
+    }
+
+    #[test]
+    fn turns_messages_into_events() {
+        let message: PushMessage =
+            serde_json::from_str(r#"{"type": "ping", "id": "1", "from": "Grace"}"#).unwrap();
+        assert_eq!(
+            message.into_events(),
+            [Event::Pinged {
+                id: "1".to_string(),
+                by: "Grace".to_string(),
+                message: None,
+            }]
+        );
+
+        let message: PushMessage = serde_json::from_value(serde_json::json!({
+            "type": "project_updated",
+            "assignment": {"owner": "Ada", "title": "Engine", "away": true},
+        }))
+        .unwrap();
+        let events = message.into_events();
+        assert!(
+            matches!(&events[..], [Event::Claimed(info), Event::OwnerAway] if info.owner == "Ada")
+        );
+
+        let message: PushMessage = serde_json::from_str(r#"{"type": "unclaimed"}"#).unwrap();
+        assert_eq!(message.into_events(), [Event::Released]);
+        let message: PushMessage = serde_json::from_str(r#"{"type": "force_update"}"#).unwrap();
+        assert_eq!(message.into_events(), [Event::UpdateRequested]);
+        let message: PushMessage = serde_json::from_str(r#"{"type": "cancel_update"}"#).unwrap();
+        assert_eq!(message.into_events(), [Event::UpdateCancelled]);
+
+        assert!(serde_json::from_str::<PushMessage>(r#"{"type": "dance"}"#).is_err());
+    }
+
+    #[test]
+    fn stops_connecting_once_cancelled() {
+        let canceller = Canceller::new();
+        let channel = PushChannel::new(url(), |_| pending::<Result<Async<TcpStream>, NetError>>())
+            .with_cancel(canceller.token());
+        let connected = channel.subscribe();
+        let (events, _events) = mpsc::unbounded_channel();
+
+        let result = block_on(async {
+            let run = tokio::task::spawn(channel.run(events, || 4));
+            Timer::after_millis(50).await;
+            canceller.cancel();
+            run.await.unwrap()
+        });
+
+        assert!(matches!(result, Err(NetError::Cancelled)));
+        assert!(!*connected.borrow());
+    }
+
+    #[test]
+    fn stops_waiting_to_reconnect_once_cancelled() {
+        let canceller = Canceller::new();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let channel = PushChannel::new(url(), {
+            let attempts = attempts.clone();
+            move |_| {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                async {
+                    Err::<Async<TcpStream>, _>(NetError::Connect(
+                        std::io::ErrorKind::ConnectionRefused.into(),
+                    ))
+                }
+            }
+        })
+        .with_cancel(canceller.token());
+        let (events, _events) = mpsc::unbounded_channel();
+
+        let result = block_on(async {
+            let run = tokio::task::spawn(channel.run(events, || 4));
+            Timer::after_millis(50).await;
+            canceller.cancel();
+            run.await.unwrap()
+        });
+
+        assert!(matches!(result, Err(NetError::Cancelled)));
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn stops_at_once_when_already_cancelled() {
+        let canceller = Canceller::new();
+        canceller.cancel();
+        let channel = PushChannel::new(url(), |_| pending::<Result<Async<TcpStream>, NetError>>())
+            .with_cancel(canceller.token());
+        let (events, _events) = mpsc::unbounded_channel();
+
+        assert!(matches!(
+            block_on(channel.run(events, || 4)),
+            Err(NetError::Cancelled)
+        ));
+    }
+}
@@ -0,0 +1,322 @@
+//! Picking which GitHub release (and which asset of it) a beacon should update to.
+
+use core::fmt;
+use core::str::FromStr;
+
+use semver::Version;
+
+/// Hardware revision of the board this firmware is built for
+pub const BOARD_REVISION: &str = "r1";
+
+/// Build flavor. Every build is the same since Wi-Fi credentials moved to NVS, so this only keeps
+/// asset names in the same shape as before.
+pub const BUILD_FLAVOR: &str = "generic";
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct GithubResponse {
+    pub tag_name: String,
+    #[serde(default)]
+    pub draft: bool,
+    pub assets: Vec<GithubAsset>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct GithubAsset {
+    pub name: String,
+    pub browser_download_url: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UpdateChannel {
+    /// Only releases without a prerelease tag
+    #[default]
+    Stable,
+    /// Stable releases plus `-beta.N` and `-rc.N`
+    Beta,
+    /// Everything, including `-alpha.N` and `-dev.N`
+    Dev,
+}
+
+impl UpdateChannel {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Stable => "stable",
+            Self::Beta => "beta",
+            Self::Dev => "dev",
+        }
+    }
+
+    pub fn accepts(self, version: &Version) -> bool {
+        if version.pre.is_empty() {
+            return true;
+        }
+
+        let label = version.pre.as_str().split('.').next().unwrap_or_default();
+
+        match self {
+            Self::Stable => false,
+            Self::Beta => matches!(label, "beta" | "rc"),
+            Self::Dev => true,
+        }
+    }
+}
+
+impl fmt::Display for UpdateChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for UpdateChannel {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stable" => Ok(Self::Stable),
+            "beta" => Ok(Self::Beta),
+            "dev" => Ok(Self::Dev),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Which hardware and flavor an image has to be built for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Target<'a> {
+    pub board: &'a str,
+    pub flavor: &'a str,
+}
+
+impl Target<'static> {
+    pub const fn this_build() -> Self {
+        Self {
+            board: BOARD_REVISION,
+            flavor: BUILD_FLAVOR,
+        }
+    }
+}
+
+impl Target<'_> {
+    /// Release asset name for this target, e.g. `beacons-r1-enterprise.bin`
+    pub fn image_name(&self) -> String {
+        format!("beacons-{}-{}.bin", self.board, self.flavor)
+    }
+
+    /// Detached signature published next to the image
+    pub fn signature_name(&self) -> String {
+        format!("{}.sig", self.image_name())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UpdateCandidate<'a> {
+    pub version: &'a str,
+    pub image: &'a GithubAsset,
+    pub signature: &'a GithubAsset,
+}
+
+/// Parses a release tag such as `v1.2.0-beta.1`
+pub fn parse_tag(tag: &str) -> Option<Version> {
+    Version::parse(tag.strip_prefix('v').unwrap_or(tag)).ok()
+}
+
+/// Finds the newest release on `channel` that is newer than `current` and carries a signed
+/// image for `target`
+pub fn select_update<'a>(
+    releases: &'a [GithubResponse],
+    channel: UpdateChannel,
+    current: &Version,
+    target: Target<'_>,
+) -> Option<UpdateCandidate<'a>> {
+    let image_name = target.image_name();
+    let signature_name = target.signature_name();
+
+    releases
+        .iter()
+        .filter(|release| !release.draft)
+        .filter_map(|release| {
+            let version = parse_tag(&release.tag_name)?;
+            if !channel.accepts(&version) || version <= *current {
+                return None;
+            }
+
+            let image = release.assets.iter().find(|a| a.name == image_name)?;
+            let signature = release.assets.iter().find(|a| a.name == signature_name)?;
+
+            Some((
+                version,
+                UpdateCandidate {
+                    version: &release.tag_name,
+                    image,
+                    signature,
+                },
+            ))
+        })
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, candidate)| candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TARGET: Target<'static> = Target {
+        board: "r1",
+        flavor: "generic",
+    };
+
+    fn asset(name: &str) -> GithubAsset {
+        GithubAsset {
+            name: name.to_string(),
+            browser_download_url: format!("https://example.com/{name}"),
+        }
+    }
+
+    /// A release carrying signed images for each of `targets`
+    fn release(tag: &str, targets: &[Target]) -> GithubResponse {
+        GithubResponse {
+            tag_name: tag.to_string(),
+            draft: false,
+            assets: targets
+                .iter()
+                .flat_map(|target| [asset(&target.image_name()), asset(&target.signature_name())])
+                .collect(),
+        }
+    }
+
+    fn selected(
+        releases: &[GithubResponse],
+        channel: UpdateChannel,
+        current: &str,
+    ) -> Option<String> {
+        let current = parse_tag(current).unwrap();
+        select_update(releases, channel, &current, TARGET).map(|c| c.version.to_string())
+    }
+
+    #[test]
+    fn names_assets_after_the_target() {
+        assert_eq!(TARGET.image_name(), "beacons-r1-generic.bin");
+        assert_eq!(TARGET.signature_name(), "beacons-r1-generic.bin.sig");
+    }
+
+    #[test]
+    fn parses_tags_with_or_without_a_v() {
+        assert_eq!(parse_tag("v1.2.0"), Some(Version::new(1, 2, 0)));
+        assert_eq!(parse_tag("1.2.0"), Some(Version::new(1, 2, 0)));
+        assert_eq!(parse_tag("latest"), None);
+    }
+
+    #[test]
+    fn filters_prereleases_by_channel() {
+        let stable = parse_tag("1.0.0").unwrap();
+        let beta = parse_tag("1.1.0-beta.1").unwrap();
+        let rc = parse_tag("1.1.0-rc.2").unwrap();
+        let alpha = parse_tag("1.1.0-alpha.3").unwrap();
+
+        assert!(UpdateChannel::Stable.accepts(&stable));
+        assert!(!UpdateChannel::Stable.accepts(&beta));
+        assert!(UpdateChannel::Beta.accepts(&beta));
+        assert!(UpdateChannel::Beta.accepts(&rc));
+        assert!(!UpdateChannel::Beta.accepts(&alpha));
+        assert!(UpdateChannel::Dev.accepts(&alpha));
+    }
+
+    #[test]
+    fn picks_the_newest_release_on_the_channel() {
+        // GitHub lists newest first, but that is not relied on
+        let releases = [
+            release("v1.1.0", &[TARGET]),
+            release("v1.3.0-beta.1", &[TARGET]),
+            release("v1.2.0", &[TARGET]),
+            release("v1.3.0-alpha.1", &[TARGET]),
+            release("v1.3.0-beta.2", &[TARGET]),
+        ];
+
+        assert_eq!(
+            selected(&releases, UpdateChannel::Stable, "1.0.0").as_deref(),
+            Some("v1.2.0")
+        );
+        assert_eq!(
+            selected(&releases, UpdateChannel::Beta, "1.0.0").as_deref(),
+            Some("v1.3.0-beta.2")
+        );
+        assert_eq!(
+            selected(&releases, UpdateChannel::Dev, "1.0.0").as_deref(),
+            Some("v1.3.0-beta.2")
+        );
+    }
+
+    #[test]
+    fn orders_prereleases_before_their_release() {
+        let releases = [
+            release("v1.3.0-rc.1", &[TARGET]),
+            release("v1.3.0", &[TARGET]),
+        ];
+        assert_eq!(
+            selected(&releases, UpdateChannel::Beta, "1.2.0").as_deref(),
+            Some("v1.3.0")
+        );
+
+        // Already on the release, the release candidate is older
+        assert_eq!(selected(&releases, UpdateChannel::Beta, "1.3.0"), None);
+    }
+
+    #[test]
+    fn ignores_releases_that_are_not_newer() {
+        let releases = [release("v1.0.0", &[TARGET]), release("v0.9.0", &[TARGET])];
+        assert_eq!(selected(&releases, UpdateChannel::Dev, "1.0.0"), None);
+    }
+
+    #[test]
+    fn ignores_drafts_and_bad_tags() {
+        let mut draft = release("v2.0.0", &[TARGET]);
+        draft.draft = true;
+        let releases = [
+            draft,
+            release("nightly", &[TARGET]),
+            release("v1.1.0", &[TARGET]),
+        ];
+
+        assert_eq!(
+            selected(&releases, UpdateChannel::Dev, "1.0.0").as_deref(),
+            Some("v1.1.0")
+        );
+    }
+
+    #[test]
+    fn skips_releases_without_an_image_for_the_target() {
+        let other = Target {
+            board: "r2",
+            flavor: "generic",
+        };
+        let releases = [
+            release("v1.2.0", &[other]),
+            release("v1.1.0", &[TARGET, other]),
+        ];
+
+        assert_eq!(
+            selected(&releases, UpdateChannel::Stable, "1.0.0").as_deref(),
+            Some("v1.1.0")
+        );
+    }
+
+    #[test]
+    fn skips_releases_missing_the_signature() {
+        let mut unsigned = release("v1.2.0", &[TARGET]);
+        unsigned
+            .assets
+            .retain(|asset| asset.name != TARGET.signature_name());
+        let releases = [unsigned, release("v1.1.0", &[TARGET])];
+
+        let candidate = select_update(
+            &releases,
+            UpdateChannel::Stable,
+            &Version::new(1, 0, 0),
+            TARGET,
+        )
+        .unwrap();
+        assert_eq!(candidate.version, "v1.1.0");
+        assert_eq!(candidate.image.name, "beacons-r1-generic.bin");
+        assert_eq!(candidate.signature.name, "beacons-r1-generic.bin.sig");
+    }
+}
@@ -0,0 +1,726 @@
+//! Incremental HTTP/1.1 response parsing.
+//!
+//! Nothing in here touches a socket: bytes are fed in as they arrive off the wire, which keeps the
+//! parser usable against captured byte streams on the host.
+
+use std::fmt;
+
+use http::Method;
+
+/// Largest response head (status line + headers) we are willing to buffer
+pub const MAX_HEAD_LEN: usize = 16 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HttpError {
+    HeadTooLarge,
+    InvalidStatusLine,
+    InvalidHeader,
+    InvalidContentLength,
+    InvalidChunk,
+    UnexpectedEof,
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::HeadTooLarge => write!(f, "response head exceeds {MAX_HEAD_LEN} bytes"),
+            Self::InvalidStatusLine => write!(f, "malformed status line"),
+            Self::InvalidHeader => write!(f, "malformed header line"),
+            Self::InvalidContentLength => write!(f, "invalid Content-Length"),
+            Self::InvalidChunk => write!(f, "malformed chunked encoding"),
+            Self::UnexpectedEof => write!(f, "connection closed before the response was complete"),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseHead {
+    /// Minor HTTP version, `1` for HTTP/1.1
+    pub version: u8,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+}
+
+/// Byte range carried by a `206 Partial Content` response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentRange {
+    pub start: u64,
+    pub end: u64,
+    /// Full size of the resource, if the server knows it
+    pub total: Option<u64>,
+}
+
+/// How the end of a response body is determined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    Length(u64),
+    Chunked,
+    /// Body runs until the server closes the connection
+    Close,
+}
+
+impl ResponseHead {
+    /// First value of a header, matched case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All values of a header, matched case-insensitively
+    pub fn headers_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn content_length(&self) -> Result<Option<u64>, HttpError> {
+        let mut length = None;
+        for value in self.headers_named("content-length") {
+            // Repeated headers (or comma-joined values) must all agree
+            for part in value.split(',') {
+                let parsed = part
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|_| HttpError::InvalidContentLength)?;
+                match length {
+                    Some(existing) if existing != parsed => {
+                        return Err(HttpError::InvalidContentLength)
+                    }
+                    _ => length = Some(parsed),
+                }
+            }
+        }
+
+        Ok(length)
+    }
+
+    /// Parses `Content-Range: bytes <start>-<end>/<total>`
+    pub fn content_range(&self) -> Option<ContentRange> {
+        let range = self
+            .header("content-range")?
+            .trim()
+            .strip_prefix("bytes ")?;
+        let (span, total) = range.split_once('/')?;
+        let (start, end) = span.split_once('-')?;
+
+        let start = start.trim().parse().ok()?;
+        let end = end.trim().parse().ok()?;
+        let total = match total.trim() {
+            "*" => None,
+            total => Some(total.parse().ok()?),
+        };
+
+        (start <= end).then_some(ContentRange { start, end, total })
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        // Only the final transfer coding decides whether the body is chunked
+        self.headers_named("transfer-encoding")
+            .flat_map(|value| value.split(','))
+            .last()
+            .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+    }
+
+    pub fn is_connection_close(&self) -> bool {
+        self.headers_named("connection")
+            .flat_map(|value| value.split(','))
+            .any(|option| option.trim().eq_ignore_ascii_case("close"))
+            || self.version == 0
+    }
+
+    /// How the body of this response to a `method` request ends
+    pub fn framing(&self, method: &Method) -> Result<Framing, HttpError> {
+        // A response to HEAD describes the body a GET would get, without sending it
+        if *method == Method::HEAD || matches!(self.status, 100..=199 | 204 | 304) {
+            return Ok(Framing::Length(0));
+        }
+
+        if self.is_chunked() {
+            return Ok(Framing::Chunked);
+        }
+
+        Ok(match self.content_length()? {
+            Some(length) => Framing::Length(length),
+            None => Framing::Close,
+        })
+    }
+
+    fn parse(raw: &[u8]) -> Result<Self, HttpError> {
+        let text = String::from_utf8_lossy(raw);
+        let mut lines = text.split("\r\n");
+
+        let status_line = lines.next().ok_or(HttpError::InvalidStatusLine)?;
+        let mut parts = status_line.splitn(3, ' ');
+        let version = match parts.next() {
+            Some("HTTP/1.1") => 1,
+            Some("HTTP/1.0") => 0,
+            _ => return Err(HttpError::InvalidStatusLine),
+        };
+        let status = parts
+            .next()
+            .filter(|code| code.len() == 3)
+            .and_then(|code| code.parse::<u16>().ok())
+            .filter(|code| (100..=599).contains(code))
+            .ok_or(HttpError::InvalidStatusLine)?;
+        let reason = parts.next().unwrap_or_default().to_string();
+
+        let mut headers: Vec<(String, String)> = Vec::new();
+        for line in lines.take_while(|line| !line.is_empty()) {
+            if line.starts_with([' ', '\t']) {
+                // Obsolete line folding continues the previous header's value
+                let (_, value) = headers.last_mut().ok_or(HttpError::InvalidHeader)?;
+                value.push(' ');
+                value.push_str(line.trim());
+                continue;
+            }
+
+            let (key, value) = line.split_once(':').ok_or(HttpError::InvalidHeader)?;
+            if key.is_empty() || key.ends_with([' ', '\t']) {
+                return Err(HttpError::InvalidHeader);
+            }
+            headers.push((key.to_string(), value.trim().to_string()));
+        }
+
+        Ok(Self {
+            version,
+            status,
+            reason,
+            headers,
+        })
+    }
+}
+
+/// Accumulates bytes until a complete response head has arrived
+#[derive(Debug, Default)]
+pub struct HeadParser {
+    buf: Vec<u8>,
+}
+
+impl HeadParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds the next bytes read off the connection.
+    ///
+    /// Once the head is complete it is returned together with the number of bytes of `data` that
+    /// belonged to it; everything after that is the start of the body.
+    pub fn feed(&mut self, data: &[u8]) -> Result<Option<(ResponseHead, usize)>, HttpError> {
+        let mut data = data;
+        let mut used = 0;
+
+        loop {
+            let previous = self.buf.len();
+            // The terminator may straddle the previous feed
+            let search_from = previous.saturating_sub(3);
+            self.buf.extend_from_slice(data);
+
+            let Some(end) = self.buf[search_from..]
+                .windows(4)
+                .position(|window| window == b"\r\n\r\n")
+                .map(|pos| search_from + pos + 4)
+            else {
+                if self.buf.len() > MAX_HEAD_LEN {
+                    return Err(HttpError::HeadTooLarge);
+                }
+                return Ok(None);
+            };
+
+            if end > MAX_HEAD_LEN {
+                return Err(HttpError::HeadTooLarge);
+            }
+
+            let head = ResponseHead::parse(&self.buf[..end])?;
+            let consumed = end - previous;
+            used += consumed;
+            self.buf.clear();
+
+            // Interim responses (100 Continue and friends) precede the real one
+            if (100..=199).contains(&head.status) && head.status != 101 {
+                data = &data[consumed..];
+                continue;
+            }
+
+            return Ok(Some((head, used)));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChunkState {
+    Size { size: u64, digits: u8 },
+    Extension { size: u64 },
+    SizeLf { size: u64 },
+    Data { remaining: u64 },
+    DataCr,
+    DataLf,
+    Trailer { line_empty: bool },
+    TrailerLf { line_empty: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BodyState {
+    Length { remaining: u64 },
+    Chunked(ChunkState),
+    Close,
+    Done,
+}
+
+/// Result of a single [`BodyDecoder::decode`] call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decoded {
+    /// Bytes of input that were used, including any chunk framing
+    pub consumed: usize,
+    /// Bytes of body written to the output
+    pub produced: usize,
+}
+
+/// Strips transfer framing off a response body as it streams in
+#[derive(Debug, Clone)]
+pub struct BodyDecoder {
+    state: BodyState,
+}
+
+impl BodyDecoder {
+    pub fn new(framing: Framing) -> Self {
+        let state = match framing {
+            Framing::Length(0) => BodyState::Done,
+            Framing::Length(remaining) => BodyState::Length { remaining },
+            Framing::Chunked => BodyState::Chunked(ChunkState::Size { size: 0, digits: 0 }),
+            Framing::Close => BodyState::Close,
+        };
+
+        Self { state }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == BodyState::Done
+    }
+
+    /// Body bytes still expected, if the response declared its length
+    pub fn remaining(&self) -> Option<u64> {
+        match self.state {
+            BodyState::Length { remaining } => Some(remaining),
+            BodyState::Done => Some(0),
+            _ => None,
+        }
+    }
+
+    /// Decodes as much of `input` into `out` as fits
+    pub fn decode(&mut self, input: &[u8], out: &mut [u8]) -> Result<Decoded, HttpError> {
+        let mut consumed = 0;
+        let mut produced = 0;
+
+        while consumed < input.len() {
+            let rest = &input[consumed..];
+            let space = out.len() - produced;
+
+            match &mut self.state {
+                BodyState::Done => break,
+                BodyState::Close => {
+                    let take = rest.len().min(space);
+                    if take == 0 {
+                        break;
+                    }
+                    out[produced..produced + take].copy_from_slice(&rest[..take]);
+                    consumed += take;
+                    produced += take;
+                }
+                BodyState::Length { remaining } => {
+                    let take = rest.len().min(space).min(*remaining as usize);
+                    if take == 0 {
+                        break;
+                    }
+                    out[produced..produced + take].copy_from_slice(&rest[..take]);
+                    consumed += take;
+                    produced += take;
+                    *remaining -= take as u64;
+                    if *remaining == 0 {
+                        self.state = BodyState::Done;
+                    }
+                }
+                BodyState::Chunked(ChunkState::Data { remaining }) => {
+                    let take = rest.len().min(space).min(*remaining as usize);
+                    if take == 0 {
+                        break;
+                    }
+                    out[produced..produced + take].copy_from_slice(&rest[..take]);
+                    consumed += take;
+                    produced += take;
+                    *remaining -= take as u64;
+                    if *remaining == 0 {
+                        self.state = BodyState::Chunked(ChunkState::DataCr);
+                    }
+                }
+                BodyState::Chunked(chunk) => {
+                    let byte = rest[0];
+                    consumed += 1;
+                    self.state = match Self::step_chunk_framing(*chunk, byte)? {
+                        Some(next) => BodyState::Chunked(next),
+                        None => BodyState::Done,
+                    };
+                }
+            }
+        }
+
+        Ok(Decoded { consumed, produced })
+    }
+
+    /// Advances the chunk framing by one byte, returning `None` once the body is complete
+    fn step_chunk_framing(state: ChunkState, byte: u8) -> Result<Option<ChunkState>, HttpError> {
+        let next = match state {
+            ChunkState::Size { size, digits } => match byte {
+                b'0'..=b'9' | b'a'..=b'f' | b'A'..=b'F' => {
+                    if digits >= 15 {
+                        return Err(HttpError::InvalidChunk);
+                    }
+                    let digit = (byte as char).to_digit(16).unwrap_or_default() as u64;
+                    ChunkState::Size {
+                        size: size << 4 | digit,
+                        digits: digits + 1,
+                    }
+                }
+                b';' | b' ' | b'\t' if digits > 0 => ChunkState::Extension { size },
+                b'\r' if digits > 0 => ChunkState::SizeLf { size },
+                _ => return Err(HttpError::InvalidChunk),
+            },
+            ChunkState::Extension { size } => match byte {
+                b'\r' => ChunkState::SizeLf { size },
+                _ => ChunkState::Extension { size },
+            },
+            ChunkState::SizeLf { size } => match byte {
+                b'\n' if size == 0 => ChunkState::Trailer { line_empty: true },
+                b'\n' => ChunkState::Data { remaining: size },
+                _ => return Err(HttpError::InvalidChunk),
+            },
+            ChunkState::Data { .. } => unreachable!("chunk data is copied, not stepped"),
+            ChunkState::DataCr => match byte {
+                b'\r' => ChunkState::DataLf,
+                _ => return Err(HttpError::InvalidChunk),
+            },
+            ChunkState::DataLf => match byte {
+                b'\n' => ChunkState::Size { size: 0, digits: 0 },
+                _ => return Err(HttpError::InvalidChunk),
+            },
+            ChunkState::Trailer { line_empty } => match byte {
+                b'\r' => ChunkState::TrailerLf { line_empty },
+                _ => ChunkState::Trailer { line_empty: false },
+            },
+            ChunkState::TrailerLf { line_empty } => match byte {
+                b'\n' if line_empty => return Ok(None),
+                b'\n' => ChunkState::Trailer { line_empty: true },
+                _ => return Err(HttpError::InvalidChunk),
+            },
+        };
+
+        Ok(Some(next))
+    }
+
+    /// Tells the decoder the connection has closed, failing if the body was cut short
+    pub fn finish(&mut self) -> Result<(), HttpError> {
+        match self.state {
+            BodyState::Close | BodyState::Done => {
+                self.state = BodyState::Done;
+                Ok(())
+            }
+            _ => Err(HttpError::UnexpectedEof),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `stream` to a new parser `step` bytes at a time, returning the head and body offset
+    fn parse_head(stream: &[u8], step: usize) -> Result<Option<(ResponseHead, usize)>, HttpError> {
+        let mut parser = HeadParser::new();
+        let mut offset = 0;
+        for piece in stream.chunks(step) {
+            if let Some((head, used)) = parser.feed(piece)? {
+                return Ok(Some((head, offset + used)));
+            }
+            offset += piece.len();
+        }
+        Ok(None)
+    }
+
+    /// Decodes `body` fed `step` bytes at a time into an output buffer of `space` bytes
+    fn decode(
+        framing: Framing,
+        body: &[u8],
+        step: usize,
+        space: usize,
+    ) -> Result<Vec<u8>, HttpError> {
+        let mut decoder = BodyDecoder::new(framing);
+        let mut decoded = Vec::new();
+        let mut out = vec![0; space];
+
+        for mut piece in body.chunks(step) {
+            while !piece.is_empty() && !decoder.is_done() {
+                let Decoded { consumed, produced } = decoder.decode(piece, &mut out)?;
+                decoded.extend_from_slice(&out[..produced]);
+                piece = &piece[consumed..];
+            }
+        }
+        decoder.finish()?;
+
+        Ok(decoded)
+    }
+
+    const RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: 13\r\n\
+        X-Folded: one\r\n two\r\n\
+        \r\n\
+        {\"ok\": true}\n";
+
+    #[test]
+    fn parses_a_head_split_anywhere() {
+        for step in 1..RESPONSE.len() {
+            let (head, used) = parse_head(RESPONSE, step).unwrap().unwrap();
+            assert_eq!(head.status, 200);
+            assert_eq!(head.reason, "OK");
+            assert_eq!(head.header("content-type"), Some("application/json"));
+            assert_eq!(head.header("x-folded"), Some("one two"));
+            assert_eq!(&RESPONSE[used..], b"{\"ok\": true}\n");
+        }
+    }
+
+    #[test]
+    fn skips_interim_responses() {
+        let stream = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 204 No Content\r\n\r\n";
+        let (head, used) = parse_head(stream, stream.len()).unwrap().unwrap();
+        assert_eq!(head.status, 204);
+        assert_eq!(used, stream.len());
+        assert_eq!(head.framing(&Method::GET), Ok(Framing::Length(0)));
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_the_head() {
+        assert_eq!(parse_head(b"HTTP/1.1 200 OK\r\nServer: x\r\n", 4), Ok(None));
+    }
+
+    #[test]
+    fn rejects_oversized_heads() {
+        let mut stream = b"HTTP/1.1 200 OK\r\n".to_vec();
+        while stream.len() <= MAX_HEAD_LEN {
+            stream.extend_from_slice(b"X-Padding: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n");
+        }
+        assert_eq!(parse_head(&stream, 1000), Err(HttpError::HeadTooLarge));
+
+        // Also when the terminator turns up, but too late
+        stream.extend_from_slice(b"\r\n");
+        assert_eq!(
+            parse_head(&stream, stream.len()),
+            Err(HttpError::HeadTooLarge)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_heads() {
+        let cases: [(&[u8], HttpError); 6] = [
+            (b"HTTP/2 200 OK\r\n\r\n", HttpError::InvalidStatusLine),
+            (b"HTTP/1.1 20 OK\r\n\r\n", HttpError::InvalidStatusLine),
+            (b"HTTP/1.1 999 Nope\r\n\r\n", HttpError::InvalidStatusLine),
+            (b"garbage\r\n\r\n", HttpError::InvalidStatusLine),
+            (
+                b"HTTP/1.1 200 OK\r\nNo colon\r\n\r\n",
+                HttpError::InvalidHeader,
+            ),
+            (
+                b"HTTP/1.1 200 OK\r\nKey : value\r\n\r\n",
+                HttpError::InvalidHeader,
+            ),
+        ];
+
+        for (stream, error) in cases {
+            assert_eq!(parse_head(stream, stream.len()), Err(error));
+        }
+    }
+
+    fn head(headers: &[(&str, &str)]) -> ResponseHead {
+        ResponseHead {
+            version: 1,
+            status: 200,
+            reason: "OK".to_string(),
+            headers: headers
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn picks_the_framing() {
+        assert_eq!(
+            head(&[("Content-Length", "42")]).framing(&Method::GET),
+            Ok(Framing::Length(42))
+        );
+        assert_eq!(
+            head(&[("Content-Length", "42"), ("content-length", "42, 42")]).framing(&Method::GET),
+            Ok(Framing::Length(42))
+        );
+        assert_eq!(
+            head(&[("Content-Length", "42"), ("Content-Length", "43")]).framing(&Method::GET),
+            Err(HttpError::InvalidContentLength)
+        );
+        assert_eq!(
+            head(&[("Content-Length", "-1")]).framing(&Method::GET),
+            Err(HttpError::InvalidContentLength)
+        );
+        // Chunked wins over a length, but only as the final coding
+        assert_eq!(
+            head(&[
+                ("Transfer-Encoding", "gzip, chunked"),
+                ("Content-Length", "42")
+            ])
+            .framing(&Method::GET),
+            Ok(Framing::Chunked)
+        );
+        assert_eq!(
+            head(&[("Transfer-Encoding", "chunked, gzip")]).framing(&Method::GET),
+            Ok(Framing::Close)
+        );
+        assert_eq!(head(&[]).framing(&Method::GET), Ok(Framing::Close));
+        assert_eq!(
+            head(&[("Content-Length", "42")]).framing(&Method::HEAD),
+            Ok(Framing::Length(0))
+        );
+    }
+
+    #[test]
+    fn parses_content_ranges() {
+        assert_eq!(
+            head(&[("Content-Range", "bytes 100-199/1000")]).content_range(),
+            Some(ContentRange {
+                start: 100,
+                end: 199,
+                total: Some(1000),
+            })
+        );
+        assert_eq!(
+            head(&[("Content-Range", "bytes 0-9/*")]).content_range(),
+            Some(ContentRange {
+                start: 0,
+                end: 9,
+                total: None,
+            })
+        );
+        assert_eq!(
+            head(&[("Content-Range", "bytes 9-0/10")]).content_range(),
+            None
+        );
+        assert_eq!(
+            head(&[("Content-Range", "items 0-9/10")]).content_range(),
+            None
+        );
+    }
+
+    #[test]
+    fn decodes_content_length_bodies() {
+        for step in 1..=8 {
+            for space in [1, 3, 64] {
+                assert_eq!(
+                    decode(Framing::Length(5), b"hello", step, space).unwrap(),
+                    b"hello"
+                );
+            }
+        }
+
+        // Bytes after the body belong to the next response
+        let mut decoder = BodyDecoder::new(Framing::Length(5));
+        let mut out = [0; 16];
+        let decoded = decoder.decode(b"helloHTTP/1.1", &mut out).unwrap();
+        assert_eq!(
+            decoded,
+            Decoded {
+                consumed: 5,
+                produced: 5
+            }
+        );
+        assert!(decoder.is_done());
+    }
+
+    #[test]
+    fn fails_on_short_content_length_bodies() {
+        assert_eq!(
+            decode(Framing::Length(10), b"hello", 5, 16),
+            Err(HttpError::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn decodes_close_delimited_bodies() {
+        assert_eq!(
+            decode(Framing::Close, b"until the end", 4, 3).unwrap(),
+            b"until the end"
+        );
+    }
+
+    const CHUNKED: &[u8] = b"5;name=value\r\nhello\r\n\
+        7 ; quoted=\"a;b\"\r\n, world\r\n\
+        A\r\n! chunked!\r\n\
+        0\r\n\
+        Expires: never\r\n\
+        X-Trailer: yes\r\n\
+        \r\n";
+
+    #[test]
+    fn decodes_chunked_bodies_with_extensions_and_trailers() {
+        for step in 1..CHUNKED.len() {
+            for space in [1, 4, 64] {
+                assert_eq!(
+                    decode(Framing::Chunked, CHUNKED, step, space).unwrap(),
+                    b"hello, world! chunked!"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn stops_after_the_last_chunk() {
+        let mut stream = CHUNKED.to_vec();
+        stream.extend_from_slice(b"HTTP/1.1 200 OK\r\n");
+
+        let mut decoder = BodyDecoder::new(Framing::Chunked);
+        let mut out = [0; 64];
+        let decoded = decoder.decode(&stream, &mut out).unwrap();
+        assert_eq!(decoded.consumed, CHUNKED.len());
+        assert!(decoder.is_done());
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        let cases: [&[u8]; 5] = [
+            b"x\r\n",
+            b"\r\n",
+            b"5\r\nhello!\r\n",
+            b"5\rxhello\r\n",
+            b"10000000000000000\r\n",
+        ];
+
+        for stream in cases {
+            assert_eq!(
+                decode(Framing::Chunked, stream, stream.len(), 64),
+                Err(HttpError::InvalidChunk),
+                "{}",
+                String::from_utf8_lossy(stream)
+            );
+        }
+    }
+
+    #[test]
+    fn fails_on_truncated_chunked_bodies() {
+        assert_eq!(
+            decode(Framing::Chunked, b"5\r\nhello\r\n", 3, 64),
+            Err(HttpError::UnexpectedEof)
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! Keeps the beacon on Wi-Fi for as long as it runs.
+//!
+//! The supervisor owns the Wi-Fi driver, watches the system event loop for disconnects and
+//! rejoins the known networks with exponential backoff. Everything else watches the published
+//! [`Connectivity`] instead of touching the driver.
+
+use embassy_time::{Duration, Timer};
+use esp_idf_svc::eventloop::{EspAsyncSubscription, EspSystemEventLoop, System};
+use esp_idf_svc::wifi::{AsyncWifi, EspWifi, WifiEvent};
+use log::{info, warn};
+use tokio::sync::watch;
+
+use super::wifi::{connect_with, rank_networks, start_station, wifi_error};
+use super::{backoff, Connectivity, NetError};
+use crate::credentials::CredentialStore;
+
+pub struct WifiSupervisor {
+    wifi: AsyncWifi<EspWifi<'static>>,
+    credentials: CredentialStore,
+    events: EspAsyncSubscription<WifiEvent<'static>, System>,
+    state: watch::Sender<Connectivity>,
+}
+
+impl WifiSupervisor {
+    pub fn new(
+        wifi: AsyncWifi<EspWifi<'static>>,
+        credentials: CredentialStore,
+        sys_loop: &EspSystemEventLoop,
+    ) -> Result<Self, NetError> {
+        let events = sys_loop
+            .subscribe_async::<WifiEvent>()
+            .map_err(wifi_error)?;
+
+        let initial = match wifi.wifi().get_configuration() {
+            Ok(config) if wifi.is_connected().unwrap_or(false) => Connectivity::Online {
+                ssid: config
+                    .as_client_conf_ref()
+                    .map(|client| client.ssid.to_string())
+                    .unwrap_or_default(),
+            },
+            _ => Connectivity::Offline {
+                retry_in: Duration::from_secs(0),
+            },
+        };
+
+        Ok(Self {
+            wifi,
+            credentials,
+            events,
+            state: watch::channel(initial).0,
+        })
+    }
+
+    /// A handle that sees every change in connectivity
+    pub fn subscribe(&self) -> watch::Receiver<Connectivity> {
+        self.state.subscribe()
+    }
+
+    /// Supervises the connection forever
+    pub async fn run(mut self) -> ! {
+        loop {
+            if self.wifi.is_connected().unwrap_or(false) {
+                self.wait_for_disconnect().await;
+                warn!("Wi-Fi connection lost");
+                self.state.send_replace(Connectivity::Offline {
+                    retry_in: Duration::from_secs(0),
+                });
+            }
+
+            self.reconnect().await;
+        }
+    }
+
+    async fn wait_for_disconnect(&mut self) {
+        loop {
+            match self.events.recv().await {
+                // Stale events from our own reconnects can still be queued, so check the driver
+                Ok(WifiEvent::StaDisconnected(_)) if !self.wifi.is_connected().unwrap_or(false) => {
+                    return
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Wi-Fi event subscription failed: {e}");
+                    Timer::after_secs(1).await;
+                    if !self.wifi.is_connected().unwrap_or(false) {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Rotates through the known networks in rounds until one of them is joined
+    async fn reconnect(&mut self) {
+        let mut round = 0;
+
+        loop {
+            if let Err(e) = start_station(&mut self.wifi).await {
+                warn!("Could not start Wi-Fi: {e}");
+            }
+
+            let networks = match self.credentials.networks() {
+                Ok(networks) => networks,
+                Err(e) => {
+                    warn!("Could not read known networks: {e}");
+                    Vec::new()
+                }
+            };
+
+            for network in rank_networks(&mut self.wifi, networks).await {
+                self.state.send_replace(Connectivity::Connecting {
+                    ssid: network.ssid.clone(),
+                });
+
+                match connect_with(&mut self.wifi, &network).await {
+                    Ok(()) => {
+                        self.state
+                            .send_replace(Connectivity::Online { ssid: network.ssid });
+                        return;
+                    }
+                    Err(e) => warn!("Could not join {}: {e}", network.ssid),
+                }
+            }
+
+            let retry_in = backoff(round, random());
+            info!("No network joined, retrying in {}s", retry_in.as_secs());
+            self.state.send_replace(Connectivity::Offline { retry_in });
+
+            Timer::after(retry_in).await;
+            round = round.saturating_add(1);
+        }
+    }
+}
+
+fn random() -> u32 {
+    unsafe { esp_idf_svc::sys::esp_random() }
+}
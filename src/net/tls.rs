@@ -0,0 +1,393 @@
+//! How TLS servers are verified: against the ESP certificate bundle, or a custom CA for the
+//! self-hosted servers it is given for, optionally narrowed down by per-host pins.
+
+use core::fmt;
+use core::str::FromStr;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use sha2::{Digest, Sha256};
+
+const PEM_CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----";
+
+/// Why mbedtls rejected a certificate, from its `MBEDTLS_X509_BADCERT_*` flags
+const VERIFY_REASONS: &[(u32, &str)] = &[
+    (0x01, "certificate expired"),
+    (0x02, "certificate revoked"),
+    (0x04, "certificate is not for this host"),
+    (0x08, "certificate is not signed by a trusted CA"),
+    (0x40, "server sent no certificate"),
+    (0x0100, "certificate could not be verified"),
+    (0x0200, "certificate is not valid yet"),
+    (0x0800, "certificate key usage does not allow this"),
+    (0x1000, "certificate extended key usage does not allow this"),
+    (0x4000, "certificate uses an unacceptable hash"),
+    (0x8000, "certificate uses an unacceptable key type"),
+    (0x01_0000, "certificate key is too weak"),
+];
+
+#[derive(Debug)]
+pub enum TlsError {
+    /// The connection failed during the handshake, before the certificate was judged
+    Handshake(anyhow::Error),
+    /// The server's certificate did not verify, with mbedtls' `MBEDTLS_X509_BADCERT_*` flags
+    Untrusted(u32),
+    /// None of the pins for this host match the certificate it presented
+    PinMismatch(String),
+    /// The handshake finished without the server's certificate being kept around to check pins
+    MissingCertificate,
+    InvalidCa(String),
+    InvalidPin(String),
+}
+
+impl TlsError {
+    /// Whether another attempt might get through, as opposed to a server we do not trust
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::Handshake(_))
+    }
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Handshake(e) => write!(f, "{e}"),
+            Self::Untrusted(flags) => {
+                let mut reasons = VERIFY_REASONS
+                    .iter()
+                    .filter(|(flag, _)| flags & flag != 0)
+                    .map(|(_, reason)| *reason);
+
+                match reasons.next() {
+                    Some(first) => {
+                        f.write_str(first)?;
+                        reasons.try_for_each(|reason| write!(f, ", {reason}"))
+                    }
+                    None => write!(f, "certificate rejected (flags {flags:#x})"),
+                }
+            }
+            Self::PinMismatch(host) => write!(f, "certificate for {host} does not match its pins"),
+            Self::MissingCertificate => write!(f, "server certificate unavailable for pinning"),
+            Self::InvalidCa(reason) => write!(f, "invalid CA certificate: {reason}"),
+            Self::InvalidPin(pin) => write!(f, "invalid pin {pin:?}"),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+/// A SHA-256 fingerprint a host's certificate has to match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pin {
+    /// Of the whole DER certificate, written `cert-sha256/<base64>`. Breaks whenever the
+    /// certificate is renewed.
+    Certificate([u8; 32]),
+    /// Of the DER SubjectPublicKeyInfo, written `sha256/<base64>` as in HPKP. Survives renewals
+    /// that keep the key.
+    PublicKey([u8; 32]),
+}
+
+impl Pin {
+    /// Whether the DER certificate `cert` matches
+    pub fn matches(&self, cert: &[u8]) -> bool {
+        match self {
+            Self::Certificate(hash) => Sha256::digest(cert).as_slice() == hash,
+            Self::PublicKey(hash) => {
+                public_key_info(cert).is_some_and(|spki| Sha256::digest(spki).as_slice() == hash)
+            }
+        }
+    }
+}
+
+impl FromStr for Pin {
+    type Err = TlsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || TlsError::InvalidPin(s.to_string());
+
+        let (kind, hash) = s.split_once('/').ok_or_else(invalid)?;
+        let hash = BASE64_STANDARD
+            .decode(hash)
+            .ok()
+            .and_then(|hash| <[u8; 32]>::try_from(hash).ok())
+            .ok_or_else(invalid)?;
+
+        match kind {
+            "sha256" => Ok(Self::PublicKey(hash)),
+            "cert-sha256" => Ok(Self::Certificate(hash)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Certificate(hash) => write!(f, "cert-sha256/{}", BASE64_STANDARD.encode(hash)),
+            Self::PublicKey(hash) => write!(f, "sha256/{}", BASE64_STANDARD.encode(hash)),
+        }
+    }
+}
+
+/// Which servers TLS connections trust
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// Hosts and the PEM of the only CA to trust for them, NUL-terminated for mbedtls. Every
+    /// other host is checked against the certificate bundle.
+    cas: Vec<(String, Vec<u8>)>,
+    pins: Vec<(String, Pin)>,
+}
+
+impl TlsConfig {
+    /// Trusts the certificate bundle built into the firmware
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts only the CA in `pem` for `host`, a self-hosted server, so other hosts such as
+    /// GitHub keep the bundle
+    pub fn with_ca(mut self, host: &str, pem: &str) -> Result<Self, TlsError> {
+        if !pem.contains(PEM_CERTIFICATE) {
+            return Err(TlsError::InvalidCa("not a PEM certificate".to_string()));
+        }
+        if pem.contains('\0') {
+            return Err(TlsError::InvalidCa("contains a NUL byte".to_string()));
+        }
+
+        let mut ca = pem.as_bytes().to_vec();
+        ca.push(0);
+        self.cas
+            .retain(|(trusting, _)| !trusting.eq_ignore_ascii_case(host));
+        self.cas.push((host.to_ascii_lowercase(), ca));
+
+        Ok(self)
+    }
+
+    /// Requires `host` to present a certificate matching `pin`, or any other pin added for it
+    pub fn with_pin(mut self, host: &str, pin: Pin) -> Self {
+        self.pins.push((host.to_ascii_lowercase(), pin));
+        self
+    }
+
+    /// The custom CA for `host` as NUL-terminated PEM, if it has one
+    pub fn ca(&self, host: &str) -> Option<&[u8]> {
+        self.cas
+            .iter()
+            .find(|(trusting, _)| trusting.eq_ignore_ascii_case(host))
+            .map(|(_, ca)| ca.as_slice())
+    }
+
+    pub fn is_pinned(&self, host: &str) -> bool {
+        self.pins
+            .iter()
+            .any(|(pinned, _)| pinned.eq_ignore_ascii_case(host))
+    }
+
+    /// Checks the DER certificate `cert` presented by `host` against its pins, if it has any
+    pub fn check_pins(&self, host: &str, cert: &[u8]) -> Result<(), TlsError> {
+        let mut pins = self
+            .pins
+            .iter()
+            .filter(|(pinned, _)| pinned.eq_ignore_ascii_case(host))
+            .peekable();
+
+        if pins.peek().is_none() || pins.any(|(_, pin)| pin.matches(cert)) {
+            Ok(())
+        } else {
+            Err(TlsError::PinMismatch(host.to_string()))
+        }
+    }
+}
+
+/// Splits the DER element at the start of `der` into its tag, its whole encoding and its
+/// contents
+fn der_element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+
+    let (len, rest) = if first & 0x80 == 0 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let len = rest[..count]
+            .iter()
+            .fold(0, |len, byte| len << 8 | *byte as usize);
+        (len, &rest[count..])
+    };
+
+    let header = der.len() - rest.len();
+    let contents = rest.get(..len)?;
+
+    Some((tag, &der[..header + len], contents))
+}
+
+/// The encoded SubjectPublicKeyInfo of a DER certificate
+fn public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const VERSION: u8 = 0xa0;
+
+    let (SEQUENCE, _, cert) = der_element(cert)? else {
+        return None;
+    };
+    let (SEQUENCE, _, mut tbs) = der_element(cert)? else {
+        return None;
+    };
+
+    // The version is optional, then the serial number, signature algorithm, issuer, validity
+    // and subject come before the key
+    let mut skip = 5;
+    if tbs.first() == Some(&VERSION) {
+        skip += 1;
+    }
+    for _ in 0..skip {
+        let (_, element, _) = der_element(tbs)?;
+        tbs = &tbs[element.len()..];
+    }
+
+    match der_element(tbs)? {
+        (SEQUENCE, spki, _) => Some(spki),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEM: &str = "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n";
+
+    /// Encodes a DER element, with a long-form length where it needs one
+    fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut element = vec![tag];
+        match contents.len() {
+            len @ 0..=0x7f => element.push(len as u8),
+            len @ 0x80..=0xff => element.extend([0x81, len as u8]),
+            len => element.extend([0x82, (len >> 8) as u8, len as u8]),
+        }
+        element.extend(contents);
+        element
+    }
+
+    fn spki(key: &[u8]) -> Vec<u8> {
+        let algorithm = der(0x30, &der(0x06, &[0x2b, 0x65, 0x70]));
+        der(0x30, &[algorithm, der(0x03, key)].concat())
+    }
+
+    /// A certificate laid out like a real one, though nothing in it is signed
+    fn certificate(versioned: bool, spki: &[u8]) -> Vec<u8> {
+        let name = der(0x30, &der(0x31, &der(0x30, &der(0x0c, b"beacon"))));
+        let mut tbs = Vec::new();
+        if versioned {
+            tbs.extend(der(0xa0, &der(0x02, &[2])));
+        }
+        tbs.extend(der(0x02, &[1]));
+        tbs.extend(der(0x30, &der(0x06, &[0x2b, 0x65, 0x70])));
+        tbs.extend(&name);
+        tbs.extend(der(
+            0x30,
+            &[der(0x17, b"250101"), der(0x17, b"350101")].concat(),
+        ));
+        tbs.extend(&name);
+        tbs.extend(spki);
+
+        let signature = [
+            der(0x30, &der(0x06, &[0x2b, 0x65, 0x70])),
+            der(0x03, &[0; 65]),
+        ];
+        der(0x30, &[der(0x30, &tbs), signature.concat()].concat())
+    }
+
+    fn hash(data: &[u8]) -> [u8; 32] {
+        Sha256::digest(data).into()
+    }
+
+    #[test]
+    fn parses_and_writes_pins() {
+        let pin = "sha256/47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+        assert_eq!(pin.parse::<Pin>().unwrap(), Pin::PublicKey(hash(b"")));
+        assert_eq!(pin.parse::<Pin>().unwrap().to_string(), pin);
+
+        let pin = Pin::Certificate(hash(b"certificate"));
+        assert_eq!(pin.to_string().parse::<Pin>().unwrap(), pin);
+
+        for invalid in [
+            "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+            "sha1/47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+            "sha256/47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hS",
+            "sha256/not base64!",
+        ] {
+            assert!(
+                matches!(invalid.parse::<Pin>(), Err(TlsError::InvalidPin(pin)) if pin == invalid),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn finds_the_public_key_with_or_without_a_version() {
+        let key = spki(&[7; 33]);
+        assert_eq!(public_key_info(&certificate(true, &key)), Some(&key[..]));
+        assert_eq!(public_key_info(&certificate(false, &key)), Some(&key[..]));
+
+        // Long enough to need multi-byte lengths all the way up
+        let key = spki(&[7; 300]);
+        assert_eq!(public_key_info(&certificate(true, &key)), Some(&key[..]));
+    }
+
+    #[test]
+    fn rejects_malformed_certificates() {
+        let cert = certificate(true, &spki(&[7; 33]));
+
+        assert_eq!(public_key_info(&cert[..cert.len() - 80]), None);
+        assert_eq!(public_key_info(&[]), None);
+        assert_eq!(public_key_info(&der(0x02, &cert)), None);
+        // A key that is not a sequence
+        assert_eq!(
+            public_key_info(&certificate(true, &der(0x04, &[7; 33]))),
+            None
+        );
+    }
+
+    #[test]
+    fn checks_only_the_pins_of_the_host() {
+        let key = spki(&[7; 33]);
+        let cert = certificate(true, &key);
+        let config = TlsConfig::new()
+            .with_pin(
+                "Beacons.example",
+                Pin::Certificate(hash(b"old certificate")),
+            )
+            .with_pin("beacons.example", Pin::PublicKey(hash(&key)))
+            .with_pin("github.com", Pin::Certificate(hash(b"other certificate")));
+
+        assert!(config.is_pinned("beacons.EXAMPLE"));
+        assert!(!config.is_pinned("example.com"));
+
+        assert!(config.check_pins("beacons.example", &cert).is_ok());
+        assert!(config.check_pins("example.com", &cert).is_ok());
+        assert!(matches!(
+            config.check_pins("github.com", &cert),
+            Err(TlsError::PinMismatch(host)) if host == "github.com"
+        ));
+
+        let config = TlsConfig::new().with_pin("github.com", Pin::Certificate(hash(&cert)));
+        assert!(config.check_pins("github.com", &cert).is_ok());
+    }
+
+    #[test]
+    fn trusts_a_custom_ca_only_for_its_host() {
+        let config = TlsConfig::new().with_ca("Beacons.example", PEM).unwrap();
+
+        assert_eq!(
+            config.ca("beacons.example"),
+            Some(format!("{PEM}\0").as_bytes())
+        );
+        assert_eq!(config.ca("api.github.com"), None);
+
+        assert!(matches!(
+            TlsConfig::new().with_ca("beacons.example", "MIIB"),
+            Err(TlsError::InvalidCa(_))
+        ));
+    }
+}
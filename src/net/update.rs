@@ -0,0 +1,116 @@
+//! Signed over-the-air updates from GitHub releases.
+
+use anyhow::anyhow;
+use dotenvy_macro::dotenv;
+use esp_idf_svc::hal::reset::restart;
+use esp_idf_svc::io::Write;
+use esp_idf_svc::ota::EspOta;
+use esp_idf_svc::sys::EspError;
+use log::info;
+
+use super::download::download;
+use super::release::{select_update, GithubResponse, Target, UpdateChannel};
+use super::{HttpsClient, NetError, Progress};
+use crate::convert_error;
+use crate::verify::{parse_hex_key, ImageVerifier, PUBLIC_KEY_LEN, SIGNATURE_LEN};
+
+/// Public half of the key releases are signed with
+const RELEASE_PUBLIC_KEY: [u8; PUBLIC_KEY_LEN] = parse_hex_key(dotenv!("RELEASE_PUBLIC_KEY"));
+
+/// Checks `channel` for a newer release and, if there is one, flashes and boots into it.
+///
+/// `on_progress` is called as the image downloads so the caller can show it on the outputs.
+pub async fn self_update(
+    client: &HttpsClient,
+    channel: UpdateChannel,
+    mut on_progress: impl FnMut(Progress),
+) -> Result<(), NetError> {
+    info!("Checking for self-update");
+
+    let releases: Vec<GithubResponse> = {
+        let url = "https://api.github.com/repos/purduehackers/beacons/releases?per_page=10";
+
+        let mut response = client.get(url).await?.error_for_status()?;
+        let body = response.read_to_end().await?;
+
+        // A captive portal or an outage can answer with anything
+        serde_json::from_slice(&body).map_err(|e| NetError::parse("GitHub releases", e))?
+    };
+
+    let local = semver::Version::parse(env!("CARGO_PKG_VERSION"))
+        .map_err(|e| NetError::parse("firmware version", e))?;
+
+    if let Some(candidate) = select_update(&releases, channel, &local, Target::this_build()) {
+        info!(
+            "New {channel} release {} found! Downloading and updating",
+            candidate.version
+        );
+        // Grab new release and update
+        let image = candidate.image;
+        let signature_asset = candidate.signature;
+
+        let signature = client
+            .get(&signature_asset.browser_download_url)
+            .await?
+            .error_for_status()?
+            .read_to_end()
+            .await?;
+
+        if signature.len() != SIGNATURE_LEN {
+            return Err(NetError::parse(
+                "signature",
+                format!("{} bytes instead of {SIGNATURE_LEN}", signature.len()),
+            ));
+        }
+
+        let mut verifier =
+            ImageVerifier::new(&RELEASE_PUBLIC_KEY).map_err(|e| NetError::Update(e.into()))?;
+
+        let mut ota = EspOta::new().map_err(ota_error)?;
+
+        let mut update = ota.initiate_update().map_err(ota_error)?;
+
+        let downloaded = download(
+            client,
+            &image.browser_download_url,
+            |data| {
+                verifier.update(data);
+                update
+                    .write_all(data)
+                    .map_err(|e| NetError::Update(anyhow!("writing update data: {e:?}")))
+            },
+            &mut on_progress,
+        )
+        .await;
+
+        if let Err(e) = downloaded {
+            update.abort().map_err(ota_error)?;
+            return Err(e);
+        }
+
+        info!("Download completed, verifying {} bytes", verifier.len());
+
+        if let Err(e) = verifier.verify(&signature) {
+            update.abort().map_err(ota_error)?;
+            return Err(NetError::Update(anyhow!("rejecting image: {e}")));
+        }
+
+        info!("Signature OK! Activating...");
+
+        update
+            .finish()
+            .map_err(ota_error)?
+            .activate()
+            .map_err(ota_error)?;
+
+        restart();
+    } else {
+        info!("Already on latest version.");
+    }
+
+    Ok(())
+}
+
+fn ota_error(e: EspError) -> NetError {
+    NetError::Update(convert_error(e))
+}
@@ -0,0 +1,798 @@
+//! WebSocket client (RFC 6455), for the companion site's push channel.
+//!
+//! Framing is done by [`FrameDecoder`] and [`MessageAssembler`], which are fed bytes the same way
+//! as the HTTP [`HeadParser`], and [`WebSocket`] drives them over any [`Connection`]: TLS on the
+//! device, or plain TCP against a local server on a dev machine.
+
+use core::fmt;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use embassy_time::{with_timeout, Duration};
+use http::Request;
+use sha1::{Digest, Sha1};
+
+use super::response::{HeadParser, HttpError, ResponseHead};
+use super::{create_raw_request_no_body, Connection, NetError};
+
+/// Appended to the client's key before hashing it into `Sec-WebSocket-Accept`
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message we are willing to reassemble
+pub const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+const READ_BUF_LEN: usize = 1024;
+
+#[derive(Debug)]
+pub enum WsError {
+    Http(HttpError),
+    /// The server did not switch protocols
+    Rejected(u16),
+    InvalidAccept,
+    /// The server broke the framing rules
+    Protocol(&'static str),
+    MessageTooLarge,
+    /// The server closed the connection, with its status code if it sent one
+    Closed(Option<u16>),
+    Transport(NetError),
+}
+
+impl fmt::Display for WsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Http(e) => write!(f, "handshake: {e}"),
+            Self::Rejected(status) => write!(f, "upgrade rejected with status {status}"),
+            Self::InvalidAccept => write!(f, "wrong Sec-WebSocket-Accept"),
+            Self::Protocol(reason) => write!(f, "protocol violation: {reason}"),
+            Self::MessageTooLarge => write!(f, "message exceeds {MAX_MESSAGE_LEN} bytes"),
+            Self::Closed(Some(code)) => write!(f, "closed by server with code {code}"),
+            Self::Closed(None) => write!(f, "closed by server"),
+            Self::Transport(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for WsError {}
+
+impl From<HttpError> for WsError {
+    fn from(e: HttpError) -> Self {
+        Self::Http(e)
+    }
+}
+
+impl From<NetError> for WsError {
+    fn from(e: NetError) -> Self {
+        Self::Transport(e)
+    }
+}
+
+/// Upgrade request for `path` (with any query) on `host`
+pub fn handshake_request(host: &str, path: &str, key: &str) -> Result<String, NetError> {
+    let request = Request::get(path)
+        .header("Host", host)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Key", key)
+        .header("Sec-WebSocket-Version", "13")
+        .header("User-Agent", super::USER_AGENT)
+        .body(())
+        .map_err(NetError::InvalidRequest)?;
+
+    Ok(create_raw_request_no_body(&request))
+}
+
+/// The `Sec-WebSocket-Accept` a server must answer `key` with
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+
+    BASE64.encode(hasher.finalize())
+}
+
+/// Checks that the response to an upgrade request with `key` accepted it
+pub fn check_handshake(head: &ResponseHead, key: &str) -> Result<(), WsError> {
+    if head.status != 101 {
+        return Err(WsError::Rejected(head.status));
+    }
+
+    let upgrade = head.header("upgrade").unwrap_or_default();
+    if !upgrade.eq_ignore_ascii_case("websocket") {
+        return Err(WsError::Protocol(
+            "upgraded to something other than websocket",
+        ));
+    }
+
+    match head.header("sec-websocket-accept") {
+        Some(accept) if accept == accept_key(key) => Ok(()),
+        _ => Err(WsError::InvalidAccept),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_bits(bits: u8) -> Option<Self> {
+        Some(match bits {
+            0x0 => Self::Continuation,
+            0x1 => Self::Text,
+            0x2 => Self::Binary,
+            0x8 => Self::Close,
+            0x9 => Self::Ping,
+            0xA => Self::Pong,
+            _ => return None,
+        })
+    }
+
+    const fn bits(self) -> u8 {
+        match self {
+            Self::Continuation => 0x0,
+            Self::Text => 0x1,
+            Self::Binary => 0x2,
+            Self::Close => 0x8,
+            Self::Ping => 0x9,
+            Self::Pong => 0xA,
+        }
+    }
+
+    pub const fn is_control(self) -> bool {
+        matches!(self, Self::Close | Self::Ping | Self::Pong)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Whether this is the last frame of its message
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+/// Encodes a frame as a client sends it, masked with `mask`
+pub fn encode_frame(fin: bool, opcode: Opcode, payload: &[u8], mask: [u8; 4]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(((fin as u8) << 7) | opcode.bits());
+
+    match payload.len() {
+        len @ 0..=125 => frame.push(0x80 | len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    frame.extend_from_slice(&mask);
+    frame.extend(
+        payload
+            .iter()
+            .zip(mask.iter().cycle())
+            .map(|(byte, mask)| byte ^ mask),
+    );
+
+    frame
+}
+
+/// Splits the bytes coming from the server into frames
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Takes the next complete frame out of what has been fed so far
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, WsError> {
+        let [first, second, ..] = self.buf[..] else {
+            return Ok(None);
+        };
+
+        if first & 0x70 != 0 {
+            return Err(WsError::Protocol("reserved bits set"));
+        }
+        // Servers never mask what they send
+        if second & 0x80 != 0 {
+            return Err(WsError::Protocol("masked server frame"));
+        }
+
+        let fin = first & 0x80 != 0;
+        let opcode = Opcode::from_bits(first & 0x0F).ok_or(WsError::Protocol("unknown opcode"))?;
+
+        let (len, header_len) = match second & 0x7F {
+            126 => {
+                let Some(bytes) = self.buf.get(2..4) else {
+                    return Ok(None);
+                };
+                (u16::from_be_bytes([bytes[0], bytes[1]]) as u64, 4)
+            }
+            127 => {
+                let Some(bytes) = self.buf.get(2..10) else {
+                    return Ok(None);
+                };
+                (
+                    u64::from_be_bytes(bytes.try_into().expect("eight bytes")),
+                    10,
+                )
+            }
+            len => (len as u64, 2),
+        };
+
+        if opcode.is_control() && (!fin || len > 125) {
+            return Err(WsError::Protocol("oversized or fragmented control frame"));
+        }
+        if len > MAX_MESSAGE_LEN as u64 {
+            return Err(WsError::MessageTooLarge);
+        }
+
+        let end = header_len + len as usize;
+        if self.buf.len() < end {
+            return Ok(None);
+        }
+
+        let payload = self.buf[header_len..end].to_vec();
+        self.buf.drain(..end);
+
+        Ok(Some(Frame {
+            fin,
+            opcode,
+            payload,
+        }))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The server's close status code, if it sent one
+    Close(Option<u16>),
+}
+
+/// Joins fragmented frames back into messages, passing control frames straight through
+#[derive(Debug, Default)]
+pub struct MessageAssembler {
+    partial: Option<(Opcode, Vec<u8>)>,
+}
+
+impl MessageAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a frame, returning the message it completes, if any
+    pub fn push(&mut self, frame: Frame) -> Result<Option<Message>, WsError> {
+        let Frame {
+            fin,
+            opcode,
+            payload,
+        } = frame;
+
+        // Control frames may arrive in the middle of a fragmented message
+        match opcode {
+            Opcode::Ping => return Ok(Some(Message::Ping(payload))),
+            Opcode::Pong => return Ok(Some(Message::Pong(payload))),
+            Opcode::Close => {
+                let code = payload
+                    .get(..2)
+                    .map(|code| u16::from_be_bytes([code[0], code[1]]));
+                return Ok(Some(Message::Close(code)));
+            }
+            _ => {}
+        }
+
+        let (opcode, data) = match (opcode, self.partial.take()) {
+            (Opcode::Continuation, Some((opcode, mut data))) => {
+                if data.len() + payload.len() > MAX_MESSAGE_LEN {
+                    return Err(WsError::MessageTooLarge);
+                }
+                data.extend_from_slice(&payload);
+                (opcode, data)
+            }
+            (Opcode::Continuation, None) => {
+                return Err(WsError::Protocol("continuation without a message"))
+            }
+            (_, Some(_)) => return Err(WsError::Protocol("new message before the last ended")),
+            (opcode, None) => (opcode, payload),
+        };
+
+        if !fin {
+            self.partial = Some((opcode, data));
+            return Ok(None);
+        }
+
+        match opcode {
+            Opcode::Text => String::from_utf8(data)
+                .map(|text| Some(Message::Text(text)))
+                .map_err(|_| WsError::Protocol("text message is not UTF-8")),
+            _ => Ok(Some(Message::Binary(data))),
+        }
+    }
+}
+
+/// The code to answer a close frame carrying `code` with.
+///
+/// Codes such as 1005 (no code given) and 1006 (dropped connection) only describe a close
+/// locally and must never go out in a frame, so those and anything unknown become 1000.
+fn echo_code(code: Option<u16>) -> u16 {
+    code.filter(|code| matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999))
+        .unwrap_or(1000)
+}
+
+/// Client end of a WebSocket over `C`
+pub struct WebSocket<C, R> {
+    conn: C,
+    decoder: FrameDecoder,
+    assembler: MessageAssembler,
+    /// Picks the handshake key and every frame mask
+    random: R,
+}
+
+impl<C, R> WebSocket<C, R>
+where
+    C: Connection,
+    R: FnMut() -> u32,
+{
+    /// Performs the upgrade handshake for `path` on `host` over an open connection.
+    ///
+    /// `random` has to be a real random source, such as the hardware RNG: the handshake key goes
+    /// out in the clear, so masks derived from it would be predictable to anyone on the path.
+    pub async fn connect(
+        mut conn: C,
+        host: &str,
+        path: &str,
+        mut random: R,
+    ) -> Result<Self, WsError> {
+        let mut nonce = [0; 16];
+        for chunk in nonce.chunks_mut(4) {
+            chunk.copy_from_slice(&random().to_le_bytes());
+        }
+        let key = BASE64.encode(nonce);
+        conn.write_all(handshake_request(host, path, &key)?.as_bytes())
+            .await?;
+
+        let mut parser = HeadParser::new();
+        let mut decoder = FrameDecoder::new();
+        let mut buf = [0; READ_BUF_LEN];
+
+        loop {
+            let read = conn.read(&mut buf).await?;
+            if read == 0 {
+                return Err(HttpError::UnexpectedEof.into());
+            }
+
+            if let Some((head, used)) = parser.feed(&buf[..read])? {
+                check_handshake(&head, &key)?;
+                // The server may start sending frames right behind its response
+                decoder.feed(&buf[used..read]);
+                break;
+            }
+        }
+
+        Ok(Self {
+            conn,
+            decoder,
+            assembler: MessageAssembler::new(),
+            random,
+        })
+    }
+
+    pub async fn send_text(&mut self, text: &str) -> Result<(), WsError> {
+        self.send(Opcode::Text, text.as_bytes()).await
+    }
+
+    pub async fn ping(&mut self, payload: &[u8]) -> Result<(), WsError> {
+        self.send(Opcode::Ping, payload).await
+    }
+
+    /// Starts a clean close with `code`; the connection should be dropped afterwards
+    pub async fn close(&mut self, code: u16) -> Result<(), WsError> {
+        self.send(Opcode::Close, &code.to_be_bytes()).await
+    }
+
+    /// Waits for the next message, answering pings and close frames along the way
+    pub async fn recv(&mut self) -> Result<Message, WsError> {
+        loop {
+            if let Some(message) = self.next_message(None).await? {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Like [`recv`](Self::recv), but gives up with `None` once the server has been quiet for
+    /// `quiet`.
+    ///
+    /// Only the wait for the server is cut short, never a reply being written, so the
+    /// connection stays usable afterwards.
+    pub async fn recv_within(&mut self, quiet: Duration) -> Result<Option<Message>, WsError> {
+        self.next_message(Some(quiet)).await
+    }
+
+    async fn next_message(&mut self, quiet: Option<Duration>) -> Result<Option<Message>, WsError> {
+        let mut buf = [0; READ_BUF_LEN];
+
+        loop {
+            while let Some(frame) = self.decoder.next_frame()? {
+                match self.assembler.push(frame)? {
+                    Some(Message::Ping(payload)) => {
+                        self.send(Opcode::Pong, &payload).await?;
+                    }
+                    Some(Message::Close(code)) => {
+                        // Echo the close so the server can drop the connection cleanly
+                        let _ = self.close(echo_code(code)).await;
+                        return Err(WsError::Closed(code));
+                    }
+                    Some(message) => return Ok(Some(message)),
+                    None => {}
+                }
+            }
+
+            let read = match quiet {
+                Some(quiet) => match with_timeout(quiet, self.conn.read(&mut buf)).await {
+                    Ok(read) => read?,
+                    Err(_) => return Ok(None),
+                },
+                None => self.conn.read(&mut buf).await?,
+            };
+            if read == 0 {
+                return Err(WsError::Closed(None));
+            }
+            self.decoder.feed(&buf[..read]);
+        }
+    }
+
+    async fn send(&mut self, opcode: Opcode, payload: &[u8]) -> Result<(), WsError> {
+        let mask = self.next_mask();
+        self.conn
+            .write_all(&encode_frame(true, opcode, payload, mask))
+            .await?;
+
+        Ok(())
+    }
+
+    fn next_mask(&mut self) -> [u8; 4] {
+        (self.random)().to_le_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread::{self, JoinHandle};
+
+    use async_io::Async;
+
+    use super::*;
+    use crate::testing::block_on;
+
+    /// What a client sent, frame by frame
+    type Received = Vec<(Opcode, Vec<u8>)>;
+
+    /// An unmasked frame, as a server sends it
+    fn server_frame(opcode: Opcode, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x80 | opcode.bits(), payload.len() as u8];
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Reads a client frame, returning its opcode, its unmasked payload and its mask
+    fn read_frame(stream: &mut TcpStream) -> (Opcode, Vec<u8>, [u8; 4]) {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).unwrap();
+        assert_eq!(head[1] & 0x80, 0x80, "client frames are masked");
+
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).unwrap();
+                u16::from_be_bytes(len) as usize
+            }
+            len => len as usize,
+        };
+
+        let mut mask = [0; 4];
+        stream.read_exact(&mut mask).unwrap();
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).unwrap();
+        for (byte, mask) in payload.iter_mut().zip(mask.iter().cycle()) {
+            *byte ^= mask;
+        }
+
+        (Opcode::from_bits(head[0] & 0x0F).unwrap(), payload, mask)
+    }
+
+    /// Accepts a connection on `listener` and answers its upgrade request
+    fn upgrade(listener: &TcpListener) -> TcpStream {
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head).unwrap();
+        let key = head
+            .lines()
+            .filter_map(|line| line.split_once(": "))
+            .find(|(name, _)| name.eq_ignore_ascii_case("sec-websocket-key"))
+            .map(|(_, key)| key.to_string())
+            .unwrap();
+
+        write!(
+            stream,
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(&key)
+        )
+        .unwrap();
+
+        stream
+    }
+
+    /// Connects to a local server at `addr`, drawing masks from a counter
+    async fn connect(addr: &str) -> WebSocket<Async<TcpStream>, impl FnMut() -> u32> {
+        let conn = Async::<TcpStream>::connect(addr.parse::<std::net::SocketAddr>().unwrap())
+            .await
+            .unwrap();
+        let mut counter = 0;
+        let random = move || {
+            counter += 1;
+            counter
+        };
+
+        WebSocket::connect(conn, addr, "/events?v=1", random)
+            .await
+            .unwrap()
+    }
+
+    /// Echoes text messages on a local port until the client sends "bye", then closes with
+    /// `close` as the payload. Returns the address and everything the client sent.
+    fn echo_server(close: Vec<u8>) -> (String, JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let server = thread::spawn(move || {
+            let mut stream = upgrade(&listener);
+            stream
+                .write_all(&server_frame(Opcode::Ping, b"hi"))
+                .unwrap();
+
+            let mut received = Vec::new();
+            loop {
+                let (opcode, payload, _) = read_frame(&mut stream);
+                received.push((opcode, payload.clone()));
+
+                match opcode {
+                    Opcode::Text => {
+                        stream
+                            .write_all(&server_frame(Opcode::Text, &payload))
+                            .unwrap();
+                        if payload == b"bye" {
+                            stream
+                                .write_all(&server_frame(Opcode::Close, &close))
+                                .unwrap();
+                        }
+                    }
+                    Opcode::Close => return received,
+                    _ => {}
+                }
+            }
+        });
+
+        (addr, server)
+    }
+
+    /// Talks to an echo server closing with `close`, returning the close error and what the
+    /// server received
+    fn talk(close: &[u8]) -> (WsError, Received) {
+        let (addr, server) = echo_server(close.to_vec());
+
+        let error = block_on(async {
+            let mut ws = connect(&addr).await;
+
+            ws.send_text("hello").await.unwrap();
+            assert_eq!(ws.recv().await.unwrap(), Message::Text("hello".to_string()));
+            ws.send_text("bye").await.unwrap();
+            assert_eq!(ws.recv().await.unwrap(), Message::Text("bye".to_string()));
+
+            ws.recv().await.unwrap_err()
+        });
+
+        (error, server.join().unwrap())
+    }
+
+    #[test]
+    fn talks_to_an_echo_server() {
+        let (error, received) = talk(&[0x03, 0xE9]);
+
+        assert!(matches!(error, WsError::Closed(Some(1001))));
+        assert_eq!(
+            received,
+            [
+                (Opcode::Text, b"hello".to_vec()),
+                (Opcode::Pong, b"hi".to_vec()),
+                (Opcode::Text, b"bye".to_vec()),
+                (Opcode::Close, vec![0x03, 0xE9]),
+            ]
+        );
+    }
+
+    #[test]
+    fn never_echoes_reserved_close_codes() {
+        let (error, received) = talk(&[]);
+        assert!(matches!(error, WsError::Closed(None)));
+        assert_eq!(received.last().unwrap(), &(Opcode::Close, vec![0x03, 0xE8]));
+
+        for reserved in [1005u16, 1006, 1015] {
+            let (error, received) = talk(&reserved.to_be_bytes());
+            assert!(matches!(error, WsError::Closed(Some(code)) if code == reserved));
+            assert_eq!(received.last().unwrap(), &(Opcode::Close, vec![0x03, 0xE8]));
+        }
+    }
+
+    #[test]
+    fn draws_every_mask_from_the_random_source() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let mut stream = upgrade(&listener);
+            [read_frame(&mut stream).2, read_frame(&mut stream).2]
+        });
+
+        block_on(async {
+            let mut ws = connect(&addr).await;
+            ws.send_text("one").await.unwrap();
+            ws.send_text("two").await.unwrap();
+        });
+
+        // The first four numbers went into the handshake key
+        assert_eq!(
+            server.join().unwrap(),
+            [5u32.to_le_bytes(), 6u32.to_le_bytes()]
+        );
+    }
+
+    #[test]
+    fn answers_pings_in_full_while_waiting_for_a_quiet_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let mut stream = upgrade(&listener);
+            stream
+                .write_all(&server_frame(Opcode::Ping, b"hi"))
+                .unwrap();
+            let pong = read_frame(&mut stream);
+            let next = read_frame(&mut stream);
+            [(pong.0, pong.1), (next.0, next.1)]
+        });
+
+        block_on(async {
+            let mut ws = connect(&addr).await;
+            let quiet = ws.recv_within(Duration::from_millis(50)).await.unwrap();
+            assert_eq!(quiet, None);
+            ws.send_text("still here").await.unwrap();
+        });
+
+        assert_eq!(
+            server.join().unwrap(),
+            [
+                (Opcode::Pong, b"hi".to_vec()),
+                (Opcode::Text, b"still here".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn accepts_the_rfc_handshake() {
+        // From section 1.3 of RFC 6455
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        assert_eq!(accept_key(key), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let request = handshake_request("example.com", "/chat", key).unwrap();
+        assert!(request.starts_with("GET /chat HTTP/1.1\r\n"));
+        assert!(request.ends_with("\r\n\r\n"));
+
+        assert!(matches!(
+            handshake_request("example.com", "not a path", key),
+            Err(NetError::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn decodes_frames_split_anywhere() {
+        let mut stream = server_frame(Opcode::Text, b"hel");
+        stream[0] &= 0x7F;
+        stream.extend(server_frame(Opcode::Ping, b"?"));
+        stream.extend(server_frame(Opcode::Continuation, b"lo"));
+        let mut long = vec![0x82, 126, 0x01, 0x00];
+        long.extend([0xAB; 256]);
+        stream.extend(long);
+
+        for step in 1..stream.len() {
+            let mut decoder = FrameDecoder::new();
+            let mut assembler = MessageAssembler::new();
+            let mut messages = Vec::new();
+
+            for chunk in stream.chunks(step) {
+                decoder.feed(chunk);
+                while let Some(frame) = decoder.next_frame().unwrap() {
+                    messages.extend(assembler.push(frame).unwrap());
+                }
+            }
+
+            assert_eq!(
+                messages,
+                [
+                    Message::Ping(b"?".to_vec()),
+                    Message::Text("hello".to_string()),
+                    Message::Binary(vec![0xAB; 256]),
+                ],
+                "step {step}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_broken_framing() {
+        for (stream, expected) in [
+            (vec![0xC1, 0x00], "reserved bits set"),
+            (vec![0x81, 0x80, 0, 0, 0, 0], "masked server frame"),
+            (vec![0x83, 0x00], "unknown opcode"),
+            (vec![0x09, 0x00], "oversized or fragmented control frame"),
+        ] {
+            let mut decoder = FrameDecoder::new();
+            decoder.feed(&stream);
+            let error = decoder.next_frame().unwrap_err();
+            assert!(
+                matches!(error, WsError::Protocol(reason) if reason == expected),
+                "{expected}"
+            );
+        }
+
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&[0x82, 127, 0, 0, 0, 0, 0, 1, 0, 1]);
+        assert!(matches!(
+            decoder.next_frame(),
+            Err(WsError::MessageTooLarge)
+        ));
+
+        let mut assembler = MessageAssembler::new();
+        assert!(assembler
+            .push(Frame {
+                fin: true,
+                opcode: Opcode::Continuation,
+                payload: Vec::new(),
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn masks_client_frames() {
+        let frame = encode_frame(true, Opcode::Text, b"Hello", [0x37, 0xFA, 0x21, 0x3D]);
+        // From section 5.7 of RFC 6455
+        assert_eq!(
+            frame,
+            [0x81, 0x85, 0x37, 0xFA, 0x21, 0x3D, 0x7F, 0x9F, 0x4D, 0x51, 0x58]
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! Joining the stored Wi-Fi networks.
+
+use core::cmp::Reverse;
+
+use anyhow::anyhow;
+use esp_idf_svc::sys::EspError;
+use esp_idf_svc::wifi::{AsyncWifi, AuthMethod, ClientConfiguration, Configuration, EspWifi};
+use log::{info, warn};
+
+use super::NetError;
+use crate::credentials::{CredentialStore, NetworkAuth, NetworkCredentials, Phase2Method};
+use crate::{anyesp, convert_error};
+
+/// Joins the strongest known network that is in range, falling back through the rest
+pub async fn connect_to_network(
+    wifi: &mut AsyncWifi<EspWifi<'static>>,
+    store: &CredentialStore,
+) -> Result<(), NetError> {
+    let networks = store.networks().map_err(NetError::Wifi)?;
+    if networks.is_empty() {
+        return Err(NetError::Wifi(anyhow!("no Wi-Fi networks stored")));
+    }
+
+    start_station(wifi).await?;
+
+    for network in rank_networks(wifi, networks).await {
+        match connect_with(wifi, &network).await {
+            Ok(()) => return Ok(()),
+            Err(e) => warn!("Could not join {}: {e}", network.ssid),
+        }
+    }
+
+    Err(NetError::Wifi(anyhow!(
+        "none of the known networks could be joined"
+    )))
+}
+
+/// Starts the driver in station mode if it is not running yet
+pub(super) async fn start_station(wifi: &mut AsyncWifi<EspWifi<'static>>) -> Result<(), NetError> {
+    if !wifi.is_started().map_err(wifi_error)? {
+        wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))
+            .map_err(wifi_error)?;
+        wifi.start().await.map_err(wifi_error)?;
+    }
+
+    Ok(())
+}
+
+/// Orders networks by signal strength. Networks the scan did not see (hidden or out of range)
+/// keep their stored order at the end.
+pub(super) async fn rank_networks(
+    wifi: &mut AsyncWifi<EspWifi<'static>>,
+    mut networks: Vec<NetworkCredentials>,
+) -> Vec<NetworkCredentials> {
+    let visible = match wifi.scan().await {
+        Ok(visible) => visible,
+        Err(e) => {
+            warn!("Wi-Fi scan failed: {e}");
+            return networks;
+        }
+    };
+
+    networks.sort_by_key(|network| {
+        Reverse(
+            visible
+                .iter()
+                .filter(|ap| ap.ssid.as_str() == network.ssid)
+                .map(|ap| ap.signal_strength as i16)
+                .max()
+                .unwrap_or(i16::MIN),
+        )
+    });
+
+    networks
+}
+
+/// Joins one specific network
+pub async fn connect_with(
+    wifi: &mut AsyncWifi<EspWifi<'static>>,
+    network: &NetworkCredentials,
+) -> Result<(), NetError> {
+    let (auth_method, password) = match &network.auth {
+        NetworkAuth::Open => (AuthMethod::None, ""),
+        NetworkAuth::Personal { password } => (AuthMethod::WPA2Personal, password.as_str()),
+        NetworkAuth::Enterprise { .. } => (AuthMethod::WPA2Enterprise, ""),
+    };
+
+    let config = Configuration::Client(ClientConfiguration {
+        ssid: network
+            .ssid
+            .as_str()
+            .try_into()
+            .map_err(|_| NetError::Wifi(anyhow!("SSID too long")))?,
+        password: password
+            .try_into()
+            .map_err(|_| NetError::Wifi(anyhow!("password too long")))?,
+        auth_method,
+        ..Default::default()
+    });
+
+    if wifi.is_connected().map_err(wifi_error)? {
+        wifi.disconnect().await.map_err(wifi_error)?;
+    }
+
+    wifi.set_configuration(&config).map_err(wifi_error)?;
+
+    unsafe {
+        use esp_idf_svc::sys::*;
+        match &network.auth {
+            NetworkAuth::Enterprise {
+                identity,
+                username,
+                password,
+                phase2,
+            } => {
+                let phase2 = match phase2 {
+                    Phase2Method::Mschapv2 => {
+                        esp_eap_ttls_phase2_types_ESP_EAP_TTLS_PHASE2_MSCHAPV2
+                    }
+                    Phase2Method::Mschap => esp_eap_ttls_phase2_types_ESP_EAP_TTLS_PHASE2_MSCHAP,
+                    Phase2Method::Pap => esp_eap_ttls_phase2_types_ESP_EAP_TTLS_PHASE2_PAP,
+                    Phase2Method::Chap => esp_eap_ttls_phase2_types_ESP_EAP_TTLS_PHASE2_CHAP,
+                    Phase2Method::Eap => esp_eap_ttls_phase2_types_ESP_EAP_TTLS_PHASE2_EAP,
+                };
+
+                anyesp!(esp_eap_client_set_identity(
+                    identity.as_ptr(),
+                    identity.len() as i32
+                ))
+                .map_err(NetError::Wifi)?;
+                anyesp!(esp_eap_client_set_username(
+                    username.as_ptr(),
+                    username.len() as i32
+                ))
+                .map_err(NetError::Wifi)?;
+                anyesp!(esp_eap_client_set_password(
+                    password.as_ptr(),
+                    password.len() as i32
+                ))
+                .map_err(NetError::Wifi)?;
+                anyesp!(esp_eap_client_set_ttls_phase2_method(phase2)).map_err(NetError::Wifi)?;
+                anyesp!(esp_wifi_sta_enterprise_enable()).map_err(NetError::Wifi)?;
+            }
+            _ => anyesp!(esp_wifi_sta_enterprise_disable()).map_err(NetError::Wifi)?,
+        }
+    }
+
+    // Connect but with a longer timeout
+    wifi.wifi_mut().connect().map_err(wifi_error)?;
+    wifi.wifi_wait(
+        |this| this.wifi().is_connected().map(|s| !s),
+        Some(std::time::Duration::from_secs(10)),
+    )
+    .await
+    .map_err(wifi_error)?;
+
+    wifi.wait_netif_up().await.map_err(wifi_error)?;
+
+    info!("Wi-Fi connected to {}!", network.ssid);
+
+    Ok(())
+}
+
+pub(super) fn wifi_error(e: EspError) -> NetError {
+    NetError::Wifi(convert_error(e))
+}
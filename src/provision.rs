@@ -0,0 +1,170 @@
+//! SoftAP captive portal for setting up a beacon.
+//!
+//! The beacon hosts its own open network with a setup form. Every DNS lookup resolves to the
+//! beacon, so phones pop the form up as a captive portal. Submitting it stores the credentials
+//! and owner info in NVS and reboots into station mode.
+//!
+//! Reading the form and answering DNS queries is plain Rust; the portal itself only exists when
+//! building for the ESP.
+
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+
+use crate::credentials::{NetworkAuth, NetworkCredentials, Phase2Method};
+use crate::settings::BeaconInfo;
+
+pub mod dns;
+#[cfg(target_os = "espidf")]
+mod portal;
+
+#[cfg(target_os = "espidf")]
+pub use self::portal::{draw_instructions, long_press_at_boot, run};
+
+/// Lengths WPA2-Personal accepts for a passphrase, in bytes
+const PASSPHRASE_LEN: core::ops::RangeInclusive<usize> = 8..=63;
+
+/// What the setup form asked for
+#[derive(Debug, PartialEq, Eq)]
+pub struct Submission {
+    pub network: NetworkCredentials,
+    /// Who claims the beacon, unless the form only adds a network
+    pub info: Option<BeaconInfo>,
+}
+
+/// Reads the URL-encoded setup form
+pub fn parse_submission(body: &[u8]) -> anyhow::Result<Submission> {
+    let fields: HashMap<String, String> = url::form_urlencoded::parse(body).into_owned().collect();
+    // Passwords are taken as typed, since spaces at either end can be part of them
+    let raw = |name: &str| fields.get(name).map(String::as_str).unwrap_or_default();
+    let field = |name: &str| raw(name).trim();
+
+    let ssid = field("ssid");
+    if ssid.is_empty() || ssid.len() > 32 {
+        return Err(anyhow!("Network name must be 1 to 32 characters"));
+    }
+
+    let auth = match field("auth") {
+        "open" => NetworkAuth::Open,
+        "personal" => {
+            let password = raw("password");
+            if !PASSPHRASE_LEN.contains(&password.len()) {
+                return Err(anyhow!("WPA2 passwords are 8 to 63 characters"));
+            }
+            NetworkAuth::Personal {
+                password: password.to_string(),
+            }
+        }
+        "enterprise" => NetworkAuth::Enterprise {
+            identity: field("identity").to_string(),
+            username: field("username").to_string(),
+            password: raw("password").to_string(),
+            phase2: match field("phase2") {
+                "mschap" => Phase2Method::Mschap,
+                "pap" => Phase2Method::Pap,
+                "chap" => Phase2Method::Chap,
+                "eap" => Phase2Method::Eap,
+                _ => Phase2Method::Mschapv2,
+            },
+        },
+        other => return Err(anyhow!("Unknown security type {other:?}")),
+    };
+
+    let (owner, project) = (field("owner"), field("project"));
+    if owner.is_empty() && !project.is_empty() {
+        return Err(anyhow!("Add your name to share your project"));
+    }
+
+    Ok(Submission {
+        network: NetworkCredentials {
+            ssid: ssid.to_string(),
+            auth,
+        },
+        info: (!owner.is_empty()).then(|| BeaconInfo {
+            owner: owner.to_string(),
+            project: project.to_string(),
+            ..Default::default()
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(form: &str) -> anyhow::Result<Submission> {
+        parse_submission(form.as_bytes())
+    }
+
+    #[test]
+    fn reads_a_personal_network_and_owner() {
+        let submission =
+            parse("ssid=Lab&auth=personal&password=+hunter22+&owner=+Ada+&project=Engine").unwrap();
+
+        assert_eq!(
+            submission,
+            Submission {
+                network: NetworkCredentials {
+                    ssid: "Lab".to_string(),
+                    auth: NetworkAuth::Personal {
+                        password: " hunter22 ".to_string(),
+                    },
+                },
+                info: Some(BeaconInfo {
+                    owner: "Ada".to_string(),
+                    project: "Engine".to_string(),
+                    ..Default::default()
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn keeps_the_claim_when_only_adding_a_network() {
+        let submission = parse("ssid=Lab&auth=open&owner=+&project=").unwrap();
+        assert_eq!(submission.network.auth, NetworkAuth::Open);
+        assert_eq!(submission.info, None);
+
+        assert!(parse("ssid=Lab&auth=open&project=Engine").is_err());
+    }
+
+    #[test]
+    fn reads_enterprise_networks() {
+        let submission =
+            parse("ssid=Campus&auth=enterprise&identity=anon&username=ada&password=+pw&phase2=pap")
+                .unwrap();
+
+        assert_eq!(
+            submission.network.auth,
+            NetworkAuth::Enterprise {
+                identity: "anon".to_string(),
+                username: "ada".to_string(),
+                password: " pw".to_string(),
+                phase2: Phase2Method::Pap,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_passwords_wpa2_cannot_use() {
+        assert!(parse("ssid=Lab&auth=personal&password=short").is_err());
+        assert!(parse(&format!(
+            "ssid=Lab&auth=personal&password={}",
+            "x".repeat(64)
+        ))
+        .is_err());
+        assert!(parse("ssid=Lab&auth=personal&password=12345678").is_ok());
+        assert!(parse(&format!(
+            "ssid=Lab&auth=personal&password={}",
+            "x".repeat(63)
+        ))
+        .is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_networks() {
+        assert!(parse("auth=open").is_err());
+        assert!(parse(&format!("ssid={}&auth=open", "x".repeat(33))).is_err());
+        assert!(parse("ssid=Lab&auth=wep").is_err());
+    }
+}
@@ -0,0 +1,145 @@
+//! Catch-all DNS answers for the captive portal.
+//!
+//! Every `A` query is answered with the beacon's own address so phones open the setup page.
+
+use std::net::Ipv4Addr;
+
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Kept short so clients forget the fake answers soon after provisioning
+const TTL_SECS: u32 = 30;
+
+/// Builds the response to a single-question DNS query, or `None` if it should be ignored
+pub fn catch_all_response(query: &[u8], address: Ipv4Addr) -> Option<Vec<u8>> {
+    if query.len() < HEADER_LEN {
+        return None;
+    }
+
+    let flags = u16::from_be_bytes([query[2], query[3]]);
+    let is_response = flags & 0x8000 != 0;
+    let opcode = (flags >> 11) & 0x0F;
+    let questions = u16::from_be_bytes([query[4], query[5]]);
+    if is_response || opcode != 0 || questions == 0 {
+        return None;
+    }
+
+    // Walk the labels of the first question's name
+    let mut end = HEADER_LEN;
+    loop {
+        let len = *query.get(end)? as usize;
+        if len & 0xC0 != 0 {
+            // Compression pointers never appear in a query's first name
+            return None;
+        }
+        end += 1 + len;
+        if len == 0 {
+            break;
+        }
+    }
+
+    let question = query.get(HEADER_LEN..end + 4)?;
+    let qtype = u16::from_be_bytes([query[end], query[end + 1]]);
+    let qclass = u16::from_be_bytes([query[end + 2], query[end + 3]]);
+    let answer = matches!(qtype, TYPE_A | TYPE_ANY) && qclass == CLASS_IN;
+
+    let mut response = Vec::with_capacity(HEADER_LEN + question.len() + 16);
+    response.extend_from_slice(&query[..2]);
+    // Response, recursion desired copied from the query, recursion available
+    response.extend_from_slice(&(0x8080 | (flags & 0x0100)).to_be_bytes());
+    response.extend_from_slice(&1_u16.to_be_bytes());
+    response.extend_from_slice(&(answer as u16).to_be_bytes());
+    response.extend_from_slice(&[0, 0, 0, 0]);
+    response.extend_from_slice(question);
+
+    if answer {
+        // Name is a pointer back to the question
+        response.extend_from_slice(&[0xC0, HEADER_LEN as u8]);
+        response.extend_from_slice(&TYPE_A.to_be_bytes());
+        response.extend_from_slice(&CLASS_IN.to_be_bytes());
+        response.extend_from_slice(&TTL_SECS.to_be_bytes());
+        response.extend_from_slice(&4_u16.to_be_bytes());
+        response.extend_from_slice(&address.octets());
+    }
+
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BEACON: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 1);
+
+    /// A query for `name` with recursion desired
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.push(0);
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+        query
+    }
+
+    #[test]
+    fn answers_every_name_with_the_beacon() {
+        let query = query("captive.apple.com", TYPE_A);
+        let response = catch_all_response(&query, BEACON).unwrap();
+
+        // Same ID, a response with recursion desired and available, one question and one answer
+        assert_eq!(
+            response[..12],
+            [0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]
+        );
+        assert_eq!(response[12..query.len()], query[12..]);
+        assert_eq!(
+            response[query.len()..],
+            [0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 30, 0, 4, 192, 168, 71, 1]
+        );
+    }
+
+    #[test]
+    fn answers_other_types_without_records() {
+        const TYPE_AAAA: u16 = 28;
+
+        let query = query("example.com", TYPE_AAAA);
+        let response = catch_all_response(&query, BEACON).unwrap();
+        assert_eq!(response[6..8], [0, 0]);
+        assert_eq!(response.len(), query.len());
+    }
+
+    #[test]
+    fn ignores_what_is_not_a_plain_query() {
+        let mut response = query("example.com", TYPE_A);
+        response[2] |= 0x80;
+        assert_eq!(catch_all_response(&response, BEACON), None);
+
+        let mut update = query("example.com", TYPE_A);
+        update[2] |= 5 << 3;
+        assert_eq!(catch_all_response(&update, BEACON), None);
+
+        let mut empty = query("example.com", TYPE_A);
+        empty[5] = 0;
+        assert_eq!(catch_all_response(&empty, BEACON), None);
+    }
+
+    #[test]
+    fn ignores_truncated_queries() {
+        let query = query("example.com", TYPE_A);
+        for len in [0, 11, 14, query.len() - 1] {
+            assert_eq!(
+                catch_all_response(&query[..len], BEACON),
+                None,
+                "{len} bytes"
+            );
+        }
+
+        let mut pointer = query.clone();
+        pointer[12] = 0xC0;
+        assert_eq!(catch_all_response(&pointer, BEACON), None);
+    }
+}
@@ -0,0 +1,299 @@
+//! The SoftAP, setup form and DNS responder the portal runs on.
+
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use embassy_time::Timer;
+use embedded_graphics::mono_font::{ascii::FONT_10X20, MonoTextStyle};
+use embedded_graphics::pixelcolor::Rgb888;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+use embedded_graphics::text::{Alignment, Text};
+use esp_idf_svc::hal::gpio::{Input, Pin, PinDriver};
+use esp_idf_svc::hal::reset::restart;
+use esp_idf_svc::http::server::{self, EspHttpServer};
+use esp_idf_svc::http::{Headers, Method};
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::wifi::{AccessPointConfiguration, AsyncWifi, AuthMethod, Configuration, EspWifi};
+use log::{info, warn};
+use qrcodegen::{QrCode, QrCodeEcc};
+
+use super::{dns, parse_submission, Submission};
+use crate::amoled;
+use crate::convert_error;
+use crate::credentials::CredentialStore;
+use crate::settings::Settings;
+
+/// How long the boot button has to be held to force provisioning
+const LONG_PRESS: Duration = Duration::from_secs(2);
+
+/// Largest form submission accepted
+const FORM_LIMIT: usize = 2048;
+
+const FORM_HTML: &str = r#"<!DOCTYPE html>
+<html><head><meta name="viewport" content="width=device-width"><title>Beacon setup</title></head>
+<body style="font-family:sans-serif;max-width:28em;margin:auto">
+<h1>Beacon setup</h1>
+<form method="post" action="/save">
+<h2>Wi-Fi</h2>
+<p><label>Network name<br><input name="ssid" maxlength="32" required></label></p>
+<p><label>Security<br><select name="auth">
+<option value="personal">WPA2-Personal</option>
+<option value="enterprise">WPA2-Enterprise</option>
+<option value="open">Open</option>
+</select></label></p>
+<p><label>Password<br><input name="password" type="password"></label></p>
+<p><label>Identity (enterprise)<br><input name="identity"></label></p>
+<p><label>Username (enterprise)<br><input name="username"></label></p>
+<p><label>Phase 2 (enterprise)<br><select name="phase2">
+<option value="mschapv2">MSCHAPv2</option>
+<option value="mschap">MSCHAP</option>
+<option value="pap">PAP</option>
+<option value="chap">CHAP</option>
+<option value="eap">EAP</option>
+</select></label></p>
+<h2>Beacon</h2>
+<p><label>Your name (leave blank to keep the current one)<br><input name="owner"></label></p>
+<p><label>What are you working on?<br><input name="project"></label></p>
+<p><button>Save and reboot</button></p>
+</form></body></html>"#;
+
+const SAVED_HTML: &str = "<!DOCTYPE html><html><body style=\"font-family:sans-serif\">\
+<h1>Saved!</h1><p>The beacon is rebooting and will join your network.</p></body></html>";
+
+/// Whether the boot button is being held down for a long press right after reset
+pub fn long_press_at_boot<T: Pin>(button: &PinDriver<'_, T, Input>) -> bool {
+    let start = Instant::now();
+
+    while button.is_low() {
+        if start.elapsed() >= LONG_PRESS {
+            return true;
+        }
+
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    false
+}
+
+/// Runs the captive portal until the form is submitted, then reboots.
+///
+/// Only returns if the portal could not be brought up.
+pub async fn run<D>(
+    wifi: &mut AsyncWifi<EspWifi<'static>>,
+    credentials: &mut CredentialStore,
+    settings: &mut Settings,
+    display: &mut D,
+) -> anyhow::Result<()>
+where
+    D: DrawTarget<Color = Rgb888>,
+    D::Error: core::fmt::Debug,
+{
+    let mac = wifi.wifi().ap_netif().get_mac().map_err(convert_error)?;
+    let ap_name = format!("Beacon-{:02X}{:02X}", mac[4], mac[5]);
+
+    if wifi.is_started().map_err(convert_error)? {
+        wifi.stop().await.map_err(convert_error)?;
+    }
+
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: ap_name
+            .as_str()
+            .try_into()
+            .map_err(|_| anyhow!("AP name too long"))?,
+        auth_method: AuthMethod::None,
+        channel: 6,
+        max_connections: 4,
+        ..Default::default()
+    }))
+    .map_err(convert_error)?;
+
+    wifi.start().await.map_err(convert_error)?;
+    wifi.wait_netif_up().await.map_err(convert_error)?;
+
+    let address = wifi
+        .wifi()
+        .ap_netif()
+        .get_ip_info()
+        .map_err(convert_error)?
+        .ip;
+
+    info!("Provisioning: join {ap_name} and open http://{address}/");
+
+    draw_instructions(display, &ap_name, address).map_err(|e| anyhow!("drawing: {e:?}"))?;
+
+    spawn_dns(address)?;
+
+    let (tx, rx) = mpsc::channel();
+    let _server = start_server(tx, address)?;
+
+    let submission = loop {
+        match rx.try_recv() {
+            Ok(submission) => break submission,
+            Err(mpsc::TryRecvError::Empty) => Timer::after_millis(100).await,
+            Err(mpsc::TryRecvError::Disconnected) => return Err(anyhow!("setup server stopped")),
+        }
+    };
+
+    info!("Provisioned network {}", submission.network.ssid);
+    credentials.add(submission.network)?;
+    // Adding a network alone keeps whoever has claimed the beacon
+    if let Some(info) = &submission.info {
+        settings.set_beacon_info(info)?;
+    }
+
+    // Give the confirmation page time to reach the phone
+    Timer::after_secs(2).await;
+    restart();
+}
+
+/// Shows the network to join, along with a QR code that joins it
+pub fn draw_instructions<D>(
+    display: &mut D,
+    ap_name: &str,
+    address: Ipv4Addr,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = Rgb888>,
+{
+    const MODULE: i32 = 10;
+    const QUIET_ZONE: i32 = 2;
+
+    display.clear(Rgb888::BLACK)?;
+
+    let style = MonoTextStyle::new(&FONT_10X20, Rgb888::WHITE);
+    let center = amoled::WIDTH as i32 / 2;
+
+    Text::with_alignment(
+        "Set up this beacon",
+        Point::new(center, 60),
+        style,
+        Alignment::Center,
+    )
+    .draw(display)?;
+    Text::with_alignment(
+        &format!("Join {ap_name}"),
+        Point::new(center, 100),
+        style,
+        Alignment::Center,
+    )
+    .draw(display)?;
+
+    let qr = QrCode::encode_text(&format!("WIFI:T:nopass;S:{ap_name};;"), QrCodeEcc::Medium)
+        .expect("network name fits in a QR code");
+
+    let side = (qr.size() + QUIET_ZONE * 2) * MODULE;
+    // The panel addresses pixels in pairs, so keep everything on even coordinates
+    let origin = Point::new((center - side / 2) & !1, 140);
+
+    Rectangle::new(origin, Size::new(side as u32, side as u32))
+        .into_styled(PrimitiveStyle::with_fill(Rgb888::WHITE))
+        .draw(display)?;
+
+    for y in 0..qr.size() {
+        for x in 0..qr.size() {
+            if qr.get_module(x, y) {
+                Rectangle::new(
+                    origin + Point::new((x + QUIET_ZONE) * MODULE, (y + QUIET_ZONE) * MODULE),
+                    Size::new_equal(MODULE as u32),
+                )
+                .into_styled(PrimitiveStyle::with_fill(Rgb888::BLACK))
+                .draw(display)?;
+            }
+        }
+    }
+
+    Text::with_alignment(
+        &format!("then open http://{address}/"),
+        Point::new(center, 140 + side + 40),
+        style,
+        Alignment::Center,
+    )
+    .draw(display)?;
+
+    Ok(())
+}
+
+fn spawn_dns(address: Ipv4Addr) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 53))?;
+
+    std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(move || {
+            let mut buf = [0; 512];
+            loop {
+                match socket.recv_from(&mut buf) {
+                    Ok((len, peer)) => {
+                        if let Some(response) = dns::catch_all_response(&buf[..len], address) {
+                            let _ = socket.send_to(&response, peer);
+                        }
+                    }
+                    Err(e) => {
+                        warn!("DNS receive failed: {e}");
+                        std::thread::sleep(Duration::from_millis(100));
+                    }
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+fn start_server(
+    tx: mpsc::Sender<Submission>,
+    address: Ipv4Addr,
+) -> anyhow::Result<EspHttpServer<'static>> {
+    let mut server = EspHttpServer::new(&server::Configuration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })
+    .map_err(convert_error)?;
+
+    server
+        .fn_handler("/", Method::Get, |req| {
+            req.into_ok_response()?.write_all(FORM_HTML.as_bytes())?;
+            Ok::<(), anyhow::Error>(())
+        })
+        .map_err(convert_error)?;
+
+    server
+        .fn_handler("/save", Method::Post, move |mut req| {
+            let len = req.content_len().unwrap_or_default() as usize;
+            if len > FORM_LIMIT {
+                req.into_status_response(413)?
+                    .write_all(b"Form too large")?;
+                return Ok(());
+            }
+
+            let mut body = vec![0; len];
+            req.read_exact(&mut body)
+                .map_err(|e| anyhow!("reading form: {e:?}"))?;
+
+            match parse_submission(&body) {
+                Ok(submission) => {
+                    req.into_ok_response()?.write_all(SAVED_HTML.as_bytes())?;
+                    tx.send(submission)?;
+                }
+                Err(e) => {
+                    req.into_status_response(400)?
+                        .write_all(e.to_string().as_bytes())?;
+                }
+            }
+
+            Ok::<(), anyhow::Error>(())
+        })
+        .map_err(convert_error)?;
+
+    // Phones probe well-known URLs to detect captive portals; send them all to the form
+    let portal = format!("http://{address}/");
+    server
+        .fn_handler("/*", Method::Get, move |req| {
+            req.into_response(302, Some("Found"), &[("Location", portal.as_str())])?;
+            Ok::<(), anyhow::Error>(())
+        })
+        .map_err(convert_error)?;
+
+    Ok(server)
+}
@@ -0,0 +1,366 @@
+//! Per-beacon settings persisted in NVS.
+
+use core::fmt;
+use core::str::FromStr;
+
+use anyhow::anyhow;
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::app::dimming::NightDimming;
+use crate::app::notification::Patterns;
+use crate::hal::{DefaultStore, KeyValueStore, PowerBudget};
+use crate::net::api::DEFAULT_BASE_URL;
+use crate::net::mqtt;
+use crate::net::release::UpdateChannel;
+use crate::net::{Pin, TlsConfig, TlsError};
+
+#[cfg(target_os = "espidf")]
+const NAMESPACE: &str = "settings";
+const UPDATE_CHANNEL_KEY: &str = "channel";
+const BEACON_INFO_KEY: &str = "info";
+const API_URL_KEY: &str = "api_url";
+const TRANSPORT_KEY: &str = "transport";
+const BROKER_URL_KEY: &str = "broker_url";
+/// A blob, since CA certificates can outgrow NVS strings
+const TLS_CA_KEY: &str = "tls_ca";
+const TLS_PINS_KEY: &str = "tls_pins";
+const DIMMING_KEY: &str = "dimming";
+const POWER_BUDGET_KEY: &str = "led_budget";
+const NOTIFICATIONS_KEY: &str = "notifications";
+
+/// How the beacon talks to whatever hands out claims and pings
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// The companion site's HTTPS API and push channel
+    #[default]
+    Https,
+    /// An MQTT broker, for deployments without the companion site
+    Mqtt,
+}
+
+impl Transport {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Https => "https",
+            Self::Mqtt => "mqtt",
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Transport {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "https" => Ok(Self::Https),
+            "mqtt" => Ok(Self::Mqtt),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Who the beacon belongs to and what they are working on
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BeaconInfo {
+    pub owner: String,
+    pub project: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+pub struct Settings<S = DefaultStore> {
+    store: S,
+}
+
+#[cfg(target_os = "espidf")]
+impl Settings {
+    pub fn new(partition: esp_idf_svc::nvs::EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        Ok(Self::with_store(crate::hal::esp::NvsStore::new(
+            partition, NAMESPACE,
+        )?))
+    }
+}
+
+impl<S: KeyValueStore> Settings<S> {
+    /// Keeps the settings in `store`, which should be dedicated to them
+    pub fn with_store(store: S) -> Self {
+        Self { store }
+    }
+
+    /// The value `read` finds, or `None` with a warning naming `what` if it failed
+    fn warn_on_error<T>(what: &str, read: anyhow::Result<Option<T>>) -> Option<T> {
+        read.unwrap_or_else(|e| {
+            warn!("Could not read {what}, using the default: {e}");
+            None
+        })
+    }
+
+    /// The JSON blob at `key`, or the default if it is missing, unreadable or malformed
+    fn read_or_default<T: DeserializeOwned + Default>(&self, key: &str, what: &str) -> T {
+        let read = self.store.get_blob(key).and_then(|blob| {
+            blob.map(|blob| serde_json::from_slice(&blob))
+                .transpose()
+                .map_err(Into::into)
+        });
+
+        Self::warn_on_error(what, read).unwrap_or_default()
+    }
+
+    /// The string at `key` parsed, or the default if it is missing, unreadable or unknown
+    fn parse_or_default<T: FromStr + Default>(&self, key: &str, what: &str) -> T {
+        let read = self.store.get_str(key).and_then(|value| {
+            value
+                .map(|value| {
+                    value
+                        .parse()
+                        .map_err(|_| anyhow!("unknown {what} {value:?}"))
+                })
+                .transpose()
+        });
+
+        Self::warn_on_error(what, read).unwrap_or_default()
+    }
+
+    /// The channel self-updates are taken from, stable unless set otherwise
+    pub fn update_channel(&self) -> UpdateChannel {
+        self.parse_or_default(UPDATE_CHANNEL_KEY, "update channel")
+    }
+
+    pub fn set_update_channel(&mut self, channel: UpdateChannel) -> anyhow::Result<()> {
+        self.store.set_str(UPDATE_CHANNEL_KEY, channel.as_str())
+    }
+
+    /// Base URL of the companion site's API, the production server unless set otherwise
+    pub fn api_url(&self) -> String {
+        Self::warn_on_error("API URL", self.store.get_str(API_URL_KEY))
+            .unwrap_or_else(|| DEFAULT_BASE_URL.to_string())
+    }
+
+    pub fn set_api_url(&mut self, url: &str) -> anyhow::Result<()> {
+        self.store.set_str(API_URL_KEY, url)
+    }
+
+    /// How to reach the companion site or broker, HTTPS unless set otherwise
+    pub fn transport(&self) -> Transport {
+        self.parse_or_default(TRANSPORT_KEY, "transport")
+    }
+
+    pub fn set_transport(&mut self, transport: Transport) -> anyhow::Result<()> {
+        self.store.set_str(TRANSPORT_KEY, transport.as_str())
+    }
+
+    /// The `mqtt://` or `mqtts://` URL of the broker used by the MQTT transport
+    pub fn broker_url(&self) -> anyhow::Result<Option<String>> {
+        self.store.get_str(BROKER_URL_KEY)
+    }
+
+    pub fn set_broker_url(&mut self, url: &str) -> anyhow::Result<()> {
+        self.store.set_str(BROKER_URL_KEY, url)
+    }
+
+    /// How TLS servers are verified. The custom CA only covers the API and broker set here, so
+    /// GitHub and the production server keep the certificate bundle.
+    ///
+    /// Unusable entries are skipped, so a bad CA falls back to the bundle and a bad pin leaves
+    /// its host unpinned.
+    pub fn tls_config(&self) -> TlsConfig {
+        let ca = match self.store.get_blob(TLS_CA_KEY) {
+            Ok(Some(pem)) => String::from_utf8(pem)
+                .map_err(|_| TlsError::InvalidCa("not text".to_string()))
+                .inspect_err(|e| warn!("Ignoring custom CA: {e}"))
+                .ok(),
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Could not read custom CA: {e}");
+                None
+            }
+        };
+
+        let mut config = TlsConfig::new();
+        if let Some(ca) = ca {
+            let hosts = self.self_hosted();
+            if hosts.is_empty() {
+                warn!("Ignoring custom CA without a self-hosted API or broker");
+            }
+            for host in hosts {
+                match config.clone().with_ca(&host, &ca) {
+                    Ok(trusting) => config = trusting,
+                    Err(e) => {
+                        warn!("Ignoring custom CA: {e}");
+                        break;
+                    }
+                }
+            }
+        }
+
+        match self.store.get_str(TLS_PINS_KEY) {
+            Ok(Some(pins)) => {
+                for line in pins.lines().filter(|line| !line.trim().is_empty()) {
+                    let pin = line
+                        .split_once(' ')
+                        .and_then(|(host, pin)| Some((host, pin.trim().parse::<Pin>().ok()?)));
+                    match pin {
+                        Some((host, pin)) => config = config.with_pin(host, pin),
+                        None => warn!("Ignoring malformed pin {line:?}"),
+                    }
+                }
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Could not read pins: {e}"),
+        }
+
+        config
+    }
+
+    /// Hosts of the API and broker, where they are set rather than left at the defaults
+    fn self_hosted(&self) -> Vec<String> {
+        let api = Self::warn_on_error("API URL", self.store.get_str(API_URL_KEY))
+            .and_then(|url| Url::parse(&url).ok());
+        let broker = Self::warn_on_error("broker URL", self.broker_url())
+            .and_then(|url| mqtt::broker_url(&url).ok());
+
+        [api, broker]
+            .into_iter()
+            .flatten()
+            .filter_map(|url| url.host_str().map(str::to_ascii_lowercase))
+            .collect()
+    }
+
+    /// Trusts only the CA in `pem` for the self-hosted API and broker, or the certificate bundle
+    /// again with `None`
+    pub fn set_tls_ca(&mut self, pem: Option<&str>) -> anyhow::Result<()> {
+        match pem {
+            Some(pem) => self.store.set_blob(TLS_CA_KEY, pem.as_bytes()),
+            None => self.store.remove(TLS_CA_KEY).map(drop),
+        }
+    }
+
+    /// Replaces the pins, given as hosts and the pins their certificates have to match
+    pub fn set_tls_pins(&mut self, pins: &[(&str, Pin)]) -> anyhow::Result<()> {
+        let pins = pins
+            .iter()
+            .map(|(host, pin)| format!("{host} {pin}\n"))
+            .collect::<String>();
+
+        self.store.set_str(TLS_PINS_KEY, &pins)
+    }
+
+    /// When and how far the display dims, the defaults unless set otherwise
+    pub fn night_dimming(&self) -> NightDimming {
+        self.read_or_default(DIMMING_KEY, "night dimming")
+    }
+
+    pub fn set_night_dimming(&mut self, dimming: &NightDimming) -> anyhow::Result<()> {
+        self.store
+            .set_blob(DIMMING_KEY, &serde_json::to_vec(dimming)?)
+    }
+
+    /// What the LEDs may draw, the defaults unless set otherwise
+    pub fn power_budget(&self) -> PowerBudget {
+        self.read_or_default(POWER_BUDGET_KEY, "LED power budget")
+    }
+
+    pub fn set_power_budget(&mut self, budget: &PowerBudget) -> anyhow::Result<()> {
+        self.store
+            .set_blob(POWER_BUDGET_KEY, &serde_json::to_vec(budget)?)
+    }
+
+    /// How each kind of notification plays, the defaults unless set otherwise
+    pub fn notification_patterns(&self) -> Patterns {
+        self.read_or_default(NOTIFICATIONS_KEY, "notification patterns")
+    }
+
+    pub fn set_notification_patterns(&mut self, patterns: &Patterns) -> anyhow::Result<()> {
+        self.store
+            .set_blob(NOTIFICATIONS_KEY, &serde_json::to_vec(patterns)?)
+    }
+
+    pub fn beacon_info(&self) -> anyhow::Result<Option<BeaconInfo>> {
+        match self.store.get_blob(BEACON_INFO_KEY)? {
+            Some(blob) => Ok(Some(serde_json::from_slice(&blob)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_beacon_info(&mut self, info: &BeaconInfo) -> anyhow::Result<()> {
+        self.store
+            .set_blob(BEACON_INFO_KEY, &serde_json::to_vec(info)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::fake::MemoryStore;
+
+    #[test]
+    fn falls_back_to_defaults() {
+        let mut settings = Settings::with_store(MemoryStore::default());
+        assert_eq!(settings.update_channel(), UpdateChannel::Stable);
+        assert_eq!(settings.api_url(), DEFAULT_BASE_URL);
+        assert_eq!(settings.power_budget(), PowerBudget::default());
+
+        settings
+            .store
+            .set_str(UPDATE_CHANNEL_KEY, "nightly")
+            .unwrap();
+        settings
+            .store
+            .set_str(TRANSPORT_KEY, "carrier pigeon")
+            .unwrap();
+        settings
+            .store
+            .set_blob(POWER_BUDGET_KEY, b"{not json")
+            .unwrap();
+        assert_eq!(settings.update_channel(), UpdateChannel::Stable);
+        assert_eq!(settings.transport(), Transport::Https);
+        assert_eq!(settings.power_budget(), PowerBudget::default());
+    }
+
+    #[test]
+    fn reads_back_what_was_set() {
+        let mut settings = Settings::with_store(MemoryStore::default());
+        let budget = PowerBudget {
+            brightness: 128,
+            ..PowerBudget::default()
+        };
+
+        settings.set_update_channel(UpdateChannel::Beta).unwrap();
+        settings.set_transport(Transport::Mqtt).unwrap();
+        settings.set_power_budget(&budget).unwrap();
+
+        assert_eq!(settings.update_channel(), UpdateChannel::Beta);
+        assert_eq!(settings.transport(), Transport::Mqtt);
+        assert_eq!(settings.power_budget(), budget);
+    }
+
+    #[test]
+    fn trusts_the_custom_ca_only_for_self_hosted_servers() {
+        let pem = "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n";
+        let mut settings = Settings::with_store(MemoryStore::default());
+        settings.set_tls_ca(Some(pem)).unwrap();
+
+        // Nothing self-hosted for it to cover yet
+        let config = settings.tls_config();
+        assert_eq!(config.ca("beacons.purduehackers.com"), None);
+
+        settings
+            .set_api_url("https://Beacons.example/api/")
+            .unwrap();
+        settings.set_broker_url("mqtts://broker.example").unwrap();
+        let config = settings.tls_config();
+        assert!(config.ca("beacons.example").is_some());
+        assert!(config.ca("broker.example").is_some());
+        assert_eq!(config.ca("api.github.com"), None);
+        assert_eq!(config.ca("objects.githubusercontent.com"), None);
+    }
+}
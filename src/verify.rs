@@ -0,0 +1,214 @@
+//! Release image verification.
+//!
+//! Releases ship a detached Ed25519 signature over the SHA-256 digest of the firmware image. The
+//! digest is built up while the image streams into the OTA partition so the image never has to be
+//! held in memory.
+
+use core::fmt;
+
+use ed25519_dalek::{Signature, VerifyingKey, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+use sha2::{Digest, Sha256};
+
+pub const SIGNATURE_LEN: usize = SIGNATURE_LENGTH;
+pub const PUBLIC_KEY_LEN: usize = PUBLIC_KEY_LENGTH;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    InvalidPublicKey,
+    InvalidSignatureLength(usize),
+    BadSignature,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidPublicKey => write!(f, "release public key is not a valid Ed25519 key"),
+            Self::InvalidSignatureLength(len) => {
+                write!(f, "signature is {len} bytes, expected {SIGNATURE_LEN}")
+            }
+            Self::BadSignature => write!(f, "image signature does not match"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Decodes a hex-encoded public key at compile time
+pub const fn parse_hex_key(hex: &str) -> [u8; PUBLIC_KEY_LEN] {
+    const fn nibble(c: u8) -> u8 {
+        match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            b'A'..=b'F' => c - b'A' + 10,
+            _ => panic!("public key must be hex"),
+        }
+    }
+
+    let hex = hex.as_bytes();
+    assert!(
+        hex.len() == PUBLIC_KEY_LEN * 2,
+        "public key must be 64 hex digits"
+    );
+
+    let mut key = [0; PUBLIC_KEY_LEN];
+    let mut i = 0;
+    while i < PUBLIC_KEY_LEN {
+        key[i] = nibble(hex[i * 2]) << 4 | nibble(hex[i * 2 + 1]);
+        i += 1;
+    }
+
+    key
+}
+
+/// Hashes an image as it streams past and checks it against a detached signature at the end
+pub struct ImageVerifier {
+    key: VerifyingKey,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl ImageVerifier {
+    pub fn new(public_key: &[u8; PUBLIC_KEY_LEN]) -> Result<Self, VerifyError> {
+        let key =
+            VerifyingKey::from_bytes(public_key).map_err(|_| VerifyError::InvalidPublicKey)?;
+
+        Ok(Self {
+            key,
+            hasher: Sha256::new(),
+            len: 0,
+        })
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        self.len += data.len() as u64;
+    }
+
+    /// Number of image bytes hashed so far
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Consumes the verifier, returning the image digest if the signature matches it
+    pub fn verify(self, signature: &[u8]) -> Result<[u8; 32], VerifyError> {
+        let signature: &[u8; SIGNATURE_LEN] = signature
+            .try_into()
+            .map_err(|_| VerifyError::InvalidSignatureLength(signature.len()))?;
+        let signature = Signature::from_bytes(signature);
+
+        let digest: [u8; 32] = self.hasher.finalize().into();
+
+        self.key
+            .verify_strict(&digest, &signature)
+            .map_err(|_| VerifyError::BadSignature)?;
+
+        Ok(digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The key from RFC 8032's first Ed25519 test vector
+    const PUBLIC_KEY: [u8; PUBLIC_KEY_LEN] =
+        parse_hex_key("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+
+    const IMAGE: &[u8] = b"beacon firmware image";
+
+    const DIGEST: &str = "afed90906a443677222b5166508dd472e9d4109970b82f145129a705b8b52e79";
+
+    /// Made the way CI signs releases, with `openssl pkeyutl -sign -rawin` over the digest
+    const SIGNATURE: &str = "25d05658d60657ea99f78cfd93ee680ceaf4ee322961d6a3bdb352df11fe19a5\
+        b66c787c967846c11476fb9f2e1f5560434f0bc1a9472e93cecf3f6c0bbe270d";
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn verify(
+        key: &[u8; PUBLIC_KEY_LEN],
+        image: &[u8],
+        signature: &[u8],
+    ) -> Result<[u8; 32], VerifyError> {
+        let mut verifier = ImageVerifier::new(key)?;
+        // In pieces, the way the image streams in
+        for piece in image.chunks(4) {
+            verifier.update(piece);
+        }
+        assert_eq!(verifier.len(), image.len() as u64);
+
+        verifier.verify(signature)
+    }
+
+    #[test]
+    fn accepts_a_valid_signature() {
+        let digest = verify(&PUBLIC_KEY, IMAGE, &hex(SIGNATURE)).unwrap();
+        assert_eq!(digest.to_vec(), hex(DIGEST));
+    }
+
+    #[test]
+    fn rejects_a_flipped_image_byte() {
+        let mut image = IMAGE.to_vec();
+        image[7] ^= 0x01;
+        assert_eq!(
+            verify(&PUBLIC_KEY, &image, &hex(SIGNATURE)),
+            Err(VerifyError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn rejects_a_flipped_signature_byte() {
+        let mut signature = hex(SIGNATURE);
+        signature[40] ^= 0x80;
+        assert_eq!(
+            verify(&PUBLIC_KEY, IMAGE, &signature),
+            Err(VerifyError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn rejects_the_wrong_key() {
+        // The key from RFC 8032's second test vector
+        let other =
+            parse_hex_key("3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c");
+        assert_eq!(
+            verify(&other, IMAGE, &hex(SIGNATURE)),
+            Err(VerifyError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn rejects_the_wrong_signature_length() {
+        let signature = hex(SIGNATURE);
+        assert_eq!(
+            verify(&PUBLIC_KEY, IMAGE, &signature[..63]),
+            Err(VerifyError::InvalidSignatureLength(63))
+        );
+
+        let mut longer = signature;
+        longer.push(0);
+        assert_eq!(
+            verify(&PUBLIC_KEY, IMAGE, &longer),
+            Err(VerifyError::InvalidSignatureLength(65))
+        );
+    }
+
+    #[test]
+    fn rejects_an_invalid_public_key() {
+        // y = 2 is not on the curve
+        let mut key = [0; PUBLIC_KEY_LEN];
+        key[0] = 2;
+        assert_eq!(
+            ImageVerifier::new(&key).err(),
+            Some(VerifyError::InvalidPublicKey)
+        );
+    }
+}